@@ -0,0 +1,66 @@
+//! Compares the reference CHIP-8 interpreter with the cached-decode backend.
+//!
+//! Run with `cargo bench`. The benchmark harness isn't stable, so this is a plain
+//! program that times a fixed number of frames with each backend.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use libchapter5::chip_8::assembler::assemble;
+use libchapter5::chip_8::cached::CachedCPU;
+use libchapter5::chip_8::interpreter::CPU;
+
+/// A busy loop of arithmetic, memory access and drawing, typical of a game's main loop.
+const WORKLOAD: &str = "
+        LD   I, sprite
+    loop:
+        RND  V0, 0x3F
+        RND  V1, 0x1F
+        DRW  V0, V1, 4
+        ADD  V2, 1
+        LD   V3, V2
+        SHR  V3, V3
+        XOR  V4, V3
+        ADD  V4, V2
+        SUB  V5, V4
+        SE   V5, 0
+        ADD  V6, 1
+        CALL sub
+        JP   loop
+    sub:
+        LD   V7, V6
+        ADD  V7, V7
+        RET
+    sprite:
+        DB   0xF0, 0x90, 0x90, 0xF0
+";
+
+const FRAMES: usize = 20_000;
+const CYCLES_PER_FRAME: usize = 1_000;
+
+fn time(name: &str, mut run_frame: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        run_frame();
+    }
+    let elapsed = start.elapsed();
+    let mips = (FRAMES * CYCLES_PER_FRAME) as f64 / elapsed.as_secs_f64() / 1e6;
+    println!("{:<10} {:>8.1} ms {:>8.1} M instructions/s", name, elapsed.as_secs_f64() * 1e3, mips);
+    elapsed
+}
+
+fn main() {
+    let mut cpu = CPU::new();
+    cpu.load(&assemble(WORKLOAD).unwrap()).unwrap();
+    let mut cached = CachedCPU::new(cpu.clone());
+
+    let reference = time("reference", || {
+        black_box(cpu.run_frame(CYCLES_PER_FRAME).unwrap());
+    });
+    let fast = time("cached", || {
+        black_box(cached.run_frame(CYCLES_PER_FRAME).unwrap());
+    });
+
+    assert_eq!(cached.cpu(), &cpu, "the backends diverged");
+    println!("speedup    {:>8.2}x", reference.as_secs_f64() / fast.as_secs_f64());
+}
@@ -1,7 +1,7 @@
 use std::ops::Sub;
 
-const BIAS: i32 = 127;
-const RADIX: f32 = 2.0;
+use crate::endian::{Codec, Endian};
+use crate::float_parts::Ieee754;
 
 /// The data type determines what a sequence of bits represents.
 /// Although the bits are the same to the CPU, the type-system makes this distinction.
@@ -19,17 +19,11 @@ pub fn u16_vs_i16() {
 pub fn interpret_a_float_as_an_int() {
     let a: f32 = 42.42;
 
-    /// 'unsafe' tells the Rust compiler,
-    /// “Stand back, I’ll take care of things from here. I’ve got this.”
-    /// It’s a signal to the compiler that you have more context than it
-    /// does to verify the correctness of the program. This doesn't mean
-    /// that the code is unsafe, or that the borrow checker is disabled.
-    /// It just means that the developer is taking responsibility for
-    /// the safety of the code.
-    let frankentype: u32 = unsafe {
-        /// asks rust to naively reinterpret the bits of a float as an integer
-        std::mem::transmute(a)
-    };
+    /// The book does this with `std::mem::transmute`, inside an 'unsafe' block.
+    /// 'unsafe' tells the Rust compiler, “Stand back, I’ll take care of things
+    /// from here.” Reinterpreting a float's bits as an integer doesn't need it:
+    /// `to_bits` gives the same bit pattern, and the compiler checks the sizes.
+    let frankentype: u32 = a.to_bits();
 
     /// '{}' invokes the std::fmt::Display trait
     /// Side Note: '{:?}' invokes std::fmt:: Debug
@@ -40,10 +34,8 @@ pub fn interpret_a_float_as_an_int() {
     println!("int as binary: {:032b}", frankentype);
 
 
-    let b: f32 = unsafe {
-        /// asks rust to naively reinterpret the integer back to a float
-        std::mem::transmute(frankentype)
-    };
+    /// reinterprets the integer's bits back as a float
+    let b: f32 = f32::from_bits(frankentype);
 
     println!("int to float: {}", b);
     assert_eq!(a, b);
@@ -94,11 +86,16 @@ pub fn inspecting_endianness() {
     let big_endian: [u8; 4]    = [0xAA, 0xBB, 0xCC, 0xDD];
     let little_endian: [u8; 4] = [0xDD, 0xCC, 0xBB, 0xAA];
 
-    /// `std::mem::transmute()` instructs the compiler to
-    /// interpret its argument as the type on the left.
-    /// In this case, i32.
-    let a: i32 = unsafe { std::mem::transmute(big_endian)    };
-    let b: i32 = unsafe { std::mem::transmute(little_endian) };
+    /// Reading the bytes in the host's own order is what `std::mem::transmute`
+    /// would do. On a little-endian CPU, the two arrays give different numbers.
+    let a = i32::read_from(&big_endian, Endian::NATIVE).expect("4 bytes make an i32");
+    let b = i32::read_from(&little_endian, Endian::NATIVE).expect("4 bytes make an i32");
+
+    println!("{} vs {}", a, b);
+
+    /// Naming the byte order makes the result the same on every CPU.
+    let a = i32::read_from(&big_endian, Endian::Big).expect("4 bytes make an i32");
+    let b = i32::read_from(&little_endian, Endian::Little).expect("4 bytes make an i32");
 
     println!("{} vs {}", a, b);
 }
@@ -134,55 +131,14 @@ pub fn isolating_and_decoding_the_mantissa_of_a_32bit_floating_point_number(fp:
 }
 
 pub fn deconstruct_a_floating_point_number(fp: f32) -> () {
-    let (sign, exp, frac) = to_parts(fp);
-    let (sign_, exp_, mant) = decode(sign, exp, frac);
-    let n_ = from_parts(sign_, exp_, mant);
+    let parts = fp.to_parts();
+    let n_ = f32::from_parts(parts).expect("parts decoded from a float are valid");
+    let bits = fp.to_bits();
+    let sign = if parts.negative { -1 } else { 1 };
 
-    println!("{} -> {}", fp, n_);
+    println!("{} -> {} ({:?})", fp, n_, parts.category);
     println!("field              |          as bits          | as real number");
-    println!("sign               | {:01b}                         | {}", sign, sign_);
-    println!("exponent           | {:08b}                  | {}", exp, exp_);
-    println!("mantissa           | {:023b}   | {}", frac, mant);
-}
-
-fn to_parts(n: f32) -> (u32, u32, u32) {
-    let bits = n.to_bits();
-
-    let sign     = (bits >> 31) & 1;
-    let exponent = (bits >> 23) & 0xff;
-    let fraction =  bits & 0x7fffff ;
-
-    (sign, exponent, fraction)
-}
-
-fn decode(
-    sign: u32,
-    exponent: u32,
-    fraction: u32
-) -> (f32, f32, f32) {
-    let signed_1 = (-1.0_f32).powf(sign as f32);
-
-    let mut mantissa: f32 = 1.0;
-    let exponent = (exponent as i32) - BIAS;
-    let exponent = RADIX.powf(exponent as f32);
-
-    for i in 0..23 {
-        let mask = 1 << i;
-        let one_at_bit_i = fraction & mask;
-        if one_at_bit_i != 0 {
-            let i_ = i as f32;
-            let weight = 2_f32.powf( i_ - 23.0 );
-            mantissa += weight;
-        }
-    }
-
-    (signed_1, exponent, mantissa)
-}
-
-fn from_parts(
-    sign: f32,
-    exponent: f32,
-    mantissa: f32,
-) -> f32 {
-    sign *  exponent * mantissa
+    println!("sign               | {:01b}                         | {}", bits >> 31, sign);
+    println!("exponent           | {:08b}                  | {}", (bits >> 23) & 0xff, parts.exponent);
+    println!("significand        | {:023b}   | {}", bits & 0x7fffff, parts.significand);
 }
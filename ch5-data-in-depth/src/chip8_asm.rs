@@ -0,0 +1,35 @@
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use libchapter5::chip_8::assembler;
+
+const USAGE: &str = "\
+Usage:
+    chip8-asm <SOURCE> [OUTPUT]
+
+Assembles <SOURCE> into a CHIP-8 ROM that loads at 0x200.
+When [OUTPUT] is omitted, the ROM is written next to <SOURCE> with a .ch8 extension.
+";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let source_path = Path::new(args.get(1).expect(USAGE));
+    let output_path = match args.get(2) {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => source_path.with_extension("ch8"),
+    };
+
+    let source = fs::read_to_string(source_path).expect("failed to read source file");
+
+    let rom = match assembler::assemble(&source) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("{}: {}", source_path.display(), err);
+            process::exit(1);
+        }
+    };
+
+    fs::write(&output_path, &rom).expect("failed to write ROM");
+    println!("{} bytes written to {}", rom.len(), output_path.display());
+}
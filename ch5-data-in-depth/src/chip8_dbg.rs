@@ -0,0 +1,37 @@
+use std::fs;
+use std::io;
+
+use libchapter5::chip_8::debugger::Debugger;
+use libchapter5::chip_8::interpreter::CPU;
+use libchapter5::chip_8::quirks::Quirks;
+
+const USAGE: &str = "\
+Usage:
+    chip8-dbg <ROM> [vip|chip-48|schip]
+
+Loads <ROM> at 0x200 and starts a debugging session. Type 'help' for a list of commands.
+The optional quirks profile defaults to vip.
+";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let rom_path = args.get(1).expect(USAGE);
+
+    let rom = fs::read(rom_path).expect("failed to read ROM");
+
+    let quirks: Quirks = match args.get(2) {
+        Some(name) => name.parse().expect(USAGE),
+        None => Quirks::default(),
+    };
+
+    let mut cpu = CPU::with_quirks(quirks);
+    if let Err(err) = cpu.load(&rom) {
+        eprintln!("{}: {}", rom_path, err);
+        std::process::exit(1);
+    }
+
+    let mut debugger = Debugger::new(cpu);
+    debugger
+        .repl(io::stdin().lock(), io::stdout())
+        .expect("failed to read commands");
+}
@@ -0,0 +1,21 @@
+use std::fs;
+
+use libchapter5::chip_8::assembler::PROGRAM_START;
+use libchapter5::chip_8::disassembler;
+
+const USAGE: &str = "\
+Usage:
+    chip8-dis <ROM>
+
+Prints an assembly listing of <ROM>, which is assumed to load at 0x200.
+The listing can be assembled again with chip8-asm.
+";
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let rom_path = args.get(1).expect(USAGE);
+
+    let rom = fs::read(rom_path).expect("failed to read ROM");
+
+    print!("{}", disassembler::disassemble(&rom, PROGRAM_START));
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::path::Path;
+
+use libchapter5::chip_8::fuzz::{check, regression_name, silence_checked_panics, Generator, REGRESSIONS_DIR};
+use libchapter5::chip_8::quirks::Quirks;
+
+const USAGE: &str = "\
+Usage:
+    chip8-fuzz [ITERATIONS] [SEED]
+
+Runs ITERATIONS (default 10000) random programs under every quirks profile, checking the
+interpreter's invariants and comparing it with the cached-decode backend. Failing inputs
+are saved as ROMs in the crate's fuzz/regressions, where the tests pick them up.
+";
+
+const INSTRUCTIONS: usize = 64;
+const CYCLES: usize = 2_000;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let iterations: usize = match args.get(1) {
+        Some(n) => n.parse().expect(USAGE),
+        None => 10_000,
+    };
+    let seed: u64 = match args.get(2) {
+        Some(seed) => seed.parse().expect(USAGE),
+        None => 1,
+    };
+
+    // Panics in the programs are reported as failures, so the hook's message is just noise.
+    silence_checked_panics();
+    let regressions = Path::new(env!("CARGO_MANIFEST_DIR")).join(REGRESSIONS_DIR);
+
+    let mut generator = Generator::new(seed);
+    let mut failures = 0;
+
+    for _ in 0..iterations {
+        let rom = generator.rom(INSTRUCTIONS);
+        for quirks in [Quirks::COSMAC_VIP, Quirks::CHIP_48, Quirks::SUPER_CHIP] {
+            if let Err(failure) = check(&rom, quirks, CYCLES) {
+                failures += 1;
+                let path = regressions.join(regression_name(quirks, &rom));
+                fs::create_dir_all(&regressions).expect("failed to create the regressions directory");
+                fs::write(&path, &rom).expect("failed to save the failing ROM");
+                println!("{}: {}", path.display(), failure);
+            }
+        }
+    }
+
+    println!("{} programs, {} failures", iterations, failures);
+    if failures > 0 {
+        std::process::exit(1);
+    }
+}
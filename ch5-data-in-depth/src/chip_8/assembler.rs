@@ -0,0 +1,462 @@
+//! A two-pass assembler for CHIP-8 programs.
+//!
+//! Rather than writing `[0x80, 0x14, 0x80, 0x14, 0x00, 0xEE]` by hand, programs
+//! can be written using the mnemonics from Cowgod's CHIP-8 technical reference:
+//!
+//! ```text
+//! ; adds register 1 to register 0 twice
+//!     CALL add_twice
+//!     HALT
+//!
+//! add_twice:
+//!     ADD V0, V1
+//!     ADD V0, V1
+//!     RET
+//! ```
+//!
+//! - The first pass works out the address of every label.
+//! - The second pass encodes each instruction, now that all labels are known.
+//!
+//! Syntax:
+//! - `label:` defines a label. It may be followed by an instruction on the same line.
+//!   Register names and the operands `I`, `DT`, `ST`, `K`, `F`, `HF`, `B` and `R` can't be used as labels.
+//! - `;` starts a comment that runs until the end of the line.
+//! - Numbers can be written in decimal (`42`), hexadecimal (`0x2A`, `#2A`) or binary (`0b101010`).
+//! - `DB 0xF0, 0x90` emits bytes and `DW 0x8014, label` emits big-endian words.
+//! - `HALT` emits `0x0000`, the stopping condition used by the CPUs in this module.
+//! - The SUPER-CHIP instructions are available as `SCD n`, `SCR`, `SCL`, `EXIT`, `LOW`, `HIGH`,
+//!   `LD HF, Vx`, `LD R, Vx` and `LD Vx, R`.
+
+use std::collections::HashMap;
+use std::{error, fmt};
+
+/// CHIP-8 programs are conventionally loaded at 0x200. The memory below
+/// was reserved for the interpreter itself on the original hardware.
+pub const PROGRAM_START: u16 = 0x200;
+
+/// The CHIP-8 has 4KB of memory, which is addressable with 12 bits (`nnn`).
+pub const MEMORY_SIZE: usize = 0x1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    InvalidNumber(String),
+    InvalidLabel(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+    ValueOutOfRange { value: u16, max: u16 },
+    ProgramTooLarge(usize),
+}
+
+/// An error raised while assembling, along with the (1-based) source line that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub kind: AsmErrorKind,
+}
+
+impl fmt::Display for AsmErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErrorKind::UnknownMnemonic(m) => write!(f, "unknown mnemonic '{}'", m),
+            AsmErrorKind::InvalidOperands(m) => write!(f, "invalid operands for '{}'", m),
+            AsmErrorKind::InvalidNumber(n) => write!(f, "invalid number '{}'", n),
+            AsmErrorKind::InvalidLabel(l) => write!(f, "invalid label name '{}'", l),
+            AsmErrorKind::UndefinedLabel(l) => write!(f, "undefined label '{}'", l),
+            AsmErrorKind::DuplicateLabel(l) => write!(f, "label '{}' is already defined", l),
+            AsmErrorKind::ValueOutOfRange { value, max } => {
+                write!(f, "value {:#x} does not fit (maximum is {:#x})", value, max)
+            }
+            AsmErrorKind::ProgramTooLarge(size) => {
+                write!(f, "program is {} bytes long and does not fit into memory", size)
+            }
+        }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl error::Error for AsmError {}
+
+/// A value that is either known straight away, or refers to a label
+/// whose address is only known after the first pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(u16),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    I,
+    IndirectI,
+    DT,
+    ST,
+    K,
+    F,
+    HF,
+    B,
+    R,
+    Value(Expr),
+}
+
+#[derive(Debug)]
+enum Statement {
+    Instruction { mnemonic: String, operands: Vec<Operand> },
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match self {
+            Statement::Instruction { .. } => 2,
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => 2 * values.len(),
+        }
+    }
+}
+
+/// Assembles `source` into a ROM that is loaded at [`PROGRAM_START`].
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_at(source, PROGRAM_START)
+}
+
+/// Assembles `source` into a ROM that is loaded at `origin`. Labels resolve relative to `origin`.
+pub fn assemble_at(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    // Pass 1: parse every line and record the address of each label.
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut statements: Vec<(usize, Statement)> = Vec::new();
+    let mut address = origin as usize;
+
+    for (i, raw_line) in source.lines().enumerate() {
+        let line = i + 1;
+        let err = |kind| AsmError { line, kind };
+
+        let mut text = match raw_line.find(';') {
+            Some(comment_start) => &raw_line[..comment_start],
+            None => raw_line,
+        }
+        .trim();
+
+        if let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_valid_label(label) {
+                return Err(err(AsmErrorKind::InvalidLabel(label.to_string())));
+            }
+            if labels.insert(label.to_string(), address as u16).is_some() {
+                return Err(err(AsmErrorKind::DuplicateLabel(label.to_string())));
+            }
+            text = text[colon + 1..].trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        let statement = parse_statement(text).map_err(err)?;
+        address += statement.size();
+        if address > MEMORY_SIZE {
+            return Err(err(AsmErrorKind::ProgramTooLarge(address - origin as usize)));
+        }
+        statements.push((line, statement));
+    }
+
+    // Pass 2: encode each statement, now that every label has an address.
+    let mut rom = Vec::with_capacity(address - origin as usize);
+
+    for (line, statement) in &statements {
+        let err = |kind| AsmError { line: *line, kind };
+
+        match statement {
+            Statement::Instruction { mnemonic, operands } => {
+                let opcode = encode(mnemonic, operands, &labels).map_err(err)?;
+                rom.extend_from_slice(&opcode.to_be_bytes());
+            }
+            Statement::Bytes(values) => {
+                for value in values {
+                    let byte = resolve(value, &labels, 0xFF).map_err(err)?;
+                    rom.push(byte as u8);
+                }
+            }
+            Statement::Words(values) => {
+                for value in values {
+                    let word = resolve(value, &labels, 0xFFFF).map_err(err)?;
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+            }
+        }
+    }
+
+    Ok(rom)
+}
+
+fn is_valid_label(label: &str) -> bool {
+    let mut chars = label.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    if !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return false;
+    }
+    parse_operand(label) == Ok(Operand::Value(Expr::Label(label.to_string())))
+}
+
+fn parse_statement(text: &str) -> Result<Statement, AsmErrorKind> {
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_uppercase();
+
+    let operands: Vec<&str> = if rest.is_empty() {
+        Vec::new()
+    } else {
+        rest.split(',').map(str::trim).collect()
+    };
+
+    match mnemonic.as_str() {
+        "DB" | "DW" => {
+            let mut values = Vec::with_capacity(operands.len());
+            for operand in operands {
+                match parse_operand(operand)? {
+                    Operand::Value(value) => values.push(value),
+                    _ => return Err(AsmErrorKind::InvalidOperands(mnemonic)),
+                }
+            }
+            if values.is_empty() {
+                return Err(AsmErrorKind::InvalidOperands(mnemonic));
+            }
+            if mnemonic == "DB" {
+                Ok(Statement::Bytes(values))
+            } else {
+                Ok(Statement::Words(values))
+            }
+        }
+        _ => {
+            let operands = operands
+                .into_iter()
+                .map(parse_operand)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Statement::Instruction { mnemonic, operands })
+        }
+    }
+}
+
+fn parse_operand(text: &str) -> Result<Operand, AsmErrorKind> {
+    let upper = text.to_ascii_uppercase();
+
+    let operand = match upper.as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::IndirectI,
+        "DT" => Operand::DT,
+        "ST" => Operand::ST,
+        "K" => Operand::K,
+        "F" => Operand::F,
+        "HF" => Operand::HF,
+        "B" => Operand::B,
+        "R" => Operand::R,
+        _ => {
+            if let Some(register) = parse_register(&upper) {
+                Operand::V(register)
+            } else if text.starts_with(|c: char| c.is_ascii_digit() || c == '#') {
+                Operand::Value(Expr::Number(parse_number(text)?))
+            } else if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                Operand::Value(Expr::Label(text.to_string()))
+            } else {
+                return Err(AsmErrorKind::InvalidNumber(text.to_string()));
+            }
+        }
+    };
+
+    Ok(operand)
+}
+
+fn parse_register(upper: &str) -> Option<u8> {
+    let digit = upper.strip_prefix('V')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn parse_number(text: &str) -> Result<u16, AsmErrorKind> {
+    let lower = text.to_ascii_lowercase();
+
+    let parsed = if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('#')) {
+        u16::from_str_radix(hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        u16::from_str_radix(&bin.replace('_', ""), 2)
+    } else {
+        lower.parse::<u16>()
+    };
+
+    parsed.map_err(|_| AsmErrorKind::InvalidNumber(text.to_string()))
+}
+
+fn resolve(value: &Expr, labels: &HashMap<String, u16>, max: u16) -> Result<u16, AsmErrorKind> {
+    let n = match value {
+        Expr::Number(n) => *n,
+        Expr::Label(name) => *labels
+            .get(name)
+            .ok_or_else(|| AsmErrorKind::UndefinedLabel(name.clone()))?,
+    };
+
+    if n > max {
+        return Err(AsmErrorKind::ValueOutOfRange { value: n, max });
+    }
+
+    Ok(n)
+}
+
+/// Translates a single instruction into its opcode. The layout of the opcode
+/// mirrors the decoding performed by `CPU::run`: `c` is the opcode group,
+/// `x` and `y` are registers, `d` is the subtype, `kk` a byte and `nnn` an address.
+fn encode(mnemonic: &str, operands: &[Operand], labels: &HashMap<String, u16>) -> Result<u16, AsmErrorKind> {
+    use Operand::*;
+
+    let nnn = |v: &Expr| resolve(v, labels, 0x0FFF);
+    let kk = |v: &Expr| resolve(v, labels, 0x00FF);
+    let xy = |c: u16, x: u8, y: u8, d: u16| c << 12 | (x as u16) << 8 | (y as u16) << 4 | d;
+    let xkk = |c: u16, x: u8, kk: u16| c << 12 | (x as u16) << 8 | kk;
+
+    let opcode = match (mnemonic, operands) {
+        ("HALT", []) => 0x0000,
+        ("CLS", []) => 0x00E0,
+        ("RET", []) => 0x00EE,
+        ("SCD", [Value(n)]) => 0x00C0 | resolve(n, labels, 0xF)?,
+        ("SCR", []) => 0x00FB,
+        ("SCL", []) => 0x00FC,
+        ("EXIT", []) => 0x00FD,
+        ("LOW", []) => 0x00FE,
+        ("HIGH", []) => 0x00FF,
+        ("SYS", [Value(a)]) => nnn(a)?,
+        ("JP", [Value(a)]) => 0x1000 | nnn(a)?,
+        ("JP", [V(0), Value(a)]) => 0xB000 | nnn(a)?,
+        ("CALL", [Value(a)]) => 0x2000 | nnn(a)?,
+        ("SE", [V(x), Value(b)]) => xkk(0x3, *x, kk(b)?),
+        ("SNE", [V(x), Value(b)]) => xkk(0x4, *x, kk(b)?),
+        ("SE", [V(x), V(y)]) => xy(0x5, *x, *y, 0x0),
+        ("LD", [V(x), Value(b)]) => xkk(0x6, *x, kk(b)?),
+        ("ADD", [V(x), Value(b)]) => xkk(0x7, *x, kk(b)?),
+        ("LD", [V(x), V(y)]) => xy(0x8, *x, *y, 0x0),
+        ("OR", [V(x), V(y)]) => xy(0x8, *x, *y, 0x1),
+        ("AND", [V(x), V(y)]) => xy(0x8, *x, *y, 0x2),
+        ("XOR", [V(x), V(y)]) => xy(0x8, *x, *y, 0x3),
+        ("ADD", [V(x), V(y)]) => xy(0x8, *x, *y, 0x4),
+        ("SUB", [V(x), V(y)]) => xy(0x8, *x, *y, 0x5),
+        ("SHR", [V(x)]) => xy(0x8, *x, 0, 0x6),
+        ("SHR", [V(x), V(y)]) => xy(0x8, *x, *y, 0x6),
+        ("SUBN", [V(x), V(y)]) => xy(0x8, *x, *y, 0x7),
+        ("SHL", [V(x)]) => xy(0x8, *x, 0, 0xE),
+        ("SHL", [V(x), V(y)]) => xy(0x8, *x, *y, 0xE),
+        ("SNE", [V(x), V(y)]) => xy(0x9, *x, *y, 0x0),
+        ("LD", [I, Value(a)]) => 0xA000 | nnn(a)?,
+        ("RND", [V(x), Value(b)]) => xkk(0xC, *x, kk(b)?),
+        ("DRW", [V(x), V(y), Value(n)]) => xy(0xD, *x, *y, resolve(n, labels, 0xF)?),
+        ("SKP", [V(x)]) => xkk(0xE, *x, 0x9E),
+        ("SKNP", [V(x)]) => xkk(0xE, *x, 0xA1),
+        ("LD", [V(x), DT]) => xkk(0xF, *x, 0x07),
+        ("LD", [V(x), K]) => xkk(0xF, *x, 0x0A),
+        ("LD", [DT, V(x)]) => xkk(0xF, *x, 0x15),
+        ("LD", [ST, V(x)]) => xkk(0xF, *x, 0x18),
+        ("ADD", [I, V(x)]) => xkk(0xF, *x, 0x1E),
+        ("LD", [F, V(x)]) => xkk(0xF, *x, 0x29),
+        ("LD", [HF, V(x)]) => xkk(0xF, *x, 0x30),
+        ("LD", [B, V(x)]) => xkk(0xF, *x, 0x33),
+        ("LD", [IndirectI, V(x)]) => xkk(0xF, *x, 0x55),
+        ("LD", [V(x), IndirectI]) => xkk(0xF, *x, 0x65),
+        ("LD", [R, V(x)]) => xkk(0xF, *x, 0x75),
+        ("LD", [V(x), R]) => xkk(0xF, *x, 0x85),
+        _ => return Err(if is_mnemonic(mnemonic) {
+            AsmErrorKind::InvalidOperands(mnemonic.to_string())
+        } else {
+            AsmErrorKind::UnknownMnemonic(mnemonic.to_string())
+        }),
+    };
+
+    Ok(opcode)
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    matches!(
+        mnemonic,
+        "HALT" | "CLS" | "RET" | "SCD" | "SCR" | "SCL" | "EXIT" | "LOW" | "HIGH" | "SYS" | "JP" | "CALL" | "SE" | "SNE" | "LD" | "ADD" | "OR" | "AND"
+            | "XOR" | "SUB" | "SHR" | "SUBN" | "SHL" | "RND" | "DRW" | "SKP" | "SKNP"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_twice() {
+        let source = "
+            add_twice: ADD V0, V1  ; 0x8014
+                       ADD V0, V1  ; 0x8014
+                       RET         ; 0x00EE
+        ";
+
+        let add_twice = assemble(source).unwrap();
+
+        assert_eq!(add_twice, [0x80, 0x14, 0x80, 0x14, 0x00, 0xEE]);
+    }
+
+    #[test]
+    fn forward_references_and_data() {
+        let source = "
+                CALL add_twice
+                LD I, sprite
+                HALT
+            add_twice:
+                ADD V0, V1
+                RET
+            sprite:
+                DB 0xF0, #90, 0b1001_0000
+                DW add_twice
+        ";
+
+        let rom = assemble_at(source, 0x100).unwrap();
+
+        assert_eq!(
+            rom,
+            [
+                0x21, 0x06, // CALL 0x106
+                0xA1, 0x0A, // LD I, 0x10A
+                0x00, 0x00, // HALT
+                0x80, 0x14, // ADD V0, V1
+                0x00, 0xEE, // RET
+                0xF0, 0x90, 0x90,
+                0x01, 0x06,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_report_line_numbers() {
+        let err = assemble("CLS\nJP nowhere\n").unwrap_err();
+        assert_eq!(err, AsmError { line: 2, kind: AsmErrorKind::UndefinedLabel("nowhere".to_string()) });
+        assert_eq!(err.to_string(), "line 2: undefined label 'nowhere'");
+
+        let err = assemble("CLS\n\nMOV V0, V1").unwrap_err();
+        assert_eq!(err.kind, AsmErrorKind::UnknownMnemonic("MOV".to_string()));
+        assert_eq!(err.line, 3);
+
+        let err = assemble("ADD V0, 256").unwrap_err();
+        assert_eq!(err.kind, AsmErrorKind::ValueOutOfRange { value: 256, max: 0xFF });
+
+        let err = assemble("ADD I, 3").unwrap_err();
+        assert_eq!(err.kind, AsmErrorKind::InvalidOperands("ADD".to_string()));
+
+        let err = assemble("a: CLS\na: CLS").unwrap_err();
+        assert_eq!(err, AsmError { line: 2, kind: AsmErrorKind::DuplicateLabel("a".to_string()) });
+    }
+}
@@ -0,0 +1,249 @@
+//! The CHIP-8 has a single sound: a buzzer that plays while the sound timer is non-zero.
+//!
+//! Each time the timers tick (60 times per second), the CPU tells an [`AudioSink`]
+//! whether the buzzer was on during the period that just ended. Sinks can play the
+//! tone, ignore it ([`NullSink`]) or record it ([`WavSink`]), which makes the sound
+//! a program produces testable without a sound card.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The rate at which the delay and sound timers count down.
+pub const TIMER_HZ: u32 = 60;
+/// The highest sample rate a [`WavSink`] accepts, well above what sound cards play.
+pub const MAX_SAMPLE_RATE: u32 = 768_000;
+
+pub trait AudioSink {
+    /// Called once per timer tick, i.e. for every 1/60th of a second of emulated time.
+    fn tick(&mut self, beeping: bool);
+}
+
+/// Discards all audio.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn tick(&mut self, _beeping: bool) {}
+}
+
+/// Writes a 16-bit mono PCM WAV file. Each tick produces 1/60th of a second
+/// of either a square-wave tone or silence.
+///
+/// The WAV header records the length of the data that follows it, so
+/// [`WavSink::finish`] must be called once all ticks have been written.
+/// Like `BufWriter`, write errors are held on to and reported by `finish`.
+///
+/// That length is a `u32`, which limits a WAV file to 4 GiB: about 6.7 hours at 44.1kHz.
+/// Once it's full, further ticks are dropped and [`WavSink::is_full`] returns true.
+pub struct WavSink<W: Write + Seek> {
+    writer: W,
+    sample_rate: u32,
+    frequency: u32,
+    amplitude: i16,
+    samples_written: u64,
+    /// Position within the current cycle of the square wave, in samples × frequency.
+    phase: u64,
+    error: Option<io::Error>,
+}
+
+const HEADER_LEN: u32 = 44;
+const BYTES_PER_SAMPLE: u32 = 2;
+/// The most samples that fit, given that the RIFF chunk size also counts the rest of the header.
+const MAX_SAMPLES: u64 = ((u32::MAX - (HEADER_LEN - 8)) / BYTES_PER_SAMPLE) as u64;
+
+impl WavSink<BufWriter<File>> {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        WavSink::new(BufWriter::new(File::create(path)?), 44_100, 440)
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    /// `sample_rate` must be a multiple of 60, so that every tick lasts exactly the same number of
+    /// samples, and at most [`MAX_SAMPLE_RATE`]. Other rates are an `InvalidInput` error.
+    pub fn new(mut writer: W, sample_rate: u32, frequency: u32) -> io::Result<Self> {
+        if sample_rate == 0 || !sample_rate.is_multiple_of(TIMER_HZ) || sample_rate > MAX_SAMPLE_RATE {
+            let message = format!(
+                "sample rate {} Hz isn't a multiple of {} Hz up to {} Hz",
+                sample_rate, TIMER_HZ, MAX_SAMPLE_RATE
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        write_header(&mut writer, sample_rate, 0)?;
+
+        Ok(WavSink {
+            writer,
+            sample_rate,
+            frequency,
+            amplitude: i16::MAX / 4,
+            samples_written: 0,
+            phase: 0,
+            error: None,
+        })
+    }
+
+    pub fn samples_written(&self) -> u64 {
+        self.samples_written
+    }
+
+    /// Whether the file has reached the 4 GiB limit of WAV, so that ticks are being dropped.
+    pub fn is_full(&self) -> bool {
+        self.samples_written == MAX_SAMPLES
+    }
+
+    /// Fills in the sizes in the header and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, self.sample_rate, self.samples_written)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn tick(&mut self, beeping: bool) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let samples = ((self.sample_rate / TIMER_HZ) as u64).min(MAX_SAMPLES - self.samples_written);
+        let period = self.sample_rate as u64;
+        let mut buffer = Vec::with_capacity(2 * samples as usize);
+
+        for _ in 0..samples {
+            let sample = if !beeping {
+                0
+            } else if self.phase < period / 2 {
+                self.amplitude
+            } else {
+                -self.amplitude
+            };
+            // The phase keeps running during silence, so the wave never restarts mid-cycle.
+            self.phase = (self.phase + self.frequency as u64) % period;
+            buffer.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        match self.writer.write_all(&buffer) {
+            Ok(()) => self.samples_written += samples,
+            Err(err) => self.error = Some(err),
+        }
+    }
+}
+
+/// WAV files are little-endian, unlike CHIP-8.
+fn write_header(w: &mut impl Write, sample_rate: u32, samples: u64) -> io::Result<()> {
+    const CHANNELS: u16 = 1;
+    const BITS_PER_SAMPLE: u16 = 16;
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    debug_assert!(samples <= MAX_SAMPLES);
+    let data_len = samples as u32 * block_align as u32;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(HEADER_LEN - 8 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&CHANNELS.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::assemble;
+    use crate::chip_8::interpreter::CPU;
+    use std::io::Cursor;
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        wav[HEADER_LEN as usize..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn writes_a_valid_header() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new()), 48_000, 1_000).unwrap();
+        sink.tick(true);
+        sink.tick(false);
+        let wav = sink.finish().unwrap().into_inner();
+
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32::from_le_bytes(wav[24..28].try_into().unwrap()), 48_000);
+        assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 2 * 1_600);
+        assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()) as usize, wav.len() - 8);
+    }
+
+    #[test]
+    fn rejects_unusable_sample_rates() {
+        for sample_rate in [0, 44_101, MAX_SAMPLE_RATE + TIMER_HZ, 2_147_483_640] {
+            let err = WavSink::new(Cursor::new(Vec::new()), sample_rate, 440).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{} Hz", sample_rate);
+        }
+
+        let sink = WavSink::new(Cursor::new(Vec::new()), MAX_SAMPLE_RATE, 440).unwrap();
+        let wav = sink.finish().unwrap().into_inner();
+        assert_eq!(u32::from_le_bytes(wav[28..32].try_into().unwrap()), 2 * MAX_SAMPLE_RATE);
+    }
+
+    #[test]
+    fn stops_at_the_size_limit() {
+        let mut sink = WavSink::new(Cursor::new(Vec::new()), 48_000, 1_000).unwrap();
+        // As if hours had already been recorded.
+        sink.samples_written = MAX_SAMPLES - 500;
+        sink.writer.seek(SeekFrom::End(0)).unwrap();
+
+        sink.tick(true);
+        assert!(sink.is_full());
+        sink.tick(true);
+        assert_eq!(sink.samples_written(), MAX_SAMPLES);
+        let wav = sink.finish().unwrap().into_inner();
+
+        assert_eq!(wav.len(), HEADER_LEN as usize + 2 * 500);
+        let riff_len = u32::from_le_bytes(wav[4..8].try_into().unwrap());
+        let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+        assert_eq!(data_len as u64, 2 * MAX_SAMPLES);
+        assert_eq!(riff_len, data_len + (HEADER_LEN - 8));
+        assert!(u32::MAX - riff_len < 2);
+    }
+
+    #[test]
+    fn tone_follows_the_sound_timer() {
+        // Sound for 3 ticks, then idle.
+        let source = "
+                LD   V0, 3
+                LD   ST, V0
+            idle:
+                JP   idle
+        ";
+        let mut cpu = CPU::new();
+        cpu.load(&assemble(source).unwrap()).unwrap();
+
+        let mut sink = WavSink::new(Cursor::new(Vec::new()), 48_000, 1_000).unwrap();
+        for _ in 0..5 {
+            cpu.run_frame_with(10, &mut sink).unwrap();
+        }
+        let samples = samples(&sink.finish().unwrap().into_inner());
+
+        let per_tick = 48_000 / 60;
+        assert_eq!(samples.len(), 5 * per_tick);
+
+        // A 1kHz square wave at 48kHz: 24 samples high, then 24 samples low.
+        let tone = &samples[..3 * per_tick];
+        assert!(tone[..24].iter().all(|&s| s > 0));
+        assert!(tone[24..48].iter().all(|&s| s < 0));
+        assert!(samples[3 * per_tick..].iter().all(|&s| s == 0));
+        assert_eq!(cpu.sound_timer, 0);
+    }
+}
@@ -0,0 +1,492 @@
+//! A faster backend for CPU RIA/4. The [reference interpreter](super::interpreter) reads
+//! an opcode from memory and picks it apart into nibbles on every cycle. [`CachedCPU`]
+//! decodes every address in memory once, up front, into a compact instruction, so that
+//! executing a cycle is a table lookup and a single `match`.
+//!
+//! CHIP-8 programs are free to modify themselves: `Fx33` and `Fx55` write to memory, and
+//! nothing stops them writing over code. Those two instructions re-decode the addresses
+//! they write to. Everything else that could change memory or the quirks profile goes
+//! through [`CachedCPU::cpu_mut`], after which the whole cache is rebuilt.
+//!
+//! Both backends must behave identically, down to where the program counter is left
+//! after a fault. The tests below check that by comparing the state after every step.
+
+use super::assembler::MEMORY_SIZE;
+use super::audio::{AudioSink, NullSink};
+use super::interpreter::{Fault, RomTooLarge, State, BIG_FONT_START, CPU, FONT_START};
+use super::opcode::Opcode;
+use super::quirks::Quirks;
+
+/// A decoded instruction. Registers are stored as `u8` to keep the cache small:
+/// every variant fits in 4 bytes.
+///
+/// Quirks that change which instruction an opcode is, such as SUPER-CHIP's extra
+/// opcodes and the register `Bnnn` adds, are resolved when decoding. The others are
+/// left to the CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Instr {
+    Halt,
+    ScrollDown(u8),
+    ScrollRight,
+    ScrollLeft,
+    Lores,
+    Hires,
+    Cls,
+    Ret,
+    Jp(u16),
+    Call(u16),
+    SeImm(u8, u8),
+    SneImm(u8, u8),
+    SeReg(u8, u8),
+    LdImm(u8, u8),
+    AddImm(u8, u8),
+    LdReg(u8, u8),
+    Or(u8, u8),
+    And(u8, u8),
+    Xor(u8, u8),
+    Add(u8, u8),
+    Sub(u8, u8),
+    Shr(u8, u8),
+    Subn(u8, u8),
+    Shl(u8, u8),
+    SneReg(u8, u8),
+    LdI(u16),
+    JpOffset(u16, u8),
+    Rnd(u8, u8),
+    Drw(u8, u8, u8),
+    Skp(u8),
+    Sknp(u8),
+    LdVxDt(u8),
+    LdVxK(u8),
+    LdDt(u8),
+    LdSt(u8),
+    AddI(u8),
+    LdF(u8),
+    LdHf(u8),
+    Bcd(u8),
+    Store(u8),
+    Load(u8),
+    StoreFlags(u8),
+    LoadFlags(u8),
+    Unknown(u16),
+}
+
+fn decode(opcode: u16, quirks: Quirks) -> Instr {
+    let Opcode { c, x, y, d, nnn, kk } = Opcode::from(opcode);
+    let schip = quirks.super_chip;
+
+    match (c, x, y, d) {
+        (0, 0, 0, 0) => Instr::Halt,
+        (0, 0, 0xF, 0xD) if schip => Instr::Halt,
+        (0, 0, 0xC, _) if schip => Instr::ScrollDown(d),
+        (0, 0, 0xF, 0xB) if schip => Instr::ScrollRight,
+        (0, 0, 0xF, 0xC) if schip => Instr::ScrollLeft,
+        (0, 0, 0xF, 0xE) if schip => Instr::Lores,
+        (0, 0, 0xF, 0xF) if schip => Instr::Hires,
+        (0, 0, 0xE, 0x0) => Instr::Cls,
+        (0, 0, 0xE, 0xE) => Instr::Ret,
+        (0x1, _, _, _) => Instr::Jp(nnn),
+        (0x2, _, _, _) => Instr::Call(nnn),
+        (0x3, _, _, _) => Instr::SeImm(x, kk),
+        (0x4, _, _, _) => Instr::SneImm(x, kk),
+        (0x5, _, _, 0x0) => Instr::SeReg(x, y),
+        (0x6, _, _, _) => Instr::LdImm(x, kk),
+        (0x7, _, _, _) => Instr::AddImm(x, kk),
+        (0x8, _, _, 0x0) => Instr::LdReg(x, y),
+        (0x8, _, _, 0x1) => Instr::Or(x, y),
+        (0x8, _, _, 0x2) => Instr::And(x, y),
+        (0x8, _, _, 0x3) => Instr::Xor(x, y),
+        (0x8, _, _, 0x4) => Instr::Add(x, y),
+        (0x8, _, _, 0x5) => Instr::Sub(x, y),
+        (0x8, _, _, 0x6) => Instr::Shr(x, y),
+        (0x8, _, _, 0x7) => Instr::Subn(x, y),
+        (0x8, _, _, 0xE) => Instr::Shl(x, y),
+        (0x9, _, _, 0x0) => Instr::SneReg(x, y),
+        (0xA, _, _, _) => Instr::LdI(nnn),
+        (0xB, _, _, _) => Instr::JpOffset(nnn, if quirks.jump_uses_vx { x } else { 0 }),
+        (0xC, _, _, _) => Instr::Rnd(x, kk),
+        (0xD, _, _, _) => Instr::Drw(x, y, d),
+        (0xE, _, 0x9, 0xE) => Instr::Skp(x),
+        (0xE, _, 0xA, 0x1) => Instr::Sknp(x),
+        (0xF, _, 0x0, 0x7) => Instr::LdVxDt(x),
+        (0xF, _, 0x0, 0xA) => Instr::LdVxK(x),
+        (0xF, _, 0x1, 0x5) => Instr::LdDt(x),
+        (0xF, _, 0x1, 0x8) => Instr::LdSt(x),
+        (0xF, _, 0x1, 0xE) => Instr::AddI(x),
+        (0xF, _, 0x2, 0x9) => Instr::LdF(x),
+        (0xF, _, 0x3, 0x0) if schip => Instr::LdHf(x),
+        (0xF, _, 0x3, 0x3) => Instr::Bcd(x),
+        (0xF, _, 0x5, 0x5) => Instr::Store(x),
+        (0xF, _, 0x6, 0x5) => Instr::Load(x),
+        (0xF, _, 0x7, 0x5) if schip => Instr::StoreFlags(x.min(7)),
+        (0xF, _, 0x8, 0x5) if schip => Instr::LoadFlags(x.min(7)),
+        _ => Instr::Unknown(opcode),
+    }
+}
+
+/// Wraps a [`CPU`] with a cache holding the decoded instruction at every address.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct CachedCPU {
+    cpu: CPU,
+    /// One entry per address that an opcode can start at, i.e. all but the last byte of memory.
+    cache: Box<[Instr]>,
+    /// Set by [`CachedCPU::cpu_mut`], which can't know what the caller changed.
+    stale: bool,
+}
+
+impl CachedCPU {
+    pub fn new(cpu: CPU) -> Self {
+        let mut cached = CachedCPU {
+            cpu,
+            cache: vec![Instr::Halt; MEMORY_SIZE - 1].into_boxed_slice(),
+            stale: true,
+        };
+        cached.decode_all();
+        cached
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    /// Gives full access to the CPU. The cache is rebuilt before the next step, since
+    /// the caller may have changed memory or the quirks profile.
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        self.stale = true;
+        &mut self.cpu
+    }
+
+    /// Hosts set the keypad every frame, which needn't invalidate the cache.
+    pub fn set_keypad(&mut self, keypad: [bool; 16]) {
+        self.cpu.keypad = keypad;
+    }
+
+    pub fn tick_timers(&mut self) {
+        self.cpu.tick_timers();
+    }
+
+    pub fn into_inner(self) -> CPU {
+        self.cpu
+    }
+
+    pub fn load(&mut self, rom: &[u8]) -> Result<(), RomTooLarge> {
+        self.cpu_mut().load(rom)
+    }
+
+    fn decode_all(&mut self) {
+        self.redecode(0, self.cache.len());
+        self.stale = false;
+    }
+
+    /// Re-decodes after `start..end` has been written to. The instruction that starts
+    /// one byte earlier overlaps the write too.
+    fn redecode(&mut self, start: usize, end: usize) {
+        let memory = &self.cpu.memory;
+        let end = end.min(self.cache.len());
+        for address in start.saturating_sub(1)..end {
+            let opcode = u16::from_be_bytes([memory[address], memory[address + 1]]);
+            self.cache[address] = decode(opcode, self.cpu.quirks);
+        }
+    }
+
+    /// Runs until the CPU halts or faults.
+    pub fn run(&mut self) -> Result<(), Fault> {
+        if self.stale {
+            self.decode_all();
+        }
+
+        loop {
+            if self.execute()? == State::Halted {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes `cycles` instructions and then ticks the timers once, like [`CPU::run_frame`].
+    pub fn run_frame(&mut self, cycles: usize) -> Result<State, Fault> {
+        self.run_frame_with(cycles, &mut NullSink)
+    }
+
+    pub fn run_frame_with(&mut self, cycles: usize, audio: &mut dyn AudioSink) -> Result<State, Fault> {
+        if self.stale {
+            self.decode_all();
+        }
+
+        let mut state = State::Running;
+        for _ in 0..cycles {
+            state = self.execute()?;
+            if state != State::Running {
+                break;
+            }
+        }
+        audio.tick(self.cpu.beeping());
+        self.cpu.tick_timers();
+        Ok(state)
+    }
+
+    /// Executes one instruction, with exactly the same effect as [`CPU::step`].
+    pub fn step(&mut self) -> Result<State, Fault> {
+        if self.stale {
+            self.decode_all();
+        }
+        self.execute()
+    }
+
+    /// Nothing that `execute` does can leave the cache stale, so loops only need to
+    /// check for that once.
+    #[inline]
+    fn execute(&mut self) -> Result<State, Fault> {
+        let address = self.cpu.position_in_memory;
+        let Some(&instr) = self.cache.get(address) else {
+            return Err(Fault::ProgramCounterOutOfBounds { address });
+        };
+        let cpu = &mut self.cpu;
+        cpu.position_in_memory += 2;
+
+        match instr {
+            Instr::Halt => {
+                cpu.position_in_memory = address;
+                return Ok(State::Halted);
+            }
+            Instr::ScrollDown(n) => cpu.scroll_down(n as usize),
+            Instr::ScrollRight => cpu.scroll_right(4),
+            Instr::ScrollLeft => cpu.scroll_left(4),
+            Instr::Lores => cpu.set_hires(false),
+            Instr::Hires => cpu.set_hires(true),
+            Instr::Cls => cpu.clear_display(),
+            Instr::Ret => cpu.ret(address)?,
+            Instr::Jp(nnn) => cpu.position_in_memory = nnn as usize,
+            Instr::Call(nnn) => cpu.call(address, nnn)?,
+            Instr::SeImm(x, kk) => cpu.skip_if(cpu.registers[x as usize] == kk),
+            Instr::SneImm(x, kk) => cpu.skip_if(cpu.registers[x as usize] != kk),
+            Instr::SeReg(x, y) => cpu.skip_if(cpu.registers[x as usize] == cpu.registers[y as usize]),
+            Instr::LdImm(x, kk) => cpu.registers[x as usize] = kk,
+            Instr::AddImm(x, kk) => cpu.registers[x as usize] = cpu.registers[x as usize].wrapping_add(kk),
+            Instr::LdReg(x, y) => cpu.registers[x as usize] = cpu.registers[y as usize],
+            Instr::Or(x, y) => cpu.logic_xy(x as usize, y as usize, |a, b| a | b),
+            Instr::And(x, y) => cpu.logic_xy(x as usize, y as usize, |a, b| a & b),
+            Instr::Xor(x, y) => cpu.logic_xy(x as usize, y as usize, |a, b| a ^ b),
+            Instr::Add(x, y) => cpu.add_xy(x as usize, y as usize),
+            Instr::Sub(x, y) => cpu.sub_xy(x as usize, y as usize),
+            Instr::Shr(x, y) => cpu.shr_xy(x as usize, y as usize),
+            Instr::Subn(x, y) => cpu.subn_xy(x as usize, y as usize),
+            Instr::Shl(x, y) => cpu.shl_xy(x as usize, y as usize),
+            Instr::SneReg(x, y) => cpu.skip_if(cpu.registers[x as usize] != cpu.registers[y as usize]),
+            Instr::LdI(nnn) => cpu.index_register = nnn,
+            Instr::JpOffset(nnn, x) => cpu.position_in_memory = nnn as usize + cpu.registers[x as usize] as usize,
+            Instr::Rnd(x, kk) => cpu.registers[x as usize] = cpu.next_random() & kk,
+            Instr::Drw(x, y, n) => cpu.draw(address, x as usize, y as usize, n)?,
+            Instr::Skp(x) => cpu.skip_if(cpu.key_pressed(x as usize)),
+            Instr::Sknp(x) => cpu.skip_if(!cpu.key_pressed(x as usize)),
+            Instr::LdVxDt(x) => cpu.registers[x as usize] = cpu.delay_timer,
+            Instr::LdVxK(x) => match cpu.keypad.iter().position(|&pressed| pressed) {
+                Some(key) => cpu.registers[x as usize] = key as u8,
+                None => {
+                    cpu.position_in_memory = address;
+                    return Ok(State::WaitingForKey);
+                }
+            },
+            Instr::LdDt(x) => cpu.delay_timer = cpu.registers[x as usize],
+            Instr::LdSt(x) => cpu.sound_timer = cpu.registers[x as usize],
+            Instr::AddI(x) => {
+                cpu.index_register = (cpu.index_register + cpu.registers[x as usize] as u16) & 0x0FFF
+            }
+            Instr::LdF(x) => {
+                cpu.index_register = (FONT_START + 5 * (cpu.registers[x as usize] & 0xF) as usize) as u16
+            }
+            Instr::LdHf(x) => {
+                cpu.index_register = (BIG_FONT_START + 10 * (cpu.registers[x as usize] & 0xF) as usize) as u16
+            }
+            Instr::Bcd(x) => {
+                let index = cpu.index_register as usize;
+                cpu.store_bcd(address, x as usize)?;
+                self.redecode(index, index + 3);
+            }
+            Instr::Store(x) => {
+                let index = cpu.index_register as usize;
+                cpu.store_registers(address, x as usize)?;
+                self.redecode(index, index + x as usize + 1);
+            }
+            Instr::Load(x) => cpu.load_registers(address, x as usize)?,
+            Instr::StoreFlags(n) => {
+                let n = n as usize;
+                cpu.flags[..=n].copy_from_slice(&cpu.registers[..=n]);
+            }
+            Instr::LoadFlags(n) => {
+                let n = n as usize;
+                cpu.registers[..=n].copy_from_slice(&cpu.flags[..=n]);
+            }
+            Instr::Unknown(opcode) => {
+                cpu.position_in_memory = address;
+                return Err(Fault::UnknownOpcode { address, opcode });
+            }
+        }
+
+        Ok(State::Running)
+    }
+}
+
+impl From<CPU> for CachedCPU {
+    fn from(cpu: CPU) -> Self {
+        CachedCPU::new(cpu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::assemble;
+
+    /// Steps both backends in lockstep, comparing the result and the complete CPU state
+    /// after every step. `keys` gives the keypad state for each step.
+    fn assert_identical_traces(quirks: Quirks, source: &str, steps: usize, keys: impl Fn(usize) -> u16) {
+        let mut reference = CPU::with_quirks(quirks);
+        reference.load(&assemble(source).unwrap()).unwrap();
+        let mut cached = CachedCPU::new(reference.clone());
+
+        for step in 0..steps {
+            let mask = keys(step);
+            for key in 0..16 {
+                reference.keypad[key] = mask & (1 << key) != 0;
+            }
+            cached.set_keypad(reference.keypad);
+            if step % 10 == 9 {
+                reference.tick_timers();
+                cached.tick_timers();
+            }
+
+            let expected = reference.step();
+            let actual = cached.step();
+
+            assert_eq!(actual, expected, "result of step {}", step);
+            assert_eq!(cached.cpu(), &reference, "state after step {}", step);
+            if expected != Ok(State::Running) && expected != Ok(State::WaitingForKey) {
+                return;
+            }
+        }
+    }
+
+    /// Uses every instruction that the quirks profiles disagree on, along with the
+    /// timers, the keypad, random numbers and drawing.
+    const EXERCISE: &str = "
+            LD   V0, 0b1010_0110
+            LD   V1, 0b1000_0001
+            SHR  V0, V1
+            SHL  V2, V1
+            OR   V3, V0
+            AND  V3, V1
+            XOR  V3, V2
+            ADD  V0, V1
+            SUB  V0, V2
+            SUBN V1, V3
+            LD   I, scratch
+            LD   [I], V3
+            LD   B, V0
+            LD   V5, [I]
+            ADD  I, V5
+        loop:
+            RND  V6, 0x3F
+            RND  V7, 0x1F
+            LD   F, V6
+            DRW  V6, V7, 5
+            LD   V8, 4
+            LD   DT, V8
+            LD   ST, V8
+            LD   V9, DT
+            SKP  V8
+            ADD  VA, 1
+            SKNP V8
+            ADD  VB, 1
+            SE   VA, 20
+            JP   loop
+            LD   V0, 2
+            JP   V0, table
+        table:
+            HALT
+            HALT
+            LD   VC, K
+            HALT
+        scratch:
+            DB   0, 0, 0, 0, 0, 0, 0, 0
+    ";
+
+    #[test]
+    fn backends_produce_identical_traces() {
+        let keys = |step: usize| if step % 50 < 20 { 1 << 4 } else { 0 };
+        for quirks in [Quirks::COSMAC_VIP, Quirks::CHIP_48, Quirks::SUPER_CHIP] {
+            assert_identical_traces(quirks, EXERCISE, 2_000, keys);
+        }
+    }
+
+    #[test]
+    fn super_chip_traces_are_identical() {
+        let source = "
+                HIGH
+                LD   V0, 3
+                LD   HF, V0
+                LD   V1, 120
+                DRW  V1, V0, 0
+                SCD  4
+                SCR
+                SCL
+                LD   R, V1
+                LD   V0, 0
+                LD   V1, R
+                LOW
+                EXIT
+        ";
+        assert_identical_traces(Quirks::SUPER_CHIP, source, 100, |_| 0);
+        // Without SUPER-CHIP, `HIGH` is an unknown opcode in both.
+        assert_identical_traces(Quirks::COSMAC_VIP, source, 100, |_| 0);
+    }
+
+    #[test]
+    fn faults_leave_identical_state() {
+        let roms: [&[u8]; 5] = [&[0x00, 0xEE], &[0x82, 0x38], &[0x22, 0x00], &[0x1F, 0xFF], &[0xAF, 0xFF, 0xF5, 0x55]];
+        for rom in roms {
+            let mut reference = CPU::new();
+            reference.load(rom).unwrap();
+            let mut cached = CachedCPU::new(reference.clone());
+
+            assert_eq!(cached.run(), reference.run());
+            assert_eq!(cached.cpu(), &reference);
+        }
+    }
+
+    #[test]
+    fn self_modifying_code_is_redecoded() {
+        // Overwrites the `LD V1, 1` at `patch` with `LD V1, 0x42` before running it.
+        let source = "
+                LD   I, patch
+                LD   V0, 0x61
+                LD   V1, 0x42
+                LD   [I], V1
+                LD   V0, 0
+                LD   V1, 0
+            patch:
+                LD   V1, 1
+                HALT
+        ";
+        let mut cached = CachedCPU::new(CPU::new());
+        cached.load(&assemble(source).unwrap()).unwrap();
+        cached.run().unwrap();
+        assert_eq!(cached.cpu().registers[1], 0x42);
+
+        assert_identical_traces(Quirks::COSMAC_VIP, source, 100, |_| 0);
+    }
+
+    #[test]
+    fn cpu_mut_invalidates_the_cache() {
+        let mut cached = CachedCPU::new(CPU::new());
+        cached.load(&[0x60, 0x01, 0x00, 0x00]).unwrap();
+        cached.run().unwrap();
+        assert_eq!(cached.cpu().registers[0], 1);
+
+        let cpu = cached.cpu_mut();
+        cpu.memory[0x201] = 0x02;
+        cpu.position_in_memory = 0x200;
+        cached.run().unwrap();
+        assert_eq!(cached.cpu().registers[0], 2);
+    }
+}
@@ -1,3 +1,5 @@
+use super::opcode::Opcode;
+
 struct CPU {
     stack: [u16; 16],
     memory: [u8; 4096],
@@ -20,13 +22,7 @@ impl CPU {
             let opcode = self.read_opcode();
             self.position_in_memory += 2;
 
-            let c = ((opcode & 0xF000) >> 12) as u8;
-            let x = ((opcode & 0x0F00) >> 8) as u8;
-            let y = ((opcode & 0x00F0) >> 4) as u8;
-            let d = ((opcode & 0x000F) >> 0) as u8;
-
-            let nnn = opcode & 0x0FFF;
-            // let kk  = (opcode & 0x00FF) as u8;
+            let Opcode { c, x, y, d, nnn, .. } = Opcode::from(opcode);
 
             match (c, x, y, d) {
                 (0, 0, 0, 0) => { return; }
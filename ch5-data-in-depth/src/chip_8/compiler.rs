@@ -0,0 +1,1115 @@
+//! A compiler for a tiny language that runs on CPU RIA/4.
+//!
+//! ```text
+//! // Multiplies by repeated addition.
+//! fn mul(a, b) {
+//!     let product = 0;
+//!     while b != 0 {
+//!         product = product + a;
+//!         b = b - 1;
+//!     }
+//!     return product;
+//! }
+//!
+//! let answer = mul(6, 7);
+//! ```
+//!
+//! - Values are bytes. Numbers are written as in the [assembler](super::assembler) and
+//!   must fit into 8 bits. Arithmetic wraps around.
+//! - `let name = expr;` declares a variable, and `name = expr;` assigns to it. Every
+//!   variable lives in its own register, from `V0` to `VE`. `VF` is left for flags.
+//!   A variable is visible from its `let` to the end of the function, or of the
+//!   program for top-level variables.
+//! - Expressions are built from numbers, variables, calls and parentheses with the
+//!   operators `+`, `-`, `&`, `|` and `^`. They are evaluated strictly from left to right:
+//!   `a - b + c` is `(a - b) + c`, and so is `a - b & c`.
+//! - Conditions compare two expressions with `==`, `!=`, `<`, `<=`, `>` or `>=`. An
+//!   expression on its own is true when it isn't zero.
+//! - `if cond { .. } else { .. }`, `while cond { .. }`, `loop { .. }` and `break;` compile
+//!   to skips and jumps.
+//! - `fn name(params) { .. }` compiles to a subroutine that is called with `2nnn` and
+//!   returns with `00EE`. `return expr;` passes a value back.
+//!
+//! There is no memory for a call stack of variables: the parameters, variables and
+//! temporary values of each function have registers of their own. That keeps calls
+//! cheap, but means that functions can't be recursive and a program can only use 15
+//! registers' worth of values in total. Top-level statements run first and finish with
+//! `HALT`.
+//!
+//! The compiler generates assembly source, and the assembler turns that into a ROM.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::{error, fmt, mem};
+
+use super::assembler::{assemble, AsmErrorKind};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileErrorKind {
+    UnexpectedCharacter(char),
+    UnexpectedToken { found: String, expected: &'static str },
+    InvalidNumber(String),
+    UndefinedVariable(String),
+    UndefinedFunction(String),
+    DuplicateVariable(String),
+    DuplicateFunction(String),
+    WrongArgumentCount { function: String, expected: usize, found: usize },
+    NoReturnValue(String),
+    Recursion(String),
+    BreakOutsideLoop,
+    ReturnOutsideFunction,
+    OutOfRegisters,
+    ProgramTooLarge(usize),
+}
+
+/// An error raised while compiling, along with the (1-based) source line that caused it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize,
+    pub kind: CompileErrorKind,
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character '{}'", c),
+            CompileErrorKind::UnexpectedToken { found, expected } => {
+                write!(f, "expected {}, found {}", expected, found)
+            }
+            CompileErrorKind::InvalidNumber(n) => write!(f, "invalid number '{}' (values must fit into a byte)", n),
+            CompileErrorKind::UndefinedVariable(name) => write!(f, "undefined variable '{}'", name),
+            CompileErrorKind::UndefinedFunction(name) => write!(f, "undefined function '{}'", name),
+            CompileErrorKind::DuplicateVariable(name) => write!(f, "variable '{}' is already defined", name),
+            CompileErrorKind::DuplicateFunction(name) => write!(f, "function '{}' is already defined", name),
+            CompileErrorKind::WrongArgumentCount { function, expected, found } => {
+                write!(f, "'{}' takes {} arguments but {} were given", function, expected, found)
+            }
+            CompileErrorKind::NoReturnValue(name) => write!(f, "function '{}' does not return a value", name),
+            CompileErrorKind::Recursion(name) => write!(f, "function '{}' is recursive", name),
+            CompileErrorKind::BreakOutsideLoop => write!(f, "'break' outside of a loop"),
+            CompileErrorKind::ReturnOutsideFunction => write!(f, "'return' outside of a function"),
+            CompileErrorKind::OutOfRegisters => {
+                write!(f, "out of registers: variables and temporary values need more than V0-VE")
+            }
+            CompileErrorKind::ProgramTooLarge(size) => {
+                write!(f, "program is {} bytes long and does not fit into memory", size)
+            }
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind)
+    }
+}
+
+impl error::Error for CompileError {}
+
+/// The result of compiling a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    /// The assembly source that `rom` was assembled from.
+    pub assembly: String,
+    /// The register that holds each top-level variable.
+    pub globals: BTreeMap<String, u8>,
+}
+
+/// Compiles `source` into a ROM that is loaded at 0x200.
+pub fn compile(source: &str) -> Result<Program, CompileError> {
+    let tokens = tokenize(source)?;
+    let items = Parser { tokens, pos: 0 }.program()?;
+    let (assembly, globals) = Codegen::new().program(&items)?;
+
+    let rom = assemble(&assembly).map_err(|err| match err.kind {
+        AsmErrorKind::ProgramTooLarge(size) => {
+            CompileError { line: 0, kind: CompileErrorKind::ProgramTooLarge(size) }
+        }
+        _ => panic!("the compiler generated invalid assembly: {}\n{}", err, assembly),
+    })?;
+
+    Ok(Program { rom, assembly, globals })
+}
+
+// Lexing
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Tok {
+    Ident(String),
+    Number(u8),
+    Symbol(&'static str),
+    End,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Ident(name) => write!(f, "'{}'", name),
+            Tok::Number(n) => write!(f, "'{}'", n),
+            Tok::Symbol(s) => write!(f, "'{}'", s),
+            Tok::End => write!(f, "end of input"),
+        }
+    }
+}
+
+/// Two-character symbols come first, so that `<=` isn't read as `<` followed by `=`.
+const SYMBOLS: [&str; 18] = [
+    "==", "!=", "<=", ">=", "(", ")", "{", "}", ",", ";", "=", "+", "-", "&", "|", "^", "<", ">",
+];
+
+const KEYWORDS: [&str; 8] = ["let", "fn", "while", "loop", "if", "else", "break", "return"];
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Tok)>, CompileError> {
+    let mut tokens = Vec::new();
+
+    for (i, raw_line) in source.lines().enumerate() {
+        let line = i + 1;
+        let err = |kind| CompileError { line, kind };
+        let mut rest = match raw_line.find("//") {
+            Some(comment_start) => &raw_line[..comment_start],
+            None => raw_line,
+        }
+        .trim_start();
+
+        while let Some(c) = rest.chars().next() {
+            let len = if c.is_ascii_alphanumeric() || c == '_' {
+                let len = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(rest.len());
+                let word = &rest[..len];
+                if c.is_ascii_digit() {
+                    let n = parse_number(word).ok_or_else(|| err(CompileErrorKind::InvalidNumber(word.into())))?;
+                    tokens.push((line, Tok::Number(n)));
+                } else {
+                    tokens.push((line, Tok::Ident(word.to_string())));
+                }
+                len
+            } else {
+                let symbol = SYMBOLS
+                    .iter()
+                    .find(|s| rest.starts_with(*s))
+                    .ok_or_else(|| err(CompileErrorKind::UnexpectedCharacter(c)))?;
+                tokens.push((line, Tok::Symbol(symbol)));
+                symbol.len()
+            };
+            rest = rest[len..].trim_start();
+        }
+    }
+
+    let last_line = source.lines().count().max(1);
+    tokens.push((last_line, Tok::End));
+    Ok(tokens)
+}
+
+fn parse_number(word: &str) -> Option<u8> {
+    let word = word.replace('_', "");
+    if let Some(hex) = word.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = word.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+// Parsing
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(u8),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Binary(Box<Expr>, BinOp, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Cond {
+    Compare(Expr, CmpOp, Expr),
+    NonZero(Expr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum StmtKind {
+    Let(String, Expr),
+    Assign(String, Expr),
+    If(Cond, Vec<Stmt>, Vec<Stmt>),
+    While(Cond, Vec<Stmt>),
+    Loop(Vec<Stmt>),
+    Break,
+    Return(Option<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stmt {
+    line: usize,
+    kind: StmtKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Fn { line: usize, name: String, params: Vec<String>, body: Vec<Stmt> },
+    Stmt(Stmt),
+}
+
+struct Parser {
+    tokens: Vec<(usize, Tok)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Tok {
+        &self.tokens[self.pos].1
+    }
+
+    fn line(&self) -> usize {
+        self.tokens[self.pos].0
+    }
+
+    fn next(&mut self) -> Tok {
+        let tok = self.tokens[self.pos].1.clone();
+        if tok != Tok::End {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    fn unexpected(&self, expected: &'static str) -> CompileError {
+        let found = self.peek().to_string();
+        CompileError { line: self.line(), kind: CompileErrorKind::UnexpectedToken { found, expected } }
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Tok::Symbol(s) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), CompileError> {
+        if self.eat(symbol) { Ok(()) } else { Err(self.unexpected(symbol)) }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Tok::Ident(word) if word == keyword)
+    }
+
+    fn ident(&mut self) -> Result<String, CompileError> {
+        match self.peek() {
+            Tok::Ident(name) if !KEYWORDS.contains(&name.as_str()) => match self.next() {
+                Tok::Ident(name) => Ok(name),
+                _ => unreachable!(),
+            },
+            _ => Err(self.unexpected("a name")),
+        }
+    }
+
+    fn program(mut self) -> Result<Vec<Item>, CompileError> {
+        let mut items = Vec::new();
+        while *self.peek() != Tok::End {
+            if self.is_keyword("fn") {
+                let line = self.line();
+                self.next();
+                let name = self.ident()?;
+                self.expect("(")?;
+                let mut params = Vec::new();
+                if !self.eat(")") {
+                    loop {
+                        params.push(self.ident()?);
+                        if self.eat(")") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                let body = self.block()?;
+                items.push(Item::Fn { line, name, params, body });
+            } else {
+                items.push(Item::Stmt(self.statement()?));
+            }
+        }
+        Ok(items)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, CompileError> {
+        self.expect("{")?;
+        let mut statements = Vec::new();
+        while !self.eat("}") {
+            if *self.peek() == Tok::End {
+                return Err(self.unexpected("'}'"));
+            }
+            statements.push(self.statement()?);
+        }
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Stmt, CompileError> {
+        let line = self.line();
+        let keyword = match self.peek() {
+            Tok::Ident(word) if KEYWORDS.contains(&word.as_str()) => word.clone(),
+            _ => String::new(),
+        };
+
+        let kind = match keyword.as_str() {
+            "let" => {
+                self.next();
+                let name = self.ident()?;
+                self.expect("=")?;
+                let value = self.expr()?;
+                self.expect(";")?;
+                StmtKind::Let(name, value)
+            }
+            "if" => {
+                self.next();
+                let cond = self.cond()?;
+                let then = self.block()?;
+                let otherwise = if self.is_keyword("else") {
+                    self.next();
+                    if self.is_keyword("if") { vec![self.statement()?] } else { self.block()? }
+                } else {
+                    Vec::new()
+                };
+                StmtKind::If(cond, then, otherwise)
+            }
+            "while" => {
+                self.next();
+                let cond = self.cond()?;
+                StmtKind::While(cond, self.block()?)
+            }
+            "loop" => {
+                self.next();
+                StmtKind::Loop(self.block()?)
+            }
+            "break" => {
+                self.next();
+                self.expect(";")?;
+                StmtKind::Break
+            }
+            "return" => {
+                self.next();
+                let value = if self.eat(";") {
+                    None
+                } else {
+                    let value = self.expr()?;
+                    self.expect(";")?;
+                    Some(value)
+                };
+                StmtKind::Return(value)
+            }
+            "" => {
+                let name = self.ident()?;
+                let kind = if self.eat("=") {
+                    StmtKind::Assign(name, self.expr()?)
+                } else if *self.peek() == Tok::Symbol("(") {
+                    StmtKind::Call(name.clone(), self.args()?)
+                } else {
+                    return Err(self.unexpected("'=' or '('"));
+                };
+                self.expect(";")?;
+                kind
+            }
+            _ => return Err(self.unexpected("a statement")),
+        };
+
+        Ok(Stmt { line, kind })
+    }
+
+    fn args(&mut self) -> Result<Vec<Expr>, CompileError> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.expr()?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(args)
+    }
+
+    fn cond(&mut self) -> Result<Cond, CompileError> {
+        let lhs = self.expr()?;
+        let op = match self.peek() {
+            Tok::Symbol("==") => CmpOp::Eq,
+            Tok::Symbol("!=") => CmpOp::Ne,
+            Tok::Symbol("<") => CmpOp::Lt,
+            Tok::Symbol("<=") => CmpOp::Le,
+            Tok::Symbol(">") => CmpOp::Gt,
+            Tok::Symbol(">=") => CmpOp::Ge,
+            _ => return Ok(Cond::NonZero(lhs)),
+        };
+        self.next();
+        Ok(Cond::Compare(lhs, op, self.expr()?))
+    }
+
+    fn expr(&mut self) -> Result<Expr, CompileError> {
+        let mut lhs = self.term()?;
+        loop {
+            let op = match self.peek() {
+                Tok::Symbol("+") => BinOp::Add,
+                Tok::Symbol("-") => BinOp::Sub,
+                Tok::Symbol("&") => BinOp::And,
+                Tok::Symbol("|") => BinOp::Or,
+                Tok::Symbol("^") => BinOp::Xor,
+                _ => return Ok(lhs),
+            };
+            self.next();
+            lhs = Expr::Binary(Box::new(lhs), op, Box::new(self.term()?));
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, CompileError> {
+        match self.peek() {
+            Tok::Number(n) => {
+                let n = *n;
+                self.next();
+                Ok(Expr::Number(n))
+            }
+            Tok::Symbol("(") => {
+                self.next();
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Tok::Ident(_) => {
+                let name = self.ident()?;
+                if *self.peek() == Tok::Symbol("(") {
+                    Ok(Expr::Call(name, self.args()?))
+                } else {
+                    Ok(Expr::Var(name))
+                }
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+}
+
+// Code generation
+
+struct Signature {
+    params: Vec<u8>,
+    returns: Option<u8>,
+}
+
+/// The state of the function being compiled, or of the top-level program.
+#[derive(Default)]
+struct Frame {
+    function: Option<String>,
+    locals: HashMap<String, u8>,
+    /// Registers set aside for this function's temporary values, used like a stack.
+    temps: Vec<u8>,
+    temps_in_use: usize,
+    /// The label that `break` jumps to, for each enclosing loop.
+    loops: Vec<String>,
+    code: String,
+}
+
+struct Codegen {
+    next_register: u8,
+    globals: HashMap<String, u8>,
+    global_order: BTreeMap<String, u8>,
+    functions: HashMap<String, Signature>,
+    frame: Frame,
+    /// Who calls whom, and from which line, to detect recursion.
+    calls: Vec<(Option<String>, String, usize)>,
+    labels: usize,
+}
+
+/// The last register available for variables. `VF` is clobbered by arithmetic.
+const LAST_REGISTER: u8 = 0xE;
+
+fn function_label(name: &str) -> String {
+    format!("fn_{}", name)
+}
+
+fn returns_value(statements: &[Stmt]) -> bool {
+    statements.iter().any(|stmt| match &stmt.kind {
+        StmtKind::Return(value) => value.is_some(),
+        StmtKind::If(_, then, otherwise) => returns_value(then) || returns_value(otherwise),
+        StmtKind::While(_, body) | StmtKind::Loop(body) => returns_value(body),
+        _ => false,
+    })
+}
+
+/// Whether evaluating `expr` could read `name`, directly or through a call.
+fn depends_on(expr: &Expr, name: &str) -> bool {
+    match expr {
+        Expr::Number(_) => false,
+        Expr::Var(var) => var == name,
+        Expr::Call(..) => true,
+        Expr::Binary(lhs, _, rhs) => depends_on(lhs, name) || depends_on(rhs, name),
+    }
+}
+
+impl Codegen {
+    fn new() -> Self {
+        Codegen {
+            next_register: 0,
+            globals: HashMap::new(),
+            global_order: BTreeMap::new(),
+            functions: HashMap::new(),
+            frame: Frame::default(),
+            calls: Vec::new(),
+            labels: 0,
+        }
+    }
+
+    fn program(mut self, items: &[Item]) -> Result<(String, BTreeMap<String, u8>), CompileError> {
+        // Functions can be called before they're defined, so their parameters
+        // and return values are allocated up front.
+        for item in items {
+            if let Item::Fn { line, name, params, body } = item {
+                let err = |kind| CompileError { line: *line, kind };
+                if self.functions.contains_key(name) {
+                    return Err(err(CompileErrorKind::DuplicateFunction(name.clone())));
+                }
+                let mut seen = HashSet::new();
+                let mut registers = Vec::new();
+                for param in params {
+                    if !seen.insert(param) {
+                        return Err(err(CompileErrorKind::DuplicateVariable(param.clone())));
+                    }
+                    registers.push(self.allocate(*line)?);
+                }
+                let returns = if returns_value(body) { Some(self.allocate(*line)?) } else { None };
+                self.functions.insert(name.clone(), Signature { params: registers, returns });
+            }
+        }
+
+        let mut functions = String::new();
+        for item in items {
+            match item {
+                Item::Stmt(stmt) => self.statement(stmt)?,
+                Item::Fn { name, params, body, .. } => {
+                    let signature = &self.functions[name];
+                    let locals = params.iter().cloned().zip(signature.params.iter().copied()).collect();
+                    let frame = Frame { function: Some(name.clone()), locals, ..Frame::default() };
+                    let main = mem::replace(&mut self.frame, frame);
+
+                    self.emit_label(&function_label(name));
+                    for stmt in body {
+                        self.statement(stmt)?;
+                    }
+                    self.emit("RET");
+
+                    functions += &mem::replace(&mut self.frame, main).code;
+                }
+            }
+        }
+        self.emit("HALT");
+
+        self.check_recursion()?;
+
+        let mut assembly = mem::take(&mut self.frame.code);
+        assembly += &functions;
+        Ok((assembly, self.global_order))
+    }
+
+    fn check_recursion(&self) -> Result<(), CompileError> {
+        for name in self.functions.keys() {
+            let mut stack = vec![name];
+            let mut seen = HashSet::new();
+            while let Some(caller) = stack.pop() {
+                for (from, callee, line) in &self.calls {
+                    if from.as_ref() != Some(caller) {
+                        continue;
+                    }
+                    if callee == name {
+                        return Err(CompileError { line: *line, kind: CompileErrorKind::Recursion(name.clone()) });
+                    }
+                    if seen.insert(callee) {
+                        stack.push(callee);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: &str) {
+        writeln!(self.frame.code, "    {}", instruction).unwrap();
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        writeln!(self.frame.code, "{}:", label).unwrap();
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.labels += 1;
+        format!("{}_{}", kind, self.labels)
+    }
+
+    fn allocate(&mut self, line: usize) -> Result<u8, CompileError> {
+        if self.next_register > LAST_REGISTER {
+            return Err(CompileError { line, kind: CompileErrorKind::OutOfRegisters });
+        }
+        self.next_register += 1;
+        Ok(self.next_register - 1)
+    }
+
+    fn temp(&mut self, line: usize) -> Result<u8, CompileError> {
+        let register = match self.frame.temps.get(self.frame.temps_in_use).copied() {
+            Some(register) => register,
+            None => {
+                let register = self.allocate(line)?;
+                self.frame.temps.push(register);
+                register
+            }
+        };
+        self.frame.temps_in_use += 1;
+        Ok(register)
+    }
+
+    fn variable(&self, name: &str, line: usize) -> Result<u8, CompileError> {
+        self.frame
+            .locals
+            .get(name)
+            .or_else(|| self.globals.get(name))
+            .copied()
+            .ok_or_else(|| CompileError { line, kind: CompileErrorKind::UndefinedVariable(name.to_string()) })
+    }
+
+    fn statements(&mut self, statements: &[Stmt]) -> Result<(), CompileError> {
+        statements.iter().try_for_each(|stmt| self.statement(stmt))
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<(), CompileError> {
+        let line = stmt.line;
+        let err = |kind| CompileError { line, kind };
+        // Temporary values never outlive the statement that needs them.
+        let temps_in_use = self.frame.temps_in_use;
+
+        match &stmt.kind {
+            StmtKind::Let(name, value) => {
+                let in_function = self.frame.function.is_some();
+                let scope = if in_function { &self.frame.locals } else { &self.globals };
+                if scope.contains_key(name) {
+                    return Err(err(CompileErrorKind::DuplicateVariable(name.clone())));
+                }
+                // The variable isn't in scope until its value has been computed.
+                let register = self.allocate(line)?;
+                self.expr(value, register, line)?;
+                if in_function {
+                    self.frame.locals.insert(name.clone(), register);
+                } else {
+                    self.globals.insert(name.clone(), register);
+                    self.global_order.insert(name.clone(), register);
+                }
+            }
+            StmtKind::Assign(name, value) => {
+                let register = self.variable(name, line)?;
+                // The result is built up in the variable's register when that can't change
+                // what the rest of the expression reads. `x = x + y` can update `x` in place,
+                // but `x = y - x` would read `x` after it's been overwritten with `y`.
+                let in_place = !depends_on(value, name)
+                    || matches!(value, Expr::Binary(lhs, _, rhs)
+                        if **lhs == Expr::Var(name.clone()) && matches!(**rhs, Expr::Number(_) | Expr::Var(_)));
+                if in_place {
+                    self.expr(value, register, line)?;
+                } else {
+                    let temp = self.temp(line)?;
+                    self.expr(value, temp, line)?;
+                    self.emit(&format!("LD   V{:X}, V{:X}", register, temp));
+                }
+            }
+            StmtKind::If(cond, then, otherwise) => {
+                let else_label = self.new_label("else");
+                let end_label = self.new_label("end_if");
+                self.cond(cond, &else_label, line)?;
+                self.frame.temps_in_use = temps_in_use;
+                self.statements(then)?;
+                if !otherwise.is_empty() {
+                    self.emit(&format!("JP   {}", end_label));
+                }
+                self.emit_label(&else_label);
+                self.statements(otherwise)?;
+                self.emit_label(&end_label);
+            }
+            StmtKind::While(cond, body) => {
+                let start_label = self.new_label("while");
+                let end_label = self.new_label("end_while");
+                self.emit_label(&start_label);
+                self.cond(cond, &end_label, line)?;
+                self.frame.temps_in_use = temps_in_use;
+                self.loop_body(body, &end_label)?;
+                self.emit(&format!("JP   {}", start_label));
+                self.emit_label(&end_label);
+            }
+            StmtKind::Loop(body) => {
+                let start_label = self.new_label("loop");
+                let end_label = self.new_label("end_loop");
+                self.emit_label(&start_label);
+                self.loop_body(body, &end_label)?;
+                self.emit(&format!("JP   {}", start_label));
+                self.emit_label(&end_label);
+            }
+            StmtKind::Break => {
+                let end_label = self.frame.loops.last().cloned().ok_or_else(|| err(CompileErrorKind::BreakOutsideLoop))?;
+                self.emit(&format!("JP   {}", end_label));
+            }
+            StmtKind::Return(value) => {
+                let function = self.frame.function.clone().ok_or_else(|| err(CompileErrorKind::ReturnOutsideFunction))?;
+                if let Some(value) = value {
+                    let register = self.functions[&function].returns.expect("return register");
+                    self.expr(value, register, line)?;
+                }
+                self.emit("RET");
+            }
+            StmtKind::Call(name, args) => self.call(name, args, line)?,
+        }
+
+        self.frame.temps_in_use = temps_in_use;
+        Ok(())
+    }
+
+    fn loop_body(&mut self, body: &[Stmt], end_label: &str) -> Result<(), CompileError> {
+        self.frame.loops.push(end_label.to_string());
+        self.statements(body)?;
+        self.frame.loops.pop();
+        Ok(())
+    }
+
+    /// Evaluates `expr` into `target`.
+    fn expr(&mut self, expr: &Expr, target: u8, line: usize) -> Result<(), CompileError> {
+        match expr {
+            Expr::Number(n) => self.emit(&format!("LD   V{:X}, {}", target, n)),
+            Expr::Var(name) => {
+                let register = self.variable(name, line)?;
+                if register != target {
+                    self.emit(&format!("LD   V{:X}, V{:X}", target, register));
+                }
+            }
+            Expr::Call(name, args) => {
+                self.call(name, args, line)?;
+                let register = self.functions[name]
+                    .returns
+                    .ok_or_else(|| CompileError { line, kind: CompileErrorKind::NoReturnValue(name.clone()) })?;
+                self.emit(&format!("LD   V{:X}, V{:X}", target, register));
+            }
+            Expr::Binary(lhs, op, rhs) => {
+                self.expr(lhs, target, line)?;
+                match (op, &**rhs) {
+                    (BinOp::Add, Expr::Number(n)) => self.emit(&format!("ADD  V{:X}, {}", target, n)),
+                    (BinOp::Sub, Expr::Number(n)) => {
+                        self.emit(&format!("ADD  V{:X}, {}", target, n.wrapping_neg()))
+                    }
+                    _ => {
+                        let temps_in_use = self.frame.temps_in_use;
+                        let operand = self.operand(rhs, line)?;
+                        let mnemonic = match op {
+                            BinOp::Add => "ADD ",
+                            BinOp::Sub => "SUB ",
+                            BinOp::And => "AND ",
+                            BinOp::Or => "OR  ",
+                            BinOp::Xor => "XOR ",
+                        };
+                        self.emit(&format!("{} V{:X}, V{:X}", mnemonic, target, operand));
+                        self.frame.temps_in_use = temps_in_use;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The register holding the value of `expr`: a variable's own register, or a temporary.
+    fn operand(&mut self, expr: &Expr, line: usize) -> Result<u8, CompileError> {
+        if let Expr::Var(name) = expr {
+            return self.variable(name, line);
+        }
+        let temp = self.temp(line)?;
+        self.expr(expr, temp, line)?;
+        Ok(temp)
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr], line: usize) -> Result<(), CompileError> {
+        let err = |kind| CompileError { line, kind };
+        let params = match self.functions.get(name) {
+            Some(signature) => signature.params.clone(),
+            None => return Err(err(CompileErrorKind::UndefinedFunction(name.to_string()))),
+        };
+        if params.len() != args.len() {
+            return Err(err(CompileErrorKind::WrongArgumentCount {
+                function: name.to_string(),
+                expected: params.len(),
+                found: args.len(),
+            }));
+        }
+        self.calls.push((self.frame.function.clone(), name.to_string(), line));
+
+        // Arguments are all evaluated before any is passed, as evaluating one
+        // could call the same function and overwrite its parameters.
+        let temps_in_use = self.frame.temps_in_use;
+        let mut registers = Vec::with_capacity(args.len());
+        for arg in args {
+            registers.push(match arg {
+                Expr::Number(_) => None,
+                _ => Some(self.operand(arg, line)?),
+            });
+        }
+        for ((param, arg), register) in params.iter().zip(args).zip(registers) {
+            match (arg, register) {
+                (Expr::Number(n), _) => self.emit(&format!("LD   V{:X}, {}", param, n)),
+                (_, Some(register)) if register != *param => {
+                    self.emit(&format!("LD   V{:X}, V{:X}", param, register))
+                }
+                _ => {}
+            }
+        }
+        self.frame.temps_in_use = temps_in_use;
+
+        self.emit(&format!("CALL {}", function_label(name)));
+        Ok(())
+    }
+
+    /// Jumps to `false_label` unless `cond` holds.
+    fn cond(&mut self, cond: &Cond, false_label: &str, line: usize) -> Result<(), CompileError> {
+        match cond {
+            Cond::NonZero(expr) => {
+                let register = self.operand(expr, line)?;
+                self.emit(&format!("SNE  V{:X}, 0", register));
+            }
+            Cond::Compare(lhs, op @ (CmpOp::Eq | CmpOp::Ne), rhs) => {
+                let mnemonic = if *op == CmpOp::Eq { "SE  " } else { "SNE " };
+                let register = self.operand(lhs, line)?;
+                match rhs {
+                    Expr::Number(n) => self.emit(&format!("{} V{:X}, {}", mnemonic, register, n)),
+                    _ => {
+                        let other = self.operand(rhs, line)?;
+                        self.emit(&format!("{} V{:X}, V{:X}", mnemonic, register, other));
+                    }
+                }
+            }
+            Cond::Compare(lhs, op, rhs) => {
+                // `SUB` sets VF to 1 when there's no borrow, i.e. when `a >= b`.
+                let (a, b, want_no_borrow) = match op {
+                    CmpOp::Lt => (lhs, rhs, false),
+                    CmpOp::Ge => (lhs, rhs, true),
+                    CmpOp::Gt => (rhs, lhs, false),
+                    CmpOp::Le => (rhs, lhs, true),
+                    CmpOp::Eq | CmpOp::Ne => unreachable!(),
+                };
+                let difference = self.temp(line)?;
+                self.expr(a, difference, line)?;
+                let subtrahend = self.operand(b, line)?;
+                self.emit(&format!("SUB  V{:X}, V{:X}", difference, subtrahend));
+                self.emit(&format!("SE   VF, {}", want_no_borrow as u8));
+            }
+        }
+        self.emit(&format!("JP   {}", false_label));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::interpreter::{State, CPU};
+
+    /// Compiles and runs `source`, returning the value of each top-level variable.
+    fn run(source: &str) -> BTreeMap<String, u8> {
+        let program = compile(source).unwrap_or_else(|err| panic!("{}", err));
+        let mut cpu = CPU::new();
+        cpu.load(&program.rom).unwrap();
+        for _ in 0..100_000 {
+            if cpu.step().unwrap() != State::Running {
+                break;
+            }
+        }
+        program
+            .globals
+            .iter()
+            .map(|(name, &register)| (name.clone(), cpu.registers[register as usize]))
+            .collect()
+    }
+
+    fn error(source: &str) -> CompileError {
+        compile(source).unwrap_err()
+    }
+
+    #[test]
+    fn arithmetic_wraps_around() {
+        let vars = run("
+            let a = 5;
+            let b = a + 10 - 3;
+            let c = 200 + 100;
+            let d = 3 - 5;
+            let e = (0xF0 | 0x0F) & 0b0011_1100 ^ 4;
+            let f = a - (b - 20);
+        ");
+
+        assert_eq!(vars["b"], 12);
+        assert_eq!(vars["c"], 44);
+        assert_eq!(vars["d"], 254);
+        assert_eq!(vars["e"], 0x38);
+        assert_eq!(vars["f"], 13);
+    }
+
+    #[test]
+    fn loops_and_conditions() {
+        let vars = run("
+            let sum = 0;
+            let i = 1;
+            while i <= 10 {
+                sum = sum + i;
+                i = i + 1;
+            }
+
+            let below = 0;
+            let above = 0;
+            let same = 0;
+            let n = 0;
+            loop {
+                if n < 5 {
+                    below = below + 1;
+                } else if n > 5 {
+                    above = above + 1;
+                } else {
+                    same = same + 1;
+                }
+                n = n + 1;
+                if n >= 9 {
+                    break;
+                }
+            }
+        ");
+
+        assert_eq!(vars["sum"], 55);
+        assert_eq!((vars["below"], vars["same"], vars["above"]), (5, 1, 3));
+    }
+
+    #[test]
+    fn functions_compile_to_call_and_ret() {
+        let source = "
+            fn add(x, y) {
+                return x + y;
+            }
+
+            fn mul(a, b) {
+                let product = 0;
+                while b != 0 {
+                    product = add(product, a);
+                    b = b - 1;
+                }
+                return product;
+            }
+
+            let nested = add(3, add(4, 5));
+            let answer = mul(6, 7);
+        ";
+        let vars = run(source);
+        assert_eq!(vars["nested"], 12);
+        assert_eq!(vars["answer"], 42);
+
+        let program = compile(source).unwrap();
+        assert!(program.assembly.contains("CALL fn_add"));
+        assert!(program.assembly.contains("fn_mul:"));
+        assert!(program.assembly.lines().filter(|line| line.trim() == "RET").count() >= 2);
+    }
+
+    #[test]
+    fn fibonacci() {
+        let vars = run("
+            fn fib(n) {
+                let a = 0;
+                let b = 1;
+                while n != 0 {
+                    let next = a + b;
+                    a = b;
+                    b = next;
+                    n = n - 1;
+                }
+                return a;
+            }
+
+            let f10 = fib(10);
+            let f13 = fib(13);
+        ");
+
+        assert_eq!(vars["f10"], 55);
+        assert_eq!(vars["f13"], 233);
+    }
+
+    #[test]
+    fn assignment_reads_the_old_value() {
+        let vars = run("
+            let x = 10;
+            let y = 3;
+            x = y - x;
+            y = 1 - y + y;
+        ");
+
+        assert_eq!(vars["x"], 249);
+        assert_eq!(vars["y"], 1);
+    }
+
+    #[test]
+    fn functions_see_earlier_globals() {
+        let vars = run("
+            let counter = 0;
+            fn bump(by) {
+                counter = counter + by;
+            }
+            bump(2);
+            bump(3);
+        ");
+
+        assert_eq!(vars["counter"], 5);
+    }
+
+    #[test]
+    fn errors_report_line_numbers() {
+        assert_eq!(error("let a = 1;\nlet b = c;"), CompileError {
+            line: 2,
+            kind: CompileErrorKind::UndefinedVariable("c".to_string()),
+        });
+        assert_eq!(error("let a = 256;").kind, CompileErrorKind::InvalidNumber("256".to_string()));
+        assert_eq!(error("let a = 1 $ 2;").kind, CompileErrorKind::UnexpectedCharacter('$'));
+        assert_eq!(error("break;").kind, CompileErrorKind::BreakOutsideLoop);
+        assert_eq!(error("return 1;").kind, CompileErrorKind::ReturnOutsideFunction);
+        assert_eq!(error("fn f() {}\nlet a = f();").kind, CompileErrorKind::NoReturnValue("f".to_string()));
+        assert_eq!(
+            error("fn f(a) { return a; }\nlet a = f(1, 2);").kind,
+            CompileErrorKind::WrongArgumentCount { function: "f".to_string(), expected: 1, found: 2 }
+        );
+        assert_eq!(
+            error("let a = 1").kind,
+            CompileErrorKind::UnexpectedToken { found: "end of input".to_string(), expected: ";" }
+        );
+    }
+
+    #[test]
+    fn rejects_recursion_and_too_many_variables() {
+        let recursive = "
+            fn ping(n) { pong(n); }
+            fn pong(n) { if n { ping(n - 1); } }
+            ping(3);
+        ";
+        assert!(matches!(error(recursive).kind, CompileErrorKind::Recursion(_)));
+
+        let lets: String = (0..16).map(|i| format!("let v{} = {};\n", i, i)).collect();
+        assert_eq!(error(&lets), CompileError { line: 16, kind: CompileErrorKind::OutOfRegisters });
+    }
+}
@@ -0,0 +1,572 @@
+//! An interactive debugger for the [`interpreter`](super::interpreter) CPU.
+//!
+//! The debugger executes one instruction at a time, checking after each one whether
+//! it should hand control back to the user:
+//! - breakpoints stop before the instruction at an address is executed
+//! - opcode breakpoints stop before any instruction matching a pattern such as `8..4`
+//! - watchpoints stop after a memory location, register or `I` changes value
+//!
+//! The timers tick once every [`CYCLES_PER_FRAME`] instructions, as if the program were
+//! running at its usual speed, so that loops waiting on the delay timer finish.
+//!
+//! Type `help` at the `(chip8)` prompt for the list of commands.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use super::disassembler::mnemonic;
+use super::interpreter::{Fault, State, CPU};
+use super::save_state;
+
+const HELP: &str = "\
+Commands:
+    step [n]            execute n instructions (default 1)
+    continue            run until a breakpoint, watchpoint, halt or fault (at most 1000000 instructions)
+    break <addr>        stop before executing the instruction at <addr>
+    break op <pattern>  stop before opcodes matching <pattern>, '.' is a wildcard nibble (e.g. 8..4)
+    watch <target>      stop when <target> changes: v0-vf, i, or a memory address
+    delete              remove all breakpoints and watchpoints
+    regs                show registers, I, the program counter and timers
+    stack               show the call stack
+    mem <addr> [len]    dump memory
+    dis [addr] [count]  disassemble, starting at the program counter by default
+    trace <file>|off    write every executed instruction to <file>
+    save <file>         write a save state to <file>
+    load <file>         restore a save state from <file>
+    quit                leave the debugger
+
+Addresses are hexadecimal, with or without a 0x prefix. Counts and lengths are decimal,
+or hexadecimal with a 0x prefix.
+";
+
+/// How many instructions make up a frame, after each of which the timers tick.
+pub const CYCLES_PER_FRAME: usize = 10;
+
+/// How many instructions `continue` executes before handing control back anyway, so that
+/// a program stuck in a loop doesn't hang the debugger.
+pub const STEP_LIMIT: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Register(u8),
+    Index,
+    Memory(u16),
+}
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Stepped,
+    Breakpoint(usize),
+    OpcodeBreakpoint { address: usize, opcode: u16 },
+    Watchpoint { watch: Watch, old: u16, new: u16 },
+    WaitingForKey,
+    Halted,
+    Fault(Fault),
+    /// `resume` executed its limit of instructions without anything else stopping it.
+    StepLimit(usize),
+}
+
+/// An opcode pattern such as `8..4`: each `.` matches any nibble.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OpcodePattern {
+    value: u16,
+    mask: u16,
+}
+
+impl OpcodePattern {
+    fn parse(text: &str) -> Option<Self> {
+        if text.len() != 4 {
+            return None;
+        }
+
+        let mut value = 0;
+        let mut mask = 0;
+        for c in text.chars() {
+            value <<= 4;
+            mask <<= 4;
+            if c != '.' {
+                value |= c.to_digit(16)? as u16;
+                mask |= 0xF;
+            }
+        }
+
+        Some(OpcodePattern { value, mask })
+    }
+
+    fn matches(&self, opcode: u16) -> bool {
+        opcode & self.mask == self.value
+    }
+}
+
+pub struct Debugger {
+    pub cpu: CPU,
+    breakpoints: BTreeSet<usize>,
+    opcode_breakpoints: Vec<OpcodePattern>,
+    watchpoints: Vec<Watch>,
+    trace: Option<BufWriter<File>>,
+    /// Instructions executed since the timers last ticked.
+    cycles: usize,
+    /// The most instructions `resume` executes. [`STEP_LIMIT`] by default.
+    pub step_limit: usize,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Self {
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            opcode_breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            trace: None,
+            cycles: 0,
+            step_limit: STEP_LIMIT,
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Returns `false` when `pattern` is not four hex digits or `.` wildcards.
+    pub fn add_opcode_breakpoint(&mut self, pattern: &str) -> bool {
+        match OpcodePattern::parse(pattern) {
+            Some(pattern) => {
+                self.opcode_breakpoints.push(pattern);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch) {
+        self.watchpoints.push(watch);
+    }
+
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+        self.opcode_breakpoints.clear();
+        self.watchpoints.clear();
+    }
+
+    pub fn trace_to(&mut self, path: &Path) -> io::Result<()> {
+        self.trace = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    pub fn stop_tracing(&mut self) -> io::Result<()> {
+        if let Some(mut trace) = self.trace.take() {
+            trace.flush()?;
+        }
+        Ok(())
+    }
+
+    fn watched_value(&self, watch: Watch) -> u16 {
+        match watch {
+            Watch::Register(x) => self.cpu.registers[x as usize] as u16,
+            Watch::Index => self.cpu.index_register,
+            Watch::Memory(addr) => self.cpu.memory.get(addr as usize).copied().unwrap_or(0) as u16,
+        }
+    }
+
+    /// Executes a single instruction, regardless of any breakpoint at the program counter.
+    pub fn step(&mut self) -> Stop {
+        let address = self.cpu.position_in_memory;
+        let before: Vec<u16> = self.watchpoints.iter().map(|&w| self.watched_value(w)).collect();
+
+        if let Some(trace) = self.trace.as_mut() {
+            let cpu = &self.cpu;
+            let opcode = cpu.read_opcode().unwrap_or(0);
+            let text = mnemonic(opcode).unwrap_or_else(|| "???".to_string());
+            let registers: Vec<String> = cpu.registers.iter().map(|v| format!("{:02x}", v)).collect();
+            // Tracing is best effort: a full disk shouldn't stop the program being debugged.
+            let _ = writeln!(
+                trace,
+                "{:03x}: {:04X} {:<18} V={} I={:03x} SP={}",
+                address, opcode, text, registers.join(" "), cpu.index_register, cpu.stack_pointer
+            );
+        }
+
+        let state = self.cpu.step();
+        if state.is_ok() {
+            self.cycles += 1;
+            if self.cycles == CYCLES_PER_FRAME {
+                self.cycles = 0;
+                self.cpu.tick_timers();
+            }
+        }
+        match state {
+            Ok(State::Running) => {}
+            Ok(State::Halted) => return Stop::Halted,
+            Ok(State::WaitingForKey) => return Stop::WaitingForKey,
+            Err(fault) => return Stop::Fault(fault),
+        }
+
+        for (&watch, &old) in self.watchpoints.iter().zip(&before) {
+            let new = self.watched_value(watch);
+            if new != old {
+                return Stop::Watchpoint { watch, old, new };
+            }
+        }
+
+        Stop::Stepped
+    }
+
+    /// Runs until something interesting happens, or for at most `step_limit` instructions.
+    /// The instruction at the program counter is always executed, so that continuing from a
+    /// breakpoint makes progress.
+    pub fn resume(&mut self) -> Stop {
+        for executed in 0..self.step_limit {
+            let address = self.cpu.position_in_memory;
+
+            if executed > 0 {
+                if self.breakpoints.contains(&address) {
+                    return Stop::Breakpoint(address);
+                }
+                if let Ok(opcode) = self.cpu.read_opcode() {
+                    if self.opcode_breakpoints.iter().any(|p| p.matches(opcode)) {
+                        return Stop::OpcodeBreakpoint { address, opcode };
+                    }
+                }
+            }
+
+            match self.step() {
+                Stop::Stepped => continue,
+                stop => return stop,
+            }
+        }
+
+        Stop::StepLimit(self.step_limit)
+    }
+
+    fn describe(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Stepped => String::new(),
+            Stop::Breakpoint(address) => format!("breakpoint at {:03x}\n", address),
+            Stop::OpcodeBreakpoint { address, opcode } => {
+                format!("opcode breakpoint: {:04X} at {:03x}\n", opcode, address)
+            }
+            Stop::Watchpoint { watch, old, new } => {
+                let target = match watch {
+                    Watch::Register(x) => format!("V{:X}", x),
+                    Watch::Index => "I".to_string(),
+                    Watch::Memory(addr) => format!("[{:03x}]", addr),
+                };
+                format!("watchpoint: {} changed from {:#x} to {:#x}\n", target, old, new)
+            }
+            Stop::WaitingForKey => "waiting for a key press\n".to_string(),
+            Stop::Halted => "halted\n".to_string(),
+            Stop::Fault(fault) => format!("fault: {}\n", fault),
+            Stop::StepLimit(limit) => format!("still running after {} instructions\n", limit),
+        }
+    }
+
+    pub fn registers(&self) -> String {
+        let cpu = &self.cpu;
+        let mut out = String::new();
+        for (i, v) in cpu.registers.iter().enumerate() {
+            out.push_str(&format!("V{:X}={:02x}{}", i, v, if i % 8 == 7 { "\n" } else { " " }));
+        }
+        out.push_str(&format!(
+            "I={:03x} PC={:03x} SP={} DT={} ST={}\n",
+            cpu.index_register, cpu.position_in_memory, cpu.stack_pointer, cpu.delay_timer, cpu.sound_timer
+        ));
+        out
+    }
+
+    pub fn stack(&self) -> String {
+        let sp = self.cpu.stack_pointer.min(self.cpu.stack.len());
+        if sp == 0 {
+            return "stack is empty\n".to_string();
+        }
+
+        let mut out = String::new();
+        for (depth, ret) in self.cpu.stack[..sp].iter().enumerate().rev() {
+            out.push_str(&format!("#{:<2} return to {:03x}\n", depth, ret));
+        }
+        out
+    }
+
+    pub fn memory(&self, start: usize, len: usize) -> String {
+        let end = start.saturating_add(len).min(self.cpu.memory.len());
+        let mut out = String::new();
+        for row_start in (start..end).step_by(16) {
+            let row = &self.cpu.memory[row_start..(row_start + 16).min(end)];
+            let bytes: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+            out.push_str(&format!("{:03x}: {}\n", row_start, bytes.join(" ")));
+        }
+        out
+    }
+
+    /// Disassembles `count` instructions from `start`, marking the program counter with `>`.
+    pub fn disassembly(&self, start: usize, count: usize) -> String {
+        let mut out = String::new();
+        for address in (start..self.cpu.memory.len() - 1).step_by(2).take(count) {
+            let opcode = u16::from_be_bytes([self.cpu.memory[address], self.cpu.memory[address + 1]]);
+            let marker = if address == self.cpu.position_in_memory { '>' } else { ' ' };
+            let text = mnemonic(opcode).unwrap_or_else(|| "???".to_string());
+            out.push_str(&format!("{} {:03x}: {:04X}  {}\n", marker, address, opcode, text));
+        }
+        out
+    }
+
+    /// Executes one line of input. Returns `false` when the user asked to quit.
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => {}
+            ["quit"] | ["q"] => return Ok(false),
+            ["help"] | ["h"] => write!(out, "{}", HELP)?,
+            ["step"] | ["s"] => self.report_step(1, out)?,
+            ["step" | "s", n] => match parse_count(n) {
+                Some(n) => self.report_step(n, out)?,
+                None => writeln!(out, "not a number: {}", n)?,
+            },
+            ["continue"] | ["c"] => {
+                let stop = self.resume();
+                write!(out, "{}{}", self.describe(stop), self.disassembly(self.cpu.position_in_memory, 1))?;
+            }
+            ["break" | "b", "op", pattern] => {
+                if !self.add_opcode_breakpoint(pattern) {
+                    writeln!(out, "invalid opcode pattern: {}", pattern)?;
+                }
+            }
+            ["break" | "b", addr] => match parse_address(addr) {
+                Some(addr) => self.add_breakpoint(addr),
+                None => writeln!(out, "invalid address: {}", addr)?,
+            },
+            ["watch" | "w", target] => match parse_watch(target) {
+                Some(watch) => self.add_watchpoint(watch),
+                None => writeln!(out, "invalid watch target: {}", target)?,
+            },
+            ["delete"] => self.clear(),
+            ["regs" | "r"] => write!(out, "{}", self.registers())?,
+            ["stack"] => write!(out, "{}", self.stack())?,
+            ["mem" | "m", addr] | ["mem" | "m", addr, _] => {
+                let len = words.get(2).map_or(Some(64), |len| parse_count(len));
+                match (parse_address(addr), len) {
+                    (Some(addr), Some(len)) => write!(out, "{}", self.memory(addr, len))?,
+                    (None, _) => writeln!(out, "invalid address: {}", addr)?,
+                    (_, None) => writeln!(out, "not a number: {}", words[2])?,
+                }
+            }
+            ["dis" | "d"] => write!(out, "{}", self.disassembly(self.cpu.position_in_memory, 8))?,
+            ["dis" | "d", addr] | ["dis" | "d", addr, _] => {
+                let count = words.get(2).map_or(Some(8), |n| parse_count(n));
+                match (parse_address(addr), count) {
+                    (Some(addr), Some(count)) => write!(out, "{}", self.disassembly(addr, count))?,
+                    (None, _) => writeln!(out, "invalid address: {}", addr)?,
+                    (_, None) => writeln!(out, "not a number: {}", words[2])?,
+                }
+            }
+            ["trace", "off"] => self.stop_tracing()?,
+            ["trace", path] => {
+                if let Err(err) = self.trace_to(Path::new(path)) {
+                    writeln!(out, "unable to open {}: {}", path, err)?;
+                }
+            }
+            ["save", path] => {
+                if let Err(err) = std::fs::write(path, save_state::save(&self.cpu)) {
+                    writeln!(out, "unable to write {}: {}", path, err)?;
+                }
+            }
+            ["load", path] => match std::fs::read(path) {
+                Ok(bytes) => match save_state::restore(&bytes) {
+                    Ok(cpu) => self.cpu = cpu,
+                    Err(err) => writeln!(out, "unable to load {}: {}", path, err)?,
+                },
+                Err(err) => writeln!(out, "unable to read {}: {}", path, err)?,
+            },
+            _ => writeln!(out, "unknown command: {} (try 'help')", line.trim())?,
+        }
+
+        Ok(true)
+    }
+
+    fn report_step(&mut self, n: usize, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..n {
+            let stop = self.step();
+            if stop != Stop::Stepped {
+                write!(out, "{}", self.describe(stop))?;
+                break;
+            }
+        }
+        write!(out, "{}", self.disassembly(self.cpu.position_in_memory, 1))
+    }
+
+    /// Reads commands from `input` until it runs out or the user quits.
+    pub fn repl(&mut self, input: impl BufRead, mut out: impl Write) -> io::Result<()> {
+        write!(out, "{}", self.disassembly(self.cpu.position_in_memory, 1))?;
+        write!(out, "(chip8) ")?;
+        out.flush()?;
+
+        for line in input.lines() {
+            if !self.execute(&line?, &mut out)? {
+                break;
+            }
+            write!(out, "(chip8) ")?;
+            out.flush()?;
+        }
+
+        self.stop_tracing()
+    }
+}
+
+/// An address in hexadecimal, with or without `0x`. Only addresses in memory are valid.
+fn parse_address(text: &str) -> Option<usize> {
+    let text = text.to_ascii_lowercase();
+    let hex = text.strip_prefix("0x").unwrap_or(&text);
+    usize::from_str_radix(hex, 16).ok().filter(|&addr| addr <= 0xFFF)
+}
+
+/// A count in decimal, or in hexadecimal with `0x`.
+fn parse_count(text: &str) -> Option<usize> {
+    match text.to_ascii_lowercase().strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_watch(text: &str) -> Option<Watch> {
+    let lower = text.to_ascii_lowercase();
+    if lower == "i" {
+        return Some(Watch::Index);
+    }
+    if let Some(register) = lower.strip_prefix('v') {
+        if register.len() == 1 {
+            return u8::from_str_radix(register, 16).ok().map(Watch::Register);
+        }
+        return None;
+    }
+    parse_address(&lower).map(|addr| Watch::Memory(addr as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::assemble;
+
+    fn debugger(source: &str) -> Debugger {
+        let mut cpu = CPU::new();
+        cpu.load(&assemble(source).unwrap()).unwrap();
+        Debugger::new(cpu)
+    }
+
+    const PROGRAM: &str = "
+            LD   V0, 5
+            LD   V1, 10
+            CALL add_twice
+            HALT
+        add_twice:
+            ADD  V0, V1
+            ADD  V0, V1
+            RET
+    ";
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut dbg = debugger(PROGRAM);
+        dbg.add_breakpoint(0x208);
+
+        assert_eq!(dbg.resume(), Stop::Breakpoint(0x208));
+        assert_eq!(dbg.cpu.stack_pointer, 1);
+        assert_eq!(dbg.step(), Stop::Stepped);
+        assert_eq!(dbg.cpu.registers[0], 15);
+        assert_eq!(dbg.resume(), Stop::Halted);
+        assert_eq!(dbg.cpu.registers[0], 25);
+    }
+
+    #[test]
+    fn opcode_breakpoints_and_watchpoints() {
+        let mut dbg = debugger(PROGRAM);
+        assert!(dbg.add_opcode_breakpoint("00EE"));
+        assert!(!dbg.add_opcode_breakpoint("8x14"));
+
+        assert_eq!(dbg.resume(), Stop::OpcodeBreakpoint { address: 0x20C, opcode: 0x00EE });
+
+        let mut dbg = debugger(PROGRAM);
+        dbg.add_watchpoint(Watch::Register(0));
+        assert_eq!(dbg.resume(), Stop::Watchpoint { watch: Watch::Register(0), old: 0, new: 5 });
+        assert_eq!(dbg.resume(), Stop::Watchpoint { watch: Watch::Register(0), old: 5, new: 15 });
+    }
+
+    #[test]
+    fn repl_session() {
+        let mut dbg = debugger(PROGRAM);
+        let input = "break 20a\ncontinue\nstack\nregs\nbogus\nquit\nstep\n";
+        let mut out = Vec::new();
+
+        dbg.repl(input.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert!(out.contains("breakpoint at 20a"));
+        assert!(out.contains("> 20a: 8014  ADD  V0, V1"));
+        assert!(out.contains("#0  return to 206"));
+        assert!(out.contains("V0=0f V1=0a"));
+        assert!(out.contains("unknown command: bogus"));
+        // `quit` stops the session before the final `step`.
+        assert_eq!(dbg.cpu.position_in_memory, 0x20A);
+    }
+
+    #[test]
+    fn timers_tick_while_debugging() {
+        let mut dbg = debugger(
+            "
+                LD   V0, 3
+                LD   DT, V0
+            wait:
+                LD   V1, DT
+                SE   V1, 0
+                JP   wait
+                HALT
+            ",
+        );
+        assert_eq!(dbg.resume(), Stop::Halted);
+        assert_eq!(dbg.cpu.delay_timer, 0);
+    }
+
+    #[test]
+    fn resume_gives_up_eventually() {
+        let mut dbg = debugger("forever: JP forever");
+        dbg.step_limit = 1000;
+        assert_eq!(dbg.resume(), Stop::StepLimit(1000));
+
+        let mut out = Vec::new();
+        dbg.execute("continue", &mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("still running after 1000 instructions\n"));
+    }
+
+    #[test]
+    fn addresses_are_hex_and_counts_are_decimal() {
+        let mut dbg = debugger(PROGRAM);
+        let mut run = |line: &str| {
+            let mut out = Vec::new();
+            dbg.execute(line, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(run("mem 200 16"), run("mem 0x200 0x10"));
+        assert_eq!(run("mem 200 16").lines().count(), 1);
+        assert_eq!(run("dis 200 3"), run("dis 0x200 0x3"));
+        assert_eq!(run("dis 200 3").lines().count(), 3);
+        assert_eq!(run("mem 200 ten"), "not a number: ten\n");
+
+        // Nothing past the end of memory, however large the numbers.
+        assert_eq!(run("mem 1000"), "invalid address: 1000\n");
+        assert_eq!(run("mem ffffffffffffffff 99"), "invalid address: ffffffffffffffff\n");
+        assert_eq!(run("mem ff8 0xffffffffffffffff"), "ff8: 00 00 00 00 00 00 00 00\n");
+        assert_eq!(run("dis ffe 18446744073709551615"), "  ffe: 0000  HALT\n");
+        assert_eq!(dbg.memory(usize::MAX, usize::MAX), "");
+        assert_eq!(dbg.disassembly(usize::MAX, 4), "");
+    }
+
+    #[test]
+    fn faults_stop_execution() {
+        let mut dbg = debugger("DW 0x8238");
+        assert_eq!(dbg.resume(), Stop::Fault(Fault::UnknownOpcode { address: 0x200, opcode: 0x8238 }));
+        assert!(dbg.disassembly(0x200, 1).contains("???"));
+    }
+}
@@ -0,0 +1,401 @@
+//! Turns CHIP-8 bytes back into the mnemonics accepted by [`assembler`](super::assembler).
+//!
+//! A ROM mixes instructions with data such as sprites, and nothing in the bytes
+//! says which is which. Starting at the entry point, the disassembler follows
+//! every path the CPU could take: falling through to the next opcode, jumping,
+//! calling and skipping. Every byte that is reached this way is code. Anything
+//! else is emitted as `DB` data.
+//!
+//! Jump and call targets, as well as addresses loaded into `I`, are given labels
+//! so that the listing can be fed straight back into the assembler.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::assembler::MEMORY_SIZE;
+use super::opcode::Opcode;
+
+/// What the CPU does after executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flow {
+    Next,
+    Skip,
+    Jump(u16),
+    Call(u16),
+    Stop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    V(u8),
+    Byte(u8),
+    Nibble(u8),
+    Addr(u16),
+    Keyword(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Instruction {
+    mnemonic: &'static str,
+    args: Vec<Arg>,
+    flow: Flow,
+}
+
+/// Decodes an opcode in the same way as `CPU::run`: by matching on `(c, x, y, d)`
+/// and pulling `nnn` or `kk` out when the opcode group needs them.
+fn decode(opcode: u16) -> Option<Instruction> {
+    use Arg::*;
+
+    let Opcode { c, x, y, d, nnn, kk } = Opcode::from(opcode);
+
+    let (mnemonic, args, flow) = match (c, x, y, d) {
+        (0, 0, 0, 0) => ("HALT", vec![], Flow::Stop),
+        (0, 0, 0xE, 0x0) => ("CLS", vec![], Flow::Next),
+        (0, 0, 0xE, 0xE) => ("RET", vec![], Flow::Stop),
+        (0, 0, 0xC, _) => ("SCD", vec![Nibble(d)], Flow::Next),
+        (0, 0, 0xF, 0xB) => ("SCR", vec![], Flow::Next),
+        (0, 0, 0xF, 0xC) => ("SCL", vec![], Flow::Next),
+        (0, 0, 0xF, 0xD) => ("EXIT", vec![], Flow::Stop),
+        (0, 0, 0xF, 0xE) => ("LOW", vec![], Flow::Next),
+        (0, 0, 0xF, 0xF) => ("HIGH", vec![], Flow::Next),
+        (0x0, _, _, _) => ("SYS", vec![Addr(nnn)], Flow::Next),
+        (0x1, _, _, _) => ("JP", vec![Addr(nnn)], Flow::Jump(nnn)),
+        (0x2, _, _, _) => ("CALL", vec![Addr(nnn)], Flow::Call(nnn)),
+        (0x3, _, _, _) => ("SE", vec![V(x), Byte(kk)], Flow::Skip),
+        (0x4, _, _, _) => ("SNE", vec![V(x), Byte(kk)], Flow::Skip),
+        (0x5, _, _, 0x0) => ("SE", vec![V(x), V(y)], Flow::Skip),
+        (0x6, _, _, _) => ("LD", vec![V(x), Byte(kk)], Flow::Next),
+        (0x7, _, _, _) => ("ADD", vec![V(x), Byte(kk)], Flow::Next),
+        (0x8, _, _, 0x0) => ("LD", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x1) => ("OR", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x2) => ("AND", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x3) => ("XOR", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x4) => ("ADD", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x5) => ("SUB", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x6) => ("SHR", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0x7) => ("SUBN", vec![V(x), V(y)], Flow::Next),
+        (0x8, _, _, 0xE) => ("SHL", vec![V(x), V(y)], Flow::Next),
+        (0x9, _, _, 0x0) => ("SNE", vec![V(x), V(y)], Flow::Skip),
+        (0xA, _, _, _) => ("LD", vec![Keyword("I"), Addr(nnn)], Flow::Next),
+        (0xB, _, _, _) => ("JP", vec![V(0), Addr(nnn)], Flow::Stop),
+        (0xC, _, _, _) => ("RND", vec![V(x), Byte(kk)], Flow::Next),
+        (0xD, _, _, _) => ("DRW", vec![V(x), V(y), Nibble(d)], Flow::Next),
+        (0xE, _, 0x9, 0xE) => ("SKP", vec![V(x)], Flow::Skip),
+        (0xE, _, 0xA, 0x1) => ("SKNP", vec![V(x)], Flow::Skip),
+        (0xF, _, 0x0, 0x7) => ("LD", vec![V(x), Keyword("DT")], Flow::Next),
+        (0xF, _, 0x0, 0xA) => ("LD", vec![V(x), Keyword("K")], Flow::Next),
+        (0xF, _, 0x1, 0x5) => ("LD", vec![Keyword("DT"), V(x)], Flow::Next),
+        (0xF, _, 0x1, 0x8) => ("LD", vec![Keyword("ST"), V(x)], Flow::Next),
+        (0xF, _, 0x1, 0xE) => ("ADD", vec![Keyword("I"), V(x)], Flow::Next),
+        (0xF, _, 0x2, 0x9) => ("LD", vec![Keyword("F"), V(x)], Flow::Next),
+        (0xF, _, 0x3, 0x0) => ("LD", vec![Keyword("HF"), V(x)], Flow::Next),
+        (0xF, _, 0x3, 0x3) => ("LD", vec![Keyword("B"), V(x)], Flow::Next),
+        (0xF, _, 0x5, 0x5) => ("LD", vec![Keyword("[I]"), V(x)], Flow::Next),
+        (0xF, _, 0x6, 0x5) => ("LD", vec![V(x), Keyword("[I]")], Flow::Next),
+        (0xF, _, 0x7, 0x5) => ("LD", vec![Keyword("R"), V(x)], Flow::Next),
+        (0xF, _, 0x8, 0x5) => ("LD", vec![V(x), Keyword("R")], Flow::Next),
+        _ => return None,
+    };
+
+    Some(Instruction { mnemonic, args, flow })
+}
+
+fn format_instruction(instruction: &Instruction, name: &dyn Fn(u16) -> String) -> String {
+    let args: Vec<String> = instruction
+        .args
+        .iter()
+        .map(|arg| match *arg {
+            Arg::V(x) => format!("V{:X}", x),
+            Arg::Byte(kk) => format!("{:#04X}", kk),
+            Arg::Nibble(n) => format!("{}", n),
+            Arg::Addr(nnn) => name(nnn),
+            Arg::Keyword(k) => k.to_string(),
+        })
+        .collect();
+
+    if args.is_empty() {
+        instruction.mnemonic.to_string()
+    } else {
+        format!("{:<4} {}", instruction.mnemonic, args.join(", "))
+    }
+}
+
+fn hex_address(nnn: u16) -> String {
+    format!("{:#05X}", nnn)
+}
+
+/// Returns the mnemonic for a single opcode, e.g. `ADD  V0, V1` for 0x8014.
+/// Returns `None` when the opcode is not a CHIP-8 instruction.
+pub fn mnemonic(opcode: u16) -> Option<String> {
+    decode(opcode).map(|instruction| format_instruction(&instruction, &hex_address))
+}
+
+/// Finds every address that the CPU can reach when it starts executing at `origin`.
+fn trace_code(rom: &[u8], origin: u16) -> BTreeSet<u16> {
+    let end = origin as usize + rom.len();
+    let mut code = BTreeSet::new();
+    let mut pending = vec![origin];
+
+    while let Some(address) = pending.pop() {
+        if (address as usize) < origin as usize || address as usize + 2 > end {
+            continue;
+        }
+        if !code.insert(address) {
+            continue;
+        }
+
+        let i = (address - origin) as usize;
+        let opcode = u16::from_be_bytes([rom[i], rom[i + 1]]);
+
+        let instruction = match decode(opcode) {
+            Some(instruction) => instruction,
+            None => {
+                code.remove(&address);
+                continue;
+            }
+        };
+
+        // Addresses past the end of the ROM, which stops at the end of memory, aren't followed.
+        let next = address.checked_add(2);
+        match instruction.flow {
+            Flow::Next => pending.extend(next),
+            Flow::Skip => pending.extend(next.into_iter().chain(address.checked_add(4))),
+            Flow::Jump(target) => pending.push(target),
+            Flow::Call(target) => pending.extend(next.into_iter().chain([target])),
+            Flow::Stop => {}
+        }
+    }
+
+    code
+}
+
+enum Line {
+    Code,
+    Data(Vec<u8>),
+}
+
+/// Produces an assembly listing of `rom`, which is expected to be loaded at `origin`.
+/// Bytes that would land past the end of the CHIP-8's 4 KiB of memory are left out.
+///
+/// Each line carries the address and raw bytes as a comment, so the output is
+/// both readable and accepted by [`assemble_at`](super::assembler::assemble_at).
+pub fn disassemble(rom: &[u8], origin: u16) -> String {
+    const DATA_PER_LINE: usize = 8;
+
+    // The CPU can't reach bytes past the end of memory, so they're neither code nor data.
+    let rom = &rom[..rom.len().min(MEMORY_SIZE.saturating_sub(origin as usize))];
+    let code = trace_code(rom, origin);
+
+    // Lay the ROM out into lines. Instructions are two bytes long; anything
+    // that was never reached (or overlaps with an instruction) becomes data.
+    let mut lines: Vec<(u16, Line)> = Vec::new();
+    let mut address = origin as usize;
+    let end = origin as usize + rom.len();
+
+    while address < end {
+        let here = address as u16;
+        if code.contains(&here) && !code.contains(&here.wrapping_add(1)) {
+            lines.push((here, Line::Code));
+            address += 2;
+            continue;
+        }
+
+        let byte = rom[address - origin as usize];
+        match lines.last_mut() {
+            Some((_, Line::Data(bytes))) if bytes.len() < DATA_PER_LINE => bytes.push(byte),
+            _ => lines.push((here, Line::Data(vec![byte]))),
+        }
+        address += 1;
+    }
+
+    // A label can go at the start of a line, or in the middle of data, which is then
+    // split there. Inside an instruction, there's nowhere to put it.
+    let mut labelable: BTreeSet<u16> = BTreeSet::new();
+    for (start, line) in &lines {
+        match line {
+            Line::Code => labelable.insert(*start),
+            Line::Data(bytes) => {
+                labelable.extend((0..bytes.len() as u16).map(|offset| start + offset));
+                true
+            }
+        };
+    }
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+
+    for &address in &code {
+        let i = (address - origin) as usize;
+        let opcode = u16::from_be_bytes([rom[i], rom[i + 1]]);
+        let Opcode { c, nnn, .. } = Opcode::from(opcode);
+
+        let prefix = match c {
+            0x1 | 0xB => "label",
+            0x2 => "sub",
+            0xA => "data",
+            _ => continue,
+        };
+
+        if labelable.contains(&nnn) {
+            labels.entry(nnn).or_insert_with(|| format!("{}_{:03x}", prefix, nnn));
+        }
+    }
+
+    // Data lines must not run across a label, otherwise the label would be lost.
+    let mut split_lines: Vec<(u16, Line)> = Vec::with_capacity(lines.len());
+    for (start, line) in lines {
+        match line {
+            Line::Data(bytes) => {
+                let mut chunk_start = start;
+                let mut chunk = Vec::new();
+                for (offset, byte) in bytes.into_iter().enumerate() {
+                    let byte_address = start + offset as u16;
+                    if !chunk.is_empty() && labels.contains_key(&byte_address) {
+                        split_lines.push((chunk_start, Line::Data(std::mem::take(&mut chunk))));
+                        chunk_start = byte_address;
+                    }
+                    chunk.push(byte);
+                }
+                split_lines.push((chunk_start, Line::Data(chunk)));
+            }
+            code => split_lines.push((start, code)),
+        }
+    }
+
+    let name = |nnn: u16| labels.get(&nnn).cloned().unwrap_or_else(|| hex_address(nnn));
+
+    let mut listing = String::new();
+    for (start, line) in split_lines {
+        if let Some(label) = labels.get(&start) {
+            listing.push_str(&format!("{}:\n", label));
+        }
+
+        let i = (start - origin) as usize;
+        let (text, bytes) = match line {
+            Line::Code => {
+                let opcode = u16::from_be_bytes([rom[i], rom[i + 1]]);
+                let instruction = decode(opcode).expect("traced code always decodes");
+                (format_instruction(&instruction, &name), format!("{:04X}", opcode))
+            }
+            Line::Data(bytes) => {
+                let values: Vec<String> = bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+                let raw: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+                (format!("DB   {}", values.join(", ")), raw.join(" "))
+            }
+        };
+
+        listing.push_str(&format!("    {:<24} ; {:03x}: {}\n", text, start, bytes));
+    }
+
+    listing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::{assemble, PROGRAM_START};
+
+    #[test]
+    fn single_opcodes() {
+        assert_eq!(mnemonic(0x8014).unwrap(), "ADD  V0, V1");
+        assert_eq!(mnemonic(0x00EE).unwrap(), "RET");
+        assert_eq!(mnemonic(0x2100).unwrap(), "CALL 0x100");
+        assert_eq!(mnemonic(0x73EE).unwrap(), "ADD  V3, 0xEE");
+        assert_eq!(mnemonic(0xF065).unwrap(), "LD   V0, [I]");
+        assert_eq!(mnemonic(0x00C4).unwrap(), "SCD  4");
+        assert_eq!(mnemonic(0xF385).unwrap(), "LD   V3, R");
+        assert_eq!(mnemonic(0x8238), None);
+    }
+
+    #[test]
+    fn separates_code_from_data() {
+        let source = "
+                LD I, sprite
+                CALL draw
+                HALT
+            draw:
+                DRW V0, V1, 5
+                RET
+            sprite:
+                DB 0xF0, 0x90, 0x90, 0x90, 0xF0
+        ";
+        let rom = assemble(source).unwrap();
+        let listing = disassemble(&rom, PROGRAM_START);
+
+        assert!(listing.contains("sub_206:\n    DRW  V0, V1, 5"));
+        assert!(listing.contains("data_20a:\n    DB   0xF0, 0x90, 0x90, 0x90, 0xF0"));
+        assert!(listing.contains("CALL sub_206"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "
+            start:
+                LD   V0, 5
+                LD   V1, 10
+                SE   V0, 5
+                JP   skipped
+                CALL add_twice
+                HIGH
+                SCD  3
+                LD   HF, V2
+                LD   R, V7
+                JP   V0, 0x300
+            skipped:
+                HALT
+            add_twice:
+                ADD  V0, V1
+                ADD  V0, V1
+                RET
+                DB   0x12, 0x34, 0x56
+                DW   0xFFFF
+        ";
+        let rom = assemble(source).unwrap();
+        let listing = disassemble(&rom, PROGRAM_START);
+
+        assert_eq!(assemble(&listing).unwrap(), rom);
+    }
+
+    #[test]
+    fn labels_split_data() {
+        let source = "
+                LD I, second
+                HALT
+                DB 0x01, 0x02, 0x03
+            second:
+                DB 0x04, 0x05
+        ";
+        let rom = assemble(source).unwrap();
+        let listing = disassemble(&rom, PROGRAM_START);
+
+        assert!(listing.contains("LD   I, data_207"), "{}", listing);
+        assert!(listing.contains("DB   0x01, 0x02, 0x03 "), "{}", listing);
+        assert!(listing.contains("data_207:\n    DB   0x04, 0x05"), "{}", listing);
+        assert_eq!(assemble(&listing).unwrap(), rom);
+    }
+
+    #[test]
+    fn end_of_memory() {
+        // Falling through or skipping past 0xFFF is not followed.
+        let listing = disassemble(&[0x60, 0x01, 0x60, 0x02], 0xFFE);
+        assert_eq!(listing.lines().count(), 1, "{}", listing);
+        assert!(listing.contains("LD   V0, 0x01"), "{}", listing);
+        assert!(disassemble(&[0x60, 0x00, 0x30, 0x01], 0xFFC).contains("SE   V0, 0x01"));
+        assert_eq!(disassemble(&[0x60, 0x01], 0x1000), "");
+
+        // Nor are bytes that would be loaded past it.
+        let listing = disassemble(&[0xFF; 0x10000], PROGRAM_START);
+        assert!(listing.ends_with("; ff8: FF FF FF FF FF FF FF FF\n"), "{}", &listing[listing.len() - 100..]);
+        for line in listing.lines() {
+            let address = line.split("; ").nth(1).and_then(|rest| rest.split(':').next()).unwrap();
+            assert!(usize::from_str_radix(address, 16).unwrap() < MEMORY_SIZE, "{}", line);
+        }
+    }
+
+    #[test]
+    fn round_trip_misaligned_jump() {
+        // The jump lands in the middle of the 0x6A12 instruction. Both
+        // interpretations can't be labelled, so the address is kept verbatim.
+        let rom = [0x12, 0x03, 0x6A, 0x12, 0x00, 0x00];
+        let listing = disassemble(&rom, PROGRAM_START);
+
+        assert_eq!(assemble(&listing).unwrap(), rom);
+    }
+}
@@ -0,0 +1,398 @@
+//! Differential fuzzing for CPU RIA/4.
+//!
+//! A [`Generator`] produces random ROMs. Each starts with a prelude that loads random
+//! values into `V0` to `VF` and `I`, followed by random instructions. Most are valid
+//! opcodes with random operands, and jumps mostly land inside the program so that it
+//! runs for a while. [`check`] runs a ROM for a bounded number of cycles and, after
+//! every step, checks that:
+//!
+//! - the CPU didn't panic;
+//! - the stack pointer is within `stack.len()`;
+//! - a program counter that leaves no room for an opcode produces a
+//!   [`Fault::ProgramCounterOutOfBounds`] rather than a read past the end of memory;
+//! - the ALU instructions (`8xy0` to `8xyE`) leave `Vx` and `VF` as an independent model
+//!   of them expects, under the active quirks profile;
+//! - the [cached-decode backend](super::cached) ended up in exactly the same state.
+//!
+//! Because the initial state is part of the ROM, a failing input can be saved as a plain
+//! `.ch8` file and loaded into `chip8-dbg`. Files saved to `fuzz/regressions` are re-run
+//! by the tests. Their names start with the quirks profile they failed under.
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::{error, fmt};
+
+use super::assembler::PROGRAM_START;
+use super::cached::CachedCPU;
+use super::interpreter::{Fault, RomTooLarge, State, CPU};
+use super::opcode::Opcode;
+use super::quirks::Quirks;
+
+/// Where `chip8-fuzz` saves failing inputs, relative to the crate root.
+pub const REGRESSIONS_DIR: &str = "fuzz/regressions";
+
+thread_local! {
+    /// Whether this thread is inside [`check`], which turns panics into failures.
+    static CHECKING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// The prelude is 16 `LD Vx, kk` instructions and an `LD I, nnn`.
+pub const PRELUDE_LEN: usize = 17 * 2;
+
+/// What an opcode's operand bits are filled with.
+#[derive(Clone, Copy)]
+enum Operands {
+    Fixed,
+    /// Random bits under the mask.
+    Bits(u16),
+    /// An even address inside the program, most of the time.
+    Target,
+}
+
+use Operands::{Bits, Fixed, Target};
+
+#[rustfmt::skip]
+const TEMPLATES: &[(u16, Operands)] = &[
+    (0x00E0, Fixed), (0x00EE, Fixed), (0x00C0, Bits(0x000F)), (0x00FB, Fixed), (0x00FC, Fixed),
+    (0x00FD, Fixed), (0x00FE, Fixed), (0x00FF, Fixed),
+    (0x1000, Target), (0x2000, Target), (0xB000, Target),
+    (0x3000, Bits(0x0FFF)), (0x4000, Bits(0x0FFF)), (0x5000, Bits(0x0FF0)),
+    (0x6000, Bits(0x0FFF)), (0x7000, Bits(0x0FFF)),
+    (0x8000, Bits(0x0FF0)), (0x8001, Bits(0x0FF0)), (0x8002, Bits(0x0FF0)), (0x8003, Bits(0x0FF0)),
+    (0x8004, Bits(0x0FF0)), (0x8005, Bits(0x0FF0)), (0x8006, Bits(0x0FF0)), (0x8007, Bits(0x0FF0)),
+    (0x800E, Bits(0x0FF0)), (0x9000, Bits(0x0FF0)),
+    (0xA000, Bits(0x0FFF)), (0xC000, Bits(0x0FFF)), (0xD000, Bits(0x0FFF)),
+    (0xE09E, Bits(0x0F00)), (0xE0A1, Bits(0x0F00)),
+    (0xF007, Bits(0x0F00)), (0xF00A, Bits(0x0F00)), (0xF015, Bits(0x0F00)), (0xF018, Bits(0x0F00)),
+    (0xF01E, Bits(0x0F00)), (0xF029, Bits(0x0F00)), (0xF030, Bits(0x0F00)), (0xF033, Bits(0x0F00)),
+    (0xF055, Bits(0x0F00)), (0xF065, Bits(0x0F00)), (0xF075, Bits(0x0F00)), (0xF085, Bits(0x0F00)),
+];
+
+/// Generates random ROMs from a seed, so that a run of the fuzzer can be repeated.
+pub struct Generator {
+    state: u64,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck at zero.
+        Generator { state: seed.max(1) }
+    }
+
+    /// xorshift64*
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() >> 32) as usize % n
+    }
+
+    /// A ROM holding the prelude followed by `instructions` random opcodes.
+    pub fn rom(&mut self, instructions: usize) -> Vec<u8> {
+        let len = PRELUDE_LEN + 2 * instructions;
+        let mut rom = Vec::with_capacity(len);
+        for x in 0..16 {
+            let value = self.next() as u8;
+            rom.extend_from_slice(&[0x60 | x, value]);
+        }
+        let index = 0xA000 | self.next() as u16 & 0x0FFF;
+        rom.extend_from_slice(&index.to_be_bytes());
+
+        for _ in 0..instructions {
+            rom.extend_from_slice(&self.opcode(len).to_be_bytes());
+        }
+        rom
+    }
+
+    fn opcode(&mut self, rom_len: usize) -> u16 {
+        if self.below(8) == 0 {
+            return self.next() as u16;
+        }
+
+        let (base, operands) = TEMPLATES[self.below(TEMPLATES.len())];
+        let bits = self.next() as u16;
+        match operands {
+            Fixed => base,
+            Bits(mask) => base | bits & mask,
+            Target if self.below(16) == 0 => base | bits & 0x0FFF,
+            Target => base | (PROGRAM_START + 2 * self.below(rom_len / 2) as u16),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    Panic { step: usize, message: String },
+    Invariant { step: usize, message: String },
+    /// The cached backend disagreed with the reference interpreter.
+    Divergence { step: usize, message: String },
+    /// The ROM didn't fit in memory, so it wasn't run at all.
+    Load(RomTooLarge),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Panic { step, message } => write!(f, "step {}: panicked: {}", step, message),
+            Failure::Invariant { step, message } => write!(f, "step {}: {}", step, message),
+            Failure::Divergence { step, message } => {
+                write!(f, "step {}: backends diverged: {}", step, message)
+            }
+            Failure::Load(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for Failure {}
+
+/// How a ROM stopped, when it passed every check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Halted { steps: usize },
+    Faulted { steps: usize, fault: Fault },
+    /// Still running after `cycles` steps.
+    OutOfCycles,
+}
+
+/// Runs `rom` for up to `cycles` steps on both backends, checking invariants after every step.
+///
+/// The keypad is released, except that a key is pressed for one step whenever the
+/// program waits for one. The timers tick every 10 steps.
+pub fn check(rom: &[u8], quirks: Quirks, cycles: usize) -> Result<Outcome, Failure> {
+    let mut cpu = CPU::with_quirks(quirks);
+    cpu.load(rom).map_err(Failure::Load)?;
+    let mut cached = CachedCPU::new(cpu.clone());
+
+    for step in 0..cycles {
+        if step % 10 == 9 {
+            cpu.tick_timers();
+            cached.tick_timers();
+        }
+
+        let before = cpu.clone();
+        let result = catch_panic(step, || cpu.step())?;
+        let cached_result = catch_panic(step, || cached.step())?;
+
+        check_invariants(&before, &result, &cpu).map_err(|message| Failure::Invariant { step, message })?;
+
+        if cached_result != result {
+            let message = format!("reference returned {:?}, cached returned {:?}", result, cached_result);
+            return Err(Failure::Divergence { step, message });
+        }
+        if cached.cpu() != &cpu {
+            let message = format!("reference state {:?}, cached state {:?}", cpu, cached.cpu());
+            return Err(Failure::Divergence { step, message });
+        }
+
+        match result {
+            Ok(State::Running) => cpu.keypad = [false; 16],
+            Ok(State::WaitingForKey) => cpu.keypad[step % 16] = true,
+            Ok(State::Halted) => return Ok(Outcome::Halted { steps: step + 1 }),
+            Err(fault) => return Ok(Outcome::Faulted { steps: step + 1, fault }),
+        }
+        cached.set_keypad(cpu.keypad);
+    }
+
+    Ok(Outcome::OutOfCycles)
+}
+
+/// Installs a panic hook that keeps quiet about the panics [`check`] reports as failures,
+/// and hands every other panic to the hook installed before it.
+pub fn silence_checked_panics() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if !CHECKING.with(Cell::get) {
+            previous(info);
+        }
+    }));
+}
+
+fn catch_panic<T>(step: usize, f: impl FnOnce() -> T) -> Result<T, Failure> {
+    let outer = CHECKING.with(|checking| checking.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CHECKING.with(|checking| checking.set(outer));
+
+    result.map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_string());
+        Failure::Panic { step, message }
+    })
+}
+
+fn check_invariants(before: &CPU, result: &Result<State, Fault>, after: &CPU) -> Result<(), String> {
+    if after.stack_pointer > after.stack.len() {
+        return Err(format!("stack pointer {} is beyond the stack", after.stack_pointer));
+    }
+
+    if before.position_in_memory + 1 >= before.memory.len() {
+        let expected = Err(Fault::ProgramCounterOutOfBounds { address: before.position_in_memory });
+        if *result != expected || after != before {
+            return Err(format!(
+                "executed from {:03x}, which leaves no room for an opcode",
+                before.position_in_memory
+            ));
+        }
+        return Ok(());
+    }
+
+    match (result, before.read_opcode()) {
+        (Ok(_), Ok(opcode)) => check_alu(opcode, before, after),
+        _ => Ok(()),
+    }
+}
+
+/// Models `8xyN` independently of the interpreter. `VF` is written last, so when `x` is
+/// `F` it holds the flag rather than the result.
+pub fn check_alu(opcode: u16, before: &CPU, after: &CPU) -> Result<(), String> {
+    let Opcode { c, x, y, d, .. } = Opcode::from(opcode);
+    if c != 0x8 {
+        return Ok(());
+    }
+
+    let quirks = before.quirks;
+    let (vx, vy) = (before.registers[x as usize], before.registers[y as usize]);
+    let shifted = if quirks.shift_uses_vy { vy } else { vx };
+    let logic_flag = if quirks.logic_resets_vf { Some(0) } else { None };
+
+    let (result, flag) = match d {
+        0x0 => (vy, None),
+        0x1 => (vx | vy, logic_flag),
+        0x2 => (vx & vy, logic_flag),
+        0x3 => (vx ^ vy, logic_flag),
+        0x4 => {
+            let sum = vx as u16 + vy as u16;
+            (sum as u8, Some((sum > 0xFF) as u8))
+        }
+        0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+        0x6 => (shifted >> 1, Some(shifted & 1)),
+        0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+        0xE => (shifted << 1, Some(shifted >> 7)),
+        _ => return Ok(()),
+    };
+
+    let mut expected = before.registers;
+    expected[x as usize] = result;
+    if let Some(flag) = flag {
+        expected[0xF] = flag;
+    }
+
+    if after.registers != expected {
+        return Err(format!(
+            "{:04x} with V{:X} = {}, V{:X} = {}: expected registers {:?}, got {:?}",
+            opcode, x, vx, y, vy, expected, after.registers
+        ));
+    }
+    Ok(())
+}
+
+/// The name a failing input is saved under: its quirks profile and a hash of its contents.
+pub fn regression_name(quirks: Quirks, rom: &[u8]) -> String {
+    // FNV-1a
+    let hash = rom
+        .iter()
+        .fold(0xCBF2_9CE4_8422_2325u64, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01B3));
+
+    format!("{}-{:016x}.ch8", profile_name(quirks), hash)
+}
+
+pub fn profile_name(quirks: Quirks) -> &'static str {
+    if quirks == Quirks::CHIP_48 {
+        "chip-48"
+    } else if quirks == Quirks::SUPER_CHIP {
+        "schip"
+    } else {
+        "vip"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    const CYCLES: usize = 2_000;
+
+    #[test]
+    fn random_programs_pass_every_check() {
+        let mut generator = Generator::new(0x5EED);
+        for _ in 0..300 {
+            let rom = generator.rom(64);
+            for quirks in [Quirks::COSMAC_VIP, Quirks::CHIP_48, Quirks::SUPER_CHIP] {
+                if let Err(failure) = check(&rom, quirks, CYCLES) {
+                    panic!("{} ({})", failure, regression_name(quirks, &rom));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prelude_sets_the_initial_state() {
+        let rom = Generator::new(7).rom(0);
+        assert_eq!(rom.len(), PRELUDE_LEN);
+
+        let mut cpu = CPU::new();
+        cpu.load(&rom).unwrap();
+        cpu.run().unwrap();
+
+        assert_eq!(cpu.position_in_memory, PROGRAM_START as usize + PRELUDE_LEN);
+        assert_eq!(cpu.index_register, u16::from_be_bytes([rom[32], rom[33]]) & 0x0FFF);
+        for x in 0..16 {
+            assert_eq!(cpu.registers[x], rom[2 * x + 1]);
+        }
+    }
+
+    #[test]
+    fn alu_model_catches_wrong_flags() {
+        let mut before = CPU::new();
+        before.registers[1] = 200;
+        before.registers[2] = 100;
+
+        let mut after = before.clone();
+        after.registers[1] = 44;
+        after.registers[0xF] = 1;
+        assert_eq!(check_alu(0x8124, &before, &after), Ok(()));
+
+        after.registers[0xF] = 0;
+        assert!(check_alu(0x8124, &before, &after).is_err());
+    }
+
+    #[test]
+    fn panics_become_failures() {
+        let failure = catch_panic(3, || panic!("boom")).unwrap_err();
+        assert_eq!(failure.to_string(), "step 3: panicked: boom");
+        assert_eq!(
+            check(&[0; 0xE01], Quirks::COSMAC_VIP, 1),
+            Err(Failure::Load(RomTooLarge { size: 0xE01 }))
+        );
+        // Panics after the check aren't silenced.
+        assert!(!CHECKING.with(Cell::get));
+        assert_eq!(catch_panic(4, || 5), Ok(5));
+    }
+
+    #[test]
+    fn regression_roms_still_pass() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(REGRESSIONS_DIR);
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "ch8") {
+                continue;
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let profile = name.rsplit_once('-').unwrap().0;
+            let quirks: Quirks = profile.parse().unwrap();
+
+            let rom = fs::read(&path).unwrap();
+            if let Err(failure) = check(&rom, quirks, CYCLES) {
+                panic!("{}: {}", name, failure);
+            }
+        }
+    }
+}
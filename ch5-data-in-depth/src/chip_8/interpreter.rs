@@ -0,0 +1,774 @@
+use std::{error, fmt};
+
+use super::assembler::{MEMORY_SIZE, PROGRAM_START};
+use super::audio::{AudioSink, NullSink};
+use super::opcode::Opcode;
+use super::quirks::{IndexIncrement, Quirks};
+
+pub const DISPLAY_WIDTH: usize = 64;
+pub const DISPLAY_HEIGHT: usize = 32;
+
+/// The SUPER-CHIP high-resolution mode doubles the display in both directions.
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+/// Where the built-in hexadecimal font is stored. `Fx29` points `I` into it.
+pub const FONT_START: usize = 0x050;
+
+/// Sprites for the hexadecimal digits 0-F, each 4 pixels wide and 5 rows tall.
+pub const FONT: [u8; 80] = [
+    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+    0x20, 0x60, 0x20, 0x20, 0x70, // 1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+    0xF0, 0x80, 0xF0, 0x80, 0x80, // F
+];
+
+/// Where the SUPER-CHIP large font is stored. `Fx30` points `I` into it.
+pub const BIG_FONT_START: usize = FONT_START + FONT.len();
+
+/// Sprites for the hexadecimal digits 0-F, each 8 pixels wide and 10 rows tall.
+pub const BIG_FONT: [u8; 160] = [
+    0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+    0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+    0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+    0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+    0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+    0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
+/// Rather than panicking with `todo!()`, the interpreter reports what went wrong
+/// and where, so that a debugger can show the state of the CPU at the time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOpcode { address: usize, opcode: u16 },
+    StackOverflow { address: usize },
+    StackUnderflow { address: usize },
+    ProgramCounterOutOfBounds { address: usize },
+    MemoryOutOfBounds { address: usize, index: usize },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode { address, opcode } => {
+                write!(f, "unknown opcode {:04x} at {:03x}", opcode, address)
+            }
+            Fault::StackOverflow { address } => write!(f, "stack overflow at {:03x}", address),
+            Fault::StackUnderflow { address } => write!(f, "stack underflow at {:03x}", address),
+            Fault::ProgramCounterOutOfBounds { address } => {
+                write!(f, "program counter out of bounds: {:03x}", address)
+            }
+            Fault::MemoryOutOfBounds { address, index } => {
+                write!(f, "memory access out of bounds at {:03x}: I = {:03x}", address, index)
+            }
+        }
+    }
+}
+
+impl error::Error for Fault {}
+
+/// A ROM that doesn't fit between 0x200 and the end of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RomTooLarge {
+    pub size: usize,
+}
+
+impl fmt::Display for RomTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ROM too large: {} bytes, but only {} fit above 0x200", self.size, MAX_ROM_SIZE)
+    }
+}
+
+impl error::Error for RomTooLarge {}
+
+/// The largest ROM that fits in memory above 0x200.
+pub const MAX_ROM_SIZE: usize = MEMORY_SIZE - PROGRAM_START as usize;
+
+/// What the CPU is doing after a call to [`CPU::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// `Fx0A` blocks until a key is pressed.
+    WaitingForKey,
+    /// The CPU has reached a 0x0000 opcode, like the earlier CPUs in this module,
+    /// or the SUPER-CHIP `00FD` exit instruction.
+    Halted,
+}
+
+/**
+- Registers `V0` to `VF`. `VF` doubles as the flag register for carries, borrows and collisions.
+- `index_register`, known as `I` in CHIP-8 documentation, holds memory addresses for
+  drawing sprites and for loading and storing registers.
+- `delay_timer` and `sound_timer` count down at 60Hz until they reach zero. See [`CPU::tick_timers`].
+  The buzzer sounds while `sound_timer` is non-zero.
+- The display is 64x32 monochrome pixels, or 128x64 in SUPER-CHIP's high-resolution mode.
+  Sprites are drawn by XOR-ing them onto it. In low resolution, only the top-left 64x32
+  pixels of `display` are used.
+- `keypad` holds the state of the 16 keys (0-F), which the host sets.
+- `flags` are the SUPER-CHIP flags registers, which `Fx75` and `Fx85` copy registers to and from.
+- `quirks` selects how ambiguous instructions behave. See [Quirks].
+*/
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, PartialEq, Eq)]
+pub struct CPU {
+    pub registers: [u8; 16],
+    pub index_register: u16,
+    pub position_in_memory: usize,
+    pub memory: [u8; MEMORY_SIZE],
+    pub stack: [u16; 16],
+    pub stack_pointer: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub display: [[bool; HIRES_WIDTH]; HIRES_HEIGHT],
+    pub hires: bool,
+    pub keypad: [bool; 16],
+    pub flags: [u8; 8],
+    pub quirks: Quirks,
+    /// Seeds the random number generator that `Cxkk` uses, so runs can be repeated.
+    pub rng_seed: u32,
+    pub rng_state: u32,
+}
+
+impl fmt::Debug for CPU {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CPU")
+            .field("registers", &self.registers)
+            .field("index_register", &self.index_register)
+            .field("position_in_memory", &self.position_in_memory)
+            .field("stack", &&self.stack[..self.stack_pointer.min(self.stack.len())])
+            .field("delay_timer", &self.delay_timer)
+            .field("sound_timer", &self.sound_timer)
+            .field("quirks", &self.quirks)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        CPU::new()
+    }
+}
+
+impl CPU {
+    pub fn new() -> Self {
+        CPU::with_seed(0x2A2A_2A2A)
+    }
+
+    pub fn with_quirks(quirks: Quirks) -> Self {
+        CPU { quirks, ..CPU::new() }
+    }
+
+    pub fn with_seed(rng_seed: u32) -> Self {
+        let mut memory = [0; MEMORY_SIZE];
+        memory[FONT_START..FONT_START + FONT.len()].copy_from_slice(&FONT);
+        memory[BIG_FONT_START..BIG_FONT_START + BIG_FONT.len()].copy_from_slice(&BIG_FONT);
+
+        CPU {
+            registers: [0; 16],
+            index_register: 0,
+            position_in_memory: PROGRAM_START as usize,
+            memory,
+            stack: [0; 16],
+            stack_pointer: 0,
+            delay_timer: 0,
+            sound_timer: 0,
+            display: [[false; HIRES_WIDTH]; HIRES_HEIGHT],
+            hires: false,
+            keypad: [false; 16],
+            flags: [0; 8],
+            quirks: Quirks::default(),
+            rng_seed,
+            // xorshift gets stuck at zero, so a zero seed is nudged away from it.
+            rng_state: rng_seed.max(1),
+        }
+    }
+
+    /// The width of the display in the current resolution.
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { DISPLAY_WIDTH }
+    }
+
+    /// The height of the display in the current resolution.
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { DISPLAY_HEIGHT }
+    }
+
+    /// Copies a ROM into memory at 0x200, where CHIP-8 programs begin.
+    pub fn load(&mut self, rom: &[u8]) -> Result<(), RomTooLarge> {
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(RomTooLarge { size: rom.len() });
+        }
+        let start = PROGRAM_START as usize;
+        self.memory[start..start + rom.len()].copy_from_slice(rom);
+        Ok(())
+    }
+
+    pub fn read_opcode(&self) -> Result<u16, Fault> {
+        let p = self.position_in_memory;
+        if p + 1 >= MEMORY_SIZE {
+            return Err(Fault::ProgramCounterOutOfBounds { address: p });
+        }
+
+        let op_byte1 = self.memory[p] as u16;
+        let op_byte2 = self.memory[p + 1] as u16;
+
+        Ok(op_byte1 << 8 | op_byte2)
+    }
+
+    /// Runs until the CPU halts or faults.
+    pub fn run(&mut self) -> Result<(), Fault> {
+        loop {
+            if self.step()? == State::Halted {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Executes `cycles` instructions and then ticks the timers once. Calling this
+    /// 60 times per second gives programs their expected speed.
+    pub fn run_frame(&mut self, cycles: usize) -> Result<State, Fault> {
+        self.run_frame_with(cycles, &mut NullSink)
+    }
+
+    /// Like [`CPU::run_frame`], sending the state of the buzzer during the frame to `audio`.
+    pub fn run_frame_with(&mut self, cycles: usize, audio: &mut dyn AudioSink) -> Result<State, Fault> {
+        let mut state = State::Running;
+        for _ in 0..cycles {
+            state = self.step()?;
+            if state != State::Running {
+                break;
+            }
+        }
+        audio.tick(self.beeping());
+        self.tick_timers();
+        Ok(state)
+    }
+
+    pub fn beeping(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// A single iteration of the main loop: read, decode and execute one opcode.
+    pub fn step(&mut self) -> Result<State, Fault> {
+        let opcode = self.read_opcode()?;
+        let address = self.position_in_memory;
+        self.position_in_memory += 2;
+
+        let Opcode { c, x, y, d, nnn, kk } = Opcode::from(opcode);
+        let (x, y) = (x as usize, y as usize);
+        let schip = self.quirks.super_chip;
+
+        match (c, x, y, d) {
+            (0, 0, 0, 0) => {
+                self.position_in_memory = address;
+                return Ok(State::Halted);
+            }
+            (0, 0, 0xF, 0xD) if schip => {
+                self.position_in_memory = address;
+                return Ok(State::Halted);
+            }
+            (0, 0, 0xC, _) if schip => self.scroll_down(d as usize),
+            (0, 0, 0xF, 0xB) if schip => self.scroll_right(4),
+            (0, 0, 0xF, 0xC) if schip => self.scroll_left(4),
+            (0, 0, 0xF, 0xE) if schip => self.set_hires(false),
+            (0, 0, 0xF, 0xF) if schip => self.set_hires(true),
+            (0, 0, 0xE, 0x0) => self.clear_display(),
+            (0, 0, 0xE, 0xE) => self.ret(address)?,
+            (0x1, _, _, _) => self.position_in_memory = nnn as usize,
+            (0x2, _, _, _) => self.call(address, nnn)?,
+            (0x3, _, _, _) => self.skip_if(self.registers[x] == kk),
+            (0x4, _, _, _) => self.skip_if(self.registers[x] != kk),
+            (0x5, _, _, 0x0) => self.skip_if(self.registers[x] == self.registers[y]),
+            (0x6, _, _, _) => self.registers[x] = kk,
+            (0x7, _, _, _) => self.registers[x] = self.registers[x].wrapping_add(kk),
+            (0x8, _, _, 0x0) => self.registers[x] = self.registers[y],
+            (0x8, _, _, 0x1) => self.logic_xy(x, y, |a, b| a | b),
+            (0x8, _, _, 0x2) => self.logic_xy(x, y, |a, b| a & b),
+            (0x8, _, _, 0x3) => self.logic_xy(x, y, |a, b| a ^ b),
+            (0x8, _, _, 0x4) => self.add_xy(x, y),
+            (0x8, _, _, 0x5) => self.sub_xy(x, y),
+            (0x8, _, _, 0x6) => self.shr_xy(x, y),
+            (0x8, _, _, 0x7) => self.subn_xy(x, y),
+            (0x8, _, _, 0xE) => self.shl_xy(x, y),
+            (0x9, _, _, 0x0) => self.skip_if(self.registers[x] != self.registers[y]),
+            (0xA, _, _, _) => self.index_register = nnn,
+            (0xB, _, _, _) => {
+                let offset = if self.quirks.jump_uses_vx { self.registers[x] } else { self.registers[0] };
+                self.position_in_memory = nnn as usize + offset as usize;
+            }
+            (0xC, _, _, _) => self.registers[x] = self.next_random() & kk,
+            (0xD, _, _, _) => self.draw(address, x, y, d)?,
+            (0xE, _, 0x9, 0xE) => self.skip_if(self.key_pressed(x)),
+            (0xE, _, 0xA, 0x1) => self.skip_if(!self.key_pressed(x)),
+            (0xF, _, 0x0, 0x7) => self.registers[x] = self.delay_timer,
+            (0xF, _, 0x0, 0xA) => {
+                match self.keypad.iter().position(|&pressed| pressed) {
+                    Some(key) => self.registers[x] = key as u8,
+                    None => {
+                        self.position_in_memory = address;
+                        return Ok(State::WaitingForKey);
+                    }
+                }
+            }
+            (0xF, _, 0x1, 0x5) => self.delay_timer = self.registers[x],
+            (0xF, _, 0x1, 0x8) => self.sound_timer = self.registers[x],
+            (0xF, _, 0x1, 0xE) => {
+                self.index_register = (self.index_register + self.registers[x] as u16) & 0x0FFF
+            }
+            (0xF, _, 0x2, 0x9) => {
+                self.index_register = (FONT_START + 5 * (self.registers[x] & 0xF) as usize) as u16
+            }
+            (0xF, _, 0x3, 0x0) if schip => {
+                self.index_register = (BIG_FONT_START + 10 * (self.registers[x] & 0xF) as usize) as u16
+            }
+            (0xF, _, 0x3, 0x3) => self.store_bcd(address, x)?,
+            (0xF, _, 0x5, 0x5) => self.store_registers(address, x)?,
+            (0xF, _, 0x6, 0x5) => self.load_registers(address, x)?,
+            (0xF, _, 0x7, 0x5) if schip => {
+                let n = x.min(7);
+                self.flags[..=n].copy_from_slice(&self.registers[..=n]);
+            }
+            (0xF, _, 0x8, 0x5) if schip => {
+                let n = x.min(7);
+                self.registers[..=n].copy_from_slice(&self.flags[..=n]);
+            }
+            _ => {
+                self.position_in_memory = address;
+                return Err(Fault::UnknownOpcode { address, opcode });
+            }
+        }
+
+        Ok(State::Running)
+    }
+
+    pub(super) fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.position_in_memory += 2;
+        }
+    }
+
+    pub(super) fn call(&mut self, address: usize, addr: u16) -> Result<(), Fault> {
+        let sp = self.stack_pointer;
+
+        if sp >= self.stack.len() {
+            self.position_in_memory = address;
+            return Err(Fault::StackOverflow { address });
+        }
+
+        self.stack[sp] = self.position_in_memory as u16;
+        self.stack_pointer += 1;
+        self.position_in_memory = addr as usize;
+        Ok(())
+    }
+
+    pub(super) fn ret(&mut self, address: usize) -> Result<(), Fault> {
+        if self.stack_pointer == 0 {
+            self.position_in_memory = address;
+            return Err(Fault::StackUnderflow { address });
+        }
+
+        self.stack_pointer -= 1;
+        self.position_in_memory = self.stack[self.stack_pointer] as usize;
+        Ok(())
+    }
+
+    pub(super) fn logic_xy(&mut self, x: usize, y: usize, op: fn(u8, u8) -> u8) {
+        self.registers[x] = op(self.registers[x], self.registers[y]);
+        if self.quirks.logic_resets_vf {
+            self.registers[0xF] = 0;
+        }
+    }
+
+    // The result is written before VF, so that VF holds the flag even when it's also `x`.
+    pub(super) fn add_xy(&mut self, x: usize, y: usize) {
+        let (val, overflow) = self.registers[x].overflowing_add(self.registers[y]);
+        self.registers[x] = val;
+        self.registers[0xF] = overflow as u8;
+    }
+
+    pub(super) fn sub_xy(&mut self, x: usize, y: usize) {
+        let (val, borrow) = self.registers[x].overflowing_sub(self.registers[y]);
+        self.registers[x] = val;
+        self.registers[0xF] = !borrow as u8;
+    }
+
+    pub(super) fn subn_xy(&mut self, x: usize, y: usize) {
+        let (val, borrow) = self.registers[y].overflowing_sub(self.registers[x]);
+        self.registers[x] = val;
+        self.registers[0xF] = !borrow as u8;
+    }
+
+    // The COSMAC VIP shifts Vy and stores the result in Vx. Later interpreters shift Vx in place.
+    pub(super) fn shr_xy(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift_uses_vy { self.registers[y] } else { self.registers[x] };
+        self.registers[x] = value >> 1;
+        self.registers[0xF] = value & 1;
+    }
+
+    pub(super) fn shl_xy(&mut self, x: usize, y: usize) {
+        let value = if self.quirks.shift_uses_vy { self.registers[y] } else { self.registers[x] };
+        self.registers[x] = value << 1;
+        self.registers[0xF] = value >> 7;
+    }
+
+    pub(super) fn key_pressed(&self, x: usize) -> bool {
+        self.keypad[(self.registers[x] & 0xF) as usize]
+    }
+
+    /// A xorshift generator: cheap, and deterministic for a given seed.
+    pub(super) fn next_random(&mut self) -> u8 {
+        let mut s = self.rng_state;
+        s ^= s << 13;
+        s ^= s >> 17;
+        s ^= s << 5;
+        self.rng_state = s;
+        (s >> 24) as u8
+    }
+
+    pub(super) fn clear_display(&mut self) {
+        self.display = [[false; HIRES_WIDTH]; HIRES_HEIGHT];
+    }
+
+    // Switching resolution clears the display, as most SUPER-CHIP interpreters do.
+    pub(super) fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.clear_display();
+    }
+
+    pub(super) fn scroll_down(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        for row in (0..height).rev() {
+            for col in 0..width {
+                self.display[row][col] = row >= n && self.display[row - n][col];
+            }
+        }
+    }
+
+    pub(super) fn scroll_right(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        for row in self.display.iter_mut().take(height) {
+            row.copy_within(0..width - n, n);
+            row[..n].fill(false);
+        }
+    }
+
+    pub(super) fn scroll_left(&mut self, n: usize) {
+        let (width, height) = (self.width(), self.height());
+        for row in self.display.iter_mut().take(height) {
+            row.copy_within(n..width, 0);
+            row[width - n..width].fill(false);
+        }
+    }
+
+    fn check_index(&self, address: usize, len: usize) -> Result<usize, Fault> {
+        let index = self.index_register as usize;
+        if index + len > MEMORY_SIZE {
+            return Err(Fault::MemoryOutOfBounds { address, index });
+        }
+        Ok(index)
+    }
+
+    /// Sprites are `n` bytes long, one byte per row, and are XOR-ed onto the display.
+    /// The starting position wraps around the screen, but the sprite itself is clipped.
+    /// VF is set to 1 when any pixel is switched off, which games use for collision detection.
+    ///
+    /// With SUPER-CHIP enabled, `n = 0` draws a 16x16 sprite made of 32 bytes, two per row.
+    pub(super) fn draw(&mut self, address: usize, x: usize, y: usize, n: u8) -> Result<(), Fault> {
+        let (sprite_width, rows) = if n == 0 && self.quirks.super_chip { (16, 16) } else { (8, n as usize) };
+        let bytes_per_row = sprite_width / 8;
+        let index = self.check_index(address, rows * bytes_per_row)?;
+        let (width, height) = (self.width(), self.height());
+        let x0 = self.registers[x] as usize % width;
+        let y0 = self.registers[y] as usize % height;
+        let mut collision = false;
+
+        for row in 0..rows {
+            let py = y0 + row;
+            if py >= height {
+                break;
+            }
+            let start = index + row * bytes_per_row;
+            let sprite_row = self.memory[start..start + bytes_per_row]
+                .iter()
+                .fold(0u16, |bits, &byte| bits << 8 | byte as u16);
+            for col in 0..sprite_width {
+                let px = x0 + col;
+                if px >= width {
+                    break;
+                }
+                if sprite_row & (1 << (sprite_width - 1 - col)) != 0 {
+                    let pixel = &mut self.display[py][px];
+                    collision |= *pixel;
+                    *pixel = !*pixel;
+                }
+            }
+        }
+
+        self.registers[0xF] = collision as u8;
+        Ok(())
+    }
+
+    pub(super) fn store_bcd(&mut self, address: usize, x: usize) -> Result<(), Fault> {
+        let index = self.check_index(address, 3)?;
+        let value = self.registers[x];
+        self.memory[index] = value / 100;
+        self.memory[index + 1] = value / 10 % 10;
+        self.memory[index + 2] = value % 10;
+        Ok(())
+    }
+
+    pub(super) fn store_registers(&mut self, address: usize, x: usize) -> Result<(), Fault> {
+        let index = self.check_index(address, x + 1)?;
+        self.memory[index..=index + x].copy_from_slice(&self.registers[..=x]);
+        self.increment_index(x);
+        Ok(())
+    }
+
+    pub(super) fn load_registers(&mut self, address: usize, x: usize) -> Result<(), Fault> {
+        let index = self.check_index(address, x + 1)?;
+        self.registers[..=x].copy_from_slice(&self.memory[index..=index + x]);
+        self.increment_index(x);
+        Ok(())
+    }
+
+    fn increment_index(&mut self, x: usize) {
+        match self.quirks.load_store {
+            IndexIncrement::XPlusOne => self.index_register += x as u16 + 1,
+            IndexIncrement::X => self.index_register += x as u16,
+            IndexIncrement::Unchanged => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::assemble;
+
+    fn run(source: &str) -> CPU {
+        run_with(Quirks::default(), source)
+    }
+
+    fn run_with(quirks: Quirks, source: &str) -> CPU {
+        let mut cpu = CPU::with_quirks(quirks);
+        cpu.load(&assemble(source).unwrap()).unwrap();
+        cpu.run().unwrap();
+        cpu
+    }
+
+    #[test]
+    fn caller_program() {
+        let cpu = run("
+                LD   V0, 5
+                LD   V1, 10
+                CALL add_twice
+                CALL add_twice
+                HALT
+            add_twice:
+                ADD  V0, V1
+                ADD  V0, V1
+                RET
+        ");
+
+        assert_eq!(cpu.registers[0], 45);
+    }
+
+    #[test]
+    fn alu_flags() {
+        let cpu = run("
+                LD   V0, 200
+                LD   V1, 100
+                ADD  V0, V1  ; 300 overflows to 44
+                LD   V2, VF
+                LD   V3, 10
+                SUB  V3, V1  ; 10 - 100 borrows
+                LD   V4, VF
+                LD   V5, 0b1000_0001
+                SHL  V6, V5
+                LD   V7, VF
+                HALT
+        ");
+
+        assert_eq!(cpu.registers[0], 44);
+        assert_eq!(cpu.registers[2], 1);
+        assert_eq!(cpu.registers[3], 166);
+        assert_eq!(cpu.registers[4], 0);
+        assert_eq!(cpu.registers[6], 2);
+        assert_eq!(cpu.registers[7], 1);
+    }
+
+    #[test]
+    fn bcd_and_registers_round_trip_through_memory() {
+        let cpu = run("
+                LD   V0, 254
+                LD   I, scratch
+                LD   B, V0
+                LD   I, scratch
+                LD   V2, [I]
+                HALT
+            scratch:
+                DB   0, 0, 0
+        ");
+
+        assert_eq!(&cpu.registers[..3], &[2, 5, 4]);
+        assert_eq!(cpu.index_register, 0x20C + 3);
+    }
+
+    #[test]
+    fn drawing_detects_collisions() {
+        let cpu = run("
+                LD   V0, 0
+                LD   F, V0
+                DRW  V0, V0, 5
+                LD   V1, VF
+                DRW  V0, V0, 5
+                HALT
+        ");
+
+        assert_eq!(cpu.registers[1], 0);
+        assert_eq!(cpu.registers[0xF], 1);
+        assert!(cpu.display.iter().flatten().all(|&pixel| !pixel));
+    }
+
+    #[test]
+    fn roms_must_fit_in_memory() {
+        let mut cpu = CPU::new();
+        assert_eq!(cpu.load(&[0x12; MAX_ROM_SIZE]), Ok(()));
+        assert_eq!(cpu.memory[0xFFF], 0x12);
+        assert_eq!(cpu.load(&[0; MAX_ROM_SIZE + 1]), Err(RomTooLarge { size: 0xE01 }));
+        assert_eq!(
+            RomTooLarge { size: 0xE01 }.to_string(),
+            "ROM too large: 3585 bytes, but only 3584 fit above 0x200"
+        );
+    }
+
+    #[test]
+    fn faults_instead_of_panicking() {
+        let mut cpu = CPU::new();
+        cpu.load(&[0x00, 0xEE]).unwrap();
+        assert_eq!(cpu.run(), Err(Fault::StackUnderflow { address: 0x200 }));
+
+        let mut cpu = CPU::new();
+        cpu.load(&[0x82, 0x38]).unwrap();
+        assert_eq!(cpu.run(), Err(Fault::UnknownOpcode { address: 0x200, opcode: 0x8238 }));
+        assert_eq!(cpu.position_in_memory, 0x200);
+
+        let mut cpu = CPU::new();
+        cpu.load(&[0x22, 0x00]).unwrap();
+        assert_eq!(cpu.run(), Err(Fault::StackOverflow { address: 0x200 }));
+        assert_eq!(cpu.stack_pointer, 16);
+    }
+
+    #[test]
+    fn quirks_change_shifts_logic_and_jumps() {
+        let source = "
+                LD   V0, 0b0000_0110
+                LD   V1, 0b1000_0001
+                SHR  V0, V1
+                LD   V2, VF
+                LD   VF, 7
+                OR   V3, V0
+                LD   V4, VF
+                LD   V5, 2
+                JP   V0, table
+            table:
+                HALT
+                HALT
+                HALT
+                HALT
+                LD   V6, 1
+                HALT
+        ";
+
+        let vip = run_with(Quirks::COSMAC_VIP, source);
+        assert_eq!((vip.registers[0], vip.registers[2]), (0b0100_0000, 1));
+        assert_eq!(vip.registers[4], 0);
+        assert_eq!(vip.position_in_memory, 0x212 + 0b0100_0000);
+
+        let chip48 = run_with(Quirks::CHIP_48, source);
+        assert_eq!((chip48.registers[0], chip48.registers[2]), (0b0000_0011, 0));
+        assert_eq!(chip48.registers[4], 7);
+        // `JP V0, table` is read as `JP V2 + 0x12`, and V2 holds 0.
+        assert_eq!(chip48.position_in_memory, 0x212);
+    }
+
+    #[test]
+    fn quirks_change_index_after_load_and_store() {
+        let source = "
+                LD   I, 0x300
+                LD   [I], V3
+                HALT
+        ";
+
+        assert_eq!(run_with(Quirks::COSMAC_VIP, source).index_register, 0x304);
+        assert_eq!(run_with(Quirks::CHIP_48, source).index_register, 0x303);
+        assert_eq!(run_with(Quirks::SUPER_CHIP, source).index_register, 0x300);
+    }
+
+    #[test]
+    fn super_chip_extensions() {
+        let source = "
+                HIGH
+                LD   V0, 120
+                LD   V1, 60
+                LD   I, big_sprite
+                DRW  V0, V1, 0
+                SCL
+                LD   V7, 0x42
+                LD   R, V7
+                LD   V7, 0
+                LD   V7, R
+                LD   V2, 9
+                LD   HF, V2
+                EXIT
+            big_sprite:
+                DW   0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF
+                DW   0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF
+        ";
+
+        let cpu = run_with(Quirks::SUPER_CHIP, source);
+
+        assert!(cpu.hires);
+        // The 16x16 sprite is clipped to 8x4 pixels at (120, 60), then scrolled 4 pixels left.
+        let lit = cpu.display.iter().flatten().filter(|&&pixel| pixel).count();
+        assert_eq!(lit, 8 * 4);
+        assert!(cpu.display[63][116] && !cpu.display[63][124]);
+        assert_eq!(cpu.flags[7], 0x42);
+        assert_eq!(cpu.registers[7], 0x42);
+        assert_eq!(cpu.index_register as usize, BIG_FONT_START + 90);
+    }
+
+    #[test]
+    fn super_chip_instructions_need_the_super_chip_profile() {
+        let mut cpu = CPU::with_quirks(Quirks::COSMAC_VIP);
+        cpu.load(&assemble("HIGH").unwrap()).unwrap();
+        assert_eq!(cpu.run(), Err(Fault::UnknownOpcode { address: 0x200, opcode: 0x00FF }));
+    }
+}
@@ -53,7 +53,7 @@ Additions from CPU RIA/2:
   1. Define the function:
      Our function performs two addition operations and then returns. It is three opcodes long.
      The function’s internals look like this in a notation that resembles assembly language:
-     ```
+     ```text
      add_twice:
          0x8014
          0x8014
@@ -91,3 +91,96 @@ Additions from CPU RIA/2:
         -> Prints [128, 20, 128, 20, 0, 238]
 */
 pub mod caller;
+
+/**
+Writing opcodes by hand quickly becomes tedious. The assembler translates the
+notation used above (`ADD V0, V1`, `CALL add_twice`, `RET`) into bytes, resolving
+labels such as `add_twice` into memory addresses:
+    @see [assembler::assemble()]
+    -> Returns [128, 20, 128, 20, 0, 238] for `add_twice`
+
+The `chip8-asm` binary wraps the assembler to produce `.ch8` ROM files.
+*/
+pub mod assembler;
+
+/**
+The disassembler goes the other way, turning the bytes of a ROM back into mnemonics.
+It decodes opcodes with the same `(c, x, y, d)` matching as `CPU::run`, via [opcode::Opcode].
+    @see [disassembler::disassemble()]
+
+The `chip8-dis` binary prints the listing for a `.ch8` ROM file.
+*/
+pub mod disassembler;
+pub mod opcode;
+
+/**
+CPU RIA/4, the interpreter, implements the complete CHIP-8 instruction set.
+Additions from CPU RIA/3:
+- The index register `I`, a delay timer, a 64x32 display, a keypad and a random number generator.
+- Programs are loaded at 0x200. The hexadecimal font lives below it, at 0x050.
+- Rather than panicking with `todo!()`, `step()` and `run()` return a [interpreter::Fault]
+  describing what went wrong and where.
+*/
+pub mod interpreter;
+
+/**
+Interpreters written after the COSMAC VIP disagree on shifts (`8xy6`/`8xyE`), on what `Fx55`/`Fx65`
+do to `I`, and on `Bnnn`. A quirks profile selects the behaviour: COSMAC VIP (the default),
+CHIP-48 or SUPER-CHIP. The SUPER-CHIP profile also enables its extensions: a 128x64 high-resolution
+mode, scrolling, 16x16 sprites, a large font and the flags registers.
+    @see [quirks::Quirks]
+*/
+pub mod quirks;
+
+/**
+The debugger wraps CPU RIA/4 with breakpoints, watchpoints, single-stepping and an execution trace.
+    @see [debugger::Debugger::repl()]
+
+The `chip8-dbg` binary starts a debugging session for a `.ch8` ROM file.
+*/
+pub mod debugger;
+
+/**
+Save states capture everything about CPU RIA/4 in a versioned binary file: memory, registers,
+`I`, the program counter, the stack, timers, the display and the random number generator.
+Restoring a save state and replaying the recorded keypad input reproduces a run exactly.
+    @see [save_state::save()], [save_state::restore()] and [save_state::InputLog]
+*/
+pub mod save_state;
+
+/**
+The buzzer sounds while the sound timer is non-zero. Each timer tick is reported to an audio sink,
+which can discard it or write it to a WAV file so that a program's sound can be checked in a test.
+    @see [audio::AudioSink], [audio::WavSink] and [interpreter::CPU::run_frame_with()]
+*/
+pub mod audio;
+
+/**
+A faster backend for CPU RIA/4 that decodes memory once into an instruction cache, rather than
+on every cycle. Writes to memory re-decode the instructions they overlap, so self-modifying
+programs still work, and it behaves identically to the reference interpreter.
+    @see [cached::CachedCPU]
+
+`cargo bench` compares the speed of the two backends.
+*/
+pub mod cached;
+
+/**
+Differential fuzzing: random programs with random initial registers are run on CPU RIA/4 while
+checking its invariants, and compared step by step with the cached-decode backend.
+    @see [fuzz::check()] and [fuzz::Generator]
+
+The `chip8-fuzz` binary runs the fuzzer and saves failing inputs as regression ROMs.
+*/
+pub mod fuzz;
+
+/**
+CPU RIA/3 had no programming language support. The compiler adds a small one, with variables,
+arithmetic, conditions, loops and functions, and compiles it to CHIP-8 bytecode via the assembler.
+Variables live in `V0` to `VE`, functions become subroutines called with `2nnn` and returning with `00EE`,
+and loops become jumps.
+    @see [compiler::compile()]
+
+The `chip8-cc` binary compiles a source file into a `.ch8` ROM.
+*/
+pub mod compiler;
@@ -0,0 +1,34 @@
+/**
+The variables that CHIP-8 documentation uses to refer to the parts of an opcode.
+Using 0x73EE as an example:
+- `c`: the high nibble of the high byte, the 'opcode group' [7]
+- `x`: the low nibble of the high byte, usually 'register x' [3]
+- `y`: the high nibble of the low byte, usually 'register y' [E]
+- `d`: the low nibble of the low byte, usually the 'opcode subtype' [E]
+- `nnn`: the lowest 12 bits, a memory address [3EE]
+- `kk`: the low byte, an integer argument [EE]
+
+Which of these are meaningful depends on the opcode group.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Opcode {
+    pub c: u8,
+    pub x: u8,
+    pub y: u8,
+    pub d: u8,
+    pub nnn: u16,
+    pub kk: u8,
+}
+
+impl From<u16> for Opcode {
+    fn from(opcode: u16) -> Self {
+        Opcode {
+            c: ((opcode & 0xF000) >> 12) as u8,
+            x: ((opcode & 0x0F00) >> 8) as u8,
+            y: ((opcode & 0x00F0) >> 4) as u8,
+            d: (opcode & 0x000F) as u8,
+            nnn: opcode & 0x0FFF,
+            kk: (opcode & 0x00FF) as u8,
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! CHIP-8 was never formally specified. Interpreters written after the original
+//! COSMAC VIP one disagree on the details of several instructions, and programs
+//! written for one interpreter can misbehave on another. A [`Quirks`] profile
+//! selects which behaviour CPU RIA/4 follows.
+
+use std::str::FromStr;
+
+/// What `Fx55` and `Fx65` do to `I` after storing or loading registers `V0` to `Vx`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexIncrement {
+    /// `I` is left pointing just past the last register (COSMAC VIP).
+    XPlusOne,
+    /// `I` is incremented by `x`, one short of the last register (CHIP-48).
+    X,
+    /// `I` is left unchanged (SUPER-CHIP).
+    Unchanged,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift `Vy` and store the result in `Vx`. Otherwise `Vx` is shifted in place.
+    pub shift_uses_vy: bool,
+    /// `8xy1`/`8xy2`/`8xy3` set `VF` to 0 as a side effect.
+    pub logic_resets_vf: bool,
+    pub load_store: IndexIncrement,
+    /// `Bnnn` jumps to `xnn + Vx` rather than `nnn + V0`.
+    pub jump_uses_vx: bool,
+    /// Enables the SUPER-CHIP instructions: the 128x64 high-resolution mode, scrolling,
+    /// 16x16 sprites, the large font and the flags registers.
+    pub super_chip: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        logic_resets_vf: true,
+        load_store: IndexIncrement::XPlusOne,
+        jump_uses_vx: false,
+        super_chip: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        logic_resets_vf: false,
+        load_store: IndexIncrement::X,
+        jump_uses_vx: true,
+        super_chip: false,
+    };
+
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        logic_resets_vf: false,
+        load_store: IndexIncrement::Unchanged,
+        jump_uses_vx: true,
+        super_chip: true,
+    };
+
+    /// Packs the profile into a byte for save states.
+    pub fn to_byte(self) -> u8 {
+        let load_store = match self.load_store {
+            IndexIncrement::XPlusOne => 0,
+            IndexIncrement::X => 1,
+            IndexIncrement::Unchanged => 2,
+        };
+
+        (self.shift_uses_vy as u8)
+            | (self.logic_resets_vf as u8) << 1
+            | (self.jump_uses_vx as u8) << 2
+            | (self.super_chip as u8) << 3
+            | load_store << 4
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let load_store = match byte >> 4 {
+            0 => IndexIncrement::XPlusOne,
+            1 => IndexIncrement::X,
+            2 => IndexIncrement::Unchanged,
+            _ => return None,
+        };
+
+        Some(Quirks {
+            shift_uses_vy: byte & 0b0001 != 0,
+            logic_resets_vf: byte & 0b0010 != 0,
+            jump_uses_vx: byte & 0b0100 != 0,
+            super_chip: byte & 0b1000 != 0,
+            load_store,
+        })
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COSMAC_VIP
+    }
+}
+
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac-vip" | "chip-8" | "chip8" => Ok(Quirks::COSMAC_VIP),
+            "chip-48" | "chip48" => Ok(Quirks::CHIP_48),
+            "super-chip" | "superchip" | "schip" => Ok(Quirks::SUPER_CHIP),
+            _ => Err(format!("unknown quirks profile '{}' (expected vip, chip-48 or schip)", name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_round_trip_through_bytes_and_names() {
+        for quirks in [Quirks::COSMAC_VIP, Quirks::CHIP_48, Quirks::SUPER_CHIP] {
+            assert_eq!(Quirks::from_byte(quirks.to_byte()), Some(quirks));
+        }
+
+        assert_eq!("schip".parse(), Ok(Quirks::SUPER_CHIP));
+        assert_eq!("CHIP-48".parse(), Ok(Quirks::CHIP_48));
+        assert!("xo-chip".parse::<Quirks>().is_err());
+        assert_eq!(Quirks::from_byte(0xF0), None);
+    }
+}
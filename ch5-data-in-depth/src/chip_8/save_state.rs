@@ -0,0 +1,382 @@
+//! Saving and restoring the complete state of the [`interpreter`](super::interpreter) CPU.
+//!
+//! A save state is a versioned binary file. All multi-byte values are big-endian,
+//! like CHIP-8 opcodes:
+//!
+//! ```text
+//! | magic  | version | registers | I   | PC  | stack     | SP | DT | ST | quirks | hires | flags    | display    |
+//! | "CH8S" | u16     | [u8; 16]  | u16 | u16 | [u16; 16] | u8 | u8 | u8 | u8     | u8    | [u8; 8]  | [u8; 1024] |
+//!
+//! | keypad | rng seed | rng state | memory     |
+//! | u16    | u32      | u32       | [u8; 4096] |
+//! ```
+//!
+//! The display is packed 8 pixels per byte, row by row, with the leftmost pixel in the highest bit.
+//! All 128x64 pixels are stored, whichever resolution is active.
+//!
+//! The interpreter is deterministic: the random number generator is seeded, and the only
+//! other input is the keypad. Restoring a save state and feeding in the same keypad state
+//! for every frame, as recorded by an [`InputLog`], reproduces a run bit for bit.
+
+use std::{error, fmt};
+
+use super::assembler::MEMORY_SIZE;
+use super::interpreter::{Fault, State, CPU, HIRES_HEIGHT, HIRES_WIDTH};
+use super::quirks::Quirks;
+
+const MAGIC: &[u8; 4] = b"CH8S";
+const INPUT_LOG_MAGIC: &[u8; 4] = b"CH8I";
+
+/// Bumped whenever the layout changes. Files with any other version are refused.
+pub const VERSION: u16 = 1;
+const INPUT_LOG_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a CHIP-8 save file"),
+            SaveStateError::UnsupportedVersion(v) => write!(f, "unsupported save file version {}", v),
+            SaveStateError::Truncated => write!(f, "save file is truncated"),
+            SaveStateError::Invalid(field) => write!(f, "save file contains an invalid {}", field),
+        }
+    }
+}
+
+impl error::Error for SaveStateError {}
+
+/// Why an [`InputLog`] couldn't be replayed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The save state was taken after the last recorded frame.
+    StartPastEnd { start: usize, frames: usize },
+    Fault(Fault),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::StartPastEnd { start, frames } => {
+                write!(f, "cannot replay from frame {}: the input log has {} frames", start, frames)
+            }
+            ReplayError::Fault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+impl error::Error for ReplayError {}
+
+impl From<Fault> for ReplayError {
+    fn from(fault: Fault) -> Self {
+        ReplayError::Fault(fault)
+    }
+}
+
+/// Reads big-endian values from a byte slice, failing once the slice runs out.
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.bytes.len() < n {
+            return Err(SaveStateError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Converts the CPU's keypad into a bitmask, with key 0 in the lowest bit.
+pub fn keypad_to_mask(keypad: &[bool; 16]) -> u16 {
+    keypad
+        .iter()
+        .enumerate()
+        .fold(0, |mask, (key, &pressed)| mask | (pressed as u16) << key)
+}
+
+pub fn mask_to_keypad(mask: u16) -> [bool; 16] {
+    let mut keypad = [false; 16];
+    for (key, pressed) in keypad.iter_mut().enumerate() {
+        *pressed = mask & (1 << key) != 0;
+    }
+    keypad
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MEMORY_SIZE + 512);
+
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_be_bytes());
+    out.extend_from_slice(&cpu.registers);
+    out.extend_from_slice(&cpu.index_register.to_be_bytes());
+    out.extend_from_slice(&(cpu.position_in_memory as u16).to_be_bytes());
+    for addr in &cpu.stack {
+        out.extend_from_slice(&addr.to_be_bytes());
+    }
+    out.push(cpu.stack_pointer as u8);
+    out.push(cpu.delay_timer);
+    out.push(cpu.sound_timer);
+    out.push(cpu.quirks.to_byte());
+    out.push(cpu.hires as u8);
+    out.extend_from_slice(&cpu.flags);
+    for row in &cpu.display {
+        for pixels in row.chunks(8) {
+            let byte = pixels.iter().fold(0u8, |byte, &on| byte << 1 | on as u8);
+            out.push(byte);
+        }
+    }
+    out.extend_from_slice(&keypad_to_mask(&cpu.keypad).to_be_bytes());
+    out.extend_from_slice(&cpu.rng_seed.to_be_bytes());
+    out.extend_from_slice(&cpu.rng_state.to_be_bytes());
+    out.extend_from_slice(&cpu.memory);
+
+    out
+}
+
+pub fn restore(bytes: &[u8]) -> Result<CPU, SaveStateError> {
+    let mut r = Reader { bytes };
+
+    if r.take(4)? != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+    let version = r.u16()?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let mut cpu = CPU::new();
+
+    cpu.registers.copy_from_slice(r.take(16)?);
+    cpu.index_register = r.u16()?;
+    cpu.position_in_memory = r.u16()? as usize;
+    for addr in cpu.stack.iter_mut() {
+        *addr = r.u16()?;
+    }
+    cpu.stack_pointer = r.u8()? as usize;
+    if cpu.stack_pointer > cpu.stack.len() {
+        return Err(SaveStateError::Invalid("stack pointer"));
+    }
+    cpu.delay_timer = r.u8()?;
+    cpu.sound_timer = r.u8()?;
+    cpu.quirks = Quirks::from_byte(r.u8()?).ok_or(SaveStateError::Invalid("quirks profile"))?;
+    cpu.hires = match r.u8()? {
+        0 => false,
+        1 => true,
+        _ => return Err(SaveStateError::Invalid("display mode")),
+    };
+    cpu.flags.copy_from_slice(r.take(8)?);
+
+    for row in cpu.display.iter_mut().take(HIRES_HEIGHT) {
+        for pixels in row[..HIRES_WIDTH].chunks_mut(8) {
+            let byte = r.u8()?;
+            for (i, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = byte & (0x80 >> i) != 0;
+            }
+        }
+    }
+    cpu.keypad = mask_to_keypad(r.u16()?);
+    cpu.rng_seed = r.u32()?;
+    cpu.rng_state = r.u32()?;
+    if cpu.rng_state == 0 {
+        return Err(SaveStateError::Invalid("random number generator state"));
+    }
+    cpu.memory.copy_from_slice(r.take(MEMORY_SIZE)?);
+
+    Ok(cpu)
+}
+
+/// The keypad state for every frame of a run, which is all that's needed to replay it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InputLog {
+    pub cycles_per_frame: u32,
+    pub frames: Vec<u16>,
+}
+
+impl InputLog {
+    pub fn new(cycles_per_frame: u32) -> Self {
+        InputLog { cycles_per_frame, frames: Vec::new() }
+    }
+
+    /// Sets the keypad to `keys`, runs a frame and records `keys` so the frame can be replayed.
+    pub fn run_frame(&mut self, cpu: &mut CPU, keys: u16) -> Result<State, Fault> {
+        self.frames.push(keys);
+        cpu.keypad = mask_to_keypad(keys);
+        cpu.run_frame(self.cycles_per_frame as usize)
+    }
+
+    /// Feeds the recorded frames from `start` onwards into `cpu`.
+    pub fn replay(&self, cpu: &mut CPU, start: usize) -> Result<(), ReplayError> {
+        let frames = self.frames.get(start..).ok_or(ReplayError::StartPastEnd {
+            start,
+            frames: self.frames.len(),
+        })?;
+        for &keys in frames {
+            cpu.keypad = mask_to_keypad(keys);
+            cpu.run_frame(self.cycles_per_frame as usize)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(14 + 2 * self.frames.len());
+        out.extend_from_slice(INPUT_LOG_MAGIC);
+        out.extend_from_slice(&INPUT_LOG_VERSION.to_be_bytes());
+        out.extend_from_slice(&self.cycles_per_frame.to_be_bytes());
+        out.extend_from_slice(&(self.frames.len() as u32).to_be_bytes());
+        for keys in &self.frames {
+            out.extend_from_slice(&keys.to_be_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SaveStateError> {
+        let mut r = Reader { bytes };
+
+        if r.take(4)? != INPUT_LOG_MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != INPUT_LOG_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let cycles_per_frame = r.u32()?;
+        let len = r.u32()? as usize;
+        let mut frames = Vec::with_capacity(len.min(bytes.len() / 2));
+        for _ in 0..len {
+            frames.push(r.u16()?);
+        }
+
+        Ok(InputLog { cycles_per_frame, frames })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chip_8::assembler::assemble;
+
+    /// Bounces a sprite around the screen at random, pausing on the delay timer
+    /// and speeding up while key 5 is held.
+    const BOUNCER: &str = "
+            LD   I, ball
+        loop:
+            RND  V0, 63
+            RND  V1, 31
+            DRW  V0, V1, 3
+            ADD  V2, 1
+            LD   V3, 5
+            SKNP V3
+            ADD  V2, 10
+            LD   V4, 2
+            LD   DT, V4
+        wait:
+            LD   V4, DT
+            SE   V4, 0
+            JP   wait
+            JP   loop
+        ball:
+            DB   0x40, 0xE0, 0x40
+    ";
+
+    fn keys_for_frame(frame: usize) -> u16 {
+        if frame % 7 < 3 { 1 << 5 } else { 0 }
+    }
+
+    #[test]
+    fn save_and_restore_round_trip() {
+        let mut cpu = CPU::with_seed(1234);
+        cpu.load(&assemble(BOUNCER).unwrap()).unwrap();
+        for _ in 0..20 {
+            cpu.run_frame(10).unwrap();
+        }
+        cpu.keypad[0xA] = true;
+        cpu.sound_timer = 17;
+
+        let restored = restore(&save(&cpu)).unwrap();
+
+        assert_eq!(restored, cpu);
+    }
+
+    #[test]
+    fn replay_from_mid_run_save_is_bit_exact() {
+        let mut cpu = CPU::with_seed(99);
+        cpu.load(&assemble(BOUNCER).unwrap()).unwrap();
+        let mut log = InputLog::new(12);
+
+        let mut snapshot = Vec::new();
+        for frame in 0..200 {
+            if frame == 80 {
+                snapshot = save(&cpu);
+            }
+            log.run_frame(&mut cpu, keys_for_frame(frame)).unwrap();
+        }
+
+        let log = InputLog::from_bytes(&log.to_bytes()).unwrap();
+        let mut replayed = restore(&snapshot).unwrap();
+        log.replay(&mut replayed, 80).unwrap();
+
+        assert_eq!(replayed, cpu);
+        assert!(cpu.display.iter().flatten().any(|&pixel| pixel));
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let cpu = CPU::new();
+        let bytes = save(&cpu);
+
+        assert_eq!(restore(b"nope").unwrap_err(), SaveStateError::BadMagic);
+        assert_eq!(restore(&bytes[..100]).unwrap_err(), SaveStateError::Truncated);
+
+        let mut future = bytes.clone();
+        future[4..6].copy_from_slice(&99u16.to_be_bytes());
+        assert_eq!(restore(&future).unwrap_err(), SaveStateError::UnsupportedVersion(99));
+    }
+
+    #[test]
+    fn refuses_to_replay_past_the_end_of_the_log() {
+        let mut cpu = CPU::with_seed(7);
+        cpu.load(&assemble(BOUNCER).unwrap()).unwrap();
+        let mut log = InputLog::new(12);
+        for frame in 0..10 {
+            log.run_frame(&mut cpu, keys_for_frame(frame)).unwrap();
+        }
+
+        let before = cpu.clone();
+        assert_eq!(log.replay(&mut cpu, 10), Ok(()));
+        assert_eq!(cpu, before);
+        assert_eq!(log.replay(&mut cpu, 11), Err(ReplayError::StartPastEnd { start: 11, frames: 10 }));
+        assert_eq!(cpu, before);
+    }
+
+    #[test]
+    fn preserves_super_chip_state() {
+        let mut cpu = CPU::with_quirks(Quirks::SUPER_CHIP);
+        cpu.hires = true;
+        cpu.flags = [1, 2, 3, 4, 5, 6, 7, 8];
+        cpu.display[63][127] = true;
+
+        assert_eq!(restore(&save(&cpu)).unwrap(), cpu);
+    }
+}
@@ -0,0 +1,366 @@
+// Reading numbers out of a file or a network packet means choosing a byte order.
+// Big endian puts the most significant byte first, as network protocols do.
+// Little endian puts it last, as x86 and most ARM CPUs do in memory.
+//
+// `std::mem::transmute` reinterprets bytes in whatever order the host happens to
+// use, and needs `unsafe`. The `to_be_bytes`/`from_le_bytes` family on every
+// primitive does the same job safely, with the order spelled out. This module puts
+// a small codec on top of them: read values straight out of a borrowed byte slice
+// and write them straight into one, without copying into intermediate buffers.
+
+use std::{error, fmt};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+impl Endian {
+    /// The byte order of the host.
+    pub const NATIVE: Endian = if cfg!(target_endian = "big") { Endian::Big } else { Endian::Little };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecError {
+    /// A value needed more bytes than were left.
+    UnexpectedEnd { needed: usize, available: usize },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CodecError::UnexpectedEnd { needed, available } => {
+                write!(f, "needed {} bytes, but only {} are left", needed, available)
+            }
+        }
+    }
+}
+
+impl error::Error for CodecError {}
+
+fn check_len(needed: usize, available: usize) -> Result<(), CodecError> {
+    if available < needed { Err(CodecError::UnexpectedEnd { needed, available }) } else { Ok(()) }
+}
+
+/// A value with a fixed-size encoding in either byte order.
+pub trait Codec: Sized {
+    /// The number of bytes in the encoding.
+    const SIZE: usize;
+
+    /// Decodes a value from the start of `bytes`.
+    fn read_from(bytes: &[u8], endian: Endian) -> Result<Self, CodecError>;
+
+    /// Encodes the value into the start of `bytes`.
+    fn write_to(&self, bytes: &mut [u8], endian: Endian) -> Result<(), CodecError>;
+}
+
+macro_rules! primitive_codec {
+    ($($t:ty),*) => {$(
+        impl Codec for $t {
+            const SIZE: usize = std::mem::size_of::<$t>();
+
+            fn read_from(bytes: &[u8], endian: Endian) -> Result<Self, CodecError> {
+                check_len(Self::SIZE, bytes.len())?;
+                let array = bytes[..Self::SIZE].try_into().expect("length was checked");
+                Ok(match endian {
+                    Endian::Big => <$t>::from_be_bytes(array),
+                    Endian::Little => <$t>::from_le_bytes(array),
+                })
+            }
+
+            fn write_to(&self, bytes: &mut [u8], endian: Endian) -> Result<(), CodecError> {
+                check_len(Self::SIZE, bytes.len())?;
+                let array = match endian {
+                    Endian::Big => self.to_be_bytes(),
+                    Endian::Little => self.to_le_bytes(),
+                };
+                bytes[..Self::SIZE].copy_from_slice(&array);
+                Ok(())
+            }
+        }
+    )*};
+}
+
+primitive_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+/// Arrays are encoded element by element, as they are laid out in memory.
+impl<T: Codec, const N: usize> Codec for [T; N] {
+    const SIZE: usize = T::SIZE * N;
+
+    fn read_from(bytes: &[u8], endian: Endian) -> Result<Self, CodecError> {
+        check_len(Self::SIZE, bytes.len())?;
+        let mut items = Vec::with_capacity(N);
+        for chunk in bytes.chunks_exact(T::SIZE).take(N) {
+            items.push(T::read_from(chunk, endian)?);
+        }
+        Ok(items.try_into().unwrap_or_else(|_| unreachable!("exactly N items were read")))
+    }
+
+    fn write_to(&self, bytes: &mut [u8], endian: Endian) -> Result<(), CodecError> {
+        check_len(Self::SIZE, bytes.len())?;
+        for (item, chunk) in self.iter().zip(bytes.chunks_exact_mut(T::SIZE)) {
+            item.write_to(chunk, endian)?;
+        }
+        Ok(())
+    }
+}
+
+/// Defines a `#[repr(C)]` struct and implements [`Codec`] for it, much like a derive.
+///
+/// Each field is encoded at the offset it has in memory, and padding is written as
+/// zeros. Encoded in [`Endian::NATIVE`] order, the bytes are the struct's memory image,
+/// so the format matches C code that writes the same struct with `fwrite`.
+///
+/// ```
+/// use libchapter5::codec_struct;
+/// use libchapter5::endian::{Codec, Endian};
+///
+/// codec_struct! {
+///     #[derive(Debug, PartialEq)]
+///     pub struct Header {
+///         pub magic: u32,
+///         pub version: u16,
+///     }
+/// }
+///
+/// let mut bytes = [0; 8];
+/// Header { magic: 0xCAFE_F00D, version: 2 }.write_to(&mut bytes, Endian::Big).unwrap();
+/// assert_eq!(bytes, [0xCA, 0xFE, 0xF0, 0x0D, 0, 2, 0, 0]);
+/// ```
+#[macro_export]
+macro_rules! codec_struct {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($field_vis:vis $field:ident: $type:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        #[repr(C)]
+        $vis struct $name {
+            $($field_vis $field: $type),*
+        }
+
+        impl $crate::endian::Codec for $name {
+            const SIZE: usize = ::std::mem::size_of::<$name>();
+
+            fn read_from(
+                bytes: &[u8],
+                endian: $crate::endian::Endian,
+            ) -> ::std::result::Result<Self, $crate::endian::CodecError> {
+                if bytes.len() < Self::SIZE {
+                    return Err($crate::endian::CodecError::UnexpectedEnd { needed: Self::SIZE, available: bytes.len() });
+                }
+                Ok($name {
+                    $($field: <$type as $crate::endian::Codec>::read_from(
+                        &bytes[::std::mem::offset_of!($name, $field)..],
+                        endian,
+                    )?),*
+                })
+            }
+
+            fn write_to(
+                &self,
+                bytes: &mut [u8],
+                endian: $crate::endian::Endian,
+            ) -> ::std::result::Result<(), $crate::endian::CodecError> {
+                if bytes.len() < Self::SIZE {
+                    return Err($crate::endian::CodecError::UnexpectedEnd { needed: Self::SIZE, available: bytes.len() });
+                }
+                bytes[..Self::SIZE].fill(0);
+                $($crate::endian::Codec::write_to(&self.$field, &mut bytes[::std::mem::offset_of!($name, $field)..], endian)?;)*
+                Ok(())
+            }
+        }
+    };
+}
+
+/// Reads values one after another from a borrowed byte slice.
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    endian: Endian,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8], endian: Endian) -> Self {
+        Reader { bytes, position: 0, endian }
+    }
+
+    pub fn read<T: Codec>(&mut self) -> Result<T, CodecError> {
+        let value = T::read_from(&self.bytes[self.position..], self.endian)?;
+        self.position += T::SIZE;
+        Ok(value)
+    }
+
+    /// Borrows the next `n` bytes, without copying them.
+    pub fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], CodecError> {
+        check_len(n, self.remaining())?;
+        let bytes = &self.bytes[self.position..self.position + n];
+        self.position += n;
+        Ok(bytes)
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.position
+    }
+}
+
+/// Writes values one after another into a borrowed byte slice.
+#[derive(Debug)]
+pub struct Writer<'a> {
+    bytes: &'a mut [u8],
+    position: usize,
+    endian: Endian,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(bytes: &'a mut [u8], endian: Endian) -> Self {
+        Writer { bytes, position: 0, endian }
+    }
+
+    pub fn write<T: Codec>(&mut self, value: &T) -> Result<(), CodecError> {
+        value.write_to(&mut self.bytes[self.position..], self.endian)?;
+        self.position += T::SIZE;
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        check_len(bytes.len(), self.bytes.len() - self.position)?;
+        self.bytes[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+        self.position += bytes.len();
+        Ok(())
+    }
+
+    /// The number of bytes written so far.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+}
+
+/// Encodes `value` into a new buffer of exactly `T::SIZE` bytes.
+pub fn to_bytes<T: Codec>(value: &T, endian: Endian) -> Vec<u8> {
+    let mut bytes = vec![0; T::SIZE];
+    value.write_to(&mut bytes, endian).expect("the buffer is exactly T::SIZE bytes");
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::offset_of;
+
+    codec_struct! {
+        #[derive(Debug, Clone, Copy, PartialEq, Default)]
+        struct Record {
+            tag: u8,
+            // Padded to an offset of 4.
+            length: u32,
+            scale: f32,
+            // Padded to an offset of 16.
+            timestamp: i64,
+            flags: [u16; 3],
+        }
+    }
+
+    codec_struct! {
+        #[derive(Debug, Clone, Copy, PartialEq, Default)]
+        struct Nested {
+            version: u16,
+            record: Record,
+        }
+    }
+
+    fn record() -> Record {
+        Record { tag: 0xAB, length: 0x0102_0304, scale: 1.5, timestamp: -2, flags: [1, 0x0203, 0xFFFF] }
+    }
+
+    #[test]
+    fn primitives_in_both_orders() {
+        assert_eq!(to_bytes(&0x1234_5678u32, Endian::Big), [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(to_bytes(&0x1234_5678u32, Endian::Little), [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(to_bytes(&-2i16, Endian::Big), [0xFF, 0xFE]);
+        assert_eq!(to_bytes(&1.0f32, Endian::Big), [0x3F, 0x80, 0, 0]);
+        assert_eq!(to_bytes(&1.0f64, Endian::Little), [0, 0, 0, 0, 0, 0, 0xF0, 0x3F]);
+        assert_eq!(to_bytes(&1u128, Endian::Big)[15], 1);
+
+        let bytes = [0xAA, 0xBB, 0xCC, 0xDD];
+        assert_eq!(i32::read_from(&bytes, Endian::Big), Ok(0xAABB_CCDDu32 as i32));
+        assert_eq!(i32::read_from(&bytes, Endian::Little), Ok(0xDDCC_BBAAu32 as i32));
+        assert_eq!(u32::read_from(&bytes, Endian::NATIVE), Ok(u32::from_ne_bytes(bytes)));
+        assert_eq!(u32::read_from(&bytes[1..], Endian::Big), Err(CodecError::UnexpectedEnd { needed: 4, available: 3 }));
+    }
+
+    #[test]
+    fn struct_layouts() {
+        assert_eq!(Record::SIZE, 32);
+        let big = to_bytes(&record(), Endian::Big);
+        let little = to_bytes(&record(), Endian::Little);
+        #[rustfmt::skip]
+        assert_eq!(big, [
+            0xAB, 0, 0, 0, 0x01, 0x02, 0x03, 0x04, 0x3F, 0xC0, 0, 0, 0, 0, 0, 0,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE, 0, 1, 0x02, 0x03, 0xFF, 0xFF, 0, 0,
+        ]);
+        #[rustfmt::skip]
+        assert_eq!(little, [
+            0xAB, 0, 0, 0, 0x04, 0x03, 0x02, 0x01, 0, 0, 0xC0, 0x3F, 0, 0, 0, 0,
+            0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 1, 0, 0x03, 0x02, 0xFF, 0xFF, 0, 0,
+        ]);
+        assert_eq!(Record::read_from(&big, Endian::Big), Ok(record()));
+        assert_eq!(Record::read_from(&little, Endian::Little), Ok(record()));
+        assert_ne!(Record::read_from(&big, Endian::Little), Ok(record()));
+    }
+
+    #[test]
+    fn native_order_matches_memory_layout() {
+        let r = record();
+        let native = to_bytes(&r, Endian::NATIVE);
+        let field = |offset: usize, bytes: &[u8]| assert_eq!(&native[offset..offset + bytes.len()], bytes);
+        field(offset_of!(Record, tag), &r.tag.to_ne_bytes());
+        field(offset_of!(Record, length), &r.length.to_ne_bytes());
+        field(offset_of!(Record, scale), &r.scale.to_ne_bytes());
+        field(offset_of!(Record, timestamp), &r.timestamp.to_ne_bytes());
+        field(offset_of!(Record, flags) + 2, &r.flags[1].to_ne_bytes());
+        assert_eq!(native.len(), std::mem::size_of::<Record>());
+    }
+
+    #[test]
+    fn nested_structs() {
+        let nested = Nested { version: 7, record: record() };
+        // The record is aligned to 8 bytes.
+        assert_eq!(Nested::SIZE, 40);
+        let bytes = to_bytes(&nested, Endian::Big);
+        assert_eq!(&bytes[..8], [0, 7, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&bytes[8..], to_bytes(&record(), Endian::Big));
+        assert_eq!(Nested::read_from(&bytes, Endian::Big), Ok(nested));
+        assert_eq!(Nested::read_from(&bytes[..39], Endian::Big), Err(CodecError::UnexpectedEnd { needed: 40, available: 39 }));
+    }
+
+    #[test]
+    fn reader_and_writer() {
+        let mut buffer = [0xEE; 16];
+        let mut writer = Writer::new(&mut buffer, Endian::Big);
+        writer.write(&0x0102u16).unwrap();
+        writer.write_bytes(b"abc").unwrap();
+        writer.write(&-1i8).unwrap();
+        writer.write(&2.0f64).unwrap();
+        assert_eq!(writer.position(), 14);
+        assert_eq!(writer.write(&0u32), Err(CodecError::UnexpectedEnd { needed: 4, available: 2 }));
+        assert_eq!(buffer[14..], [0xEE, 0xEE]);
+
+        let mut reader = Reader::new(&buffer, Endian::Big);
+        assert_eq!(reader.read::<u16>(), Ok(0x0102));
+        assert_eq!(reader.read_bytes(3), Ok(&b"abc"[..]));
+        assert_eq!(reader.read::<i8>(), Ok(-1));
+        assert_eq!(reader.read::<f64>(), Ok(2.0));
+        assert_eq!(reader.remaining(), 2);
+        assert!(reader.read::<u32>().is_err());
+        assert_eq!(reader.position(), 14);
+    }
+}
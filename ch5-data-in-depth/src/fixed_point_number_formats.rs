@@ -17,47 +17,515 @@
 // symbol for the so-called rational numbers. Rational numbers are numbers that can
 // be represented as a fraction of two integers, such as 1/3.
 
-/// known as a 'tuple struct'. Accessors look like: `Q7(1.42).0` -> 1.42.
-/// Q7's most important role is to convert to and from floating-point types.
-#[derive(Debug,Clone,Copy,PartialEq,Eq)]
-pub struct Q7(i8);
-
-impl From<f64> for Q7 {
-    fn from (n: f64) -> Self {
-        // assert!(n >= -1.0);
-        // assert!(n <= 1.0);
-        if n >= 1.0 {
-            Q7(127)
-        } else if n <= -1.0 {
-            Q7(-128)
+// Q7 is one member of a family. Writing Qm.n for a number with m integer bits and
+// n fractional bits, plus a sign bit:
+// - Q7 stores values in -1..1 in an i8, in steps of 2^-7.
+// - Q15 and Q31 do the same in an i16 and an i32, with more precision. They are the
+//   usual formats for audio samples and filter coefficients in DSP code.
+// - Q16.16 stores values in -32768..32768 in an i32, in steps of 2^-16.
+// `Fixed<I, FRAC_BITS>` covers all of them: the value is the integer `I` divided by
+// 2^FRAC_BITS. Addition and subtraction are plain integer operations. Multiplication
+// and division need to rescale the result, which is where rounding comes in.
+
+use std::hash::Hash;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+use std::str::FromStr;
+use std::{error, fmt};
+
+/// The integer types that can hold the bits of a [`Fixed`] number.
+///
+/// Intermediate results are computed in `i64`, which holds the product of two `i32`s.
+/// 32-bit microcontrollers compute that with a single multiply-long instruction.
+pub trait FixedInt: Copy + Eq + Ord + Hash + Default + fmt::Debug {
+    const BITS: u32;
+    const MIN: Self;
+    const MAX: Self;
+    fn to_i64(self) -> i64;
+    /// Keeps the lowest `BITS` bits of `value`.
+    fn wrapping_from_i64(value: i64) -> Self;
+}
+
+macro_rules! fixed_int {
+    ($($t:ty),*) => {$(
+        impl FixedInt for $t {
+            const BITS: u32 = <$t>::BITS;
+            const MIN: Self = <$t>::MIN;
+            const MAX: Self = <$t>::MAX;
+
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+
+            fn wrapping_from_i64(value: i64) -> Self {
+                value as $t
+            }
+        }
+    )*};
+}
+
+fixed_int!(i8, i16, i32);
+
+/// How to round a result that falls between two representable values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// Towards negative infinity, which is what an arithmetic right shift does.
+    Floor,
+    /// Towards positive infinity.
+    Ceil,
+    /// Drops the extra bits of the magnitude, like `as` does when converting floats to integers.
+    TowardZero,
+    /// To the nearest value, with ties going to the one whose lowest bit is 0.
+    /// Ties are rounded up as often as down, so errors don't build up over long sums.
+    #[default]
+    NearestEven,
+    /// To the nearest value, with ties going away from zero.
+    NearestAway,
+}
+
+impl Rounding {
+    pub const ALL: [Rounding; 5] =
+        [Rounding::Floor, Rounding::Ceil, Rounding::TowardZero, Rounding::NearestEven, Rounding::NearestAway];
+}
+
+/// Divides `n` by `d`, rounding the exact quotient.
+fn div_rounded(n: i64, d: i64, rounding: Rounding) -> i64 {
+    let (quotient, remainder) = (n / d, n % d);
+    if remainder == 0 {
+        return quotient;
+    }
+
+    // `/` has already rounded toward zero, so the other candidate is one step further out.
+    let negative = (n < 0) != (d < 0);
+    let away = if negative { quotient - 1 } else { quotient + 1 };
+    let twice_remainder = 2 * remainder.abs();
+
+    match rounding {
+        Rounding::TowardZero => quotient,
+        Rounding::Floor => if negative { away } else { quotient },
+        Rounding::Ceil => if negative { quotient } else { away },
+        _ if twice_remainder < d.abs() => quotient,
+        _ if twice_remainder > d.abs() => away,
+        Rounding::NearestEven => if quotient % 2 == 0 { quotient } else { away },
+        Rounding::NearestAway => away,
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Overflow {
+    Checked,
+    Saturating,
+    Wrapping,
+}
+
+/// A signed fixed-point number whose value is `bits / 2^FRAC_BITS`, where `bits` is an `I`.
+///
+/// Like the integer types, every operation comes in checked, saturating and wrapping
+/// versions, and the operators panic on overflow when debug assertions are enabled
+/// and wrap otherwise. Multiplication and division round to nearest, ties to even,
+/// unless a [`Rounding`] is given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Fixed<I, const FRAC_BITS: u32>(I);
+
+pub type Q7 = Fixed<i8, 7>;
+pub type Q15 = Fixed<i16, 15>;
+pub type Q31 = Fixed<i32, 31>;
+pub type Q16_16 = Fixed<i32, 16>;
+
+impl<I: FixedInt, const FRAC_BITS: u32> Fixed<I, FRAC_BITS> {
+    const VALID: () = assert!(FRAC_BITS < I::BITS, "FRAC_BITS must leave room for the sign bit");
+
+    pub const MIN: Self = Fixed(I::MIN);
+    pub const MAX: Self = Fixed(I::MAX);
+
+    pub fn from_bits(bits: I) -> Self {
+        let () = Self::VALID;
+        Fixed(bits)
+    }
+
+    pub fn to_bits(self) -> I {
+        self.0
+    }
+
+    fn wide(self) -> i64 {
+        self.0.to_i64()
+    }
+
+    /// 2^FRAC_BITS, the value of `bits` that represents 1.
+    fn scale() -> i64 {
+        1 << FRAC_BITS
+    }
+
+    fn from_wide(wide: i64, overflow: Overflow) -> Option<Self> {
+        let (min, max) = (I::MIN.to_i64(), I::MAX.to_i64());
+        let bits = match overflow {
+            _ if (min..=max).contains(&wide) => wide,
+            Overflow::Checked => return None,
+            Overflow::Saturating => wide.clamp(min, max),
+            Overflow::Wrapping => wide,
+        };
+        Some(Fixed::from_bits(I::wrapping_from_i64(bits)))
+    }
+
+    fn mul_wide(self, rhs: Self, rounding: Rounding) -> i64 {
+        div_rounded(self.wide() * rhs.wide(), Self::scale(), rounding)
+    }
+
+    fn div_wide(self, rhs: Self, rounding: Rounding) -> i64 {
+        if rhs.0 == I::default() {
+            panic!("attempt to divide by zero");
+        }
+        div_rounded(self.wide() << FRAC_BITS, rhs.wide(), rounding)
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Self::from_wide(self.wide() + rhs.wide(), Overflow::Checked)
+    }
+
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self::from_wide(self.wide() + rhs.wide(), Overflow::Saturating).unwrap()
+    }
+
+    pub fn wrapping_add(self, rhs: Self) -> Self {
+        Self::from_wide(self.wide() + rhs.wide(), Overflow::Wrapping).unwrap()
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        Self::from_wide(self.wide() - rhs.wide(), Overflow::Checked)
+    }
+
+    pub fn saturating_sub(self, rhs: Self) -> Self {
+        Self::from_wide(self.wide() - rhs.wide(), Overflow::Saturating).unwrap()
+    }
+
+    pub fn wrapping_sub(self, rhs: Self) -> Self {
+        Self::from_wide(self.wide() - rhs.wide(), Overflow::Wrapping).unwrap()
+    }
+
+    /// `-MIN` doesn't fit: the range of two's complement numbers is lopsided.
+    pub fn checked_neg(self) -> Option<Self> {
+        Self::from_wide(-self.wide(), Overflow::Checked)
+    }
+
+    pub fn saturating_neg(self) -> Self {
+        Self::from_wide(-self.wide(), Overflow::Saturating).unwrap()
+    }
+
+    pub fn wrapping_neg(self) -> Self {
+        Self::from_wide(-self.wide(), Overflow::Wrapping).unwrap()
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        self.checked_mul_with(rhs, Rounding::default())
+    }
+
+    pub fn checked_mul_with(self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        Self::from_wide(self.mul_wide(rhs, rounding), Overflow::Checked)
+    }
+
+    pub fn saturating_mul(self, rhs: Self) -> Self {
+        self.saturating_mul_with(rhs, Rounding::default())
+    }
+
+    pub fn saturating_mul_with(self, rhs: Self, rounding: Rounding) -> Self {
+        Self::from_wide(self.mul_wide(rhs, rounding), Overflow::Saturating).unwrap()
+    }
+
+    pub fn wrapping_mul(self, rhs: Self) -> Self {
+        self.wrapping_mul_with(rhs, Rounding::default())
+    }
+
+    pub fn wrapping_mul_with(self, rhs: Self, rounding: Rounding) -> Self {
+        Self::from_wide(self.mul_wide(rhs, rounding), Overflow::Wrapping).unwrap()
+    }
+
+    /// Returns `None` when `rhs` is zero or the quotient doesn't fit.
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        self.checked_div_with(rhs, Rounding::default())
+    }
+
+    pub fn checked_div_with(self, rhs: Self, rounding: Rounding) -> Option<Self> {
+        if rhs.0 == I::default() {
+            return None;
+        }
+        Self::from_wide(self.div_wide(rhs, rounding), Overflow::Checked)
+    }
+
+    /// # Panics
+    /// When `rhs` is zero, like integer division.
+    pub fn saturating_div(self, rhs: Self) -> Self {
+        self.saturating_div_with(rhs, Rounding::default())
+    }
+
+    pub fn saturating_div_with(self, rhs: Self, rounding: Rounding) -> Self {
+        Self::from_wide(self.div_wide(rhs, rounding), Overflow::Saturating).unwrap()
+    }
+
+    /// # Panics
+    /// When `rhs` is zero, like integer division.
+    pub fn wrapping_div(self, rhs: Self) -> Self {
+        self.wrapping_div_with(rhs, Rounding::default())
+    }
+
+    pub fn wrapping_div_with(self, rhs: Self, rounding: Rounding) -> Self {
+        Self::from_wide(self.div_wide(rhs, rounding), Overflow::Wrapping).unwrap()
+    }
+
+    /// Converts to another format, such as Q31 to Q15. Returns `None` when the value doesn't fit.
+    pub fn convert<J: FixedInt, const TO_FRAC_BITS: u32>(self, rounding: Rounding) -> Option<Fixed<J, TO_FRAC_BITS>> {
+        let wide = if TO_FRAC_BITS >= FRAC_BITS {
+            self.wide() << (TO_FRAC_BITS - FRAC_BITS)
         } else {
-            Q7((n * 128.0) as i8)
+            div_rounded(self.wide(), 1 << (FRAC_BITS - TO_FRAC_BITS), rounding)
+        };
+        Fixed::from_wide(wide, Overflow::Checked)
+    }
+
+    /// Returns `None` for values that are out of range, infinite or NaN.
+    pub fn from_f64(value: f64, rounding: Rounding) -> Option<Self> {
+        // Multiplying by a power of two is exact, so `rounded` is the correctly rounded result.
+        let scaled = value * Self::scale() as f64;
+        let rounded = match rounding {
+            Rounding::Floor => scaled.floor(),
+            Rounding::Ceil => scaled.ceil(),
+            Rounding::TowardZero => scaled.trunc(),
+            Rounding::NearestEven => scaled.round_ties_even(),
+            Rounding::NearestAway => scaled.round(),
+        };
+        if rounded.is_nan() || rounded < I::MIN.to_i64() as f64 || rounded > I::MAX.to_i64() as f64 {
+            return None;
         }
+        Self::from_wide(rounded as i64, Overflow::Checked)
+    }
+
+    /// Clamps out of range values to `MIN` and `MAX`, and converts NaN to 0, like `as` does.
+    pub fn saturating_from_f64(value: f64, rounding: Rounding) -> Self {
+        if value.is_nan() {
+            return Fixed::from_bits(I::default());
+        }
+        let (min, max) = (Self::MIN.to_f64(), Self::MAX.to_f64());
+        Self::from_f64(value.clamp(min, max), rounding).unwrap()
+    }
+
+    /// Exact, since every format has fewer significant bits than an `f64`.
+    pub fn to_f64(self) -> f64 {
+        self.wide() as f64 / Self::scale() as f64
+    }
+}
+
+/// Drops the fraction beyond what the format can hold, and clamps to its range.
+impl<I: FixedInt, const FRAC_BITS: u32> From<f64> for Fixed<I, FRAC_BITS> {
+    fn from(n: f64) -> Self {
+        Fixed::saturating_from_f64(n, Rounding::TowardZero)
     }
 }
 
-impl From<Q7> for f64 {
-    fn from(n: Q7) -> f64 {
-        (n.0 as f64) * 2_f64.powf(-7.0)
+impl<I: FixedInt, const FRAC_BITS: u32> From<Fixed<I, FRAC_BITS>> for f64 {
+    fn from(n: Fixed<I, FRAC_BITS>) -> f64 {
+        n.to_f64()
     }
 }
 
-impl From<f32> for Q7 {
-    fn from (n: f32) -> Self {
+impl<I: FixedInt, const FRAC_BITS: u32> From<f32> for Fixed<I, FRAC_BITS> {
+    fn from(n: f32) -> Self {
         // Converting from a smaller type to a larger type is always safe.
-        Q7::from(n as f64)
+        Fixed::from(n as f64)
     }
 }
 
-impl From<Q7> for f32 {
-    fn from(n: Q7) -> f32 {
+impl<I: FixedInt, const FRAC_BITS: u32> From<Fixed<I, FRAC_BITS>> for f32 {
+    fn from(n: Fixed<I, FRAC_BITS>) -> f32 {
         // Converting from a (potentially) larger type to a smaller type is NOT always safe.
-        // In this application, this risk does not apply as we'll only have numbers between –1 and 1 to convert from.
+        // Q31 has more significant bits than an f32, so this rounds.
         f64::from(n) as f32
     }
 }
 
+macro_rules! operator {
+    ($trait:ident, $method:ident, $checked:ident, $wrapping:ident, $message:literal) => {
+        impl<I: FixedInt, const FRAC_BITS: u32> $trait for Fixed<I, FRAC_BITS> {
+            type Output = Self;
+
+            fn $method(self, rhs: Self) -> Self {
+                if cfg!(debug_assertions) {
+                    self.$checked(rhs).expect($message)
+                } else {
+                    self.$wrapping(rhs)
+                }
+            }
+        }
+    };
+}
+
+operator!(Add, add, checked_add, wrapping_add, "attempt to add with overflow");
+operator!(Sub, sub, checked_sub, wrapping_sub, "attempt to subtract with overflow");
+operator!(Mul, mul, checked_mul, wrapping_mul, "attempt to multiply with overflow");
+operator!(Div, div, checked_div, wrapping_div, "attempt to divide by zero or with overflow");
+
+impl<I: FixedInt, const FRAC_BITS: u32> Neg for Fixed<I, FRAC_BITS> {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        if cfg!(debug_assertions) {
+            self.checked_neg().expect("attempt to negate with overflow")
+        } else {
+            self.wrapping_neg()
+        }
+    }
+}
+
+/// The exact decimal digits of the fractional part of `bits`, without trailing zeros.
+/// A binary fraction with n bits always has at most n decimal digits.
+fn fraction_digits(bits: u64, frac_bits: u32) -> Vec<u8> {
+    let mask = (1u64 << frac_bits) - 1;
+    let mut fraction = bits & mask;
+    let mut digits = Vec::new();
+    while fraction != 0 {
+        fraction *= 10;
+        digits.push((fraction >> frac_bits) as u8);
+        fraction &= mask;
+    }
+    digits
+}
+
+/// Rounds the decimal `integer.digits` to `precision` digits, to nearest with ties to even.
+fn round_digits(integer: u64, digits: &[u8], precision: usize) -> (u64, Vec<u8>) {
+    let mut kept = digits[..precision.min(digits.len())].to_vec();
+    kept.resize(precision, 0);
+    if digits.len() <= precision {
+        return (integer, kept);
+    }
+
+    let first_dropped = digits[precision];
+    let rest_nonzero = digits[precision + 1..].iter().any(|&d| d != 0);
+    let last_kept_odd = match kept.last() {
+        Some(&d) => d % 2 == 1,
+        None => integer % 2 == 1,
+    };
+    let round_up = first_dropped > 5 || (first_dropped == 5 && (rest_nonzero || last_kept_odd));
+    if !round_up {
+        return (integer, kept);
+    }
+
+    for digit in kept.iter_mut().rev() {
+        if *digit < 9 {
+            *digit += 1;
+            return (integer, kept);
+        }
+        *digit = 0;
+    }
+    (integer + 1, kept)
+}
+
+/// Converts the decimal `integer.digits` into a magnitude with `frac_bits` fractional bits,
+/// rounding to nearest with ties to even. Returns `None` when it won't fit into 32 bits.
+///
+/// The fraction is converted exactly, however many digits it has: doubling a decimal
+/// fraction carries its next binary digit out into the integer part.
+fn decimal_to_bits(integer: u64, digits: &[u8], frac_bits: u32) -> Option<u64> {
+    if integer >= 1 << 32 {
+        return None;
+    }
+
+    let mut digits = digits.to_vec();
+    let mut fraction = 0u64;
+    let mut next_bit = || {
+        let mut carry = 0;
+        for digit in digits.iter_mut().rev() {
+            let doubled = *digit * 2 + carry;
+            *digit = doubled % 10;
+            carry = doubled / 10;
+        }
+        carry as u64
+    };
+    for _ in 0..frac_bits {
+        fraction = fraction << 1 | next_bit();
+    }
+    let half = next_bit() == 1;
+    let sticky = digits.iter().any(|&d| d != 0);
+
+    let bits = integer << frac_bits | fraction;
+    let round_up = half && (sticky || bits % 2 == 1);
+    Some(bits + round_up as u64)
+}
+
+/// Prints the shortest decimal that parses back to the same value, like `f64` does.
+/// With a precision, prints the exact value rounded to that many digits, ties to even.
+impl<I: FixedInt, const FRAC_BITS: u32> fmt::Display for Fixed<I, FRAC_BITS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let magnitude = self.wide().unsigned_abs();
+        let integer = magnitude >> FRAC_BITS;
+        let digits = fraction_digits(magnitude, FRAC_BITS);
+
+        let (integer, digits) = match f.precision() {
+            Some(precision) => round_digits(integer, &digits, precision),
+            None => (0..=digits.len())
+                .map(|precision| round_digits(integer, &digits, precision))
+                .find(|(integer, digits)| decimal_to_bits(*integer, digits, FRAC_BITS) == Some(magnitude))
+                .expect("the exact digits always round trip"),
+        };
+
+        let mut text = integer.to_string();
+        if !digits.is_empty() {
+            text.push('.');
+            text.extend(digits.iter().map(|&d| char::from(b'0' + d)));
+        }
+        f.pad_integral(self.wide() >= 0, "", &text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseFixedError {
+    Empty,
+    InvalidDigit,
+    OutOfRange,
+}
+
+impl fmt::Display for ParseFixedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseFixedError::Empty => write!(f, "cannot parse a fixed-point number from an empty string"),
+            ParseFixedError::InvalidDigit => write!(f, "invalid digit found in string"),
+            ParseFixedError::OutOfRange => write!(f, "number is out of range for this format"),
+        }
+    }
+}
+
+impl error::Error for ParseFixedError {}
+
+/// Parses decimal numbers such as `-0.75` or `12.5`, rounding to nearest, ties to even.
+impl<I: FixedInt, const FRAC_BITS: u32> FromStr for Fixed<I, FRAC_BITS> {
+    type Err = ParseFixedError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (integer_part, fraction_part) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if integer_part.is_empty() && fraction_part.is_empty() {
+            return Err(ParseFixedError::Empty);
+        }
+
+        let to_digits = |part: &str| -> Result<Vec<u8>, ParseFixedError> {
+            part.bytes()
+                .map(|b| if b.is_ascii_digit() { Ok(b - b'0') } else { Err(ParseFixedError::InvalidDigit) })
+                .collect()
+        };
+        let integer = to_digits(integer_part)?
+            .iter()
+            .try_fold(0u64, |n, &d| n.checked_mul(10)?.checked_add(d as u64))
+            .ok_or(ParseFixedError::OutOfRange)?;
+        let digits = to_digits(fraction_part)?;
+
+        let magnitude = decimal_to_bits(integer, &digits, FRAC_BITS).ok_or(ParseFixedError::OutOfRange)? as i64;
+        let wide = if negative { -magnitude } else { magnitude };
+        Self::from_wide(wide, Overflow::Checked).ok_or(ParseFixedError::OutOfRange)
+    }
+}
+
 // Generating f32 values in interval [0,1] from a u8 value.
+// `random::Rng::uniform_f32` uses the same trick with a real source of random bits.
 pub fn mock_rand(n: u8) -> f32 {
     let base: u32 = 0b0_01111110_00000000000000000000000;
 
@@ -90,9 +558,9 @@ mod tests {
         let n3 = 123.0;
         let q3 = Q7::from(n3);
 
-        assert_eq!(q1, Q7(89));
-        assert_eq!(q2, Q7(-51));
-        assert_eq!(q3, Q7(127));
+        assert_eq!(q1, Q7::from_bits(89));
+        assert_eq!(q2, Q7::from_bits(-51));
+        assert_eq!(q3, Q7::from_bits(127));
     }
 
     #[test]
@@ -105,4 +573,211 @@ mod tests {
         let n2 = f32::from(q2);
         assert_eq!(n1, n2);
     }
+
+    /// xorshift64, to generate test cases without any dependencies.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Random bits, shifted right by a random amount so that small values turn up too.
+        fn bits<I: FixedInt>(&mut self) -> I {
+            let shift = self.next() % I::BITS as u64;
+            I::wrapping_from_i64(self.next() as i64 >> (64 - I::BITS as u64 + shift))
+        }
+    }
+
+    fn round(value: f64, rounding: Rounding) -> f64 {
+        match rounding {
+            Rounding::Floor => value.floor(),
+            Rounding::Ceil => value.ceil(),
+            Rounding::TowardZero => value.trunc(),
+            Rounding::NearestEven => value.round_ties_even(),
+            Rounding::NearestAway => value.round(),
+        }
+    }
+
+    /// Checks `actual` against the bits of `exact`, a result computed in `f64`.
+    ///
+    /// For the 32-bit formats, the inputs to a multiplication or division have too many
+    /// significant bits for `f64` to compute the result exactly, so its rounding can be off
+    /// by one when the exact result is very close to a tie.
+    fn assert_matches<I: FixedInt, const F: u32>(actual: Option<Fixed<I, F>>, exact: f64, rounding: Rounding, what: &str) {
+        let tolerance = if I::BITS > 16 { 1 } else { 0 };
+        let expected = round(exact * (1u64 << F) as f64, rounding) as i64;
+        let (min, max) = (I::MIN.to_i64(), I::MAX.to_i64());
+
+        match actual {
+            Some(n) => assert!(
+                (n.to_bits().to_i64() - expected).abs() <= tolerance,
+                "{}: expected bits {}, got {:?}",
+                what,
+                expected,
+                n
+            ),
+            None => assert!(
+                expected < min + tolerance || expected > max - tolerance,
+                "{}: expected bits {}, got an overflow",
+                what,
+                expected
+            ),
+        }
+    }
+
+    fn check_against_f64<I: FixedInt, const F: u32>(seed: u64) {
+        let mut rng = Rng(seed);
+        let (min, max) = (Fixed::<I, F>::MIN.to_f64(), Fixed::<I, F>::MAX.to_f64());
+        let wrap = |exact: f64| I::wrapping_from_i64((exact * (1u64 << F) as f64) as i64);
+
+        for _ in 0..20_000 {
+            let a = Fixed::<I, F>::from_bits(rng.bits());
+            let b = Fixed::<I, F>::from_bits(rng.bits());
+            let (x, y) = (a.to_f64(), b.to_f64());
+
+            // Sums and differences are always exact in f64.
+            for (exact, checked, saturating, wrapping) in [
+                (x + y, a.checked_add(b), a.saturating_add(b), a.wrapping_add(b)),
+                (x - y, a.checked_sub(b), a.saturating_sub(b), a.wrapping_sub(b)),
+                (-x, a.checked_neg(), a.saturating_neg(), a.wrapping_neg()),
+            ] {
+                let in_range = (min..=max).contains(&exact);
+                assert_eq!(checked.map(f64::from), in_range.then_some(exact));
+                assert_eq!(saturating.to_f64(), exact.clamp(min, max));
+                assert_eq!(wrapping.to_bits(), wrap(exact));
+            }
+
+            for rounding in Rounding::ALL {
+                let what = format!("{:?} * {:?} rounding {:?}", a, b, rounding);
+                assert_matches(a.checked_mul_with(b, rounding), x * y, rounding, &what);
+                let saturating = a.saturating_mul_with(b, rounding);
+                assert_matches(Some(saturating), (x * y).clamp(min, max), rounding, &what);
+
+                if y != 0.0 {
+                    let what = format!("{:?} / {:?} rounding {:?}", a, b, rounding);
+                    assert_matches(a.checked_div_with(b, rounding), x / y, rounding, &what);
+                    let saturating = a.saturating_div_with(b, rounding);
+                    assert_matches(Some(saturating), (x / y).clamp(min, max), rounding, &what);
+                }
+
+                let what = format!("from_f64({}) rounding {:?}", x * 0.999, rounding);
+                assert_matches(Fixed::<I, F>::from_f64(x * 0.999, rounding), x * 0.999, rounding, &what);
+            }
+
+            assert_eq!(a.to_string().parse::<Fixed<I, F>>(), Ok(a), "{} should round trip", a);
+            // Formatted with enough digits to be exact, a float parses to the same value
+            // as converting it directly.
+            let z = x * 0.999;
+            assert_eq!(format!("{:.80}", z).parse(), Fixed::<I, F>::from_f64(z, Rounding::NearestEven).ok_or(ParseFixedError::OutOfRange));
+        }
+    }
+
+    #[test]
+    fn q7_matches_f64() {
+        check_against_f64::<i8, 7>(1);
+    }
+
+    #[test]
+    fn q15_matches_f64() {
+        check_against_f64::<i16, 15>(2);
+    }
+
+    #[test]
+    fn q31_matches_f64() {
+        check_against_f64::<i32, 31>(3);
+    }
+
+    #[test]
+    fn q16_16_matches_f64() {
+        check_against_f64::<i32, 16>(4);
+    }
+
+    #[test]
+    fn rounding_modes() {
+        let third = Q15::from_f64(1.0 / 3.0, Rounding::NearestEven).unwrap();
+        let half = Q15::from_bits(1 << 14);
+        // A third of a half is 5461.33 steps of 2^-15.
+        assert_eq!(half.checked_mul_with(third, Rounding::Floor).unwrap().to_bits(), 5461);
+        assert_eq!(half.checked_mul_with(third, Rounding::Ceil).unwrap().to_bits(), 5462);
+
+        // 3 * 2^-7 halved is 1.5 steps: a tie.
+        let q = Q7::from_bits(3);
+        assert_eq!(q.checked_mul_with(Q7::from(0.5), Rounding::NearestEven), Some(Q7::from_bits(2)));
+        assert_eq!(q.checked_mul_with(Q7::from(0.5), Rounding::NearestAway), Some(Q7::from_bits(2)));
+        assert_eq!((-q).checked_mul_with(Q7::from(0.5), Rounding::NearestAway), Some(Q7::from_bits(-2)));
+        assert_eq!(Q7::from_bits(5).checked_mul_with(Q7::from(0.5), Rounding::NearestEven), Some(Q7::from_bits(2)));
+        assert_eq!((-q).checked_mul_with(Q7::from(0.5), Rounding::TowardZero), Some(Q7::from_bits(-1)));
+        assert_eq!((-q).checked_mul_with(Q7::from(0.5), Rounding::Floor), Some(Q7::from_bits(-2)));
+    }
+
+    #[test]
+    fn overflow_behaviour() {
+        let big = Q16_16::from(30000.0);
+        assert_eq!(big.checked_add(big), None);
+        assert_eq!(big.saturating_add(big), Q16_16::MAX);
+        assert_eq!(big.wrapping_add(big).to_f64(), 60000.0 - 65536.0);
+
+        assert_eq!(Q15::MIN.checked_neg(), None);
+        assert_eq!(Q15::MIN.saturating_mul(Q15::MIN), Q15::MAX);
+        assert_eq!(Q7::from(0.5).checked_div(Q7::from(0.0)), None);
+        assert_eq!(Q7::from(0.5).saturating_div(Q7::from(0.25)), Q7::MAX);
+        assert_eq!(Q7::from(-0.5).saturating_div(Q7::from(0.25)), Q7::MIN);
+
+        assert_eq!(Q16_16::from(1.5) * Q16_16::from(-2.0), Q16_16::from(-3.0));
+        assert_eq!(Q16_16::from(1.0) / Q16_16::from(8.0), Q16_16::from(0.125));
+    }
+
+    #[test]
+    #[should_panic]
+    fn operators_panic_on_overflow_in_debug() {
+        let _ = Q7::MAX + Q7::MAX;
+    }
+
+    #[test]
+    fn converts_between_formats() {
+        let x = Q31::from(0.123456789);
+        let narrow: Q15 = x.convert(Rounding::NearestEven).unwrap();
+        assert_eq!(narrow, Q15::from_f64(x.to_f64(), Rounding::NearestEven).unwrap());
+        assert_eq!(narrow.convert::<i32, 31>(Rounding::NearestEven).unwrap().to_f64(), narrow.to_f64());
+        assert_eq!(Q16_16::from(2.0).convert::<i16, 15>(Rounding::NearestEven), None);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Q7::from_bits(89).to_string(), "0.695");
+        assert_eq!(format!("{:.7}", Q7::from_bits(89)), "0.6953125");
+        assert_eq!(Q7::from_bits(1).to_string(), "0.01");
+        assert_eq!(Q15::MIN.to_string(), "-1");
+        assert_eq!(Q16_16::from(-3.25).to_string(), "-3.25");
+        assert_eq!(Q16_16::from(0.1).to_string(), "0.09999");
+        assert_eq!(Q16_16::from_f64(0.1, Rounding::NearestEven).unwrap().to_string(), "0.1");
+        assert_eq!(format!("{:.0}", Q16_16::from(2.5)), "2");
+        assert_eq!(format!("{:.0}", Q16_16::from(3.5)), "4");
+        assert_eq!(format!("{:.1}", Q16_16::from(9.96)), "10.0");
+        assert_eq!(format!("{:>8.2}|{:<+6}|", Q16_16::from(1.5), Q7::from(0.5)), "    1.50|+0.5  |");
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("0.5".parse(), Ok(Q7::from_bits(64)));
+        assert_eq!("-1".parse(), Ok(Q7::MIN));
+        assert_eq!(".25".parse(), Ok(Q7::from_bits(32)));
+        assert_eq!("+12.".parse(), Ok(Q16_16::from(12.0)));
+        // 1.5 steps of 2^-7, a tie that rounds to even.
+        assert_eq!("0.01171875".parse(), Ok(Q7::from_bits(2)));
+        assert_eq!("0.011718750000000000000000000001".parse(), Ok(Q7::from_bits(2)));
+        assert_eq!("0.02734375".parse(), Ok(Q7::from_bits(4)));
+
+        assert_eq!("".parse::<Q7>(), Err(ParseFixedError::Empty));
+        assert_eq!("-.".parse::<Q7>(), Err(ParseFixedError::Empty));
+        assert_eq!("1.2.3".parse::<Q7>(), Err(ParseFixedError::InvalidDigit));
+        assert_eq!("0x10".parse::<Q7>(), Err(ParseFixedError::InvalidDigit));
+        assert_eq!("1".parse::<Q7>(), Err(ParseFixedError::OutOfRange));
+        assert_eq!("0.998".parse::<Q7>(), Err(ParseFixedError::OutOfRange));
+        assert_eq!("99999999999999999999999".parse::<Q16_16>(), Err(ParseFixedError::OutOfRange));
+    }
 }
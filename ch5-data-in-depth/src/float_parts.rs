@@ -0,0 +1,439 @@
+// An IEEE 754 binary floating-point number is stored as three fields:
+//
+//     | sign | exponent (biased) | fraction |
+//
+// f16 has 5 exponent bits and 10 fraction bits, bf16 has 8 and 7, f32 has 8 and 23
+// and f64 has 11 and 52. The exponent field decides how the fraction is read:
+// - 0 with a zero fraction is ±0.
+// - 0 otherwise is a subnormal number: 0.fraction × 2^(1 - bias), which fills the gap
+//   between 0 and the smallest normal number evenly.
+// - All ones with a zero fraction is ±infinity.
+// - All ones otherwise is NaN. The top fraction bit says whether it is quiet, and the
+//   rest of the fraction is a payload that operations on it are meant to preserve.
+// - Anything else is a normal number: 1.fraction × 2^(exponent - bias).
+//
+// `FloatParts` gives every value the same reading, without any floating-point
+// arithmetic: the value is (-1)^sign × significand × 2^(exponent - FRACTION_BITS),
+// where the significand includes the implicit leading 1 of a normal number.
+
+use std::num::FpCategory;
+use std::{error, fmt};
+
+/// The fields of a floating-point number, decoded exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatParts {
+    pub negative: bool,
+    pub category: FpCategory,
+    /// The unbiased exponent of a normal or subnormal number, and 0 otherwise.
+    ///
+    /// Subnormal numbers all have the minimum exponent, `1 - bias`.
+    pub exponent: i32,
+    /// The significand of a normal or subnormal number, or the payload of a NaN,
+    /// including its quiet bit. 0 for zeros and infinities.
+    pub significand: u64,
+}
+
+impl FloatParts {
+    /// Whether a NaN is signalling: its quiet bit is clear.
+    pub fn is_signaling<T: Ieee754>(&self) -> bool {
+        self.category == FpCategory::Nan && self.significand & T::QUIET_BIT == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartsError {
+    /// The significand is out of range for the category: it doesn't have exactly
+    /// `FRACTION_BITS + 1` bits for a normal number, it isn't below 2^FRACTION_BITS for a
+    /// subnormal number or NaN, or it isn't zero for a zero or infinity.
+    SignificandOutOfRange,
+    /// The exponent is outside the normal range, or isn't what the category requires.
+    ExponentOutOfRange,
+}
+
+impl fmt::Display for PartsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartsError::SignificandOutOfRange => write!(f, "significand out of range"),
+            PartsError::ExponentOutOfRange => write!(f, "exponent out of range"),
+        }
+    }
+}
+
+impl error::Error for PartsError {}
+
+/// A binary floating-point format, as described at the top of this module.
+pub trait Ieee754: Copy {
+    const NAME: &'static str;
+    const EXPONENT_BITS: u32;
+    /// The stored fraction bits, not counting the implicit leading 1.
+    const FRACTION_BITS: u32;
+
+    const BITS: u32 = 1 + Self::EXPONENT_BITS + Self::FRACTION_BITS;
+    const BIAS: i32 = (1 << (Self::EXPONENT_BITS - 1)) - 1;
+    const MIN_EXPONENT: i32 = 1 - Self::BIAS;
+    const MAX_EXPONENT: i32 = Self::BIAS;
+    const QUIET_BIT: u64 = 1 << (Self::FRACTION_BITS - 1);
+
+    /// The bits of the value, in the low `BITS` bits.
+    fn to_bits_u64(self) -> u64;
+    /// Ignores any bits above the low `BITS` bits.
+    fn from_bits_u64(bits: u64) -> Self;
+
+    fn to_parts(self) -> FloatParts {
+        let bits = self.to_bits_u64();
+        let negative = bits >> (Self::BITS - 1) & 1 == 1;
+        let biased = (bits >> Self::FRACTION_BITS) as i32 & ((1 << Self::EXPONENT_BITS) - 1);
+        let fraction = bits & ((1 << Self::FRACTION_BITS) - 1);
+        let max_biased = (1 << Self::EXPONENT_BITS) - 1;
+
+        let (category, exponent, significand) = match (biased, fraction) {
+            (0, 0) => (FpCategory::Zero, 0, 0),
+            (0, _) => (FpCategory::Subnormal, Self::MIN_EXPONENT, fraction),
+            (b, 0) if b == max_biased => (FpCategory::Infinite, 0, 0),
+            (b, _) if b == max_biased => (FpCategory::Nan, 0, fraction),
+            (b, _) => (FpCategory::Normal, b - Self::BIAS, fraction | 1 << Self::FRACTION_BITS),
+        };
+
+        FloatParts { negative, category, exponent, significand }
+    }
+
+    /// The inverse of [`to_parts`](Ieee754::to_parts), which rejects parts that no bits decode to.
+    fn from_parts(parts: FloatParts) -> Result<Self, PartsError> {
+        let implicit = 1 << Self::FRACTION_BITS;
+        let max_biased = (1 << Self::EXPONENT_BITS) - 1;
+        let significand_ok = match parts.category {
+            FpCategory::Zero | FpCategory::Infinite => parts.significand == 0,
+            FpCategory::Subnormal | FpCategory::Nan => (1..implicit).contains(&parts.significand),
+            FpCategory::Normal => (implicit..implicit << 1).contains(&parts.significand),
+        };
+        let exponent_ok = match parts.category {
+            FpCategory::Zero | FpCategory::Infinite | FpCategory::Nan => parts.exponent == 0,
+            FpCategory::Subnormal => parts.exponent == Self::MIN_EXPONENT,
+            FpCategory::Normal => (Self::MIN_EXPONENT..=Self::MAX_EXPONENT).contains(&parts.exponent),
+        };
+        if !significand_ok {
+            return Err(PartsError::SignificandOutOfRange);
+        }
+        if !exponent_ok {
+            return Err(PartsError::ExponentOutOfRange);
+        }
+
+        let biased = match parts.category {
+            FpCategory::Zero | FpCategory::Subnormal => 0,
+            FpCategory::Infinite | FpCategory::Nan => max_biased,
+            FpCategory::Normal => (parts.exponent + Self::BIAS) as u64,
+        };
+        let sign = (parts.negative as u64) << (Self::BITS - 1);
+        let fraction = parts.significand & (implicit - 1);
+
+        Ok(Self::from_bits_u64(sign | biased << Self::FRACTION_BITS | fraction))
+    }
+
+    /// Converts to another format, rounding to the nearest value, with ties to even.
+    ///
+    /// NaNs keep their sign and the top bits of their payload, and stay NaNs.
+    fn convert<T: Ieee754>(self) -> T {
+        let parts = self.to_parts();
+        let sign = (parts.negative as u64) << (T::BITS - 1);
+        let infinity = sign | ((1 << T::EXPONENT_BITS) - 1) << T::FRACTION_BITS;
+
+        let magnitude = match parts.category {
+            FpCategory::Zero => 0,
+            FpCategory::Infinite => return T::from_bits_u64(infinity),
+            FpCategory::Nan => {
+                let payload = shift_right(parts.significand, Self::FRACTION_BITS as i32 - T::FRACTION_BITS as i32, false);
+                return T::from_bits_u64(infinity | if payload == 0 { T::QUIET_BIT } else { payload });
+            }
+            FpCategory::Normal | FpCategory::Subnormal => {
+                // The value is in 2^e..2^(e + 1).
+                let top = 63 - parts.significand.leading_zeros() as i32;
+                let e = parts.exponent - Self::FRACTION_BITS as i32 + top;
+                if e > T::MAX_EXPONENT {
+                    return T::from_bits_u64(infinity);
+                }
+
+                // Line the significand up with the fraction bits of T, then add the
+                // exponent field. For a normal number the implicit bit adds the last 1 to
+                // the exponent field, and rounding up to 2^(FRACTION_BITS + 1) carries into
+                // it, possibly all the way to infinity. A subnormal number that rounds up
+                // to 2^FRACTION_BITS becomes the smallest normal number the same way.
+                let subnormal_shift = (T::MIN_EXPONENT - e).max(0);
+                let rounded = shift_right(parts.significand, top - T::FRACTION_BITS as i32 + subnormal_shift, true);
+                let base = if subnormal_shift > 0 { 0 } else { ((e + T::BIAS - 1) as u64) << T::FRACTION_BITS };
+                base + rounded
+            }
+        };
+
+        T::from_bits_u64(sign | magnitude)
+    }
+
+    fn to_f64(self) -> f64 {
+        self.convert()
+    }
+
+    fn from_f64(value: f64) -> Self {
+        value.convert()
+    }
+}
+
+/// Shifts `n` right by `shift` bits, or left if `shift` is negative, rounding to even if asked.
+fn shift_right(n: u64, shift: i32, round: bool) -> u64 {
+    if shift <= 0 {
+        return n << -shift;
+    }
+    if shift >= 64 {
+        return 0;
+    }
+
+    let (quotient, remainder, half) = (n >> shift, n & ((1 << shift) - 1), 1 << (shift - 1));
+    let round_up = round && (remainder > half || (remainder == half && quotient & 1 == 1));
+    quotient + round_up as u64
+}
+
+impl Ieee754 for f32 {
+    const NAME: &'static str = "f32";
+    const EXPONENT_BITS: u32 = 8;
+    const FRACTION_BITS: u32 = 23;
+
+    fn to_bits_u64(self) -> u64 {
+        self.to_bits() as u64
+    }
+
+    fn from_bits_u64(bits: u64) -> Self {
+        f32::from_bits(bits as u32)
+    }
+}
+
+impl Ieee754 for f64 {
+    const NAME: &'static str = "f64";
+    const EXPONENT_BITS: u32 = 11;
+    const FRACTION_BITS: u32 = 52;
+
+    fn to_bits_u64(self) -> u64 {
+        self.to_bits()
+    }
+
+    fn from_bits_u64(bits: u64) -> Self {
+        f64::from_bits(bits)
+    }
+}
+
+macro_rules! half_float {
+    ($name:ident, $display:expr, $exponent_bits:expr, $fraction_bits:expr, $doc:expr) => {
+        #[doc = $doc]
+        ///
+        /// Only the bits are stored, so two values compare equal when their bits are the
+        /// same: NaNs equal themselves and 0 doesn't equal -0. Convert to `f32` to compute.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+        pub struct $name(u16);
+
+        impl $name {
+            pub fn from_bits(bits: u16) -> Self {
+                $name(bits)
+            }
+
+            pub fn to_bits(self) -> u16 {
+                self.0
+            }
+
+            /// Rounds to the nearest value, with ties to even.
+            pub fn from_f32(value: f32) -> Self {
+                value.convert()
+            }
+
+            /// Every value is exactly representable as an `f32`.
+            pub fn to_f32(self) -> f32 {
+                self.convert()
+            }
+        }
+
+        impl Ieee754 for $name {
+            const NAME: &'static str = $display;
+            const EXPONENT_BITS: u32 = $exponent_bits;
+            const FRACTION_BITS: u32 = $fraction_bits;
+
+            fn to_bits_u64(self) -> u64 {
+                self.0 as u64
+            }
+
+            fn from_bits_u64(bits: u64) -> Self {
+                $name(bits as u16)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Display::fmt(&self.to_f32(), f)
+            }
+        }
+    };
+}
+
+half_float!(F16, "f16", 5, 10, "IEEE 754 half precision, as used for GPU textures and ML weights.");
+half_float!(BF16, "bf16", 8, 7, "Brain floating point: the top 16 bits of an `f32`, with its full range but less precision.");
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The value of finite parts, computed with exact f64 multiplications.
+    fn value<T: Ieee754>(parts: FloatParts) -> f64 {
+        let sign = if parts.negative { -1.0 } else { 1.0 };
+        let scale = parts.exponent - T::FRACTION_BITS as i32;
+        // Split the scale so that each power of two is a normal f64.
+        let half = f64::powi(2.0, scale / 2);
+        sign * parts.significand as f64 * half * f64::powi(2.0, scale - scale / 2)
+    }
+
+    fn check_round_trip<T: Ieee754>(x: T) -> FloatParts {
+        let parts = x.to_parts();
+        assert_eq!(T::from_parts(parts).map(T::to_bits_u64), Ok(x.to_bits_u64()), "{:?}", parts);
+        parts
+    }
+
+    #[test]
+    fn all_f16_values() {
+        let mut counts = [0; 5];
+        for bits in 0..=u16::MAX {
+            let x = F16::from_bits(bits);
+            let parts = check_round_trip(x);
+            let wide = x.to_f32();
+            assert_eq!(parts.negative, wide.is_sign_negative());
+            // f16 subnormals are normal numbers in f32.
+            let expected = if bits & 0x7c00 == 0 && bits & 0x3ff != 0 { FpCategory::Subnormal } else { wide.classify() };
+            assert_eq!(parts.category, expected, "{:#06x}", bits);
+
+            match parts.category {
+                FpCategory::Nan => {
+                    assert_eq!(F16::from_f32(wide), x, "NaN payloads should survive widening");
+                    counts[0] += 1;
+                }
+                FpCategory::Infinite => counts[1] += 1,
+                FpCategory::Zero => counts[2] += 1,
+                FpCategory::Subnormal => {
+                    assert_eq!(parts.exponent, -14);
+                    assert_eq!(value::<F16>(parts), wide as f64);
+                    counts[3] += 1;
+                }
+                FpCategory::Normal => {
+                    assert!((-14..=15).contains(&parts.exponent));
+                    assert_eq!(parts.significand >> 10, 1);
+                    assert_eq!(value::<F16>(parts), wide as f64);
+                    counts[4] += 1;
+                }
+            }
+            if !wide.is_nan() {
+                assert_eq!(F16::from_f32(wide), x);
+                assert_eq!(F16::from_f64(x.to_f64()), x);
+            }
+        }
+
+        // 2 signs × (NaNs, infinities, zeros, subnormals, normals).
+        assert_eq!(counts, [2 * 1023, 2, 2, 2 * 1023, 2 * 30 * 1024]);
+    }
+
+    #[test]
+    fn all_bf16_values() {
+        for bits in 0..=u16::MAX {
+            let x = BF16::from_bits(bits);
+            let parts = check_round_trip(x);
+            assert_eq!(x.to_f32().to_bits(), (bits as u32) << 16);
+            assert_eq!(parts.category, x.to_f32().classify());
+            assert_eq!(BF16::from_f32(x.to_f32()), x);
+        }
+    }
+
+    #[test]
+    fn sampled_f32_values() {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let specials = [0, 1, 0x007f_ffff, 0x0080_0000, 0x7f7f_ffff, 0x7f80_0000, 0x7f80_0001, 0x7fc0_0000];
+        let samples = (0..200_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u32
+        });
+
+        for bits in specials.into_iter().chain(specials.map(|b| b | 0x8000_0000)).chain(samples) {
+            let x = f32::from_bits(bits);
+            let parts = check_round_trip(x);
+            assert_eq!(parts.category, x.classify());
+            assert_eq!(parts.negative, x.is_sign_negative());
+            if x.is_finite() {
+                assert_eq!(value::<f32>(parts), x as f64);
+                assert_eq!(x.to_f64(), x as f64);
+            }
+
+            // Narrowing agrees with the hardware conversion.
+            let wide = f64::from_bits((bits as u64) << 32 | bits.rotate_left(7) as u64);
+            if !wide.is_nan() {
+                assert_eq!(f32::from_f64(wide).to_bits(), (wide as f32).to_bits(), "{:e}", wide);
+            }
+        }
+    }
+
+    #[test]
+    fn f64_parts() {
+        assert_eq!(
+            (-0.0f64).to_parts(),
+            FloatParts { negative: true, category: FpCategory::Zero, exponent: 0, significand: 0 }
+        );
+        assert_eq!(
+            1.5f64.to_parts(),
+            FloatParts { negative: false, category: FpCategory::Normal, exponent: 0, significand: 3 << 51 }
+        );
+        let tiny = f64::from_bits(1).to_parts();
+        assert_eq!((tiny.category, tiny.exponent, tiny.significand), (FpCategory::Subnormal, -1022, 1));
+        assert_eq!(value::<f64>(tiny), f64::from_bits(1));
+        assert_eq!(value::<f64>(f64::MAX.to_parts()), f64::MAX);
+
+        let nan = f64::from_bits(0x7ff0_0000_0000_0001).to_parts();
+        assert!(nan.is_signaling::<f64>());
+        assert!(!f64::NAN.to_parts().is_signaling::<f64>());
+        check_round_trip(f64::from_bits(0xfff0_0000_dead_beef));
+    }
+
+    #[test]
+    fn rounding() {
+        // 1 + 2^-11 is halfway between two f16 values; the tie goes to the even one.
+        assert_eq!(F16::from_f64(1.0 + 2f64.powi(-11)).to_bits(), 0x3c00);
+        assert_eq!(F16::from_f64(1.0 + 3.0 * 2f64.powi(-11)).to_bits(), 0x3c02);
+        assert_eq!(F16::from_f64(65504.0).to_bits(), 0x7bff);
+        assert_eq!(F16::from_f64(65519.0).to_bits(), 0x7bff);
+        assert_eq!(F16::from_f64(65520.0).to_bits(), 0x7c00);
+        assert_eq!(F16::from_f64(-1e10).to_bits(), 0xfc00);
+        // Half the smallest subnormal rounds to even: zero.
+        assert_eq!(F16::from_f64(2f64.powi(-25)).to_bits(), 0);
+        assert_eq!(F16::from_f64(1.5 * 2f64.powi(-25)).to_bits(), 1);
+        // The largest subnormal rounds up to the smallest normal.
+        assert_eq!(F16::from_f64(2f64.powi(-14) - 2f64.powi(-26)).to_bits(), 0x0400);
+        assert_eq!(F16::from_f64(1e-300).to_bits(), 0);
+
+        assert_eq!(BF16::from_f32(1.0 + 2f32.powi(-8)).to_bits(), 0x3f80);
+        assert_eq!(BF16::from_f32(f32::MAX).to_bits(), 0x7f80);
+        // A NaN whose payload doesn't fit stays a NaN.
+        assert!(BF16::from_f32(f32::from_bits(0x7f80_0001)).to_f32().is_nan());
+    }
+
+    #[test]
+    fn invalid_parts() {
+        let one = 1.0f32.to_parts();
+        let error = |parts| f32::from_parts(parts).map(f32::to_bits);
+        assert_eq!(error(FloatParts { significand: 1, ..one }), Err(PartsError::SignificandOutOfRange));
+        assert_eq!(error(FloatParts { exponent: 128, ..one }), Err(PartsError::ExponentOutOfRange));
+        assert_eq!(error(FloatParts { exponent: -127, ..one }), Err(PartsError::ExponentOutOfRange));
+        assert_eq!(
+            error(FloatParts { category: FpCategory::Subnormal, significand: 1, ..one }),
+            Err(PartsError::ExponentOutOfRange)
+        );
+        assert_eq!(error(FloatParts { category: FpCategory::Nan, significand: 0, exponent: 0, ..one }), Err(PartsError::SignificandOutOfRange));
+        assert_eq!(error(FloatParts { category: FpCategory::Infinite, significand: 0, exponent: 0, ..one }), Ok(0x7f80_0000));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(F16::from_f32(0.1).to_string(), "0.099975586");
+        assert_eq!(BF16::from_f32(-3.0).to_string(), "-3");
+        assert_eq!(F16::from_bits(0x7c00).to_string(), "inf");
+    }
+}
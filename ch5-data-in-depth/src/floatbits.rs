@@ -0,0 +1,31 @@
+use std::process;
+
+use libchapter5::number_bits;
+
+const USAGE: &str = "\
+Usage:
+    floatbits <TYPE> <LITERAL> [--json]
+
+Explains how <LITERAL> is stored as <TYPE>: its bits and what each field means, its
+bytes in both orders, the values either side of it, and how many ULPs it is from the
+literal. <TYPE> is one of u8-u128, i8-i128, f16, bf16, f32, f64 or Q7.
+Floats and Q7 also accept raw bits, such as 0x7fc00001.
+With --json, the report is printed as a single JSON object.
+";
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+    let type_name = args.first().expect(USAGE);
+    let literal = args.get(1).expect(USAGE);
+
+    match number_bits::inspect(type_name, literal) {
+        Ok(report) if json => println!("{}", report.to_json()),
+        Ok(report) => print!("{}", report),
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,8 @@
+pub mod bit_patterns_and_types;
+pub mod endian;
+pub mod float_parts;
+pub mod number_bits;
+pub mod fixed_point_number_formats;
+pub mod quantization;
+pub mod random;
+pub mod chip_8;
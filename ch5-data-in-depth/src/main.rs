@@ -1,9 +1,6 @@
-use crate::bit_patterns_and_types::{deconstruct_a_floating_point_number, how_u16_bit_patterns_translate_to_a_fixed_number_of_integers, inspecting_endianness, interpret_a_float_as_an_int, isolating_and_decoding_the_exponent_of_a_32bit_floating_point_number, isolating_and_decoding_the_mantissa_of_a_32bit_floating_point_number, isolating_and_decoding_the_sign_bit_of_a_32bit_floating_point_number, u16_vs_i16};
-use crate::fixed_point_number_formats::mock_rand;
-
-mod bit_patterns_and_types;
-mod fixed_point_number_formats;
-mod chip_8;
+use libchapter5::bit_patterns_and_types::{deconstruct_a_floating_point_number, how_u16_bit_patterns_translate_to_a_fixed_number_of_integers, inspecting_endianness, interpret_a_float_as_an_int, isolating_and_decoding_the_exponent_of_a_32bit_floating_point_number, isolating_and_decoding_the_mantissa_of_a_32bit_floating_point_number, isolating_and_decoding_the_sign_bit_of_a_32bit_floating_point_number, u16_vs_i16};
+use libchapter5::fixed_point_number_formats::mock_rand;
+use libchapter5::chip_8;
 
 fn main() {
     u16_vs_i16();
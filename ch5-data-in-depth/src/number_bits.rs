@@ -0,0 +1,689 @@
+// Explains how a number is stored: its bits split into fields, its bytes in both
+// orders, the values on either side of it, and how far the stored value is from
+// the literal that was typed in. This backs the `floatbits` binary.
+//
+// The gap between two neighbouring values is one unit in the last place (ULP).
+// Integers always have an ULP of 1, and Q7 of 2^-7. A float's ULP doubles every
+// time its exponent goes up, so 0.1 is stored far more precisely than 1e20.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::Write;
+use std::num::{FpCategory, IntErrorKind};
+
+use crate::fixed_point_number_formats::{ParseFixedError, Q7};
+use crate::float_parts::{Ieee754, BF16, F16};
+
+pub const TYPES: [&str; 15] = [
+    "u8", "u16", "u32", "u64", "u128", "i8", "i16", "i32", "i64", "i128", "f16", "bf16", "f32", "f64", "q7",
+];
+
+/// Decimal exponents beyond this are too far out of range to compare exactly.
+const MAX_DECIMAL_EXPONENT: i32 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InspectError {
+    UnknownType(String),
+    InvalidLiteral(String),
+    OutOfRange(String),
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InspectError::UnknownType(name) => write!(f, "unknown type {:?}, expected one of {}", name, TYPES.join(", ")),
+            InspectError::InvalidLiteral(literal) => write!(f, "invalid literal {:?}", literal),
+            InspectError::OutOfRange(literal) => write!(f, "{} is out of range", literal),
+        }
+    }
+}
+
+impl std::error::Error for InspectError {}
+
+/// A run of bits with a meaning, such as the exponent of a float.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub bits: String,
+    pub meaning: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub type_name: &'static str,
+    pub literal: String,
+    /// The stored value, as the type displays it.
+    pub value: String,
+    /// Every digit of the stored value.
+    pub exact: String,
+    /// The fields, most significant first.
+    pub fields: Vec<Field>,
+    pub big_endian: Vec<u8>,
+    pub little_endian: Vec<u8>,
+    pub previous: Option<String>,
+    pub next: Option<String>,
+    /// The gap to the next value away from zero is 2^ulp_exponent.
+    pub ulp_exponent: Option<i32>,
+    /// (literal - value) / ULP, when both are finite.
+    pub error_ulps: Option<f64>,
+}
+
+/// `name` and `bits` from the low `width` bits of `bits`.
+fn field(name: &'static str, bits: u128, width: u32, meaning: String) -> Field {
+    Field { name, bits: format!("{:0width$b}", bits, width = width as usize), meaning }
+}
+
+/// Splits an optional sign and a 0x, 0o or 0b prefix off an integer literal.
+fn split_radix(literal: &str) -> (&str, &str, u32) {
+    let (sign, rest) = match literal.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", literal.strip_prefix('+').unwrap_or(literal)),
+    };
+    let lower = rest.get(..2).map(str::to_ascii_lowercase);
+    match lower.as_deref() {
+        Some("0x") => (sign, &rest[2..], 16),
+        Some("0o") => (sign, &rest[2..], 8),
+        Some("0b") => (sign, &rest[2..], 2),
+        _ => (sign, rest, 10),
+    }
+}
+
+macro_rules! inspect_int {
+    ($t:ty, $literal:expr) => {{
+        let literal: &str = $literal;
+        let (sign, digits, radix) = split_radix(literal);
+        let digits = format!("{}{}", sign, digits.replace('_', ""));
+        let value = <$t>::from_str_radix(&digits, radix).map_err(|err| match err.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => InspectError::OutOfRange(literal.to_string()),
+            _ => InspectError::InvalidLiteral(literal.to_string()),
+        })?;
+
+        let width = <$t>::BITS;
+        let bits = value as u128 & (u128::MAX >> (128 - width));
+        let fields = if <$t>::MIN == 0 {
+            vec![field("value", bits, width, value.to_string())]
+        } else {
+            let sign = bits >> (width - 1);
+            let rest = bits & !(1 << (width - 1));
+            let sign_meaning = if sign == 1 { format!("-2^{}", width - 1) } else { "0".to_string() };
+            vec![field("sign", sign, 1, sign_meaning), field("value", rest, width - 1, format!("+{}", rest))]
+        };
+
+        Ok(Report {
+            type_name: stringify!($t),
+            literal: literal.to_string(),
+            value: value.to_string(),
+            exact: value.to_string(),
+            fields,
+            big_endian: value.to_be_bytes().to_vec(),
+            little_endian: value.to_le_bytes().to_vec(),
+            previous: value.checked_sub(1).map(|n| n.to_string()),
+            next: value.checked_add(1).map(|n| n.to_string()),
+            ulp_exponent: Some(0),
+            error_ulps: Some(0.0),
+        })
+    }};
+}
+
+/// Parses `literal` as `type_name` and explains how it is stored.
+///
+/// Floats and Q7 also accept their raw bits written in hex or binary, such as `0x7e01`,
+/// to look at values like NaN payloads that have no decimal literal.
+pub fn inspect(type_name: &str, literal: &str) -> Result<Report, InspectError> {
+    match type_name.to_ascii_lowercase().as_str() {
+        "u8" => inspect_int!(u8, literal),
+        "u16" => inspect_int!(u16, literal),
+        "u32" => inspect_int!(u32, literal),
+        "u64" => inspect_int!(u64, literal),
+        "u128" => inspect_int!(u128, literal),
+        "i8" => inspect_int!(i8, literal),
+        "i16" => inspect_int!(i16, literal),
+        "i32" => inspect_int!(i32, literal),
+        "i64" => inspect_int!(i64, literal),
+        "i128" => inspect_int!(i128, literal),
+        "f16" => inspect_float::<F16>(literal),
+        "bf16" => inspect_float::<BF16>(literal),
+        "f32" => inspect_float::<f32>(literal),
+        "f64" => inspect_float::<f64>(literal),
+        "q7" => inspect_q7(literal),
+        _ => Err(InspectError::UnknownType(type_name.to_string())),
+    }
+}
+
+/// Raw bits written as `0x...` or `0b...`, which must fit in `width` bits.
+fn raw_bits(literal: &str, width: u32) -> Result<Option<u64>, InspectError> {
+    let (sign, digits, radix) = split_radix(literal);
+    if radix == 10 {
+        return Ok(None);
+    }
+
+    let bits = match (sign, u64::from_str_radix(&digits.replace('_', ""), radix)) {
+        ("", Ok(bits)) => bits,
+        (_, Err(err)) if *err.kind() == IntErrorKind::PosOverflow => return Err(InspectError::OutOfRange(literal.to_string())),
+        _ => return Err(InspectError::InvalidLiteral(literal.to_string())),
+    };
+    if width < 64 && bits >> width != 0 {
+        return Err(InspectError::OutOfRange(literal.to_string()));
+    }
+
+    Ok(Some(bits))
+}
+
+/// A decimal literal: `digits` × 10^exponent.
+struct Decimal {
+    negative: bool,
+    digits: Vec<u8>,
+    exponent: i32,
+}
+
+impl Decimal {
+    /// Parses `[+-]digits[.digits][e[+-]digits]`.
+    fn parse(literal: &str) -> Option<Decimal> {
+        let (negative, rest) = match literal.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, literal.strip_prefix('+').unwrap_or(literal)),
+        };
+        let (mantissa, exponent) = match rest.find(['e', 'E']) {
+            Some(i) => (&rest[..i], rest[i + 1..].parse::<i32>().ok()?),
+            None => (rest, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if (integer.is_empty() && fraction.is_empty()) || exponent.abs() > MAX_DECIMAL_EXPONENT {
+            return None;
+        }
+        let digits = integer
+            .bytes()
+            .chain(fraction.bytes())
+            .map(|b| b.is_ascii_digit().then_some(b - b'0'))
+            .collect::<Option<Vec<u8>>>()?;
+
+        Some(Decimal { negative, digits, exponent: exponent - fraction.len() as i32 })
+    }
+
+    /// The magnitude × 2^k, split into its integer part and its fractional digits.
+    ///
+    /// None if the integer part doesn't fit in an `i128`.
+    fn scaled(&self, k: i32) -> Option<(i128, Vec<u8>)> {
+        let mut digits = self.digits.clone();
+        let mut exponent = self.exponent;
+        // 2^-k = 5^k / 10^k.
+        let (factor, times) = if k >= 0 { (2, k) } else { (5, -k) };
+        if k < 0 {
+            exponent += k;
+        }
+        for _ in 0..times {
+            let mut carry = 0;
+            for digit in digits.iter_mut().rev() {
+                let product = *digit * factor + carry;
+                *digit = product % 10;
+                carry = product / 10;
+            }
+            if carry > 0 {
+                digits.insert(0, carry);
+            }
+        }
+
+        let point = digits.len() as i64 + exponent as i64;
+        let (integer, fraction) = if point <= 0 {
+            let mut fraction = vec![0; -point as usize];
+            fraction.extend(&digits);
+            (0, fraction)
+        } else {
+            let split = (point as usize).min(digits.len());
+            let mut integer: i128 = 0;
+            for &digit in digits[..split].iter().chain(std::iter::repeat_n(&0, point as usize - split)) {
+                integer = integer.checked_mul(10)?.checked_add(digit as i128)?;
+            }
+            (integer, digits[split..].to_vec())
+        };
+
+        Some((integer, fraction))
+    }
+}
+
+/// How `integer + 0.fraction` compares to 1/2 in magnitude.
+fn compare_to_half(integer: i128, fraction: &[u8]) -> Ordering {
+    let end = fraction.iter().rposition(|&d| d != 0).map_or(0, |i| i + 1);
+    let fraction_vs_half = match fraction[..end] {
+        [] => Ordering::Less,
+        [5] => Ordering::Equal,
+        [d, ..] if d >= 5 => Ordering::Greater,
+        _ => Ordering::Less,
+    };
+    match integer {
+        0 => fraction_vs_half,
+        // |fraction - 1| = 1 - fraction.
+        -1 => fraction_vs_half.reverse(),
+        _ => Ordering::Greater,
+    }
+}
+
+fn fraction_to_f64(fraction: &[u8]) -> f64 {
+    let digits: String = fraction.iter().take(20).map(|d| (b'0' + d) as char).collect();
+    format!("0.{}", digits).parse().unwrap_or(0.0)
+}
+
+/// Trims trailing zeros after a decimal point, and the point itself if nothing is left.
+fn trim_zeros(s: String) -> String {
+    if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.').to_string() } else { s }
+}
+
+/// Sets the sign bit of `bits` to that of `negative`.
+fn with_sign<T: Ieee754>(bits: u64, negative: bool) -> T {
+    let sign = 1 << (T::BITS - 1);
+    T::from_bits_u64(if negative { bits | sign } else { bits & !sign })
+}
+
+/// The next value towards +infinity, or None for NaN and +infinity.
+pub fn next_up<T: Ieee754>(x: T) -> Option<T> {
+    let parts = x.to_parts();
+    let bits = x.to_bits_u64();
+    match (parts.category, parts.negative) {
+        (FpCategory::Nan, _) | (FpCategory::Infinite, false) => None,
+        (FpCategory::Zero, _) => Some(T::from_bits_u64(1)),
+        // The magnitude is in the low bits, so stepping it steps through the values.
+        (_, false) => Some(T::from_bits_u64(bits + 1)),
+        (_, true) => Some(T::from_bits_u64(bits - 1)),
+    }
+}
+
+/// The next value towards -infinity, or None for NaN and -infinity.
+pub fn next_down<T: Ieee754>(x: T) -> Option<T> {
+    let negated = with_sign::<T>(x.to_bits_u64(), !x.to_parts().negative);
+    next_up(negated).map(|n| with_sign(n.to_bits_u64(), !n.to_parts().negative))
+}
+
+/// The exact difference between a decimal literal and `value`, in ULPs of `value`.
+///
+/// Returns the difference of the magnitudes, |literal| - |value|, as an integer part and
+/// fractional digits, along with the ULP exponent. None if either is not finite.
+fn ulp_difference<T: Ieee754>(decimal: &Decimal, value: T) -> Option<(i128, Vec<u8>, i32)> {
+    let parts = value.to_parts();
+    let exponent = match parts.category {
+        FpCategory::Normal | FpCategory::Subnormal => parts.exponent,
+        FpCategory::Zero => T::MIN_EXPONENT,
+        FpCategory::Infinite | FpCategory::Nan => return None,
+    };
+    if parts.negative != decimal.negative && parts.category != FpCategory::Zero {
+        return None;
+    }
+
+    let ulp_exponent = exponent - T::FRACTION_BITS as i32;
+    let (integer, fraction) = decimal.scaled(-ulp_exponent)?;
+    Some((integer - parts.significand as i128, fraction, ulp_exponent))
+}
+
+/// Like `ulp_difference`, but in units of the gap between `value` and its neighbour on
+/// the literal's side, which is where the tie between them lies.
+///
+/// Below a power of two the neighbour is in the binade below, so the gap is half an ULP.
+fn gap_difference<T: Ieee754>(decimal: &Decimal, value: T) -> Option<(i128, Vec<u8>)> {
+    let (integer, fraction, ulp_exponent) = ulp_difference(decimal, value)?;
+    let parts = value.to_parts();
+    let power_of_two = parts.category == FpCategory::Normal && parts.significand == 1 << T::FRACTION_BITS;
+    if integer >= 0 || !power_of_two || parts.exponent == T::MIN_EXPONENT {
+        return Some((integer, fraction));
+    }
+
+    let (integer, fraction) = decimal.scaled(1 - ulp_exponent)?;
+    Some((integer - 2 * parts.significand as i128, fraction))
+}
+
+fn inspect_float<T: Ieee754 + fmt::Display>(literal: &str) -> Result<Report, InspectError> {
+    let invalid = || InspectError::InvalidLiteral(literal.to_string());
+    let raw = raw_bits(literal, T::BITS)?;
+    let decimal = if raw.is_some() { None } else { Decimal::parse(literal) };
+    let mut value = match raw {
+        Some(bits) => T::from_bits_u64(bits),
+        None => T::from_f64(literal.parse::<f64>().map_err(|_| invalid())?),
+    };
+
+    // Parsing to f64 rounds once, and converting to T rounds again. When the literal is
+    // within 2^-53 of a tie between two values of T, that can pick the wrong one.
+    if let Some((integer, fraction)) = decimal.as_ref().and_then(|d| gap_difference(d, value)) {
+        let bits = value.to_bits_u64();
+        let step = match compare_to_half(integer, &fraction) {
+            Ordering::Greater => true,
+            Ordering::Equal => bits & 1 == 1,
+            Ordering::Less => false,
+        };
+        if step {
+            // Moving the magnitude towards the literal's.
+            value = T::from_bits_u64(if integer >= 0 { bits + 1 } else { bits - 1 });
+        }
+    }
+    if raw.is_none() && value.to_parts().category == FpCategory::Infinite && !literal.to_ascii_lowercase().contains("inf") {
+        return Err(InspectError::OutOfRange(literal.to_string()));
+    }
+
+    let parts = value.to_parts();
+    let bits = value.to_bits_u64();
+    let fraction_bits = T::FRACTION_BITS;
+    let biased = (bits >> fraction_bits) & ((1 << T::EXPONENT_BITS) - 1);
+    let fraction = bits & ((1 << fraction_bits) - 1);
+    let exponent_meaning = match parts.category {
+        FpCategory::Normal => format!("{} - {} = {}", biased, T::BIAS, parts.exponent),
+        FpCategory::Subnormal | FpCategory::Zero => format!("subnormal: {}", T::MIN_EXPONENT),
+        FpCategory::Infinite | FpCategory::Nan => "all ones: infinity or NaN".to_string(),
+    };
+    // The significand is below 2^53, so the quotient and all its digits are exact.
+    let significand = parts.significand as f64 / 2f64.powi(fraction_bits as i32);
+    let fraction_meaning = match parts.category {
+        FpCategory::Normal | FpCategory::Subnormal | FpCategory::Zero => {
+            trim_zeros(format!("{:.*}", fraction_bits as usize, significand))
+        }
+        FpCategory::Infinite => "infinity".to_string(),
+        FpCategory::Nan if parts.is_signaling::<T>() => format!("signaling NaN, payload {:#x}", fraction),
+        FpCategory::Nan => format!("quiet NaN, payload {:#x}", fraction & !T::QUIET_BIT),
+    };
+    let fields = vec![
+        field("sign", (bits >> (T::BITS - 1)) as u128, 1, if parts.negative { "-" } else { "+" }.to_string()),
+        field("exponent", biased as u128, T::EXPONENT_BITS, exponent_meaning),
+        field("fraction", fraction as u128, fraction_bits, fraction_meaning),
+    ];
+
+    let difference = decimal.as_ref().and_then(|d| ulp_difference(d, value));
+    let ulp_exponent = match parts.category {
+        FpCategory::Normal | FpCategory::Subnormal => Some(parts.exponent - fraction_bits as i32),
+        FpCategory::Zero => Some(T::MIN_EXPONENT - fraction_bits as i32),
+        FpCategory::Infinite | FpCategory::Nan => None,
+    };
+    let error_ulps = difference.map(|(integer, fraction, _)| {
+        let magnitude = integer as f64 + fraction_to_f64(&fraction);
+        if decimal.as_ref().is_some_and(|d| d.negative) && magnitude != 0.0 { -magnitude } else { magnitude }
+    });
+    let exact = match ulp_exponent {
+        Some(e) => trim_zeros(format!("{:.*}", (-e).max(0) as usize, value.to_f64())),
+        None => value.to_string(),
+    };
+    let bytes = (T::BITS / 8) as usize;
+
+    Ok(Report {
+        type_name: T::NAME,
+        literal: literal.to_string(),
+        value: value.to_string(),
+        exact,
+        fields,
+        big_endian: bits.to_be_bytes()[8 - bytes..].to_vec(),
+        little_endian: bits.to_le_bytes()[..bytes].to_vec(),
+        previous: next_down(value).map(|n| n.to_string()),
+        next: next_up(value).map(|n| n.to_string()),
+        ulp_exponent,
+        error_ulps,
+    })
+}
+
+fn inspect_q7(literal: &str) -> Result<Report, InspectError> {
+    let value = match raw_bits(literal, 8)? {
+        Some(bits) => Q7::from_bits(bits as u8 as i8),
+        None => literal.parse::<Q7>().map_err(|err| match err {
+            ParseFixedError::OutOfRange => InspectError::OutOfRange(literal.to_string()),
+            _ => InspectError::InvalidLiteral(literal.to_string()),
+        })?,
+    };
+
+    let bits = value.to_bits() as u8;
+    let sign_meaning = if bits >> 7 == 1 { "-1" } else { "0" };
+    let fields = vec![
+        field("sign", (bits >> 7) as u128, 1, sign_meaning.to_string()),
+        field("fraction", (bits & 0x7f) as u128, 7, format!("+{}/128", bits & 0x7f)),
+    ];
+    let error_ulps = Decimal::parse(literal).and_then(|d| {
+        let (integer, fraction) = d.scaled(7)?;
+        let magnitude = integer as f64 + fraction_to_f64(&fraction);
+        Some(if d.negative { -magnitude } else { magnitude } - value.to_bits() as f64)
+    });
+    let neighbour = |bits: Option<i8>| bits.map(|b| Q7::from_bits(b).to_string());
+
+    Ok(Report {
+        type_name: "Q7",
+        literal: literal.to_string(),
+        value: value.to_string(),
+        exact: format!("{:.7}", value).trim_end_matches('0').trim_end_matches('.').to_string(),
+        fields,
+        big_endian: vec![bits],
+        little_endian: vec![bits],
+        previous: neighbour(value.to_bits().checked_sub(1)),
+        next: neighbour(value.to_bits().checked_add(1)),
+        ulp_exponent: Some(-7),
+        error_ulps,
+    })
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option<T>(value: &Option<T>, to_json: impl Fn(&T) -> String) -> String {
+    value.as_ref().map_or("null".to_string(), to_json)
+}
+
+impl Report {
+    pub fn bits(&self) -> String {
+        self.fields.iter().map(|f| f.bits.as_str()).collect()
+    }
+
+    pub fn hex(&self) -> String {
+        format!("0x{}", hex_bytes(&self.big_endian).replace(' ', ""))
+    }
+
+    /// The report as a single JSON object, with numbers that need every digit as strings.
+    pub fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .fields
+            .iter()
+            .map(|f| format!("{{\"name\":{},\"bits\":\"{}\",\"meaning\":{}}}", json_string(f.name), f.bits, json_string(&f.meaning)))
+            .collect();
+        let bytes = |bytes: &[u8]| bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",");
+
+        format!(
+            "{{\"type\":{},\"literal\":{},\"value\":{},\"exact\":{},\"bits\":\"{}\",\"hex\":\"{}\",\"fields\":[{}],\
+             \"big_endian\":[{}],\"little_endian\":[{}],\"previous\":{},\"next\":{},\"ulp_exponent\":{},\"error_ulps\":{}}}",
+            json_string(self.type_name),
+            json_string(&self.literal),
+            json_string(&self.value),
+            json_string(&self.exact),
+            self.bits(),
+            self.hex(),
+            fields.join(","),
+            bytes(&self.big_endian),
+            bytes(&self.little_endian),
+            json_option(&self.previous, |s| json_string(s)),
+            json_option(&self.next, |s| json_string(s)),
+            json_option(&self.ulp_exponent, |e| e.to_string()),
+            json_option(&self.error_ulps, |e| format!("{:?}", e)),
+        )
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let none = || "none".to_string();
+        let width = self.fields.iter().map(|field| field.bits.len()).max().unwrap_or(0);
+        let bits: Vec<&str> = self.fields.iter().map(|field| field.bits.as_str()).collect();
+
+        writeln!(f, "{} {}", self.type_name, self.literal)?;
+        writeln!(f, "value          {}", self.value)?;
+        writeln!(f, "exact          {}", self.exact)?;
+        writeln!(f, "bits           {}", bits.join(" "))?;
+        for field in &self.fields {
+            writeln!(f, "  {:<12} {:<width$}  {}", field.name, field.bits, field.meaning, width = width)?;
+        }
+        writeln!(f, "hex            {}", self.hex())?;
+        writeln!(f, "big endian     {}", hex_bytes(&self.big_endian))?;
+        writeln!(f, "little endian  {}", hex_bytes(&self.little_endian))?;
+        writeln!(f, "previous       {}", self.previous.clone().unwrap_or_else(none))?;
+        writeln!(f, "next           {}", self.next.clone().unwrap_or_else(none))?;
+        match self.ulp_exponent {
+            Some(e) => writeln!(f, "ulp            2^{} = {:e}", e, 2f64.powi(e))?,
+            None => writeln!(f, "ulp            none")?,
+        }
+        match self.error_ulps {
+            Some(error) => writeln!(f, "error          literal - value = {:+.6} ulp", error),
+            None => writeln!(f, "error          none"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meanings(report: &Report) -> Vec<&str> {
+        report.fields.iter().map(|f| f.meaning.as_str()).collect()
+    }
+
+    #[test]
+    fn f32_fields() {
+        let report = inspect("f32", "42.42").unwrap();
+        assert_eq!(report.bits(), "01000010001010011010111000010100");
+        assert_eq!(meanings(&report), ["+", "132 - 127 = 5", "1.325624942779541015625"]);
+        assert_eq!(report.value, "42.42");
+        assert_eq!(report.exact, "42.4199981689453125");
+        assert_eq!(report.hex(), "0x4229ae14");
+        assert_eq!(report.big_endian, [0x42, 0x29, 0xae, 0x14]);
+        assert_eq!(report.little_endian, [0x14, 0xae, 0x29, 0x42]);
+        assert_eq!(report.previous.as_deref(), Some("42.419994"));
+        assert_eq!(report.next.as_deref(), Some("42.420002"));
+        assert_eq!(report.ulp_exponent, Some(-18));
+        // 42.42 - 42.41999816894531 = 1.83e-6, and the ULP is 3.81e-6.
+        assert!((report.error_ulps.unwrap() - 0.48).abs() < 0.001);
+    }
+
+    #[test]
+    fn f64_error_is_exact() {
+        let report = inspect("f64", "0.1").unwrap();
+        assert_eq!(report.exact, "0.1000000000000000055511151231257827021181583404541015625");
+        assert_eq!(report.ulp_exponent, Some(-56));
+        // The stored value is 0.4 ULP above 0.1.
+        assert!((report.error_ulps.unwrap() + 0.4).abs() < 1e-9);
+        assert_eq!(inspect("f64", "-2.5e-1").unwrap().error_ulps.map(f64::to_bits), Some(0));
+    }
+
+    #[test]
+    fn special_floats() {
+        let zero = inspect("f32", "-0").unwrap();
+        assert_eq!(zero.bits(), format!("1{:031b}", 0));
+        assert_eq!(zero.next, Some(f32::from_bits(1).to_string()));
+        assert_eq!(zero.previous, Some((-f32::from_bits(1)).to_string()));
+
+        let nan = inspect("f32", "0x7fc00001").unwrap();
+        assert_eq!(meanings(&nan)[2], "quiet NaN, payload 0x1");
+        assert_eq!((nan.previous, nan.next, nan.error_ulps), (None, None, None));
+        assert_eq!(meanings(&inspect("f16", "0x7d00").unwrap())[2], "signaling NaN, payload 0x100");
+
+        let infinity = inspect("f16", "inf").unwrap();
+        assert_eq!(infinity.previous.as_deref(), Some("65504"));
+        assert_eq!(infinity.next, None);
+        assert_eq!(inspect("f16", "70000"), Err(InspectError::OutOfRange("70000".to_string())));
+
+        let subnormal = inspect("f16", "0x0001").unwrap();
+        assert_eq!(meanings(&subnormal), ["+", "subnormal: -14", "0.0009765625"]);
+        assert_eq!(subnormal.exact, "0.000000059604644775390625");
+    }
+
+    #[test]
+    fn f16_avoids_double_rounding() {
+        // Just above the tie between 1 and 1 + 2^-10. As an f64 it is exactly the tie,
+        // which rounds to even: 1.
+        let report = inspect("f16", "1.00048828125000000000001").unwrap();
+        assert_eq!(report.hex(), "0x3c01");
+        assert!(report.error_ulps.unwrap() < 0.0);
+        assert_eq!(inspect("f16", "1.00048828125").unwrap().hex(), "0x3c00");
+        assert_eq!(inspect("f16", "1.00146484375").unwrap().hex(), "0x3c02");
+        assert_eq!(inspect("bf16", "1.5").unwrap().big_endian, [0x3f, 0xc0]);
+    }
+
+    #[test]
+    fn double_rounding_below_a_power_of_two() {
+        // Just below the tie between 1 - 2^-11 and 1, which is a quarter of 1's ULP away.
+        assert_eq!(inspect("f16", "0.99975585937499999999999").unwrap().hex(), "0x3bff");
+        assert_eq!(inspect("f16", "0.999755859375").unwrap().hex(), "0x3c00");
+        assert_eq!(inspect("f16", "0.99975585937500000000001").unwrap().hex(), "0x3c00");
+        // The same between 2 - 2^-10 and 2.
+        assert_eq!(inspect("f16", "1.99951171874999999999999").unwrap().hex(), "0x3fff");
+
+        assert_eq!(inspect("f32", "0.99999997019767761230468749999999").unwrap().hex(), "0x3f7fffff");
+        assert_eq!(inspect("f32", "0.999999970197677612304687500").unwrap().hex(), "0x3f800000");
+        assert_eq!(inspect("f32", "0.99999997019767761230468750000001").unwrap().hex(), "0x3f800000");
+
+        let tie = "0.999999999999999944488848768742172978818416595458984375";
+        assert_eq!(inspect("f64", &format!("{}1", tie)).unwrap().hex(), "0x3ff0000000000000");
+        assert_eq!(inspect("f64", tie).unwrap().hex(), "0x3ff0000000000000");
+        assert_eq!(inspect("f64", &tie.replace("375", "374")).unwrap().hex(), "0x3fefffffffffffff");
+
+        // The smallest normal's neighbour below is a subnormal a whole ULP away.
+        assert_eq!(inspect("f16", "0.0000610053539276123046875").unwrap().hex(), "0x0400");
+        assert_eq!(inspect("f16", "0.00006100535392761230468749999").unwrap().hex(), "0x03ff");
+    }
+
+    #[test]
+    fn integers() {
+        let report = inspect("i8", "-1").unwrap();
+        assert_eq!(report.bits(), "11111111");
+        assert_eq!(meanings(&report), ["-2^7", "+127"]);
+        assert_eq!((report.previous.as_deref(), report.next.as_deref()), (Some("-2"), Some("0")));
+
+        let report = inspect("u16", "0x1_02").unwrap();
+        assert_eq!(report.value, "258");
+        assert_eq!(report.big_endian, [1, 2]);
+        assert_eq!(report.little_endian, [2, 1]);
+        assert_eq!(inspect("u8", "255").unwrap().next, None);
+        assert_eq!(inspect("i128", "-0x80000000000000000000000000000000").unwrap().previous, None);
+
+        assert_eq!(inspect("u8", "256"), Err(InspectError::OutOfRange("256".to_string())));
+        assert_eq!(inspect("u8", "-1"), Err(InspectError::InvalidLiteral("-1".to_string())));
+        assert_eq!(inspect("i32", "1.5"), Err(InspectError::InvalidLiteral("1.5".to_string())));
+        assert_eq!(inspect("u7", "1"), Err(InspectError::UnknownType("u7".to_string())));
+    }
+
+    #[test]
+    fn q7() {
+        let report = inspect("Q7", "-0.3").unwrap();
+        assert_eq!(report.bits(), "11011010");
+        assert_eq!(meanings(&report), ["-1", "+90/128"]);
+        assert_eq!(report.exact, "-0.296875");
+        // -0.3 × 128 = -38.4, stored as -38.
+        assert!((report.error_ulps.unwrap() + 0.4).abs() < 1e-9);
+        assert_eq!(inspect("q7", "0x80").unwrap().value, "-1");
+        assert_eq!(inspect("q7", "-1").unwrap().previous, None);
+        assert_eq!(inspect("q7", "1"), Err(InspectError::OutOfRange("1".to_string())));
+    }
+
+    #[test]
+    fn neighbours_match_std() {
+        for x in [0.0f32, -0.0, 1.0, -1.0, f32::MAX, -f32::MAX, f32::MIN_POSITIVE, 1e-45, -1e-45, f32::INFINITY, f32::NEG_INFINITY] {
+            let up = (x != f32::INFINITY).then(|| x.next_up().to_bits());
+            let down = (x != f32::NEG_INFINITY).then(|| x.next_down().to_bits());
+            assert_eq!(next_up(x).map(f32::to_bits), up, "{}", x);
+            assert_eq!(next_down(x).map(f32::to_bits), down, "{}", x);
+        }
+    }
+
+    #[test]
+    fn json() {
+        let report = inspect("u8", "5").unwrap();
+        assert_eq!(
+            report.to_json(),
+            "{\"type\":\"u8\",\"literal\":\"5\",\"value\":\"5\",\"exact\":\"5\",\"bits\":\"00000101\",\"hex\":\"0x05\",\
+             \"fields\":[{\"name\":\"value\",\"bits\":\"00000101\",\"meaning\":\"5\"}],\"big_endian\":[5],\
+             \"little_endian\":[5],\"previous\":\"4\",\"next\":\"6\",\"ulp_exponent\":0,\"error_ulps\":0.0}"
+        );
+        assert!(inspect("f32", "0x7f800000").unwrap().to_json().ends_with("\"next\":null,\"ulp_exponent\":null,\"error_ulps\":null}"));
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
@@ -0,0 +1,309 @@
+// Quantizing a model means storing its weights and activations as small integers
+// instead of floats. Each tensor gets an affine mapping from its Q7 bits q back to
+// the real values it stands for:
+//
+//     real = scale * (q - zero_point)
+//
+// `scale` is the real value of one step of q, and `zero_point` is the q that
+// represents 0.0 exactly, so that zero padding and ReLU outputs lose nothing.
+//
+// A dot product of two quantized vectors is then an integer dot product of
+// (q - zero_point) terms, scaled once at the end by the product of both scales.
+// Each term fits in 17 bits, so an i32 accumulator can sum tens of thousands of
+// them without overflowing. That is how integer-only hardware runs a dense layer.
+//
+// The result differs from the f32 one only by the rounding of each input to its
+// nearest step. Writing w and x for the real inputs, and ŵ and x̂ for their
+// quantized values, w·x - ŵ·x̂ = Σ w_i (x_i - x̂_i) + Σ x̂_i (w_i - ŵ_i), and each
+// rounding error is at most half a step, so every output of a dense layer is
+// within
+//
+//     (s_x · Σ|w_i| + s_w · Σ|x̂_i|) / 2 + s_w · s_x / 2
+//
+// of the f32 result, where the last term is the rounding of the bias. See
+// `error_bound`.
+//
+// That assumes every input is within the range its scale and zero point can
+// represent, scale * (-128 - zero_point) ..= scale * (127 - zero_point), give or
+// take half a step. `quantize` and `quantize_symmetric` choose the range to fit the
+// values, so theirs always are. Values outside it saturate at -128 or 127, and are
+// off by however far outside they were, which the bound doesn't include.
+
+use crate::fixed_point_number_formats::Q7;
+
+/// The longest vectors whose dot product is guaranteed to fit in an `i32`.
+///
+/// Each term is at most 255 * 255 in magnitude.
+pub const MAX_DOT_LEN: usize = (i32::MAX / (255 * 255)) as usize;
+
+/// A tensor of [`Q7`] values sharing one scale and zero point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quantized {
+    pub values: Vec<Q7>,
+    pub scale: f32,
+    pub zero_point: i8,
+}
+
+impl Quantized {
+    /// Quantizes `values` using the whole Q7 range for `min(values, 0)..=max(values, 0)`.
+    pub fn quantize(values: &[f32]) -> Self {
+        let min = values.iter().copied().fold(0.0, f32::min);
+        let max = values.iter().copied().fold(0.0, f32::max);
+        let scale = if max > min { (max - min) / 255.0 } else { 1.0 };
+        let zero_point = (-128.0 - min / scale).round().clamp(-128.0, 127.0) as i8;
+
+        Quantized::with_params(values, scale, zero_point)
+    }
+
+    /// Quantizes `values` with a zero point of 0, as is usual for weights.
+    ///
+    /// Products then need no zero point correction, at the cost of never using -128.
+    pub fn quantize_symmetric(values: &[f32]) -> Self {
+        let max_abs = values.iter().fold(0.0f32, |max, x| max.max(x.abs()));
+        let scale = if max_abs > 0.0 { max_abs / 127.0 } else { 1.0 };
+
+        Quantized::with_params(values, scale, 0)
+    }
+
+    /// Quantizes `values` to the nearest step, saturating those outside the range.
+    pub fn with_params(values: &[f32], scale: f32, zero_point: i8) -> Self {
+        assert!(scale > 0.0 && scale.is_finite(), "scale must be positive");
+        let values = values
+            .iter()
+            .map(|x| {
+                let q = (x / scale).round() + zero_point as f32;
+                Q7::from_bits(q.clamp(-128.0, 127.0) as i8)
+            })
+            .collect();
+
+        Quantized { values, scale, zero_point }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|q| self.scale * (q.to_bits() as i32 - self.zero_point as i32) as f32).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The dot product of two quantized vectors, computed with an `i32` accumulator.
+    pub fn dot(&self, other: &Quantized) -> f32 {
+        assert_eq!(self.len(), other.len(), "vectors must have the same length");
+        let sum = dot(&self.values, self.zero_point, &other.values, other.zero_point);
+        sum as f32 * self.scale * other.scale
+    }
+}
+
+/// Σ (a_i - a_zero) * (b_i - b_zero), in integer arithmetic.
+///
+/// # Panics
+///
+/// If the slices differ in length, or are longer than [`MAX_DOT_LEN`].
+pub fn dot(a: &[Q7], a_zero: i8, b: &[Q7], b_zero: i8) -> i32 {
+    assert_eq!(a.len(), b.len(), "vectors must have the same length");
+    assert!(a.len() <= MAX_DOT_LEN, "vectors are too long for an i32 accumulator");
+
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a.to_bits() as i32 - a_zero as i32) * (b.to_bits() as i32 - b_zero as i32))
+        .sum()
+}
+
+/// A row-major matrix of quantized weights.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix {
+    pub rows: usize,
+    pub cols: usize,
+    pub weights: Quantized,
+}
+
+impl Matrix {
+    /// Quantizes the row-major `weights` symmetrically.
+    pub fn quantize(rows: usize, cols: usize, weights: &[f32]) -> Self {
+        assert_eq!(weights.len(), rows * cols, "expected {} x {} weights", rows, cols);
+        Matrix { rows, cols, weights: Quantized::quantize_symmetric(weights) }
+    }
+
+    pub fn row(&self, row: usize) -> &[Q7] {
+        &self.weights.values[row * self.cols..(row + 1) * self.cols]
+    }
+
+    /// Multiplies the matrix by `vector`, leaving the results in their accumulators.
+    pub fn matvec(&self, vector: &Quantized) -> Accumulators {
+        assert_eq!(vector.len(), self.cols, "expected a vector of length {}", self.cols);
+        let values = (0..self.rows)
+            .map(|row| dot(self.row(row), self.weights.zero_point, &vector.values, vector.zero_point))
+            .collect();
+
+        Accumulators { values, scale: self.weights.scale * vector.scale }
+    }
+}
+
+/// The `i32` results of a matrix-vector multiply, in steps of `scale`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accumulators {
+    pub values: Vec<i32>,
+    pub scale: f32,
+}
+
+impl Accumulators {
+    /// Adds `bias`, rounded to the accumulators' scale, as integer hardware does.
+    pub fn add_bias(&mut self, bias: &[f32]) {
+        assert_eq!(bias.len(), self.values.len(), "expected {} biases", self.values.len());
+        for (acc, b) in self.values.iter_mut().zip(bias) {
+            let b = (b / self.scale).round().clamp(i32::MIN as f32, i32::MAX as f32) as i32;
+            *acc = acc.saturating_add(b);
+        }
+    }
+
+    pub fn dequantize(&self) -> Vec<f32> {
+        self.values.iter().map(|&acc| acc as f32 * self.scale).collect()
+    }
+}
+
+/// The bound on the error of each output of [`dense`], as derived at the top of this module.
+///
+/// It only holds if neither the weights nor the input saturated when they were quantized,
+/// as with [`Quantized::quantize`], or [`Quantized::with_params`] given values in range.
+pub fn error_bound(weights: &Matrix, input: &Quantized) -> Vec<f32> {
+    let (s_w, s_x) = (weights.weights.scale, input.scale);
+    let weights_f32 = weights.weights.dequantize();
+    let input_abs: f32 = input.dequantize().iter().map(|x| x.abs()).sum();
+
+    (0..weights.rows)
+        .map(|row| {
+            // The true weights are within half a step of the quantized ones.
+            let row_abs: f32 = weights_f32[row * weights.cols..(row + 1) * weights.cols]
+                .iter()
+                .map(|w| w.abs() + s_w / 2.0)
+                .sum();
+            (s_x * row_abs + s_w * input_abs) / 2.0 + s_w * s_x / 2.0
+        })
+        .collect()
+}
+
+/// Runs a dense layer, `weights * input + bias`, in integer arithmetic.
+pub fn dense(weights: &Matrix, bias: &[f32], input: &Quantized) -> Vec<f32> {
+    let mut acc = weights.matvec(input);
+    acc.add_bias(bias);
+    acc.dequantize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64, mapped to -1.0..1.0.
+    fn random_values(seed: &mut u64, n: usize) -> Vec<f32> {
+        (0..n)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                (*seed >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quantize_round_trip() {
+        let values = [-0.75, -0.1, 0.0, 0.2, 1.5];
+        for q in [Quantized::quantize(&values), Quantized::quantize_symmetric(&values)] {
+            for (x, y) in values.iter().zip(q.dequantize()) {
+                assert!((x - y).abs() <= q.scale / 2.0, "{} became {} with {:?}", x, y, q);
+            }
+            // Zero is always exact.
+            assert_eq!(Quantized::with_params(&[0.0], q.scale, q.zero_point).dequantize(), [0.0]);
+        }
+
+        let q = Quantized::quantize(&values);
+        assert_eq!(q.values.iter().map(|q| q.to_bits()).min(), Some(-128));
+        assert_eq!(q.values.iter().map(|q| q.to_bits()).max(), Some(127));
+        assert_eq!(Quantized::quantize(&[0.0; 3]).dequantize(), [0.0; 3]);
+        assert_eq!(Quantized::with_params(&[100.0, -100.0], 0.5, 0).dequantize(), [63.5, -64.0]);
+    }
+
+    #[test]
+    fn dot_product() {
+        let a: Vec<Q7> = [1, -2, 3].into_iter().map(Q7::from_bits).collect();
+        let b: Vec<Q7> = [4, 5, -6].into_iter().map(Q7::from_bits).collect();
+        assert_eq!(dot(&a, 0, &b, 0), 4 - 10 - 18);
+        assert_eq!(dot(&a, 1, &b, -1), -3 * 6 + 2 * -5);
+
+        let extreme = vec![Q7::from_bits(-128); MAX_DOT_LEN];
+        assert_eq!(dot(&extreme, 127, &extreme, 127), MAX_DOT_LEN as i32 * 255 * 255);
+
+        let a = Quantized::quantize(&[0.5, -0.25, 1.0]);
+        let b = Quantized::quantize_symmetric(&[0.1, 0.2, -0.3]);
+        let exact = 0.5 * 0.1 - 0.25 * 0.2 - 0.3;
+        assert!((a.dot(&b) - exact).abs() < 0.01);
+    }
+
+    #[test]
+    #[should_panic]
+    fn dot_product_rejects_mismatched_lengths() {
+        dot(&[Q7::from_bits(1)], 0, &[], 0);
+    }
+
+    #[test]
+    fn dense_layer_matches_f32() {
+        const ROWS: usize = 16;
+        const COLS: usize = 64;
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+
+        for _ in 0..20 {
+            let weights = random_values(&mut seed, ROWS * COLS);
+            let bias = random_values(&mut seed, ROWS);
+            let input: Vec<f32> = random_values(&mut seed, COLS).iter().map(|x| x * 3.0 + 0.5).collect();
+
+            let expected: Vec<f32> = (0..ROWS)
+                .map(|row| {
+                    let w = &weights[row * COLS..(row + 1) * COLS];
+                    bias[row] + w.iter().zip(&input).map(|(w, x)| w * x).sum::<f32>()
+                })
+                .collect();
+
+            let matrix = Matrix::quantize(ROWS, COLS, &weights);
+            let input = Quantized::quantize(&input);
+            let actual = dense(&matrix, &bias, &input);
+            let bounds = error_bound(&matrix, &input);
+
+            for row in 0..ROWS {
+                let error = (actual[row] - expected[row]).abs();
+                // Leave a little room for the f32 reference's own rounding.
+                assert!(error <= bounds[row] * 1.001, "row {}: error {} exceeds bound {}", row, error, bounds[row]);
+                // With weights in -1..1, inputs in -2.5..3.5 and 64 terms, s_w is about 1/127
+                // and s_x about 6/255, so the bound stays below 1.0. The bound is for the
+                // worst case: typical errors are a few hundredths.
+                assert!(bounds[row] < 1.0, "row {}: bound {} is too loose", row, bounds[row]);
+            }
+        }
+    }
+
+    #[test]
+    fn bound_holds_up_to_the_edges_of_the_range() {
+        let matrix = Matrix::quantize(1, 2, &[1.0, -0.5]);
+        let exact = |x: &[f32]| x[0] - 0.5 * x[1];
+        // Represents -5.0..=7.75 in steps of 0.05.
+        let (scale, zero_point) = (0.05, -28);
+
+        for input in [[7.75, -5.0], [7.77, -5.02], [-5.0, 7.75], [-5.02, 7.77]] {
+            let quantized = Quantized::with_params(&input, scale, zero_point);
+            let error = (dense(&matrix, &[0.0], &quantized)[0] - exact(&input)).abs();
+            let bound = error_bound(&matrix, &quantized)[0];
+            assert!(error <= bound * 1.001, "{:?}: error {} exceeds bound {}", input, error, bound);
+        }
+
+        // Further out, the input saturates and the bound no longer applies.
+        let input = [10.0, -5.0];
+        let quantized = Quantized::with_params(&input, scale, zero_point);
+        assert_eq!(quantized.dequantize(), [7.75, -5.0]);
+        let error = (dense(&matrix, &[0.0], &quantized)[0] - exact(&input)).abs();
+        assert!(error > error_bound(&matrix, &quantized)[0], "error {}", error);
+    }
+}
@@ -0,0 +1,314 @@
+// `mock_rand` in fixed_point_number_formats turns 8 random bits into a float by
+// splicing them into the mantissa of a number whose exponent is fixed, so that the
+// result lands in 1.0..2.0 before it is shifted. This module grows that trick into
+// a small pseudo-random number generator.
+//
+// A float with the exponent of 1.0 and a mantissa of n random bits is exactly
+// 1 + k / 2^n for a uniformly chosen k. Subtracting 1.0 is exact too, so the result is
+// one of 2^n evenly spaced values in 0.0..1.0, each equally likely. Dividing a random
+// integer by 2^32 instead would round some of those values onto their neighbours.
+//
+// The generators are fast and statistically good, but predictable: never use them
+// for keys, nonces or anything else an attacker shouldn't be able to guess.
+
+use std::ops::Range;
+
+/// A source of random bits, and the distributions built on top of them.
+pub trait Rng {
+    fn next_u64(&mut self) -> u64;
+
+    fn next_u32(&mut self) -> u32 {
+        // The high bits are the better ones for most generators.
+        (self.next_u64() >> 32) as u32
+    }
+
+    /// A uniform value from the 2^23 values k / 2^23 in 0.0..1.0.
+    fn uniform_f32(&mut self) -> f32 {
+        let one = 1.0f32.to_bits();
+        f32::from_bits(one | self.next_u32() >> 9) - 1.0
+    }
+
+    /// A uniform value from the 2^52 values k / 2^52 in 0.0..1.0.
+    fn uniform_f64(&mut self) -> f64 {
+        let one = 1.0f64.to_bits();
+        f64::from_bits(one | self.next_u64() >> 12) - 1.0
+    }
+
+    /// A uniform integer in `range`, without the bias of taking a remainder.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is empty.
+    fn range_u64(&mut self, range: Range<u64>) -> u64 {
+        assert!(range.start < range.end, "empty range");
+        let span = range.end - range.start;
+        // Lemire's method: the high half of a 128-bit product is uniform in 0..span,
+        // once the few low halves that would make some results more likely are rejected.
+        let threshold = span.wrapping_neg() % span;
+        loop {
+            let product = self.next_u64() as u128 * span as u128;
+            if product as u64 >= threshold {
+                return range.start + (product >> 64) as u64;
+            }
+        }
+    }
+
+    fn range_i64(&mut self, range: Range<i64>) -> i64 {
+        assert!(range.start < range.end, "empty range");
+        let span = range.end.wrapping_sub(range.start) as u64;
+        range.start.wrapping_add(self.range_u64(0..span) as i64)
+    }
+
+    /// A uniform value in `range`.
+    ///
+    /// # Panics
+    ///
+    /// If `range` is empty or not finite.
+    fn range_f64(&mut self, range: Range<f64>) -> f64 {
+        let width = range.end - range.start;
+        assert!(width > 0.0 && width.is_finite(), "range must be non-empty and finite");
+        loop {
+            // Rounding can land exactly on the end, which is excluded.
+            let x = range.start + width * self.uniform_f64();
+            if x < range.end {
+                return x;
+            }
+        }
+    }
+
+    /// A value from the standard normal distribution, with a mean of 0 and a standard
+    /// deviation of 1.
+    fn normal(&mut self) -> f64 {
+        // Marsaglia's polar method: a uniform point in the unit circle, stretched radially.
+        // It produces two independent values; this keeps things simple and drops one.
+        loop {
+            let x = 2.0 * self.uniform_f64() - 1.0;
+            let y = 2.0 * self.uniform_f64() - 1.0;
+            let s = x * x + y * y;
+            if s > 0.0 && s < 1.0 {
+                return x * (-2.0 * s.ln() / s).sqrt();
+            }
+        }
+    }
+
+    fn normal_with(&mut self, mean: f64, std_dev: f64) -> f64 {
+        mean + std_dev * self.normal()
+    }
+}
+
+/// Marsaglia's xorshift, with its output multiplied to hide the weak low bits.
+///
+/// 8 bytes of state and a period of 2^64 - 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XorShift64Star {
+    state: u64,
+}
+
+impl XorShift64Star {
+    /// A zero state would stay zero forever, so a seed of 0 is replaced.
+    pub fn new(seed: u64) -> Self {
+        XorShift64Star { state: if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed } }
+    }
+}
+
+impl Rng for XorShift64Star {
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+}
+
+/// O'Neill's PCG32 (XSH RR): a 64-bit linear congruential generator whose state is
+/// permuted into each 32-bit output.
+///
+/// Every odd increment gives a different sequence, so `stream` selects one of 2^63.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64,
+}
+
+impl Pcg32 {
+    const MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+    pub fn new(seed: u64, stream: u64) -> Self {
+        let mut pcg = Pcg32 { state: 0, increment: stream << 1 | 1 };
+        pcg.step();
+        pcg.state = pcg.state.wrapping_add(seed);
+        pcg.step();
+        pcg
+    }
+
+    fn step(&mut self) -> u64 {
+        let old = self.state;
+        self.state = old.wrapping_mul(Pcg32::MULTIPLIER).wrapping_add(self.increment);
+        old
+    }
+}
+
+impl Rng for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let old = self.step();
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        xorshifted.rotate_right((old >> 59) as u32)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pearson's statistic for `observed` counts against equally likely buckets.
+    fn chi_squared(observed: &[u64]) -> f64 {
+        let total: u64 = observed.iter().sum();
+        let expected = total as f64 / observed.len() as f64;
+        observed.iter().map(|&o| (o as f64 - expected).powi(2) / expected).sum()
+    }
+
+    /// The Kolmogorov–Smirnov statistic: the largest gap between the empirical
+    /// distribution of `samples` and `cdf`.
+    fn kolmogorov_smirnov(mut samples: Vec<f64>, cdf: impl Fn(f64) -> f64) -> f64 {
+        samples.sort_by(f64::total_cmp);
+        let n = samples.len() as f64;
+        samples
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| {
+                let f = cdf(x);
+                (f - i as f64 / n).max((i + 1) as f64 / n - f)
+            })
+            .fold(0.0, f64::max)
+    }
+
+    /// The critical value of the KS statistic at a significance level of 0.001.
+    fn ks_critical(n: usize) -> f64 {
+        1.949 / (n as f64).sqrt()
+    }
+
+    /// The standard normal CDF, using the erf approximation 7.1.26 from Abramowitz and
+    /// Stegun, which is accurate to 1.5e-7: far below what these tests can detect.
+    fn normal_cdf(x: f64) -> f64 {
+        let z = x.abs() / 2f64.sqrt();
+        let t = 1.0 / (1.0 + 0.327_591_1 * z);
+        let poly = t * (0.254_829_592 + t * (-0.284_496_736 + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+        let erf = 1.0 - poly * (-z * z).exp();
+        if x >= 0.0 { 0.5 * (1.0 + erf) } else { 0.5 * (1.0 - erf) }
+    }
+
+    fn generators() -> Vec<Box<dyn Rng>> {
+        vec![Box::new(XorShift64Star::new(1)), Box::new(Pcg32::new(42, 54))]
+    }
+
+    #[test]
+    fn pcg32_reference_output() {
+        // From the PCG reference implementation's demo program.
+        let mut pcg = Pcg32::new(42, 54);
+        let output: Vec<u32> = (0..6).map(|_| pcg.next_u32()).collect();
+        assert_eq!(output, [0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e]);
+        assert_ne!(Pcg32::new(42, 55).next_u32(), 0xa15c02b7);
+    }
+
+    #[test]
+    fn xorshift_never_gets_stuck() {
+        let mut rng = XorShift64Star::new(0);
+        assert_ne!(rng.next_u64(), rng.next_u64());
+    }
+
+    #[test]
+    fn uniform_floats_are_on_the_grid() {
+        for mut rng in generators() {
+            for _ in 0..10_000 {
+                let x = rng.uniform_f32();
+                assert!((0.0..1.0).contains(&x));
+                assert_eq!((x * (1 << 23) as f32).fract(), 0.0);
+                let y = rng.uniform_f64();
+                assert!((0.0..1.0).contains(&y));
+                assert_eq!((y * (1u64 << 52) as f64).fract(), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_f32_chi_squared() {
+        // 99 degrees of freedom: the statistic exceeds 148.23 with probability 0.001.
+        for mut rng in generators() {
+            let mut buckets = [0; 100];
+            for _ in 0..100_000 {
+                buckets[(rng.uniform_f32() * 100.0) as usize] += 1;
+            }
+            let statistic = chi_squared(&buckets);
+            assert!(statistic < 148.23, "chi-squared {}", statistic);
+        }
+    }
+
+    #[test]
+    fn range_u64_chi_squared() {
+        // 9 degrees of freedom: the statistic exceeds 27.88 with probability 0.001.
+        for mut rng in generators() {
+            let mut buckets = [0; 10];
+            for _ in 0..100_000 {
+                buckets[(rng.range_u64(20..30) - 20) as usize] += 1;
+            }
+            let statistic = chi_squared(&buckets);
+            assert!(statistic < 27.88, "chi-squared {}", statistic);
+
+            // A span that doesn't divide 2^64, where a plain remainder would favour the
+            // bottom third of the range.
+            let span = u64::MAX / 3 * 2;
+            let low = (0..30_000).filter(|_| rng.range_u64(0..span) < span / 2).count();
+            assert!((14_000..16_000).contains(&low), "{} in the lower half", low);
+        }
+    }
+
+    #[test]
+    fn ranges_stay_in_bounds() {
+        let mut rng = Pcg32::new(7, 0);
+        for _ in 0..10_000 {
+            assert!((-5..5).contains(&rng.range_i64(-5..5)));
+            assert!((1.0..1.0 + f64::EPSILON * 4.0).contains(&rng.range_f64(1.0..1.0 + f64::EPSILON * 4.0)));
+        }
+        assert_eq!(rng.range_u64(3..4), 3);
+        assert!((i64::MIN..i64::MAX).contains(&rng.range_i64(i64::MIN..i64::MAX)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn empty_range_panics() {
+        Pcg32::new(7, 0).range_u64(4..4);
+    }
+
+    #[test]
+    fn uniform_f64_kolmogorov_smirnov() {
+        for mut rng in generators() {
+            let samples: Vec<f64> = (0..10_000).map(|_| rng.range_f64(-2.0..6.0)).collect();
+            let statistic = kolmogorov_smirnov(samples, |x| (x + 2.0) / 8.0);
+            assert!(statistic < ks_critical(10_000), "KS statistic {}", statistic);
+        }
+    }
+
+    #[test]
+    fn normal_kolmogorov_smirnov() {
+        for mut rng in generators() {
+            let samples: Vec<f64> = (0..10_000).map(|_| rng.normal_with(3.0, 2.0)).collect();
+            let statistic = kolmogorov_smirnov(samples, |x| normal_cdf((x - 3.0) / 2.0));
+            assert!(statistic < ks_critical(10_000), "KS statistic {}", statistic);
+        }
+    }
+
+    #[test]
+    fn the_tests_can_fail() {
+        // A skewed sample, to check that the statistics actually notice.
+        let mut rng = Pcg32::new(1, 1);
+        let skewed: Vec<f64> = (0..10_000).map(|_| rng.uniform_f64().powf(1.1)).collect();
+        assert!(kolmogorov_smirnov(skewed, |x| x) > ks_critical(10_000));
+        let samples: Vec<f64> = (0..10_000).map(|_| rng.normal_with(0.1, 1.0)).collect();
+        assert!(kolmogorov_smirnov(samples, normal_cdf) > ks_critical(10_000));
+        assert!(chi_squared(&[1100, 900, 1000, 1000]) > 16.27);
+    }
+}
@@ -1,74 +1,30 @@
-use std::mem::zeroed;
-use chrono::{DateTime, Local, TimeZone};
+use chrono::{DateTime, Local, Utc};
 use clap::{App, Arg};
+use clock::{Clock, SystemClock};
+
+/// Sets `clock` to `datetime`, which is written according to `std`.
+fn set(clock: &mut dyn Clock, datetime: &str, std: &str) -> Result<(), String> {
+    let t = match std {
+        "timestamp" => datetime.parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        "rfc2822" => DateTime::parse_from_rfc2822(datetime).ok().map(|t| t.with_timezone(&Utc)),
+        "rfc3339" => DateTime::parse_from_rfc3339(datetime).ok().map(|t| t.with_timezone(&Utc)),
+        _ => unreachable!()
+    };
 
-struct Clock;
-
-impl Clock {
-    fn get() -> DateTime<Local> {
-        Local::now()
-    }
-
-    #[cfg(not(windows))]
-    pub fn set<Tz: TimeZone>(t: DateTime<Tz>) -> () {
-        use libc::{timeval, time_t, suseconds_t};
-        use libc::{settimeofday, timezone};
-
-        let t = t.with_timezone(&Local);
-        let mut u: timeval = unsafe { zeroed() };
-
-        u.tv_sec = t.timestamp() as time_t;
-        u.tv_usec = t.timestamp_subsec_micros() as suseconds_t;
-
-        unsafe {
-            let mock_tz: *const timezone = std::ptr::null();
-            settimeofday(&u as *const timeval, mock_tz);
-        }
-    }
-
-    #[cfg(windows)]
-    pub fn set<TZ: TimeZone>(t: DateTime<Tz>) -> () {
-        use crono::Weekday;
-        use kernel32::SetSystemTime;
-        use winapi::{SYSTEMTIME,WORD};
-
-        let t = t.with_timezone(&Local);
-
-        let mut systime: SYSTEMTIME = unsafe { zeroed() };
-
-        let dow = match t.weekday() {
-            Weekday::Sun => 0,
-            Weekday::Mon => 1,
-            Weekday::Tue => 2,
-            Weekday::Wed => 3,
-            Weekday::Thu => 4,
-            Weekday::Fri => 5,
-            Weekday::Sat => 6,
-        };
-
-        let mut ns = t.nanosecond();
-        let mut leap = 0;
-        let is_leap_second = ns > 1_000_000_000;
-
-        if is_leap_second {
-            ns -= 1_000_000_000;
-            leap += 1;
-        }
-
-        systime.wYear = t.year() as WORD;
-        systime.wMonth = t.month() as WORD;
-        systime.wDayOfWeek = dow as WORD;
-        systime.wDay = t.day() as WORD;
-        systime.wHour = t.hour() as WORD;
-        systime.wMinute = t.minute() as WORD;
-        systime.wSecond = (leap + t.second()) as WORD;
-        systime.wMilliseconds = (ns / 1_000_000) as WORD;
+    let t = t.ok_or_else(|| format!(
+        "Unable to parse {} according to {}",
+        datetime, std
+    ))?;
 
-        let systime_ptr = &systime as *const SYSTEMTIME;
+    clock.set(t).map_err(|err| format!("Unable to set the time: {}", err))
+}
 
-        unsafe {
-            SetSystemTime(systime_ptr);
-        }
+fn format(now: DateTime<Local>, std: &str) -> String {
+    match std {
+        "timestamp" => now.timestamp().to_string(),
+        "rfc2822" => now.to_rfc2822(),
+        "rfc3339" => now.to_rfc3339(),
+        _ => unreachable!()
     }
 }
 
@@ -102,45 +58,62 @@ fn main() {
     let action = args.value_of("action").unwrap();
     let std    = args.value_of("std").unwrap();
 
+    let mut clock = SystemClock;
+
     if action == "set" {
         let t_ = args.value_of("datetime").unwrap();
 
-        let parser = match std {
-            "rfc2822" => DateTime::parse_from_rfc2822,
-            "rfc3339" => DateTime::parse_from_rfc3339,
-            _ => unimplemented!()
-        };
+        if let Err(err) = set(&mut clock, t_, std) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let now = clock.local();
 
-        let err_msg = format!(
-            "Unable to parse {} according to {}",
-            t_, std
-        );
+    println!("{}", format(now, std));
+    println!("The current time is: {}", now);
+}
 
-        let t = parser(t_).expect(&err_msg);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use clock::MockClock;
 
-        Clock::set(t);
+    fn clock() -> MockClock {
+        MockClock::new(Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap())
+    }
 
-        let maybe_error = std::io::Error::last_os_error();
-        let os_error_code = &maybe_error.raw_os_error();
+    #[test]
+    fn sets_the_clock() {
+        let mut clock = clock();
+        set(&mut clock, "2024-05-17T09:30:00+02:00", "rfc3339").unwrap();
+        set(&mut clock, "Fri, 17 May 2024 08:30:00 +0100", "rfc2822").unwrap();
+        set(&mut clock, "1715931000", "timestamp").unwrap();
 
-        match os_error_code {
-            Some(0) => (),
-            Some(code) => {
-                eprintln!("Error code: {}", code);
-                eprintln!("Error message: {}", maybe_error);
-            },
-            None => ()
-        }
+        let expected = Utc.with_ymd_and_hms(2024, 5, 17, 7, 30, 0).unwrap();
+        assert_eq!(clock.sets(), &[expected, expected, expected]);
+        assert_eq!(clock.now(), expected);
     }
 
-    let now  = Clock::get();
+    #[test]
+    fn reports_failures() {
+        let mut clock = clock();
+        let err = set(&mut clock, "last Tuesday", "rfc3339").unwrap_err();
+        assert_eq!(err, "Unable to parse last Tuesday according to rfc3339");
+        assert!(clock.sets().is_empty());
 
-    match std {
-        "timestamp" => println!("{}", now.timestamp()),
-        "rfc2822" => println!("{}", now.to_rfc2822()),
-        "rfc3339" => println!("{}", now.to_rfc3339()),
-        _ => unreachable!()
+        let mut clock = MockClock::read_only(clock.now());
+        let err = set(&mut clock, "2024-05-17T09:30:00Z", "rfc3339").unwrap_err();
+        assert!(err.starts_with("Unable to set the time: permission denied"), "{}", err);
     }
 
-    println!("The current time is: {}", now);
+    #[test]
+    fn formats_the_time() {
+        let now = clock().local();
+        assert_eq!(format(now, "timestamp"), "1704067200");
+        assert_eq!(DateTime::parse_from_rfc3339(&format(now, "rfc3339")).unwrap(), now);
+        assert_eq!(DateTime::parse_from_rfc2822(&format(now, "rfc2822")).unwrap(), now);
+    }
 }
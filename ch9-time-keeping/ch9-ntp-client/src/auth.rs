@@ -0,0 +1,353 @@
+/*!
+Symmetric-key authentication, as in RFC 5905 section 7.3 and RFC 8573.
+
+Client and server share a secret key, known to both by a numeric ID. The sender appends
+a MAC: the key ID followed by a digest of the header and extension fields, computed with
+the key. The receiver recomputes the digest and rejects the packet if it differs, so a
+third party can neither forge responses nor alter them in flight.
+
+Keys are read from a file in the format ntpd uses, one per line:
+
+```text
+# id  type        key
+1     AES128CMAC  2b7e151628aed2a6abf7158809cf4f3c
+2     SHA1        a-legacy-secret
+```
+
+A key of up to 20 characters is taken as ASCII, and a longer one as hex. AES-CMAC is
+what RFC 8573 recommends. SHA-1 (a digest of the key followed by the packet) is only
+here for older servers that can't do better.
+*/
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::{error, fmt, fs, io};
+
+use aes::Aes128;
+use cmac::{Cmac, Mac as _};
+use sha1::{Digest as _, Sha1};
+
+use crate::packet::{Mac, NtpPacket};
+
+/// The key ID a server uses for a crypto-NAK: a MAC with no digest, sent when it
+/// can't authenticate a request.
+pub const CRYPTO_NAK: u32 = 0;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Algorithm {
+    AesCmac,
+    Sha1,
+}
+
+impl Algorithm {
+    pub fn digest_length(&self) -> usize {
+        match self {
+            Algorithm::AesCmac => 16,
+            Algorithm::Sha1 => 20,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Algorithm::AesCmac => write!(f, "AES128CMAC"),
+            Algorithm::Sha1 => write!(f, "SHA1"),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    pub id: u32,
+    pub algorithm: Algorithm,
+    secret: Vec<u8>,
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Keep the secret out of logs.
+        f.debug_struct("Key").field("id", &self.id).field("algorithm", &self.algorithm).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// The packet has no MAC, but a key is configured.
+    Unauthenticated,
+    /// The server couldn't authenticate our request.
+    CryptoNak,
+    UnknownKey(u32),
+    WrongKey { expected: u32, actual: u32 },
+    BadDigest,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "packet isn't authenticated"),
+            AuthError::CryptoNak => write!(f, "server couldn't authenticate the request"),
+            AuthError::UnknownKey(id) => write!(f, "unknown key {}", id),
+            AuthError::WrongKey { expected, actual } => write!(f, "expected key {}, got key {}", expected, actual),
+            AuthError::BadDigest => write!(f, "MAC doesn't match"),
+        }
+    }
+}
+
+impl error::Error for AuthError {}
+
+impl Key {
+    pub fn new(id: u32, algorithm: Algorithm, secret: &[u8]) -> Result<Key, String> {
+        if id == CRYPTO_NAK {
+            return Err("key ID 0 is reserved".to_string());
+        }
+        match algorithm {
+            Algorithm::AesCmac if secret.len() != 16 => Err("AES128CMAC keys must be 16 bytes".to_string()),
+            _ if secret.is_empty() => Err("keys can't be empty".to_string()),
+            _ => Ok(Key { id, algorithm, secret: secret.to_vec() }),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm {
+            Algorithm::AesCmac => {
+                let mut mac = Cmac::<Aes128>::new_from_slice(&self.secret).expect("the key length was checked");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Algorithm::Sha1 => {
+                let mut hash = Sha1::new();
+                hash.update(&self.secret);
+                hash.update(data);
+                hash.finalize().to_vec()
+            }
+        }
+    }
+
+    /// Appends a MAC to `packet`, replacing any it had.
+    pub fn sign(&self, packet: &mut NtpPacket) {
+        let digest = self.digest(&packet.to_bytes_without_mac());
+        packet.mac = Some(Mac { key_id: self.id, digest });
+    }
+
+    /// Checks the MAC of `packet`, which was parsed from `datagram`.
+    pub fn verify(&self, packet: &NtpPacket, datagram: &[u8]) -> Result<(), AuthError> {
+        let mac = packet.mac.as_ref().ok_or(AuthError::Unauthenticated)?;
+        if mac.key_id == CRYPTO_NAK && mac.digest.is_empty() {
+            return Err(AuthError::CryptoNak);
+        }
+        if mac.key_id != self.id {
+            return Err(AuthError::WrongKey { expected: self.id, actual: mac.key_id });
+        }
+
+        // The digest covers exactly the bytes that were received before the MAC.
+        let signed = &datagram[..datagram.len() - 4 - mac.digest.len()];
+        if constant_time_eq(&self.digest(signed), &mac.digest) {
+            Ok(())
+        } else {
+            Err(AuthError::BadDigest)
+        }
+    }
+}
+
+/// Compares digests without stopping at the first difference, which would tell an
+/// attacker how much of a forgery is right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Debug)]
+pub enum KeyFileError {
+    Io(io::Error),
+    Invalid { line: usize, reason: String },
+}
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyFileError::Io(err) => write!(f, "unable to read the key file: {}", err),
+            KeyFileError::Invalid { line, reason } => write!(f, "key file line {}: {}", line, reason),
+        }
+    }
+}
+
+impl error::Error for KeyFileError {}
+
+impl From<io::Error> for KeyFileError {
+    fn from(err: io::Error) -> Self {
+        KeyFileError::Io(err)
+    }
+}
+
+/// The keys from a key file, by ID.
+#[derive(Debug, Clone, Default)]
+pub struct Keys {
+    keys: HashMap<u32, Key>,
+}
+
+impl Keys {
+    pub fn load(path: impl AsRef<Path>) -> Result<Keys, KeyFileError> {
+        Keys::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Keys, KeyFileError> {
+        let mut keys = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| KeyFileError::Invalid { line: number + 1, reason };
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (id, algorithm, secret) = match fields[..] {
+                [id, algorithm, secret] => (id, algorithm, secret),
+                _ => return Err(invalid("expected an ID, a type and a key".to_string())),
+            };
+            let id: u32 = id.parse().map_err(|_| invalid(format!("invalid key ID {:?}", id)))?;
+            let algorithm = match algorithm.to_ascii_uppercase().as_str() {
+                "AES128CMAC" | "AES-128-CMAC" | "CMAC" => Algorithm::AesCmac,
+                "SHA1" | "SHA-1" => Algorithm::Sha1,
+                _ => return Err(invalid(format!("unsupported key type {:?}", algorithm))),
+            };
+            let secret = if secret.len() <= 20 {
+                secret.as_bytes().to_vec()
+            } else {
+                parse_hex(secret).ok_or_else(|| invalid("keys over 20 characters must be hex".to_string()))?
+            };
+
+            let key = Key::new(id, algorithm, &secret).map_err(invalid)?;
+            if keys.insert(id, key).is_some() {
+                return Err(invalid(format!("key {} is defined twice", id)));
+            }
+        }
+        Ok(Keys { keys })
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Key> {
+        self.keys.get(&id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RFC_4493_KEY: &str = "2b7e151628aed2a6abf7158809cf4f3c";
+
+    fn hex(text: &str) -> Vec<u8> {
+        parse_hex(text).unwrap()
+    }
+
+    fn cmac_key() -> Key {
+        Key::new(1, Algorithm::AesCmac, &hex(RFC_4493_KEY)).unwrap()
+    }
+
+    #[test]
+    fn aes_cmac_test_vectors() {
+        // RFC 4493 section 4.
+        let key = cmac_key();
+        assert_eq!(key.digest(b""), hex("bb1d6929e95937287fa37d129b756746"));
+        assert_eq!(key.digest(&hex("6bc1bee22e409f96e93d7e117393172a")), hex("070a16b46b4d4144f79bdd9dd04a287c"));
+    }
+
+    #[test]
+    fn sha1_digests_the_key_then_the_packet() {
+        let key = Key::new(2, Algorithm::Sha1, b"a").unwrap();
+        assert_eq!(key.digest(b"bc"), hex("a9993e364706816aba3e25717850c26c9cd0d89d"));
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        for key in [cmac_key(), Key::new(7, Algorithm::Sha1, b"secret").unwrap()] {
+            let mut packet = NtpPacket::client();
+            key.sign(&mut packet);
+            let bytes = packet.to_bytes();
+            assert_eq!(bytes.len(), 48 + 4 + key.algorithm.digest_length());
+
+            let parsed = NtpPacket::parse(&bytes).unwrap();
+            assert_eq!(key.verify(&parsed, &bytes), Ok(()));
+
+            let mut tampered = bytes.clone();
+            tampered[1] ^= 1;
+            let parsed = NtpPacket::parse(&tampered).unwrap();
+            assert_eq!(key.verify(&parsed, &tampered), Err(AuthError::BadDigest));
+        }
+    }
+
+    #[test]
+    fn verify_rejects() {
+        let key = cmac_key();
+        let unsigned = NtpPacket::client().to_bytes();
+        assert_eq!(key.verify(&NtpPacket::parse(&unsigned).unwrap(), &unsigned), Err(AuthError::Unauthenticated));
+
+        let mut other = NtpPacket::client();
+        Key::new(2, Algorithm::AesCmac, &[0; 16]).unwrap().sign(&mut other);
+        let bytes = other.to_bytes();
+        let expected = AuthError::WrongKey { expected: 1, actual: 2 };
+        assert_eq!(key.verify(&NtpPacket::parse(&bytes).unwrap(), &bytes), Err(expected));
+
+        let mut nak = NtpPacket::client();
+        nak.mac = Some(Mac { key_id: CRYPTO_NAK, digest: Vec::new() });
+        let bytes = nak.to_bytes();
+        assert_eq!(key.verify(&NtpPacket::parse(&bytes).unwrap(), &bytes), Err(AuthError::CryptoNak));
+
+        // Same ID, different secret.
+        let mut forged = NtpPacket::client();
+        Key::new(1, Algorithm::AesCmac, &[0; 16]).unwrap().sign(&mut forged);
+        let bytes = forged.to_bytes();
+        assert_eq!(key.verify(&NtpPacket::parse(&bytes).unwrap(), &bytes), Err(AuthError::BadDigest));
+    }
+
+    #[test]
+    fn key_file() {
+        let keys = Keys::parse(
+            "# internal servers\n\
+             1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c\n\
+             \n\
+             2 SHA1 legacy   # for the old GPS box\n\
+             3 sha1 000102030405060708090a0b0c0d0e0f10111213\n",
+        )
+        .unwrap();
+        assert_eq!(keys.get(1), Some(&cmac_key()));
+        assert_eq!(keys.get(2), Some(&Key::new(2, Algorithm::Sha1, b"legacy").unwrap()));
+        assert_eq!(keys.get(3).unwrap().secret, (0..20).collect::<Vec<u8>>());
+        assert_eq!(keys.get(4), None);
+        assert!(Keys::parse("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_key_files() {
+        let cases = [
+            ("1 AES128CMAC\n", 1),
+            ("# fine\nx SHA1 secret\n", 2),
+            ("1 MD5 secret\n", 1),
+            ("1 AES128CMAC tooshort\n", 1),
+            ("0 SHA1 secret\n", 1),
+            ("1 SHA1 this-is-not-hex-and-too-long\n", 1),
+            ("1 SHA1 a\n1 SHA1 b\n", 2),
+        ];
+        for (text, line) in cases {
+            match Keys::parse(text) {
+                Err(KeyFileError::Invalid { line: actual, .. }) => assert_eq!(actual, line, "{:?}", text),
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+        }
+    }
+
+    #[test]
+    fn debug_hides_the_secret() {
+        assert!(!format!("{:?}", cmac_key()).contains("2b"));
+    }
+}
@@ -0,0 +1,269 @@
+/*!
+Steers the local clock toward the selected offset without ever stepping it, following
+the clock discipline of RFC 5905 section 11.3.
+
+Stepping makes time jump, and can make it run backwards. Slewing speeds the clock up or
+slows it down by at most 500 ppm until the offset is gone, so time stays monotonic. The
+catch is that slewing away a second takes over half an hour, so offsets that would take
+longer than that, beyond `PANIC_THRESHOLD`, are refused: set the clock by hand first.
+
+Removing the offset isn't enough, because a quartz clock drifts at a fairly steady rate
+of its own. The discipline estimates that frequency error and corrects for it too, using
+a hybrid of two loops:
+
+- The phase-locked loop (PLL) adjusts the frequency in proportion to the offset, with
+  the gains from RFC 5905. It's deliberately slow, to stay steady under network jitter.
+- The frequency-locked loop (FLL) adjusts it by the offset that built up since the last
+  update, divided by the time it took. Every update slews its whole offset away, so that
+  build-up is the frequency error, less whatever of the last slew hadn't finished. The
+  FLL trusts it more at longer poll intervals, where it's less affected by jitter, and
+  does most of the work.
+
+The poll interval grows while offsets stay within the noise, and shrinks when they don't.
+*/
+
+use std::io;
+
+use crate::validate::{MAX_POLL, MIN_POLL};
+
+/// Offsets larger than this don't update the frequency: they're probably a glitch, or
+/// the clock was just set.
+pub const STEP_THRESHOLD: f64 = 0.128;
+/// How fast the kernel slews a one-shot adjustment, in seconds per second.
+pub const SLEW_RATE: f64 = 500e-6;
+/// Offsets larger than this would take over half an hour to slew away.
+pub const PANIC_THRESHOLD: f64 = SLEW_RATE * 1800.0;
+/// The largest frequency correction, in seconds per second.
+pub const MAX_FREQUENCY: f64 = 500e-6;
+
+/// The PLL's loop gain.
+const PLL: f64 = 65.0;
+/// The FLL averages its estimate over `FLL - poll` updates, but at least `AVG`.
+const FLL: f64 = MAX_POLL as f64 + 1.0;
+const AVG: f64 = 4.0;
+/// How far the poll counter goes before the poll interval changes.
+const LIMIT: i32 = 30;
+/// Offsets within this many jitters count as noise.
+const PGATE: f64 = 4.0;
+
+/// What to do to the clock after an update.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Adjustment {
+    /// Slew away `phase` seconds, and run the clock `frequency` seconds per second fast.
+    Slew { phase: f64, frequency: f64 },
+    /// The offset is too large to slew.
+    Panic(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct Discipline {
+    /// The frequency correction, in seconds per second.
+    pub frequency: f64,
+    /// The poll interval, as a power of two in seconds.
+    pub poll: i8,
+    /// The time of the last update, and the phase it slewed.
+    last: Option<(f64, f64)>,
+    count: i32,
+}
+
+impl Default for Discipline {
+    fn default() -> Self {
+        Discipline { frequency: 0.0, poll: MIN_POLL, last: None, count: 0 }
+    }
+}
+
+impl Discipline {
+    /// The poll interval in seconds.
+    pub fn interval(&self) -> f64 {
+        2f64.powi(self.poll as i32)
+    }
+
+    /// Feeds in an `offset` measured at `time`, with its `jitter`, all in seconds.
+    pub fn update(&mut self, offset: f64, jitter: f64, time: f64) -> Adjustment {
+        if offset.abs() > PANIC_THRESHOLD {
+            return Adjustment::Panic(offset);
+        }
+
+        if let Some((last_time, last_phase)) = self.last {
+            let mu = time - last_time;
+            if offset.abs() <= STEP_THRESHOLD && mu > 0.0 {
+                let pending = last_phase.signum() * (last_phase.abs() - SLEW_RATE * mu).max(0.0);
+                let averaging = (FLL - self.poll as f64).max(AVG);
+                self.frequency += (offset - pending) / mu / averaging;
+
+                let tau = self.interval();
+                self.frequency += offset * mu.min(tau) / (4.0 * PLL * tau).powi(2);
+                self.frequency = self.frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY);
+            }
+        }
+        self.last = Some((time, offset));
+        self.adapt_poll(offset, jitter);
+
+        Adjustment::Slew { phase: offset, frequency: self.frequency }
+    }
+
+    fn adapt_poll(&mut self, offset: f64, jitter: f64) {
+        if offset.abs() < PGATE * jitter {
+            self.count += self.poll as i32;
+            if self.count > LIMIT {
+                self.count = 0;
+                self.poll = (self.poll + 1).min(MAX_POLL);
+            }
+        } else {
+            self.count -= 2 * self.poll as i32;
+            if self.count < -LIMIT {
+                self.count = 0;
+                self.poll = (self.poll - 1).max(MIN_POLL);
+            }
+        }
+    }
+}
+
+/// Something that can slew a clock.
+pub trait ClockAdjust {
+    fn adjust(&mut self, phase: f64, frequency: f64) -> io::Result<()>;
+}
+
+/// Limits a frequency correction to what the kernel accepts.
+fn clamp_frequency(frequency: f64) -> f64 {
+    frequency.clamp(-MAX_FREQUENCY, MAX_FREQUENCY)
+}
+
+/// Slews the system clock with `adjtimex`, which needs root.
+pub struct Adjtimex;
+
+#[cfg(target_os = "linux")]
+impl ClockAdjust for Adjtimex {
+    fn adjust(&mut self, phase: f64, frequency: f64) -> io::Result<()> {
+        use libc::{adjtimex, timex, ADJ_FREQUENCY, ADJ_OFFSET_SINGLESHOT};
+
+        // The kernel won't take a one-shot offset together with anything else.
+        let mut tx: timex = unsafe { std::mem::zeroed() };
+        tx.modes = ADJ_FREQUENCY;
+        // In ppm, with 16 fractional bits.
+        tx.freq = (clamp_frequency(frequency) * 1e6 * 65536.0).round() as libc::c_long;
+        if unsafe { adjtimex(&mut tx) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut tx: timex = unsafe { std::mem::zeroed() };
+        tx.modes = ADJ_OFFSET_SINGLESHOT;
+        // In microseconds. Slewed at 500 ppm, like adjtime(3).
+        tx.offset = (phase * 1e6).round() as libc::c_long;
+        if unsafe { adjtimex(&mut tx) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl ClockAdjust for Adjtimex {
+    fn adjust(&mut self, _phase: f64, _frequency: f64) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "slewing is only implemented for Linux"))
+    }
+}
+
+/// Logs adjustments instead of making them.
+#[derive(Debug, Default)]
+pub struct DryRun {
+    /// The frequency correction that would be in effect, in seconds per second.
+    pub frequency: f64,
+}
+
+impl ClockAdjust for DryRun {
+    fn adjust(&mut self, phase: f64, frequency: f64) -> io::Result<()> {
+        let frequency = clamp_frequency(frequency);
+        self.frequency = frequency;
+        println!(
+            "dry run: would slew {:+.3} ms and set the frequency correction to {:+.3} ppm",
+            phase * 1e3,
+            frequency * 1e6
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A clock losing `drift` seconds per second, which starts `offset` seconds behind,
+    /// disciplined from noiseless measurements. Returns the discipline and final offset.
+    fn simulate(drift: f64, mut offset: f64, updates: usize) -> (Discipline, f64) {
+        let mut discipline = Discipline::default();
+        let mut time = 0.0;
+        for _ in 0..updates {
+            let (phase, frequency) = match discipline.update(offset, 1e-4, time) {
+                Adjustment::Slew { phase, frequency } => (phase, frequency),
+                Adjustment::Panic(offset) => panic!("panic at {}", offset),
+            };
+            let interval = discipline.interval();
+            time += interval;
+            // Like adjtime(3): the slew runs at SLEW_RATE until done.
+            let pending = phase.signum() * (phase.abs() - SLEW_RATE * interval).max(0.0);
+            offset = pending + (drift - frequency) * interval;
+        }
+        (discipline, offset)
+    }
+
+    #[test]
+    fn learns_the_frequency_error() {
+        for drift in [50e-6, -120e-6, 0.0] {
+            let (discipline, offset) = simulate(drift, 0.050, 300);
+            assert!((discipline.frequency - drift).abs() < 1e-7, "frequency {}", discipline.frequency);
+            assert!(offset.abs() < 1e-4, "offset {}", offset);
+            assert!(discipline.poll > MIN_POLL, "poll {}", discipline.poll);
+        }
+    }
+
+    #[test]
+    fn frequency_is_clamped() {
+        let mut discipline = Discipline::default();
+        for update in 0..50 {
+            discipline.update(0.12, 1e-4, update as f64 * 200.0);
+        }
+        assert_eq!(discipline.frequency, MAX_FREQUENCY);
+    }
+
+    #[test]
+    fn dry_run_clamps_the_frequency_like_the_kernel() {
+        let mut dry_run = DryRun::default();
+        dry_run.adjust(0.0, 2e-3).unwrap();
+        assert_eq!(dry_run.frequency, MAX_FREQUENCY);
+        dry_run.adjust(0.0, -1.0).unwrap();
+        assert_eq!(dry_run.frequency, -MAX_FREQUENCY);
+        dry_run.adjust(0.0, 20e-6).unwrap();
+        assert_eq!(dry_run.frequency, 20e-6);
+    }
+
+    #[test]
+    fn large_offsets_dont_disturb_the_frequency() {
+        let mut discipline = Discipline::default();
+        discipline.update(0.001, 1e-4, 0.0);
+        let adjustment = discipline.update(0.5, 1e-4, 16.0);
+        assert_eq!(adjustment, Adjustment::Slew { phase: 0.5, frequency: 0.0 });
+        assert_eq!(discipline.update(-2000.0, 1e-4, 32.0), Adjustment::Panic(-2000.0));
+        // Just over half an hour of slewing.
+        assert_eq!(discipline.update(0.91, 1e-4, 48.0), Adjustment::Panic(0.91));
+    }
+
+    #[test]
+    fn poll_interval_adapts() {
+        let mut discipline = Discipline::default();
+        let mut time = 0.0;
+        for _ in 0..100 {
+            discipline.update(1e-5, 1e-4, time);
+            time += discipline.interval();
+        }
+        assert!(discipline.poll > MIN_POLL + 2, "poll {}", discipline.poll);
+
+        let quiet = discipline.poll;
+        for _ in 0..20 {
+            discipline.update(0.05, 1e-4, time);
+            time += discipline.interval();
+        }
+        assert!(discipline.poll < quiet, "poll {}", discipline.poll);
+        assert!(discipline.poll >= MIN_POLL);
+    }
+}
@@ -0,0 +1,213 @@
+/*!
+A rolling record of what each peer said, for watching drift over longer than one run.
+
+`check-ntp` and the daemon append a line per answered query, its fields separated by
+tabs:
+
+```text
+1760000000.123456  time.google.com (216.239.35.0)  0.001234567  0.012345678  0.000123456  1
+```
+
+That's the time of the sample in seconds since the Unix epoch, the peer, then its
+offset, round-trip delay and jitter in seconds, and its stratum. Lines starting with `#`
+are ignored. Once the file holds a quarter more than its capacity, the oldest lines
+are dropped.
+*/
+
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::{error, fmt};
+
+/// How many lines a history keeps: a few days of a daemon with a handful of peers.
+pub const DEFAULT_CAPACITY: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Seconds since the Unix epoch.
+    pub time: f64,
+    pub peer: String,
+    pub offset: f64,
+    pub delay: f64,
+    pub jitter: f64,
+    pub stratum: u8,
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.6}\t{}\t{:.9}\t{:.9}\t{:.9}\t{}",
+            self.time, self.peer, self.offset, self.delay, self.jitter, self.stratum
+        )
+    }
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Record, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 6 {
+            return Err(format!("expected 6 tab-separated fields, found {}", fields.len()));
+        }
+        let number = |index: usize, name: &str| {
+            fields[index].parse::<f64>().ok().filter(|value| value.is_finite()).ok_or(format!("invalid {}", name))
+        };
+
+        Ok(Record {
+            time: number(0, "time")?,
+            peer: fields[1].to_string(),
+            offset: number(2, "offset")?,
+            delay: number(3, "delay")?,
+            jitter: number(4, "jitter")?,
+            stratum: fields[5].parse().map_err(|_| "invalid stratum".to_string())?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Io(io::Error),
+    Invalid { line: usize, reason: String },
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HistoryError::Io(err) => write!(f, "can't read the history: {}", err),
+            HistoryError::Invalid { line, reason } => write!(f, "history line {}: {}", line, reason),
+        }
+    }
+}
+
+impl error::Error for HistoryError {}
+
+impl From<io::Error> for HistoryError {
+    fn from(err: io::Error) -> Self {
+        HistoryError::Io(err)
+    }
+}
+
+pub struct History {
+    path: PathBuf,
+    capacity: usize,
+    lines: usize,
+}
+
+impl History {
+    /// Opens the history at `path`, which is created on the first append if need be.
+    pub fn open(path: impl Into<PathBuf>, capacity: usize) -> io::Result<History> {
+        let path = path.into();
+        let lines = match fs::read_to_string(&path) {
+            Ok(text) => text.lines().count(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => 0,
+            Err(err) => return Err(err),
+        };
+        Ok(History { path, capacity, lines })
+    }
+
+    pub fn append(&mut self, records: &[Record]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let text: String = records.iter().map(|record| format!("{}\n", record)).collect();
+        OpenOptions::new().create(true).append(true).open(&self.path)?.write_all(text.as_bytes())?;
+        self.lines += records.len();
+
+        // Some slack, so that the file isn't rewritten on every append.
+        if self.lines > self.capacity + self.capacity / 4 {
+            self.trim()?;
+        }
+        Ok(())
+    }
+
+    /// Keeps the newest `capacity` lines. They're written to a new file that then
+    /// replaces the old one, so a crash halfway leaves the history as it was.
+    fn trim(&mut self) -> io::Result<()> {
+        let text = fs::read_to_string(&self.path)?;
+        let lines: Vec<&str> = text.lines().collect();
+        let kept = &lines[lines.len().saturating_sub(self.capacity)..];
+
+        let mut temporary = OsString::from(self.path.as_os_str());
+        temporary.push(".tmp");
+        fs::write(&temporary, kept.iter().map(|line| format!("{}\n", line)).collect::<String>())?;
+        fs::rename(&temporary, &self.path)?;
+        self.lines = kept.len();
+        Ok(())
+    }
+
+    /// Reads every record in the history at `path`, oldest first.
+    pub fn load(path: impl AsRef<Path>) -> Result<Vec<Record>, HistoryError> {
+        let text = fs::read_to_string(path)?;
+        let mut records = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let record = Record::parse(line).map_err(|reason| HistoryError::Invalid { line: index + 1, reason })?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: f64, peer: &str) -> Record {
+        Record { time, peer: peer.to_string(), offset: -0.0125, delay: 0.031, jitter: 0.0004, stratum: 2 }
+    }
+
+    /// A path in the temporary directory that no other test uses.
+    fn temporary(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("clock-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let original = record(1_760_000_000.25, "time.google.com (216.239.35.0)");
+        let line = original.to_string();
+        assert_eq!(line, "1760000000.250000\ttime.google.com (216.239.35.0)\t-0.012500000\t0.031000000\t0.000400000\t2");
+        assert_eq!(Record::parse(&line), Ok(original));
+    }
+
+    #[test]
+    fn appends_and_rolls_over() {
+        let path = temporary("rolls");
+        let mut history = History::open(&path, 8).unwrap();
+        for time in 0..10 {
+            history.append(&[record(time as f64, "a")]).unwrap();
+        }
+        // Over capacity, but within the slack.
+        assert_eq!(History::load(&path).unwrap().len(), 10);
+
+        history.append(&[record(10.0, "a")]).unwrap();
+        let records = History::load(&path).unwrap();
+        assert_eq!(records.len(), 8);
+        assert_eq!(records[0].time, 3.0);
+        assert_eq!(records[7].time, 10.0);
+
+        // Reopening picks up where it left off.
+        let mut history = History::open(&path, 8).unwrap();
+        history.append(&[record(11.0, "a"), record(11.0, "b")]).unwrap();
+        assert_eq!(History::load(&path).unwrap().len(), 10);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_histories() {
+        let path = temporary("invalid");
+        fs::write(&path, "# a comment\n\n1\ta\t0.1\t0.1\t0.1\t2\n1\ta\tsoon\t0.1\t0.1\t2\n").unwrap();
+        let err = History::load(&path).unwrap_err();
+        assert_eq!(err.to_string(), "history line 4: invalid offset");
+
+        fs::write(&path, "1\ta\t0.1\n").unwrap();
+        assert!(matches!(History::load(&path), Err(HistoryError::Invalid { line: 1, .. })));
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(History::load(&path), Err(HistoryError::Io(_))));
+    }
+}
@@ -1,10 +1,36 @@
-use std::mem::zeroed;
-use std::net::UdpSocket;
-use std::time::Duration;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use chrono::{Duration as ChronoDuration};
-use byteorder::{BigEndian, ReadBytesExt};
-use chrono::{DateTime, Local, Timelike, TimeZone, Utc};
+use chrono::{DateTime, Utc};
 use clap::{App, Arg};
+use clock::{Clock, ClockError, SystemClock};
+
+mod auth;
+mod discipline;
+mod history;
+mod metrics;
+mod nts;
+mod packet;
+mod selection;
+mod server;
+mod siv;
+mod stats;
+mod validate;
+
+use auth::{Key, Keys};
+use discipline::{Adjtimex, Adjustment, ClockAdjust, Discipline, DryRun};
+use history::{History, Record};
+use metrics::{Metrics, MetricsServer, PeerMetrics};
+use nts::{KeServer, MasterKey, NtsSession};
+use packet::NtpPacket;
+use selection::{Peer, Sample, SelectionReport};
+use server::{Server, ServerConfig};
+use stats::Summary;
+use validate::{Backoff, Permission, ResponseError};
 
 /**
 Coming to a consensus about the correct time is known formally as clock synchronization.
@@ -12,161 +38,29 @@ There are multiple international standards for synchronizing clocks. This sectio
 on the most prominent one—the Network Time Protocol (NTP).
 */
 
-const NTP_MESSAGE_LENGTH: usize = 48;
-const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
-const LOCAL_ADDR: &'static str = "0.0.0.0:12300";
+/// The precision of `Utc::now()`, as a power of two in seconds: about a microsecond.
+const LOCAL_PRECISION: i8 = -20;
 
-#[derive(Default, Debug, Copy, Clone)]
-struct NTPTimestamp {
-    seconds: u32,
-    fraction: u32,
-}
-
-impl From<NTPTimestamp> for DateTime<Utc> {
-    fn from(ntp: NTPTimestamp) -> Self {
-        let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
-        let mut nanos = ntp.fraction as f64;
-        nanos *= 1e9;
-        nanos /= 2_f64.powi(32);
-
-        Utc.timestamp(secs, nanos as u32)
-    }
-}
-
-impl From<DateTime<Utc>> for NTPTimestamp {
-    fn from(utc: DateTime<Utc>) -> Self {
-        let secs = utc.timestamp() + NTP_TO_UNIX_SECONDS;
-        let mut fraction = utc.nanosecond() as f64;
-
-        fraction *= 2_f64.powi(32);
-        fraction /= 1e9;
-
-        NTPTimestamp {
-            seconds: secs as u32,
-            fraction: fraction as u32,
-        }
-    }
-}
-
-struct NTPMessage {
-    data: [u8; NTP_MESSAGE_LENGTH],
-}
-
-impl NTPMessage {
-    fn new () -> Self {
-        NTPMessage {
-            data: [0; NTP_MESSAGE_LENGTH],
-        }
-    }
-
-    fn client () -> Self {
-        const VERSION: u8 = 0b00_011_000;
-        const MODE: u8 = 0b00_000_011;
-
-        let mut msg = NTPMessage::new();
-
-        msg.data[0] |= VERSION;
-        msg.data[0] |= MODE;
-        msg
-    }
-
-    fn parse_timestamp(&self, i: usize) -> Result<NTPTimestamp, std::io::Error> {
-        let mut reader = &self.data[i..i + 8];
-        let seconds = reader.read_u32::<BigEndian>()?;
-        let fraction = reader.read_u32::<BigEndian>()?;
-
-        Ok(NTPTimestamp { seconds, fraction })
-    }
-
-    fn rx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(32)
-    }
-
-    fn tx_time(&self) -> Result<NTPTimestamp, std::io::Error> {
-        self.parse_timestamp(40)
-    }
-}
-
-struct Clock;
-
-impl Clock {
-    fn get() -> DateTime<Local> {
-        Local::now()
-    }
-
-    #[cfg(not(windows))]
-    pub fn set<Tz: TimeZone>(t: DateTime<Tz>) -> () {
-        use libc::{timeval, time_t, suseconds_t};
-        use libc::{settimeofday, timezone};
-
-        let t = t.with_timezone(&Local);
-        let mut u: timeval = unsafe { zeroed() };
-
-        u.tv_sec = t.timestamp() as time_t;
-        u.tv_usec = t.timestamp_subsec_micros() as suseconds_t;
-
-        unsafe {
-            let mock_tz: *const timezone = std::ptr::null();
-            settimeofday(&u as *const timeval, mock_tz);
-        }
-    }
-
-    #[cfg(windows)]
-    pub fn set<TZ: TimeZone>(t: DateTime<Tz>) -> () {
-        use crono::Weekday;
-        use kernel32::SetSystemTime;
-        use winapi::{SYSTEMTIME,WORD};
-
-        let t = t.with_timezone(&Local);
-
-        let mut systime: SYSTEMTIME = unsafe { zeroed() };
-
-        let dow = match t.weekday() {
-            Weekday::Sun => 0,
-            Weekday::Mon => 1,
-            Weekday::Tue => 2,
-            Weekday::Wed => 3,
-            Weekday::Thu => 4,
-            Weekday::Fri => 5,
-            Weekday::Sat => 6,
-        };
-
-        let mut ns = t.nanosecond();
-        let mut leap = 0;
-        let is_leap_second = ns > 1_000_000_000;
-
-        if is_leap_second {
-            ns -= 1_000_000_000;
-            leap += 1;
-        }
-
-        systime.wYear = t.year() as WORD;
-        systime.wMonth = t.month() as WORD;
-        systime.wDayOfWeek = dow as WORD;
-        systime.wDay = t.day() as WORD;
-        systime.wHour = t.hour() as WORD;
-        systime.wMinute = t.minute() as WORD;
-        systime.wSecond = (leap + t.second()) as WORD;
-        systime.wMilliseconds = (ns / 1_000_000) as WORD;
-
-        let systime_ptr = &systime as *const SYSTEMTIME;
-
-        unsafe {
-            SetSystemTime(systime_ptr);
-        }
-    }
-}
+const DEFAULT_SERVERS: [&str; 5] = [
+    "time.nist.gov",
+    "time.apple.com",
+    "time.euro.apple.com",
+    "time.google.com",
+    "time2.google.com",
+    // "time.windows.come"
+];
 
 struct NTPResult {
     t1: DateTime<Utc>,
     t2: DateTime<Utc>,
     t3: DateTime<Utc>,
     t4: DateTime<Utc>,
+    response: NtpPacket,
 }
 
 impl NTPResult {
     fn offset(&self) -> i64 {
-        let duration = (self.t2 - self.t1) + (self.t4 - self.t3);
+        let duration = (self.t2 - self.t1) + (self.t3 - self.t4);
         duration.num_milliseconds() / 2
     }
 
@@ -174,6 +68,18 @@ impl NTPResult {
         let duration = (self.t4 - self.t1) - (self.t3 - self.t2);
         duration.num_milliseconds()
     }
+
+    /**
+     * The exchange as a clock filter sample taken at `time`, in seconds on a monotonic timescale.
+     */
+    fn sample(&self, time: f64) -> Sample {
+        let seconds = |d: ChronoDuration| d.num_nanoseconds().unwrap_or(i64::MAX) as f64 / 1e9;
+        let offset = seconds((self.t2 - self.t1) + (self.t3 - self.t4)) / 2.0;
+        let delay = seconds((self.t4 - self.t1) - (self.t3 - self.t2)).max(0.0);
+        let precision = 2f64.powi(self.response.precision as i32) + 2f64.powi(LOCAL_PRECISION as i32);
+
+        Sample { offset, delay, dispersion: precision + selection::PHI * delay, time }
+    }
 }
 
 fn main() {
@@ -186,7 +92,7 @@ fn main() {
         )
         .arg(Arg::with_name("action")
             .takes_value(true)
-            .possible_values(&["get", "set", "check-ntp"])
+            .possible_values(&["get", "set", "check-ntp", "serve", "daemon", "stats"])
             .default_value("get")
         )
         .arg(Arg::with_name("std")
@@ -198,145 +104,1172 @@ fn main() {
         )
         .arg(Arg::with_name("datetime").help(
             "When <action> is 'set', apply <datetime>. Otherwise, ignore"
-        ));
+        ))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("With 'check-ntp' or 'daemon', query <server>[:<port>] instead of the default list")
+        )
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .default_value("1000")
+            .help("With 'check-ntp' or 'daemon', milliseconds to wait for each response")
+        )
+        .arg(Arg::with_name("retries")
+            .long("retries")
+            .takes_value(true)
+            .default_value("1")
+            .help("With 'check-ntp' or 'daemon', how often to resend a request that timed out")
+        )
+        .arg(Arg::with_name("keys")
+            .long("keys")
+            .takes_value(true)
+            .help("A key file of '<id> <AES128CMAC|SHA1> <key>' lines. 'serve' answers signed requests")
+        )
+        .arg(Arg::with_name("key-id")
+            .long("key-id")
+            .takes_value(true)
+            .requires("keys")
+            .help("With 'check-ntp' or 'daemon', sign requests with this key and reject unsigned responses")
+        )
+        .arg(Arg::with_name("nts")
+            .long("nts")
+            .conflicts_with("key-id")
+            .help("With 'check-ntp' or 'daemon', authenticate with NTS: <server> is an NTS-KE server, port 4460 by default")
+        )
+        .arg(Arg::with_name("nts-ca")
+            .long("nts-ca")
+            .takes_value(true)
+            .requires("nts")
+            .help("Trust the certificates in this PEM file for NTS-KE, instead of the system's")
+        )
+        .arg(Arg::with_name("nts-cert")
+            .long("nts-cert")
+            .takes_value(true)
+            .requires("nts-key")
+            .help("With 'serve', also serve NTS-KE with this PEM certificate chain")
+        )
+        .arg(Arg::with_name("nts-key")
+            .long("nts-key")
+            .takes_value(true)
+            .requires("nts-cert")
+            .help("The PEM private key for --nts-cert")
+        )
+        .arg(Arg::with_name("nts-port")
+            .long("nts-port")
+            .takes_value(true)
+            .default_value("4460")
+            .help("With 'serve' and --nts-cert, the TCP port to serve NTS-KE on")
+        )
+        .arg(Arg::with_name("history")
+            .long("history")
+            .takes_value(true)
+            .help("With 'check-ntp' or 'daemon', append each peer's offset, delay, jitter and stratum to this file. 'stats' summarizes it")
+        )
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .takes_value(true)
+            .help("With 'daemon', serve Prometheus metrics at http://<address>/metrics, such as 127.0.0.1:9123")
+        )
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("With 'daemon', log the adjustments instead of making them")
+        )
+        .arg(Arg::with_name("port")
+            .long("port")
+            .takes_value(true)
+            .default_value("123")
+            .help("With 'serve', the UDP port to listen on")
+        )
+        .arg(Arg::with_name("stratum")
+            .long("stratum")
+            .takes_value(true)
+            .default_value("10")
+            .help("With 'serve', the stratum to report")
+        )
+        .arg(Arg::with_name("reference-id")
+            .long("reference-id")
+            .takes_value(true)
+            .default_value("LOCL")
+            .help("With 'serve', the reference ID to report: up to 4 ASCII characters")
+        )
+        .arg(Arg::with_name("rate-limit")
+            .long("rate-limit")
+            .takes_value(true)
+            .default_value("2")
+            .help("With 'serve', the minimum seconds between requests from one client")
+        );
 
     let args = app.get_matches();
 
     let action = args.value_of("action").unwrap();
     let std = args.value_of("std").unwrap();
 
+    let mut clock = SystemClock;
+
     if action == "set" {
         let t_ = args.value_of("datetime").unwrap();
 
-        let parser = match std {
-            "rfc2822" => DateTime::parse_from_rfc2822,
-            "rfc3339" => DateTime::parse_from_rfc3339,
-            _ => unimplemented!()
+        if let Err(err) = set(&mut clock, t_, std) {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let servers: Vec<&str> = match args.values_of("server") {
+        Some(servers) => servers.collect(),
+        None => DEFAULT_SERVERS.to_vec(),
+    };
+    let keys = match args.value_of("keys").map(Keys::load) {
+        Some(Ok(keys)) => keys,
+        Some(Err(err)) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+        None => Keys::default(),
+    };
+    let key = args.value_of("key-id").map(|id| {
+        let id: u32 = id.parse().expect("--key-id must be a whole number");
+        keys.get(id).cloned().unwrap_or_else(|| {
+            eprintln!("Key {} isn't in the key file", id);
+            std::process::exit(1);
+        })
+    });
+    let nts = if args.is_present("nts") {
+        match nts::load_roots(args.value_of("nts-ca").map(Path::new)).and_then(nts::client_config) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                eprintln!("Unable to set up NTS: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let options = QueryOptions {
+        timeout: Duration::from_millis(
+            args.value_of("timeout").unwrap().parse().expect("--timeout must be whole milliseconds")
+        ),
+        retries: args.value_of("retries").unwrap().parse().expect("--retries must be a whole number"),
+        key,
+        nts,
+    };
+
+    if action == "stats" {
+        let path = args.value_of("history").unwrap_or_else(|| {
+            eprintln!("'stats' needs the --history file to summarize");
+            std::process::exit(1);
+        });
+        match History::load(path) {
+            Ok(records) => println!("{}", Summary::new(&records)),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut history = args.value_of("history").map(|path| {
+        History::open(path, history::DEFAULT_CAPACITY).unwrap_or_else(|err| {
+            eprintln!("Unable to open the history {}: {}", path, err);
+            std::process::exit(1);
+        })
+    });
+
+    if action == "check-ntp" {
+        let offset = match check_os_vendor_time(&servers, &options, history.as_mut()) {
+            Ok(offset) => offset,
+            Err(err) => {
+                eprintln!("Unable to check the time: {}", err);
+                std::process::exit(1);
+            }
         };
 
-        let err_msg = format!(
-            "Unable to parse {} according to {}",
-            t_, std
-        );
+        if let Err(err) = nudge(&mut clock, offset) {
+            eprintln!("Unable to set the time: {}", err);
+            std::process::exit(1);
+        }
+    } else if action == "daemon" {
+        let mut adjuster: Box<dyn ClockAdjust> = if args.is_present("dry-run") {
+            Box::new(DryRun::default())
+        } else {
+            Box::new(Adjtimex)
+        };
+        let mut daemon = Daemon::new(&servers, options);
+        daemon.history = history;
+        if let Some(addr) = args.value_of("metrics") {
+            let metrics = Arc::new(Mutex::new(Metrics::default()));
+            let server = match MetricsServer::bind(addr, metrics.clone()) {
+                Ok(server) => server,
+                Err(err) => {
+                    eprintln!("Unable to serve metrics on {}: {}", addr, err);
+                    std::process::exit(1);
+                }
+            };
+            println!("Serving metrics on http://{}/metrics", server.local_addr().unwrap());
+            thread::spawn(move || server.run());
+            daemon.metrics = Some(metrics);
+        }
+        if let Err(err) = daemon.run(adjuster.as_mut()) {
+            eprintln!("Unable to discipline the clock: {}", err);
+            std::process::exit(1);
+        }
+    } else if action == "serve" {
+        let port: u16 = args.value_of("port").unwrap().parse().expect("--port must be a port number");
+        let mut config = ServerConfig {
+            stratum: args.value_of("stratum").unwrap().parse().expect("--stratum must be 0..=255"),
+            reference_id: ServerConfig::parse_reference_id(args.value_of("reference-id").unwrap())
+                .expect("--reference-id must be 1 to 4 printable ASCII characters"),
+            rate_limit: Duration::from_secs(
+                args.value_of("rate-limit").unwrap().parse().expect("--rate-limit must be whole seconds")
+            ),
+            keys,
+            ..ServerConfig::default()
+        };
+        let identity = match (args.value_of("nts-cert"), args.value_of("nts-key")) {
+            (Some(cert), Some(key)) => match nts::load_identity(Path::new(cert), Path::new(key)) {
+                Ok(identity) => Some(identity),
+                Err(err) => {
+                    eprintln!("Unable to load the NTS-KE certificate: {}", err);
+                    std::process::exit(1);
+                }
+            },
+            _ => None,
+        };
+        if let Some((chain, key)) = identity {
+            let ke_port: u16 = args.value_of("nts-port").unwrap().parse().expect("--nts-port must be a port number");
+            let master = MasterKey::generate();
+            config.nts = Some(master.clone());
+            let ke = match KeServer::bind(("0.0.0.0", ke_port), chain, key, master, port) {
+                Ok(ke) => ke,
+                Err(err) => {
+                    eprintln!("Unable to serve NTS-KE on port {}: {}", ke_port, err);
+                    std::process::exit(1);
+                }
+            };
+            println!("Serving NTS-KE on {}", ke.local_addr().unwrap());
+            thread::spawn(move || {
+                if let Err(err) = ke.run() {
+                    eprintln!("NTS-KE server stopped: {}", err);
+                    std::process::exit(1);
+                }
+            });
+        }
 
-        let t = parser(t_).expect(&err_msg);
+        let mut server = match Server::bind(("0.0.0.0", port), config) {
+            Ok(server) => server,
+            Err(err) => {
+                eprintln!("Unable to listen on port {}: {}", port, err);
+                std::process::exit(1);
+            }
+        };
+        println!("Serving NTP on {}", server.local_addr().unwrap());
+        if let Err(err) = server.run() {
+            eprintln!("Server stopped: {}", err);
+            std::process::exit(1);
+        }
+    }
 
-        Clock::set(t);
-    } else if action == "check-ntp" {
-        let offset = check_os_vendor_time().unwrap() as isize;
+    let now = clock.local();
 
-        let adjust_ms = offset.signum() * offset.abs().min(200) / 5;
-        let adjust_ms = ChronoDuration::milliseconds(adjust_ms as i64);
+    match std {
+        "rfc2822" => println!("{}", now.to_rfc2822()),
+        "rfc3339" => println!("{}", now.to_rfc3339()),
+        "timestamp" => println!("{}", now.timestamp()),
+        _ => unreachable!()
+    }
+}
 
-        let now: DateTime<Utc> = Utc::now() + adjust_ms;
+/**
+ * How patient to be with each server.
+ */
+#[derive(Debug, Clone)]
+struct QueryOptions {
+    timeout: Duration,
+    /// How many more requests to send after one times out.
+    retries: u32,
+    /// Sign requests with this key, and only accept responses signed with it.
+    key: Option<Key>,
+    /// Authenticate with NTS, trusting NTS-KE servers as this configuration does.
+    nts: Option<Arc<rustls::ClientConfig>>,
+}
 
-        Clock::set(now);
+impl Default for QueryOptions {
+    fn default() -> Self {
+        QueryOptions { timeout: Duration::from_secs(1), retries: 1, key: None, nts: None }
     }
+}
 
-    let maybe_error = std::io::Error::last_os_error();
-    let os_error_code = &maybe_error.raw_os_error();
+/**
+ * One address of a server, and what we've learned about it. A name with several A and
+ * AAAA records becomes several associations, which the selection algorithms can then
+ * check against each other.
+ */
+struct Association {
+    address: SocketAddr,
+    peer: Peer,
+    backoff: Backoff,
+    nts: Option<NtsSession>,
+}
 
-    match os_error_code {
-        Some(0) => (),
-        Some(_code) => eprintln!("Unable to set the time: {:?}", maybe_error),
-        None => ()
+/**
+ * Splits `server` into a host and a port, which defaults to `default_port`. IPv6
+ * addresses with a port need brackets, as in `[::1]:123`.
+ */
+fn split_host_port(server: &str, default_port: u16) -> (&str, u16) {
+    if let Some((host, rest)) = server.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        let port = rest.strip_prefix(':').and_then(|port| port.parse().ok());
+        return (host, port.unwrap_or(default_port));
     }
 
-    let now = Clock::get();
+    match server.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, port),
+            Err(_) => (server, default_port),
+        },
+        _ => (server, default_port),
+    }
+}
 
-    match std {
-        "rfc2822" => println!("{}", now.to_rfc2822()),
-        "rfc3339" => println!("{}", now.to_rfc3339()),
-        "timestamp" => println!("{}", now.timestamp()),
-        _ => unreachable!()
+/**
+ * How to tell the peer at `address` apart from others of `server`, which is `host`
+ * with maybe a port.
+ */
+fn peer_name(server: &str, host: &str, address: SocketAddr) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(_) => server.to_string(),
+        Err(_) => format!("{} ({})", host, address.ip()),
     }
 }
 
-fn check_os_vendor_time() -> Result<f64, std::io::Error> {
-    const NTP_PORT: u16 = 123;
+/**
+ * Looks up every address of every server. Servers that can't be resolved are reported
+ * and left out.
+ */
+fn associate(servers: &[&str], options: &QueryOptions) -> Vec<Association> {
+    if let Some(config) = &options.nts {
+        return associate_nts(servers, config);
+    }
 
-    let servers = [
-        "time.nist.gov",
-        "time.apple.com",
-        "time.euro.apple.com",
-        "time.google.com",
-        "time2.google.com",
-        // "time.windows.come"
-    ];
+    let mut associations = Vec::new();
+    for &server in servers {
+        let (host, port) = split_host_port(server, 123);
+        let addresses = match (host, port).to_socket_addrs() {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                println!("{} => ", server);
+                println!(" ? [{}]", err);
+                continue;
+            }
+        };
 
-    let mut times = Vec::with_capacity(servers.len());
+        let mut seen = Vec::new();
+        for address in addresses {
+            if seen.contains(&address) {
+                continue;
+            }
+            seen.push(address);
 
-    for &server in servers.iter() {
-        println!("{} => ", server);
+            let peer = Peer::new(peer_name(server, host, address));
+            associations.push(Association { address, peer, backoff: Backoff::default(), nts: None });
+        }
+    }
+    associations
+}
 
-        let calc = ntp_round_trip(&server, NTP_PORT);
+/**
+ * Runs NTS-KE with every server, and associates with the NTP server each one names.
+ * Cookies can't be shared, so that's one association per server rather than one per
+ * address. Servers we can't agree keys with are reported and left out.
+ */
+fn associate_nts(servers: &[&str], config: &Arc<rustls::ClientConfig>) -> Vec<Association> {
+    let mut associations = Vec::new();
+    for &server in servers {
+        let (host, port) = split_host_port(server, nts::KE_PORT);
+        match NtsSession::establish(config, host, port, nts::KE_TIMEOUT) {
+            Ok(session) => {
+                let peer = Peer::new(peer_name(server, host, session.address));
+                let address = session.address;
+                associations.push(Association { address, peer, backoff: Backoff::default(), nts: Some(session) });
+            }
+            Err(err) => {
+                println!("{} => ", server);
+                println!(" ? [{}]", err);
+            }
+        }
+    }
+    associations
+}
+
+/**
+ * Queries every association that isn't backing off, all at once, and adds what each
+ * says to its clock filter. Samples are timed in seconds since `start`. Returns a
+ * history record for each association that answered.
+ */
+fn poll_peers(associations: &mut [Association], options: &QueryOptions, start: Instant) -> Vec<Record> {
+    let results: Vec<_> = thread::scope(|scope| {
+        let queries: Vec<_> = associations
+            .iter_mut()
+            .map(|association| {
+                scope.spawn(move || {
+                    let calc = ntp_round_trip(
+                        association.address,
+                        &mut association.backoff,
+                        association.nts.as_mut(),
+                        options,
+                    );
+                    (calc, start.elapsed().as_secs_f64())
+                })
+            })
+            .collect();
+        queries.into_iter().map(|query| query.join().expect("query thread panicked")).collect()
+    });
+
+    let mut records = Vec::new();
+    for (association, (calc, time)) in associations.iter_mut().zip(results) {
+        let peer = &mut association.peer;
+        println!("{} => ", peer.name);
 
         match calc {
-            Ok(time) => {
-                println!(" {}ms away from local system time", time.offset());
-                times.push(time);
+            Ok(result) => {
+                println!(" {}ms away from local system time", result.offset());
+                peer.stratum = result.response.stratum;
+                peer.root_delay = result.response.root_delay.to_seconds();
+                peer.root_dispersion = result.response.root_dispersion.to_seconds();
+                let sample = result.sample(time);
+                peer.filter.push(sample);
+                records.push(Record {
+                    time: result.t4.timestamp() as f64 + result.t4.timestamp_subsec_nanos() as f64 / 1e9,
+                    peer: peer.name.clone(),
+                    offset: sample.offset,
+                    delay: sample.delay,
+                    jitter: peer.filter.estimate(time).map_or(0.0, |estimate| estimate.jitter),
+                    stratum: peer.stratum,
+                });
             }
-            Err(_) => {
-                println!(" ? [response took too long]")
+            Err(err) => {
+                println!(" ? [{}]", err)
             }
         }
     }
+    records
+}
+
+/**
+ * Appends `records` to `history`. Failing to is worth a warning, but not worth giving
+ * up on keeping time.
+ */
+fn record_history(history: Option<&mut History>, records: &[Record]) {
+    if let Some(Err(err)) = history.map(|history| history.append(records)) {
+        eprintln!("Unable to record the history: {}", err);
+    }
+}
+
+fn select(associations: &[Association], start: Instant) -> SelectionReport {
+    let peers: Vec<Peer> = associations.iter().map(|association| association.peer.clone()).collect();
+    selection::select(&peers, start.elapsed().as_secs_f64())
+}
+
+/**
+ * Queries each of `servers` and returns the offset, in milliseconds, that the clock
+ * selection algorithms settle on. What each server said is added to `history`.
+ */
+fn check_os_vendor_time(
+    servers: &[&str],
+    options: &QueryOptions,
+    history: Option<&mut History>,
+) -> Result<f64, std::io::Error> {
+    let start = Instant::now();
+    let mut associations = associate(servers, options);
+
+    let records = poll_peers(&mut associations, options, start);
+    record_history(history, &records);
+
+    let report = select(&associations, start);
+    println!("{}", report);
+
+    match report.offset {
+        Some(offset) => Ok(offset * 1000.0),
+        None => Err(io::Error::other("no majority of servers agree on the time")),
+    }
+}
+
+/**
+ * Sets `clock` to `datetime`, which is written according to `std`.
+ */
+fn set(clock: &mut dyn Clock, datetime: &str, std: &str) -> Result<(), String> {
+    let t = match std {
+        "timestamp" => datetime.parse().ok().and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        "rfc2822" => DateTime::parse_from_rfc2822(datetime).ok().map(|t| t.with_timezone(&Utc)),
+        "rfc3339" => DateTime::parse_from_rfc3339(datetime).ok().map(|t| t.with_timezone(&Utc)),
+        _ => unreachable!()
+    };
+
+    let t = t.ok_or_else(|| format!(
+        "Unable to parse {} according to {}",
+        datetime, std
+    ))?;
+
+    clock.set(t).map_err(|err| format!("Unable to set the time: {}", err))
+}
+
+/**
+ * Steps `clock` a fifth of the way towards `offset_ms`, by at most 40ms at a time, and
+ * returns the step.
+ */
+fn nudge(clock: &mut dyn Clock, offset_ms: f64) -> Result<ChronoDuration, ClockError> {
+    let offset = offset_ms as isize;
+    let adjust_ms = offset.signum() * offset.abs().min(200) / 5;
+    let adjust_ms = ChronoDuration::milliseconds(adjust_ms as i64);
+
+    clock.set(clock.now() + adjust_ms)?;
+    Ok(adjust_ms)
+}
+
+/**
+ * The state of `clock daemon`: the peers, and the loop that disciplines the clock.
+ */
+struct Daemon {
+    start: Instant,
+    associations: Vec<Association>,
+    options: QueryOptions,
+    discipline: Discipline,
+    /// Where to record what each peer says.
+    history: Option<History>,
+    /// Updated after every round, for the metrics server.
+    metrics: Option<Arc<Mutex<Metrics>>>,
+}
 
-    let mut offsets = Vec::with_capacity(servers.len());
-    let mut pffset_weights = Vec::with_capacity(servers.len());
+impl Daemon {
+    fn new(servers: &[&str], options: QueryOptions) -> Self {
+        Daemon {
+            start: Instant::now(),
+            associations: associate(servers, &options),
+            options,
+            discipline: Discipline::default(),
+            history: None,
+            metrics: None,
+        }
+    }
 
-    for time in &times {
-        let offset = time.offset() as f64;
-        let delay = time.delay() as f64;
+    /**
+     * Polls the peers once and slews the clock through `adjuster`. Returns the
+     * adjustment, or `None` when the peers didn't agree on the time.
+     */
+    fn round(&mut self, adjuster: &mut dyn ClockAdjust) -> Result<Option<Adjustment>, std::io::Error> {
+        let records = poll_peers(&mut self.associations, &self.options, self.start);
+        record_history(self.history.as_mut(), &records);
+
+        let now = self.start.elapsed().as_secs_f64();
+        let report = select(&self.associations, self.start);
+        println!("{}", report);
+
+        let (offset, jitter) = match (report.offset, report.jitter) {
+            (Some(offset), Some(jitter)) => (offset, jitter),
+            _ => {
+                self.publish(&report);
+                return Ok(None);
+            }
+        };
 
-        let weight = 1_000_000.0 / (delay * delay);
-        if weight.is_finite() {
-            offsets.push(offset);
-            pffset_weights.push(weight);
+        // A single sample from a single server has no jitter to speak of.
+        let jitter = jitter.max(2f64.powi(LOCAL_PRECISION as i32));
+        let adjustment = self.discipline.update(offset, jitter, now);
+        self.publish(&report);
+        match adjustment {
+            Adjustment::Slew { phase, frequency } => adjuster.adjust(phase, frequency)?,
+            Adjustment::Panic(offset) => {
+                let message = format!("an offset of {:.3}s is too large to slew: set the clock first", offset);
+                return Err(io::Error::other(message));
+            }
         }
+        Ok(Some(adjustment))
     }
 
-    let avg_offset = weighted_mean(&offsets, &pffset_weights);
+    /**
+     * Updates the metrics, if anyone is watching, with the outcome of a round.
+     */
+    fn publish(&self, report: &SelectionReport) {
+        let metrics = match &self.metrics {
+            Some(metrics) => metrics,
+            None => return,
+        };
+        // The report lists the peers in the order of the associations.
+        let peers = report
+            .peers
+            .iter()
+            .zip(&self.associations)
+            .filter_map(|(peer, association)| {
+                let estimate = peer.estimate.as_ref()?;
+                Some(PeerMetrics {
+                    name: peer.name.clone(),
+                    stratum: association.peer.stratum,
+                    offset: estimate.offset,
+                    delay: estimate.delay,
+                    jitter: estimate.jitter,
+                })
+            })
+            .collect();
+
+        let mut metrics = metrics.lock().expect("metrics lock poisoned");
+        *metrics = Metrics {
+            peers,
+            offset: report.offset,
+            jitter: report.jitter,
+            frequency: self.discipline.frequency,
+            poll_interval: self.discipline.interval(),
+            rounds: metrics.rounds + 1,
+        };
+    }
 
-    Ok(avg_offset)
+    /**
+     * Disciplines the clock until it can't be adjusted.
+     */
+    fn run(&mut self, adjuster: &mut dyn ClockAdjust) -> Result<(), std::io::Error> {
+        loop {
+            self.round(adjuster)?;
+            let interval = self.discipline.interval();
+            println!(
+                "frequency correction {:+.3} ppm, next poll in {}s",
+                self.discipline.frequency * 1e6, interval
+            );
+            thread::sleep(Duration::from_secs_f64(interval));
+        }
+    }
 }
 
-fn ntp_round_trip(host: &str, port: u16) -> Result<NTPResult, std::io::Error> {
-    let destination = format!("{}:{}", host, port);
-    let timeout = Duration::from_secs(1);
+/**
+ * Queries `server`, unless `backoff` says it has asked us to wait or stop. A request
+ * that gets no answer within the timeout is sent again, up to `options.retries` times.
+ * With an `nts` session, requests and responses are authenticated with NTS.
+ */
+fn ntp_round_trip(
+    server: SocketAddr,
+    backoff: &mut Backoff,
+    mut nts: Option<&mut NtsSession>,
+    options: &QueryOptions,
+) -> Result<NTPResult, std::io::Error> {
+    match backoff.permission(Instant::now()) {
+        Permission::Allowed => (),
+        Permission::Wait(wait) => {
+            let message = format!("rate limited, next query in {}s", wait.as_secs());
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, message));
+        }
+        Permission::Denied(code) => {
+            let message = format!("server sent kiss-o'-death {}", code);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+    }
+
+    // An ephemeral port, so that concurrent queries and runs don't collide, of the
+    // same family as the server.
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let udp = UdpSocket::bind(local)?;
+    udp.connect(server)?;
+
+    let mut attempt = 0;
+    loop {
+        match ntp_exchange(&udp, backoff, nts.as_deref_mut(), options) {
+            Err(err) if is_timeout(&err) && attempt < options.retries => attempt += 1,
+            result => return result,
+        }
+    }
+}
 
-    let request = NTPMessage::client();
-    let mut response = NTPMessage::new();
+fn is_timeout(err: &io::Error) -> bool {
+    // A read timeout is reported as WouldBlock on Unix, and TimedOut on Windows.
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
 
-    let message = request.data;
+/**
+ * Sends one request over the connected socket `udp` and waits for its response.
+ * Responses that don't echo our nonce, or aren't signed with our key or NTS session,
+ * are dropped while we wait for the real one.
+ */
+fn ntp_exchange(
+    udp: &UdpSocket,
+    backoff: &mut Backoff,
+    mut nts: Option<&mut NtsSession>,
+    options: &QueryOptions,
+) -> Result<NTPResult, std::io::Error> {
+    let mut request = validate::request_with_nonce();
+    if let Some(key) = &options.key {
+        key.sign(&mut request);
+    }
+    let unique_id = match nts.as_deref_mut() {
+        Some(session) => Some(session.protect(&mut request).map_err(io::Error::other)?),
+        None => None,
+    };
+    let mut buffer = [0; 1024];
 
-    let udp = UdpSocket::bind(LOCAL_ADDR)?;
-    udp.connect(&destination).expect("Failed to connect");
+    let message = request.to_bytes();
 
     let t1 = Utc::now();
+    let deadline = Instant::now() + options.timeout;
 
     udp.send(&message)?;
-    udp.set_read_timeout(Some(timeout))?;
-    udp.recv_from(&mut response.data)?;
 
-    let t4 = Utc::now();
+    let mut rejected = None;
+    let mut nak = false;
+    let (response, t4) = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            if let (true, Some(session)) = (nak, nts.as_deref_mut()) {
+                // RFC 8915 section 5.7: only now does the NAK count. A retry starts
+                // over with NTS-KE.
+                session.forget_cookies();
+            }
+            let message = match rejected {
+                Some(err) => format!("no authenticated response, last rejected: {}", err),
+                None => "no matching response".to_string(),
+            };
+            return Err(io::Error::new(io::ErrorKind::TimedOut, message));
+        }
+        udp.set_read_timeout(Some(remaining))?;
+        let length = match udp.recv(&mut buffer) {
+            Ok(length) => length,
+            // Back to the deadline check, which says why nothing was accepted.
+            Err(err) if is_timeout(&err) => continue,
+            Err(err) => return Err(err),
+        };
+        let t4 = Utc::now();
 
-    let t2: DateTime<Utc> = response.rx_time().unwrap().into();
-    let t3: DateTime<Utc> = response.tx_time().unwrap().into();
+        let response = match NtpPacket::parse(&buffer[..length]) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+        // Before anything in the response is believed, even a kiss-o'-death.
+        if let Some(key) = &options.key {
+            if let Err(err) = key.verify(&response, &buffer[..length]) {
+                rejected = Some(err.to_string());
+                continue;
+            }
+        }
+        if let (Some(session), Some(unique_id)) = (nts.as_deref_mut(), &unique_id) {
+            match session.verify(unique_id, &response, &buffer[..length]) {
+                Ok(()) => (),
+                Err(nts::NtsError::UniqueIdMismatch) => continue,
+                // Anyone who saw the request could have sent it, so keep waiting for
+                // an authenticated response.
+                Err(err @ nts::NtsError::Nak) => {
+                    nak = true;
+                    rejected = Some(err.to_string());
+                    continue;
+                }
+                Err(err) => {
+                    rejected = Some(err.to_string());
+                    continue;
+                }
+            }
+        }
+        match validate::validate(&request, &response) {
+            Err(ResponseError::OriginMismatch) => continue,
+            result => {
+                backoff.record(&response, &result, Instant::now());
+                result.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                break (response, t4);
+            }
+        }
+    };
+
+    let t2: DateTime<Utc> = response.receive_timestamp.into();
+    let t3: DateTime<Utc> = response.transmit_timestamp.into();
 
     Ok(NTPResult {
-        t1, t2, t3, t4
+        t1, t2, t3, t4, response
     })
 }
 
-fn weighted_mean(values: &[f64], weights: &[f64]) -> f64 {
-    let mut result = 0.0;
-    let mut sum_of_weights = 0.0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clock::{MockClock, OffsetClock};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    fn spawn_with(config: ServerConfig) -> SocketAddr {
+        let mut server = Server::bind("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
 
-    for (v, w) in values.iter().zip(weights) {
-        result += v * w;
-        sum_of_weights += w;
+    fn spawn(offset: ChronoDuration) -> String {
+        spawn_with(ServerConfig { offset, rate_limit: Duration::ZERO, ..ServerConfig::default() }).to_string()
     }
 
-    result / sum_of_weights
+    /// A server that ignores the first `drop` requests it gets.
+    fn spawn_unreliable(drop: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            for received in 0.. {
+                let (length, client) = socket.recv_from(&mut buffer).unwrap();
+                if received < drop {
+                    continue;
+                }
+                let request = NtpPacket::parse(&buffer[..length]).unwrap();
+                let now = Utc::now().into();
+                let mut response = server::respond(&ServerConfig::default(), &request, now, false);
+                response.transmit_timestamp = now;
+                socket.send_to(&response.to_bytes(), client).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Answers every NTS request with an unauthenticated NTSN kiss-o'-death, then passes
+    /// it on to `server`, if any, and relays its response.
+    fn spawn_nak_spoofer(server: Option<SocketAddr>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut buffer = [0; 2048];
+            loop {
+                let (length, client) = socket.recv_from(&mut buffer).unwrap();
+                let request = NtpPacket::parse(&buffer[..length]).unwrap();
+                let mut nak = server::respond(&ServerConfig::default(), &request, Utc::now().into(), false);
+                nak.stratum = 0;
+                nak.reference_id = *b"NTSN";
+                nak.extensions = request
+                    .extensions
+                    .iter()
+                    .filter(|field| field.field_type == nts::UNIQUE_IDENTIFIER)
+                    .cloned()
+                    .collect();
+                socket.send_to(&nak.to_bytes(), client).unwrap();
+
+                if let Some(server) = server {
+                    upstream.send_to(&buffer[..length], server).unwrap();
+                    let length = upstream.recv(&mut buffer).unwrap();
+                    socket.send_to(&buffer[..length], client).unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    /// A server that holds on to its request until `expected` requests have arrived
+    /// across every server sharing `arrived`, and then answers it. If they don't all
+    /// arrive within a few seconds, it never answers.
+    fn spawn_rendezvous(arrived: Arc<AtomicUsize>, expected: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            let (length, client) = socket.recv_from(&mut buffer).unwrap();
+            arrived.fetch_add(1, Ordering::SeqCst);
+
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while arrived.load(Ordering::SeqCst) < expected {
+                if Instant::now() > deadline {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            let request = NtpPacket::parse(&buffer[..length]).unwrap();
+            let now = Utc::now().into();
+            let mut response = server::respond(&ServerConfig::default(), &request, now, false);
+            response.transmit_timestamp = now;
+            socket.send_to(&response.to_bytes(), client).unwrap();
+        });
+        addr
+    }
+
+    fn quick(retries: u32) -> QueryOptions {
+        QueryOptions { timeout: Duration::from_millis(200), retries, ..QueryOptions::default() }
+    }
+
+    #[test]
+    fn split_host_port() {
+        assert_eq!(super::split_host_port("time.nist.gov", 123), ("time.nist.gov", 123));
+        assert_eq!(super::split_host_port("127.0.0.1:12345", 123), ("127.0.0.1", 12345));
+        assert_eq!(super::split_host_port("localhost:ntp", 123), ("localhost:ntp", 123));
+        assert_eq!(super::split_host_port("2001:db8::1", 123), ("2001:db8::1", 123));
+        assert_eq!(super::split_host_port("[2001:db8::1]", 123), ("2001:db8::1", 123));
+        assert_eq!(super::split_host_port("[2001:db8::1]:12345", 123), ("2001:db8::1", 12345));
+        assert_eq!(super::split_host_port("time.cloudflare.com", nts::KE_PORT), ("time.cloudflare.com", 4460));
+    }
+
+    #[test]
+    fn associate_every_address() {
+        let associations = associate(&["127.0.0.1:12345", "[::1]:123", "localhost", "no such host.invalid"], &QueryOptions::default());
+        assert!(associations.len() >= 3);
+        assert_eq!(associations[0].address, "127.0.0.1:12345".parse().unwrap());
+        assert_eq!(associations[0].peer.name, "127.0.0.1:12345");
+        assert_eq!(associations[1].address, "[::1]:123".parse().unwrap());
+        assert!(associations[2..].iter().all(|a| a.address.ip().is_loopback() && a.address.port() == 123));
+        assert!(associations[2].peer.name.starts_with("localhost ("));
+    }
+
+    #[test]
+    fn retries_after_a_timeout() {
+        let server = spawn_unreliable(1);
+        assert!(ntp_round_trip(server, &mut Backoff::default(), None, &quick(1)).is_ok());
+
+        let server = spawn_unreliable(1);
+        let err = ntp_round_trip(server, &mut Backoff::default(), None, &quick(0)).err().unwrap();
+        assert!(is_timeout(&err), "{:?}", err);
+    }
+
+    #[test]
+    fn queries_run_in_parallel() {
+        // Each server only answers once every server has a request waiting, which can't
+        // happen if the queries are made one after another.
+        let arrived = Arc::new(AtomicUsize::new(0));
+        let stubs: Vec<String> = (0..4).map(|_| spawn_rendezvous(arrived.clone(), 4).to_string()).collect();
+        let servers: Vec<&str> = stubs.iter().map(String::as_str).collect();
+
+        let options = QueryOptions { timeout: Duration::from_secs(10), ..quick(0) };
+        let offset = check_os_vendor_time(&servers, &options, None).unwrap();
+        assert!(offset.abs() < 50.0, "offset {}ms", offset);
+        assert_eq!(arrived.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn ipv6() {
+        let config = ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() };
+        let mut server = match Server::bind("[::1]:0", config) {
+            Ok(server) => server,
+            // No IPv6 loopback here.
+            Err(_) => return,
+        };
+        let addr = server.local_addr().unwrap().to_string();
+        thread::spawn(move || server.run());
+
+        let offset = check_os_vendor_time(&[&addr], &quick(1), None).unwrap();
+        assert!(offset.abs() < 50.0, "offset {}ms", offset);
+    }
+
+    #[test]
+    fn check_ntp_against_local_servers() {
+        let ahead = spawn(ChronoDuration::milliseconds(500));
+        let also_ahead = spawn(ChronoDuration::milliseconds(500));
+        let unreachable = "127.0.0.1:9".to_string();
+
+        let offset = check_os_vendor_time(&[&ahead, &unreachable, &also_ahead], &quick(0), None).unwrap();
+        assert!((offset - 500.0).abs() < 50.0, "offset {}ms", offset);
+    }
+
+    #[test]
+    fn check_ntp_ignores_a_falseticker() {
+        let ahead = spawn(ChronoDuration::milliseconds(500));
+        let liar = spawn(ChronoDuration::seconds(30));
+        let also_ahead = spawn(ChronoDuration::milliseconds(500));
+
+        let offset = check_os_vendor_time(&[&ahead, &liar, &also_ahead], &quick(1), None).unwrap();
+        assert!((offset - 500.0).abs() < 50.0, "offset {}ms", offset);
+
+        let disagreeing = check_os_vendor_time(&[&ahead, &liar], &quick(1), None);
+        assert!(disagreeing.is_err());
+    }
+
+    #[test]
+    fn sets_the_clock() {
+        let start = Utc::now();
+        let mut clock = MockClock::new(start);
+        set(&mut clock, "2024-05-17T09:30:00+02:00", "rfc3339").unwrap();
+        set(&mut clock, "Fri, 17 May 2024 08:30:00 +0100", "rfc2822").unwrap();
+        set(&mut clock, "1715931000", "timestamp").unwrap();
+        let expected = DateTime::from_timestamp(1715931000, 0).unwrap();
+        assert_eq!(clock.sets(), &[expected, expected, expected]);
+
+        let err = set(&mut clock, "last Tuesday", "rfc3339").unwrap_err();
+        assert_eq!(err, "Unable to parse last Tuesday according to rfc3339");
+
+        let mut clock = MockClock::read_only(start);
+        let err = set(&mut clock, "2024-05-17T09:30:00Z", "rfc3339").unwrap_err();
+        assert!(err.starts_with("Unable to set the time: permission denied"), "{}", err);
+    }
+
+    #[test]
+    fn check_ntp_nudges_the_clock() {
+        let start = Utc::now();
+        let mut clock = MockClock::new(start);
+
+        assert_eq!(nudge(&mut clock, 100.0).unwrap(), ChronoDuration::milliseconds(20));
+        assert_eq!(nudge(&mut clock, -3000.0).unwrap(), ChronoDuration::milliseconds(-40));
+        assert_eq!(nudge(&mut clock, 3.0).unwrap(), ChronoDuration::zero());
+        assert_eq!(clock.sets(), &[
+            start + ChronoDuration::milliseconds(20),
+            start - ChronoDuration::milliseconds(20),
+            start - ChronoDuration::milliseconds(20),
+        ]);
+
+        let mut clock = MockClock::read_only(start);
+        assert!(matches!(nudge(&mut clock, 100.0), Err(ClockError::PermissionDenied)));
+        assert_eq!(clock.now(), start);
+    }
+
+    #[test]
+    fn check_ntp_against_a_local_server_moves_the_clock() {
+        let ahead = spawn(ChronoDuration::milliseconds(500));
+        let offset = check_os_vendor_time(&[&ahead], &quick(0), None).unwrap();
+
+        // The system clock stays put: only the clock on top of it moves.
+        let mut clock = OffsetClock::new(SystemClock, ChronoDuration::zero());
+        nudge(&mut clock, offset).unwrap();
+        let error = clock.offset() - ChronoDuration::milliseconds(40);
+        assert!(error.num_milliseconds().abs() < 5, "offset {}", clock.offset());
+    }
+
+    struct Recorder(Vec<(f64, f64)>);
+
+    impl ClockAdjust for Recorder {
+        fn adjust(&mut self, phase: f64, frequency: f64) -> Result<(), std::io::Error> {
+            self.0.push((phase, frequency));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn daemon_slews_toward_the_servers() {
+        let servers = [spawn(ChronoDuration::milliseconds(20)), spawn(ChronoDuration::milliseconds(20))];
+        let servers: Vec<&str> = servers.iter().map(String::as_str).collect();
+        let mut daemon = Daemon::new(&servers, quick(1));
+        let mut recorder = Recorder(Vec::new());
+
+        for _ in 0..3 {
+            let adjustment = daemon.round(&mut recorder).unwrap();
+            assert!(matches!(adjustment, Some(Adjustment::Slew { .. })));
+        }
+        assert_eq!(recorder.0.len(), 3);
+        for &(phase, _) in &recorder.0 {
+            assert!((phase - 0.020).abs() < 0.005, "phase {}", phase);
+        }
+        // The filter has been collecting samples all along.
+        assert!(daemon.associations.iter().all(|a| a.peer.filter.len() == 3));
+    }
+
+    #[test]
+    fn daemon_records_history_and_metrics() {
+        let path = std::env::temp_dir().join(format!("clock-daemon-history-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (ahead, unreachable) = (spawn(ChronoDuration::milliseconds(20)), "127.0.0.1:9".to_string());
+        let mut daemon = Daemon::new(&[&ahead, &unreachable], quick(0));
+        daemon.history = Some(History::open(&path, 100).unwrap());
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
+        daemon.metrics = Some(metrics.clone());
+
+        for _ in 0..2 {
+            daemon.round(&mut Recorder(Vec::new())).unwrap();
+        }
+
+        // Only answers are recorded.
+        let records = History::load(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(records.iter().all(|record| record.peer == ahead && record.stratum == 10));
+        assert!((records[1].offset - 0.020).abs() < 0.005, "offset {}", records[1].offset);
+        assert!((records[1].time - Utc::now().timestamp() as f64).abs() < 5.0);
+        std::fs::remove_file(&path).unwrap();
+
+        let metrics = metrics.lock().unwrap().clone();
+        assert_eq!(metrics.rounds, 2);
+        assert_eq!(metrics.peers.len(), 1);
+        assert_eq!(metrics.peers[0].name, ahead);
+        assert!((metrics.offset.unwrap() - 0.020).abs() < 0.005);
+        assert_eq!(metrics.poll_interval, daemon.discipline.interval());
+    }
+
+    #[test]
+    fn daemon_refuses_to_slew_huge_offsets() {
+        let server = spawn(ChronoDuration::hours(1));
+        let mut daemon = Daemon::new(&[&server], quick(1));
+        let mut recorder = Recorder(Vec::new());
+
+        assert!(daemon.round(&mut recorder).is_err());
+        assert!(recorder.0.is_empty());
+    }
+
+    #[test]
+    fn daemon_waits_for_a_majority() {
+        let (near, far) = (spawn(ChronoDuration::zero()), spawn(ChronoDuration::seconds(10)));
+        let mut daemon = Daemon::new(&[&near, &far], quick(1));
+        let mut recorder = Recorder(Vec::new());
+
+        assert_eq!(daemon.round(&mut recorder).unwrap(), None);
+        assert!(recorder.0.is_empty());
+    }
+
+    #[test]
+    fn authenticated_queries() {
+        let keys = Keys::parse("1 AES128CMAC 2b7e151628aed2a6abf7158809cf4f3c\n2 SHA1 legacy\n").unwrap();
+        let keyed = spawn_with(ServerConfig { keys: keys.clone(), rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let unkeyed = spawn_with(ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let with_key = |key: &Key| QueryOptions { key: Some(key.clone()), ..quick(0) };
+        let query = |server, options: &QueryOptions| ntp_round_trip(server, &mut Backoff::default(), None, options);
+
+        for id in [1, 2] {
+            let result = query(keyed, &with_key(keys.get(id).unwrap())).unwrap();
+            assert_eq!(result.response.mac.unwrap().key_id, id);
+        }
+
+        // Clients without a key still get the time, unsigned.
+        assert!(query(keyed, &quick(0)).unwrap().response.mac.is_none());
+
+        let impostor = Keys::parse("1 AES128CMAC 000102030405060708090a0b0c0d0e0f").unwrap();
+        let err = query(keyed, &with_key(impostor.get(1).unwrap())).err().unwrap();
+        assert!(err.to_string().contains("couldn't authenticate the request"), "{}", err);
+
+        // A server without keys can't check ours either.
+        let err = query(unkeyed, &with_key(keys.get(1).unwrap())).err().unwrap();
+        assert!(err.to_string().contains("couldn't authenticate the request"), "{}", err);
+
+        // One that ignores MACs altogether gets its unsigned responses rejected.
+        let err = query(spawn_unreliable(0), &with_key(keys.get(1).unwrap())).err().unwrap();
+        assert!(err.to_string().contains("isn't authenticated"), "{}", err);
+    }
+
+    #[test]
+    fn nts_queries() {
+        let offset = ChronoDuration::milliseconds(500);
+        let (ke, client) = nts::tests::stand_in(ServerConfig { offset, rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let server = format!("localhost:{}", ke.port());
+        let options = QueryOptions { nts: Some(client), ..quick(0) };
+
+        let offset = check_os_vendor_time(&[&server], &options, None).unwrap();
+        assert!((offset - 500.0).abs() < 50.0, "offset {}ms", offset);
+
+        // More rounds than there are cookies: each verified response brings a fresh one.
+        let mut daemon = Daemon::new(&[&server], options.clone());
+        for _ in 0..nts::COOKIES + 2 {
+            poll_peers(&mut daemon.associations, &daemon.options, daemon.start);
+            assert_eq!(daemon.associations[0].nts.as_ref().unwrap().cookies(), nts::COOKIES);
+        }
+
+        // A plain NTP server doesn't echo the unique identifier, so nothing it says is believed.
+        let mut session = daemon.associations.pop().unwrap().nts.unwrap();
+        let plain = spawn_unreliable(0);
+        let err = ntp_round_trip(plain, &mut Backoff::default(), Some(&mut session), &options).err().unwrap();
+        assert!(is_timeout(&err), "{:?}", err);
+
+        // NAKs aren't authenticated: one from anyone else doesn't stop the real response.
+        let mut session = NtsSession::establish(&options.nts.clone().unwrap(), "localhost", ke.port(), nts::KE_TIMEOUT).unwrap();
+        let spoofer = spawn_nak_spoofer(Some(session.address));
+        ntp_round_trip(spoofer, &mut Backoff::default(), Some(&mut session), &options).unwrap();
+        assert_eq!(session.cookies(), nts::COOKIES);
+
+        // Only when nothing else comes back do the cookies go, and a retry gets new ones.
+        let spoofer = spawn_nak_spoofer(None);
+        let err = ntp_round_trip(spoofer, &mut Backoff::default(), Some(&mut session), &options).err().unwrap();
+        assert!(is_timeout(&err) && err.to_string().contains("NTS NAK"), "{:?}", err);
+        assert_eq!(session.cookies(), 0);
+        ntp_round_trip(session.address, &mut Backoff::default(), Some(&mut session), &options).unwrap();
+        assert_eq!(session.cookies(), nts::COOKIES);
+
+        // Neither is a server that can't prove who it is.
+        let (_, stranger) = nts::tests::stand_in(ServerConfig::default());
+        let options = QueryOptions { nts: Some(stranger), ..quick(0) };
+        assert!(associate(&[&server], &options).is_empty());
+    }
 }
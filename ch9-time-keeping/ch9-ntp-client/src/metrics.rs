@@ -0,0 +1,211 @@
+/*!
+The daemon's view of its peers and of the clock, in the Prometheus text format, served
+over just enough HTTP for a scraper: `GET /metrics`.
+
+It answers one connection at a time, and is meant to listen on localhost for a local
+Prometheus or node exporter to collect.
+*/
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Scrapers that take longer than this to send their request are hung up on.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Longer request heads are refused.
+const MAX_REQUEST: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerMetrics {
+    pub name: String,
+    pub stratum: u8,
+    pub offset: f64,
+    pub delay: f64,
+    pub jitter: f64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Peers with at least one sample.
+    pub peers: Vec<PeerMetrics>,
+    /// What the peers agreed on, if they did.
+    pub offset: Option<f64>,
+    pub jitter: Option<f64>,
+    /// The frequency correction, in seconds per second.
+    pub frequency: f64,
+    /// The poll interval, in seconds.
+    pub poll_interval: f64,
+    /// How many times the peers have been polled.
+    pub rounds: u64,
+}
+
+/// A metric with a value for each peer: its name, help text and value.
+type PeerGauge = (&'static str, &'static str, fn(&PeerMetrics) -> f64);
+
+/// Escapes a label value: backslashes, double quotes and newlines.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let header = |f: &mut fmt::Formatter, name: &str, kind: &str, help: &str| {
+            writeln!(f, "# HELP {} {}", name, help)?;
+            writeln!(f, "# TYPE {} {}", name, kind)
+        };
+
+        let per_peer: [PeerGauge; 4] = [
+            ("ntp_peer_offset_seconds", "Filtered offset of the peer's clock from ours.", |peer| peer.offset),
+            ("ntp_peer_delay_seconds", "Round-trip delay to the peer.", |peer| peer.delay),
+            ("ntp_peer_jitter_seconds", "Jitter of the peer's offsets.", |peer| peer.jitter),
+            ("ntp_peer_stratum", "Stratum the peer reports.", |peer| peer.stratum as f64),
+        ];
+        for (name, help, value) in per_peer {
+            header(f, name, "gauge", help)?;
+            for peer in &self.peers {
+                writeln!(f, "{}{{peer=\"{}\"}} {}", name, label(&peer.name), value(peer))?;
+            }
+        }
+
+        let system = [
+            ("ntp_offset_seconds", "Offset the peers agree on.", self.offset),
+            ("ntp_jitter_seconds", "Jitter of the peers that agree.", self.jitter),
+            ("ntp_frequency_correction_ppm", "Frequency correction applied to the clock.", Some(self.frequency * 1e6)),
+            ("ntp_poll_interval_seconds", "Interval between polls.", Some(self.poll_interval)),
+        ];
+        for (name, help, value) in system {
+            if let Some(value) = value {
+                header(f, name, "gauge", help)?;
+                writeln!(f, "{} {}", name, value)?;
+            }
+        }
+
+        header(f, "ntp_rounds_total", "counter", "Times the peers have been polled.")?;
+        writeln!(f, "ntp_rounds_total {}", self.rounds)
+    }
+}
+
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl MetricsServer {
+    /// Serves whatever `metrics` holds at the time of each request.
+    pub fn bind(addr: impl ToSocketAddrs, metrics: Arc<Mutex<Metrics>>) -> io::Result<MetricsServer> {
+        Ok(MetricsServer { listener: TcpListener::bind(addr)?, metrics })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answers requests until the listener fails. A misbehaving client only costs
+    /// itself its response.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let _ = self.serve(stream);
+        }
+    }
+
+    fn serve(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+            let length = stream.read(&mut buffer)?;
+            if length == 0 || request.len() > MAX_REQUEST {
+                return Ok(());
+            }
+            request.extend_from_slice(&buffer[..length]);
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let mut words = request.split_whitespace();
+        let (status, body) = match (words.next(), words.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.metrics.lock().expect("metrics lock poisoned").to_string()),
+            (Some("GET"), _) => ("404 Not Found", "try /metrics\n".to_string()),
+            _ => ("405 Method Not Allowed", String::new()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn metrics() -> Metrics {
+        Metrics {
+            peers: vec![
+                PeerMetrics {
+                    name: "time.google.com (216.239.35.0)".to_string(),
+                    stratum: 1,
+                    offset: 0.0015,
+                    delay: 0.02,
+                    jitter: 0.0001,
+                },
+                PeerMetrics { name: "odd \"name\"".to_string(), stratum: 3, offset: -0.25, delay: 0.1, jitter: 0.002 },
+            ],
+            offset: Some(0.0015),
+            jitter: None,
+            frequency: 12.5e-6,
+            poll_interval: 64.0,
+            rounds: 7,
+        }
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn text_format() {
+        let text = metrics().to_string();
+        assert!(text.contains("# TYPE ntp_peer_offset_seconds gauge\n"));
+        assert!(text.contains("ntp_peer_offset_seconds{peer=\"time.google.com (216.239.35.0)\"} 0.0015\n"));
+        assert!(text.contains("ntp_peer_stratum{peer=\"odd \\\"name\\\"\"} 3\n"));
+        assert!(text.contains("ntp_offset_seconds 0.0015\n"));
+        assert!(text.contains("ntp_frequency_correction_ppm 12.5"));
+        assert!(text.contains("ntp_poll_interval_seconds 64\n"));
+        assert!(text.ends_with("# TYPE ntp_rounds_total counter\nntp_rounds_total 7\n"));
+        // Nothing to say about the jitter of an agreement that wasn't reached.
+        assert!(!text.contains("ntp_jitter_seconds"));
+    }
+
+    #[test]
+    fn serves_over_http() {
+        let shared = Arc::new(Mutex::new(Metrics::default()));
+        let server = MetricsServer::bind("127.0.0.1:0", shared.clone()).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let response = get(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains("ntp_rounds_total 0\n"));
+
+        *shared.lock().unwrap() = metrics();
+        let response = get(addr, "/metrics");
+        assert!(response.contains("ntp_rounds_total 7\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert!(response.contains(&format!("Content-Length: {}\r\n", body.len())));
+
+        assert!(get(addr, "/").starts_with("HTTP/1.1 404"));
+    }
+}
@@ -0,0 +1,931 @@
+/*!
+Network Time Security (RFC 8915): authenticated NTP without sharing keys in advance.
+
+NTS works in two steps:
+
+1. NTS Key Establishment (NTS-KE). The client opens a TLS 1.3 connection to port 4460
+   of the server and the two agree on NTPv4 and AES-SIV-CMAC-256 (see [`crate::siv`]).
+   Both sides then export a pair of keys from the TLS session: one for requests and
+   one for responses. The server also hands out eight cookies, which are those keys
+   encrypted under a master key that only the server knows.
+2. NTP. Each request carries a unique identifier, one cookie, and an authenticator:
+   a seal over the packet made with the request key. The server opens the cookie to
+   recover the keys, so it keeps no state per client, checks the authenticator, and
+   seals its response with the response key. Fresh cookies travel inside that seal.
+
+A cookie is used only once, so that requests can't be linked by an observer. The client
+adds a placeholder for each cookie it's missing, and the server sends back one cookie
+per cookie or placeholder, which keeps the pool topped up. If the pool runs dry anyway,
+or the server can no longer open our cookies, the client goes back to step 1. A server
+that can't open a cookie says so with an NTSN kiss-o'-death, but that isn't authenticated,
+so anyone could send one: it only counts once the request has gone unanswered otherwise.
+
+NTP extension fields are laid out as RFC 7822 describes; see [`crate::packet`]. NTS-KE
+messages are sequences of records:
+
+```text
+ 0                   1                   2                   3
+ 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|C|         Record Type         |          Body Length          |
+|                         Record Body (variable)                |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+
+A record with the critical bit `C` set must be understood, or the exchange fails.
+*/
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{error, fmt, io, thread};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, RootCertStore, ServerConnection, StreamOwned};
+
+use crate::packet::{ExtensionField, NtpPacket, HEADER_LENGTH, MAX_EXTENSION_VALUE};
+use crate::siv::{self, Siv};
+
+/// The TCP port NTS-KE servers listen on.
+pub const KE_PORT: u16 = 4460;
+/// The TLS application protocol (ALPN) name of NTS-KE.
+pub const ALPN: &[u8] = b"ntske/1";
+/// How many cookies a client keeps, and a server hands out at once.
+pub const COOKIES: usize = 8;
+
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-network-time-security";
+const NTP_V4: u16 = 0;
+const AEAD_AES_SIV_CMAC_256: u16 = 15;
+const NONCE_LENGTH: usize = 16;
+const UNIQUE_ID_LENGTH: usize = 32;
+/// Gives up on an NTS-KE server, or client, that takes longer than this to answer.
+pub const KE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Far more than any sensible NTS-KE message holds.
+const MAX_RECORDS: usize = 64;
+
+// NTS-KE record types.
+const END_OF_MESSAGE: u16 = 0;
+const NEXT_PROTOCOL: u16 = 1;
+const ERROR: u16 = 2;
+const WARNING: u16 = 3;
+const AEAD_ALGORITHM: u16 = 4;
+const NEW_COOKIE: u16 = 5;
+const SERVER: u16 = 6;
+const PORT: u16 = 7;
+const CRITICAL: u16 = 0x8000;
+
+// NTS-KE error codes.
+const UNRECOGNIZED_CRITICAL_RECORD: u16 = 0;
+const BAD_REQUEST: u16 = 1;
+const INTERNAL_SERVER_ERROR: u16 = 2;
+
+// NTP extension field types.
+pub const UNIQUE_IDENTIFIER: u16 = 0x0104;
+pub const COOKIE: u16 = 0x0204;
+pub const COOKIE_PLACEHOLDER: u16 = 0x0304;
+pub const AUTHENTICATOR: u16 = 0x0404;
+
+/// Places to look for the system's trusted CA certificates, as one PEM file.
+const SYSTEM_ROOTS: [&str; 4] = [
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/ca-bundle.pem",
+    "/etc/ssl/cert.pem",
+];
+
+#[derive(Debug)]
+pub enum NtsError {
+    Io(io::Error),
+    Tls(rustls::Error),
+    /// Certificates or keys that couldn't be loaded.
+    Certificate(String),
+    /// The NTS-KE server sent an Error record with this code.
+    Refused(u16),
+    /// An NTS-KE message we can't make sense of.
+    Protocol(&'static str),
+    /// The response doesn't echo our unique identifier: it's not for this request.
+    UniqueIdMismatch,
+    /// An NTSN kiss-o'-death: the server couldn't open our cookie, or someone says so.
+    Nak,
+    /// The packet has no authenticator.
+    Unauthenticated,
+    BadAuthenticator,
+}
+
+impl fmt::Display for NtsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NtsError::Io(err) => write!(f, "{}", err),
+            NtsError::Tls(err) => write!(f, "TLS: {}", err),
+            NtsError::Certificate(reason) => write!(f, "{}", reason),
+            NtsError::Refused(code) => {
+                let reason = match *code {
+                    UNRECOGNIZED_CRITICAL_RECORD => "unrecognized critical record",
+                    BAD_REQUEST => "bad request",
+                    INTERNAL_SERVER_ERROR => "internal server error",
+                    _ => "unknown error",
+                };
+                write!(f, "NTS-KE server refused: {} ({})", reason, code)
+            }
+            NtsError::Protocol(reason) => write!(f, "NTS-KE: {}", reason),
+            NtsError::UniqueIdMismatch => write!(f, "response doesn't echo the unique identifier"),
+            NtsError::Nak => write!(f, "server couldn't authenticate the request (NTS NAK)"),
+            NtsError::Unauthenticated => write!(f, "packet has no NTS authenticator"),
+            NtsError::BadAuthenticator => write!(f, "NTS authenticator doesn't match"),
+        }
+    }
+}
+
+impl error::Error for NtsError {}
+
+impl From<io::Error> for NtsError {
+    fn from(err: io::Error) -> Self {
+        NtsError::Io(err)
+    }
+}
+
+impl From<rustls::Error> for NtsError {
+    fn from(err: rustls::Error) -> Self {
+        NtsError::Tls(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    critical: bool,
+    record_type: u16,
+    body: Vec<u8>,
+}
+
+impl Record {
+    fn new(record_type: u16, body: Vec<u8>) -> Record {
+        let critical = matches!(record_type, END_OF_MESSAGE | NEXT_PROTOCOL | ERROR);
+        Record { critical, record_type, body }
+    }
+
+    fn u16s(record_type: u16, values: &[u16]) -> Record {
+        Record::new(record_type, values.iter().flat_map(|value| value.to_be_bytes()).collect())
+    }
+
+    fn body_u16s(&self) -> Vec<u16> {
+        self.body.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect()
+    }
+}
+
+/// Writes `records` as one message, which should end with End of Message.
+fn write_records(writer: &mut impl Write, records: &[Record]) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    for record in records {
+        let critical = if record.critical { CRITICAL } else { 0 };
+        bytes.write_u16::<BigEndian>(critical | record.record_type)?;
+        bytes.write_u16::<BigEndian>(record.body.len() as u16)?;
+        bytes.write_all(&record.body)?;
+    }
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Reads records up to and including End of Message.
+fn read_records(reader: &mut impl Read) -> Result<Vec<Record>, NtsError> {
+    let mut records = Vec::new();
+    while records.len() < MAX_RECORDS {
+        let header = reader.read_u16::<BigEndian>()?;
+        let length = reader.read_u16::<BigEndian>()? as usize;
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+
+        let record = Record { critical: header & CRITICAL != 0, record_type: header & !CRITICAL, body };
+        let end = record.record_type == END_OF_MESSAGE;
+        records.push(record);
+        if end {
+            return Ok(records);
+        }
+    }
+    Err(NtsError::Protocol("too many records"))
+}
+
+/// The two keys exported from an NTS-KE session.
+#[derive(Clone, PartialEq, Eq)]
+struct SessionKeys {
+    c2s: [u8; siv::KEY_LENGTH],
+    s2c: [u8; siv::KEY_LENGTH],
+}
+
+impl SessionKeys {
+    fn export<Data>(connection: &ConnectionCommon<Data>) -> Result<SessionKeys, NtsError> {
+        // RFC 8915 section 5.1: the protocol and algorithm IDs, then 0 for the
+        // client-to-server key or 1 for server-to-client.
+        let mut context = [0; 5];
+        context[..2].copy_from_slice(&NTP_V4.to_be_bytes());
+        context[2..4].copy_from_slice(&AEAD_AES_SIV_CMAC_256.to_be_bytes());
+        let c2s = connection.export_keying_material([0; siv::KEY_LENGTH], EXPORTER_LABEL, Some(&context))?;
+        context[4] = 1;
+        let s2c = connection.export_keying_material([0; siv::KEY_LENGTH], EXPORTER_LABEL, Some(&context))?;
+        Ok(SessionKeys { c2s, s2c })
+    }
+}
+
+/// Seals `plaintext` and the packet so far into an Authenticator field, and appends it.
+fn append_authenticator(packet: &mut NtpPacket, key: &Siv, plaintext: &[u8]) {
+    let nonce: [u8; NONCE_LENGTH] = rand::random();
+    let ciphertext = key.seal(&[&packet.to_bytes_without_mac(), &nonce], plaintext);
+
+    let mut value = Vec::new();
+    value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    value.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+    value.extend_from_slice(&nonce);
+    value.extend_from_slice(&ciphertext);
+    value.resize(value.len().div_ceil(4) * 4, 0);
+    packet.extensions.push(ExtensionField { field_type: AUTHENTICATOR, value });
+}
+
+/// Where the authenticator is among the extension fields of `packet`.
+fn authenticator_index(packet: &NtpPacket) -> Option<usize> {
+    packet.extensions.iter().position(|field| field.field_type == AUTHENTICATOR)
+}
+
+/// The extension fields the authenticator covers. Any after it could have been added
+/// by anyone.
+fn authenticated_fields(packet: &NtpPacket) -> &[ExtensionField] {
+    &packet.extensions[..authenticator_index(packet).unwrap_or(packet.extensions.len())]
+}
+
+fn find_field(fields: &[ExtensionField], field_type: u16) -> Option<&[u8]> {
+    fields.iter().find(|field| field.field_type == field_type).map(|field| field.value.as_slice())
+}
+
+/// Checks the authenticator of `packet`, which was parsed from `datagram`, and returns
+/// the plaintext sealed in it.
+fn open_authenticator(packet: &NtpPacket, datagram: &[u8], key: &Siv) -> Result<Vec<u8>, NtsError> {
+    let index = authenticator_index(packet).ok_or(NtsError::Unauthenticated)?;
+    let value = &packet.extensions[index].value;
+    if value.len() < 4 {
+        return Err(NtsError::BadAuthenticator);
+    }
+    let nonce_length = u16::from_be_bytes([value[0], value[1]]) as usize;
+    let ciphertext_length = u16::from_be_bytes([value[2], value[3]]) as usize;
+    let ciphertext_start = 4 + nonce_length.div_ceil(4) * 4;
+    // RFC 8915 section 5.6: shorter nonces aren't allowed, whatever the algorithm.
+    if nonce_length < NONCE_LENGTH || value.len() < ciphertext_start + ciphertext_length {
+        return Err(NtsError::BadAuthenticator);
+    }
+    let nonce = &value[4..4 + nonce_length];
+    let ciphertext = &value[ciphertext_start..ciphertext_start + ciphertext_length];
+
+    // The associated data is exactly the bytes received before the authenticator.
+    // Parsed values keep their padding, so each field took 4 bytes more than its value.
+    let offset = HEADER_LENGTH + packet.extensions[..index].iter().map(|field| 4 + field.value.len()).sum::<usize>();
+    key.open(&[&datagram[..offset], nonce], ciphertext).ok_or(NtsError::BadAuthenticator)
+}
+
+/// Splits a run of extension fields, as sealed in an authenticator.
+fn parse_fields(mut bytes: &[u8]) -> Option<Vec<ExtensionField>> {
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return None;
+        }
+        let field_type = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let length = u16::from_be_bytes([bytes[2], bytes[3]]) as usize;
+        if length < 4 || length > bytes.len() {
+            return None;
+        }
+        fields.push(ExtensionField { field_type, value: bytes[4..length].to_vec() });
+        bytes = &bytes[length..];
+    }
+    Some(fields)
+}
+
+/// The trust anchors for NTS-KE servers: the certificates in the PEM file at `path`,
+/// or the system's when there's no path.
+pub fn load_roots(path: Option<&Path>) -> Result<RootCertStore, NtsError> {
+    let path = match path {
+        Some(path) => path,
+        None => SYSTEM_ROOTS
+            .iter()
+            .map(Path::new)
+            .find(|path| path.exists())
+            .ok_or_else(|| NtsError::Certificate("no system CA certificates found".to_string()))?,
+    };
+    let invalid = |err| NtsError::Certificate(format!("{}: {}", path.display(), err));
+
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter(path).map_err(invalid)? {
+        roots.add(certificate.map_err(invalid)?)?;
+    }
+    Ok(roots)
+}
+
+/// The client side of NTS-KE: TLS 1.3 only, trusting `roots`.
+pub fn client_config(roots: RootCertStore) -> Result<Arc<ClientConfig>, NtsError> {
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// What NTS-KE got us.
+struct Negotiated {
+    keys: SessionKeys,
+    cookies: Vec<Vec<u8>>,
+    /// The address we reached the NTS-KE server on.
+    peer: IpAddr,
+    server: Option<String>,
+    port: Option<u16>,
+}
+
+fn key_exchange(config: &Arc<ClientConfig>, host: &str, port: u16, timeout: Duration) -> Result<Negotiated, NtsError> {
+    let name = ServerName::try_from(host.to_string()).map_err(|_| NtsError::Protocol("invalid server name"))?;
+    let connection = ClientConnection::new(config.clone(), name)?;
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    let mut tcp = None;
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => {
+                tcp = Some(stream);
+                break;
+            }
+            Err(err) => last_err = err,
+        }
+    }
+    let tcp = tcp.ok_or(last_err)?;
+    let peer = tcp.peer_addr()?.ip();
+    tcp.set_read_timeout(Some(timeout))?;
+    tcp.set_write_timeout(Some(timeout))?;
+
+    let mut tls = StreamOwned::new(connection, tcp);
+    write_records(&mut tls, &[
+        Record::u16s(NEXT_PROTOCOL, &[NTP_V4]),
+        Record::u16s(AEAD_ALGORITHM, &[AEAD_AES_SIV_CMAC_256]),
+        Record::new(END_OF_MESSAGE, Vec::new()),
+    ])?;
+    let records = read_records(&mut tls)?;
+    if tls.conn.alpn_protocol() != Some(ALPN) {
+        return Err(NtsError::Protocol("server didn't agree to ntske/1"));
+    }
+
+    let mut negotiated = Negotiated {
+        keys: SessionKeys::export(&tls.conn)?,
+        cookies: Vec::new(),
+        peer,
+        server: None,
+        port: None,
+    };
+    let (mut protocol, mut algorithm) = (false, false);
+    for record in &records {
+        match record.record_type {
+            ERROR => return Err(NtsError::Refused(record.body_u16s().first().copied().unwrap_or(u16::MAX))),
+            NEXT_PROTOCOL => protocol = record.body_u16s() == [NTP_V4],
+            AEAD_ALGORITHM => algorithm = record.body_u16s() == [AEAD_AES_SIV_CMAC_256],
+            NEW_COOKIE if record.body.len() > MAX_EXTENSION_VALUE => return Err(NtsError::Protocol("cookie too long")),
+            NEW_COOKIE => negotiated.cookies.push(record.body.clone()),
+            SERVER => {
+                let server = String::from_utf8(record.body.clone());
+                negotiated.server = Some(server.map_err(|_| NtsError::Protocol("invalid server name"))?);
+            }
+            PORT => negotiated.port = record.body_u16s().first().copied(),
+            END_OF_MESSAGE | WARNING => (),
+            _ if record.critical => return Err(NtsError::Protocol("unrecognized critical record")),
+            _ => (),
+        }
+    }
+    if !protocol || !algorithm {
+        return Err(NtsError::Protocol("server didn't agree to NTPv4 with AES-SIV-CMAC-256"));
+    }
+    if negotiated.cookies.is_empty() {
+        return Err(NtsError::Protocol("server sent no cookies"));
+    }
+
+    tls.conn.send_close_notify();
+    // We have what we came for: a server that hangs up without replying is fine.
+    let _ = tls.flush();
+    Ok(negotiated)
+}
+
+/// A client's NTS state for one server: its keys and cookies, and how to get more.
+pub struct NtsSession {
+    /// Where to send NTP requests.
+    pub address: SocketAddr,
+    keys: SessionKeys,
+    cookies: Vec<Vec<u8>>,
+    ke_host: String,
+    ke_port: u16,
+    config: Arc<ClientConfig>,
+    timeout: Duration,
+}
+
+impl NtsSession {
+    /// Runs NTS-KE with `host` on `port`. NTP requests then go to the address that
+    /// answered, on port 123, unless the server names another host or port.
+    pub fn establish(
+        config: &Arc<ClientConfig>,
+        host: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<NtsSession, NtsError> {
+        let negotiated = key_exchange(config, host, port, timeout)?;
+        let ntp_port = negotiated.port.unwrap_or(123);
+        let address = match &negotiated.server {
+            Some(server) => (server.as_str(), ntp_port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or(NtsError::Protocol("the NTP server has no address"))?,
+            None => SocketAddr::new(negotiated.peer, ntp_port),
+        };
+        Ok(NtsSession {
+            address,
+            keys: negotiated.keys,
+            cookies: negotiated.cookies,
+            ke_host: host.to_string(),
+            ke_port: port,
+            config: config.clone(),
+            timeout,
+        })
+    }
+
+    /// Runs NTS-KE again, for new keys and cookies.
+    pub fn rekey(&mut self) -> Result<(), NtsError> {
+        let negotiated = key_exchange(&self.config, &self.ke_host, self.ke_port, self.timeout)?;
+        self.keys = negotiated.keys;
+        self.cookies = negotiated.cookies;
+        Ok(())
+    }
+
+    /// How many unused cookies we have.
+    pub fn cookies(&self) -> usize {
+        self.cookies.len()
+    }
+
+    /// Drops the cookies, so that the next request starts with NTS-KE. For when a request
+    /// got an NTSN kiss-o'-death and no authenticated response.
+    pub fn forget_cookies(&mut self) {
+        self.cookies.clear();
+    }
+
+    /// Adds the NTS fields to `request`, which must be otherwise complete, and returns
+    /// the unique identifier the response has to echo. Runs NTS-KE again first if
+    /// we're out of cookies.
+    pub fn protect(&mut self, request: &mut NtpPacket) -> Result<Vec<u8>, NtsError> {
+        if self.cookies.is_empty() {
+            self.rekey()?;
+        }
+        let cookie = self.cookies.pop().expect("NTS-KE always gets cookies");
+        let unique_id: [u8; UNIQUE_ID_LENGTH] = rand::random();
+        let placeholders = COOKIES.saturating_sub(self.cookies.len() + 1);
+
+        request.extensions.push(ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.to_vec() });
+        let placeholder = ExtensionField { field_type: COOKIE_PLACEHOLDER, value: vec![0; cookie.len()] };
+        request.extensions.push(ExtensionField { field_type: COOKIE, value: cookie });
+        request.extensions.extend(std::iter::repeat_n(placeholder, placeholders));
+        append_authenticator(request, &Siv::new(&self.keys.c2s), &[]);
+        Ok(unique_id.to_vec())
+    }
+
+    /// Checks that `response`, parsed from `datagram`, answers the request that was sent
+    /// with `unique_id`, and keeps the cookies it brought. An NTSN kiss-o'-death is
+    /// reported as [`NtsError::Nak`] and changes nothing: it's up to the caller to wait
+    /// for an authenticated response, and to [forget the cookies](Self::forget_cookies)
+    /// if none comes.
+    pub fn verify(&mut self, unique_id: &[u8], response: &NtpPacket, datagram: &[u8]) -> Result<(), NtsError> {
+        if find_field(authenticated_fields(response), UNIQUE_IDENTIFIER) != Some(unique_id) {
+            return Err(NtsError::UniqueIdMismatch);
+        }
+        if response.stratum == 0 && response.reference_id == *b"NTSN" {
+            return Err(NtsError::Nak);
+        }
+
+        let plaintext = open_authenticator(response, datagram, &Siv::new(&self.keys.s2c))?;
+        let fields = parse_fields(&plaintext).ok_or(NtsError::BadAuthenticator)?;
+        for field in fields {
+            if field.field_type == COOKIE && self.cookies.len() < COOKIES {
+                self.cookies.push(field.value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The secret an NTS server seals its cookies with. The NTS-KE server and the NTP server
+/// need the same one. It isn't rotated: a restart makes every cookie out there invalid,
+/// and clients go back to NTS-KE.
+#[derive(Clone)]
+pub struct MasterKey(Siv);
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    pub fn generate() -> MasterKey {
+        MasterKey(Siv::new(&rand::random()))
+    }
+
+    /// A cookie is a nonce followed by the sealed keys.
+    fn seal_cookie(&self, keys: &SessionKeys) -> Vec<u8> {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let mut cookie = nonce.to_vec();
+        cookie.extend(self.0.seal(&[&nonce], &[keys.c2s, keys.s2c].concat()));
+        cookie
+    }
+
+    fn open_cookie(&self, cookie: &[u8]) -> Option<SessionKeys> {
+        if cookie.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, sealed) = cookie.split_at(NONCE_LENGTH);
+        let keys = self.0.open(&[nonce], sealed)?;
+        if keys.len() != 2 * siv::KEY_LENGTH {
+            return None;
+        }
+        let (c2s, s2c) = keys.split_at(siv::KEY_LENGTH);
+        Some(SessionKeys { c2s: c2s.try_into().unwrap(), s2c: s2c.try_into().unwrap() })
+    }
+}
+
+/// Adds NTS to `response` if `request`, parsed from `datagram`, used it: the unique
+/// identifier, fresh cookies and an authenticator. A request whose cookie or
+/// authenticator doesn't check out gets an NTSN kiss-o'-death instead. `response`
+/// must be otherwise complete.
+pub fn protect_response(master: &MasterKey, request: &NtpPacket, datagram: &[u8], response: &mut NtpPacket) {
+    let fields = authenticated_fields(request);
+    let cookie = match find_field(fields, COOKIE) {
+        Some(cookie) => cookie,
+        None => return,
+    };
+    if let Some(unique_id) = find_field(fields, UNIQUE_IDENTIFIER) {
+        response.extensions.push(ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.to_vec() });
+    }
+
+    let keys = master
+        .open_cookie(cookie)
+        .filter(|keys| open_authenticator(request, datagram, &Siv::new(&keys.c2s)).is_ok());
+    let keys = match keys {
+        Some(keys) => keys,
+        None => {
+            response.stratum = 0;
+            response.reference_id = *b"NTSN";
+            return;
+        }
+    };
+
+    // One cookie for the one used, and one for each placeholder of the same size.
+    let placeholders = fields
+        .iter()
+        .filter(|field| field.field_type == COOKIE_PLACEHOLDER && field.value.len() == cookie.len())
+        .count();
+    let mut plaintext = Vec::new();
+    for _ in 0..(1 + placeholders).min(COOKIES) {
+        ExtensionField { field_type: COOKIE, value: master.seal_cookie(&keys) }.encode(&mut plaintext);
+    }
+    append_authenticator(response, &Siv::new(&keys.s2c), &plaintext);
+}
+
+/// Reads a certificate chain and its private key from PEM files.
+pub fn load_identity(
+    certificates: &Path,
+    key: &Path,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), NtsError> {
+    let invalid = |path: &Path, err| NtsError::Certificate(format!("{}: {}", path.display(), err));
+    let chain = CertificateDer::pem_file_iter(certificates)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid(certificates, err))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|err| invalid(key, err))?;
+    Ok((chain, key))
+}
+
+/// An NTS-KE server, handing out keys and cookies for an NTP server that shares its
+/// master key.
+pub struct KeServer {
+    listener: TcpListener,
+    config: Arc<rustls::ServerConfig>,
+    master: MasterKey,
+    ntp_port: u16,
+}
+
+impl KeServer {
+    /// Listens on `addr`, proving who it is with `chain` and `key`, and sends clients to
+    /// `ntp_port`.
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+        master: MasterKey,
+        ntp_port: u16,
+    ) -> Result<KeServer, NtsError> {
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+        config.alpn_protocols = vec![ALPN.to_vec()];
+
+        let listener = TcpListener::bind(addr)?;
+        Ok(KeServer { listener, config: Arc::new(config), master, ntp_port })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves each client on a thread of its own, until the listener fails.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (tcp, client) = self.listener.accept()?;
+            let (config, master, ntp_port) = (self.config.clone(), self.master.clone(), self.ntp_port);
+            thread::spawn(move || {
+                if let Err(err) = serve_client(config, &master, ntp_port, tcp) {
+                    eprintln!("NTS-KE with {}: {}", client, err);
+                }
+            });
+        }
+    }
+}
+
+fn serve_client(
+    config: Arc<rustls::ServerConfig>,
+    master: &MasterKey,
+    ntp_port: u16,
+    tcp: TcpStream,
+) -> Result<(), NtsError> {
+    tcp.set_read_timeout(Some(KE_TIMEOUT))?;
+    tcp.set_write_timeout(Some(KE_TIMEOUT))?;
+    let mut tls = StreamOwned::new(ServerConnection::new(config)?, tcp);
+
+    let request = read_records(&mut tls)?;
+    let response = match check_request(&request) {
+        Err(code) => vec![Record::u16s(ERROR, &[code]), Record::new(END_OF_MESSAGE, Vec::new())],
+        Ok(()) if tls.conn.alpn_protocol() != Some(ALPN) => {
+            vec![Record::u16s(ERROR, &[BAD_REQUEST]), Record::new(END_OF_MESSAGE, Vec::new())]
+        }
+        Ok(()) => {
+            let keys = SessionKeys::export(&tls.conn)?;
+            let mut records = vec![
+                Record::u16s(NEXT_PROTOCOL, &[NTP_V4]),
+                Record::u16s(AEAD_ALGORITHM, &[AEAD_AES_SIV_CMAC_256]),
+                Record::u16s(PORT, &[ntp_port]),
+            ];
+            records.extend((0..COOKIES).map(|_| Record::new(NEW_COOKIE, master.seal_cookie(&keys))));
+            records.push(Record::new(END_OF_MESSAGE, Vec::new()));
+            records
+        }
+    };
+
+    write_records(&mut tls, &response)?;
+    tls.conn.send_close_notify();
+    tls.flush()?;
+    Ok(())
+}
+
+/// Checks that a client asked for what we offer, or returns the error code to send.
+fn check_request(records: &[Record]) -> Result<(), u16> {
+    let (mut protocol, mut algorithm) = (false, false);
+    for record in records {
+        match record.record_type {
+            NEXT_PROTOCOL => protocol = record.body_u16s().contains(&NTP_V4),
+            AEAD_ALGORITHM => algorithm = record.body_u16s().contains(&AEAD_AES_SIV_CMAC_256),
+            END_OF_MESSAGE | WARNING => (),
+            _ if record.critical => return Err(UNRECOGNIZED_CRITICAL_RECORD),
+            _ => (),
+        }
+    }
+    if protocol && algorithm { Ok(()) } else { Err(BAD_REQUEST) }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::server::{Server, ServerConfig};
+    use crate::validate;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use std::net::UdpSocket;
+
+    /// An NTS-KE server and the NTP server it hands out cookies for, both on localhost
+    /// with a fresh self-signed certificate. Returns the NTS-KE address and a client
+    /// configuration that trusts it.
+    pub fn stand_in(config: ServerConfig) -> (SocketAddr, Arc<ClientConfig>) {
+        let master = MasterKey::generate();
+        let mut server = Server::bind("127.0.0.1:0", ServerConfig { nts: Some(master.clone()), ..config }).unwrap();
+        let ntp_port = server.local_addr().unwrap().port();
+        thread::spawn(move || server.run());
+
+        let identity = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(identity.key_pair.serialize_der());
+        let chain = vec![identity.cert.der().clone()];
+        let ke = KeServer::bind("127.0.0.1:0", chain, key.into(), master, ntp_port).unwrap();
+        let addr = ke.local_addr().unwrap();
+        thread::spawn(move || ke.run());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(identity.cert.der().clone()).unwrap();
+        (addr, client_config(roots).unwrap())
+    }
+
+    fn establish(addr: SocketAddr, config: &Arc<ClientConfig>) -> Result<NtsSession, NtsError> {
+        NtsSession::establish(config, "localhost", addr.port(), Duration::from_secs(2))
+    }
+
+    /// Sends `request` to the NTP server of `session` and returns the raw response.
+    fn exchange(session: &NtsSession, request: &NtpPacket) -> Vec<u8> {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        socket.send_to(&request.to_bytes(), session.address).unwrap();
+        let mut buffer = [0; 2048];
+        let length = socket.recv(&mut buffer).unwrap();
+        buffer[..length].to_vec()
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let records = vec![
+            Record::u16s(NEXT_PROTOCOL, &[NTP_V4]),
+            Record::new(NEW_COOKIE, vec![1, 2, 3]),
+            Record::new(END_OF_MESSAGE, Vec::new()),
+        ];
+        let mut bytes = Vec::new();
+        write_records(&mut bytes, &records).unwrap();
+        assert_eq!(&bytes[..6], [0x80, 0x01, 0x00, 0x02, 0x00, 0x00]);
+        assert_eq!(&bytes[13..], [0x80, 0x00, 0x00, 0x00]);
+
+        // Whatever follows End of Message is left unread.
+        bytes.extend_from_slice(&[0xff; 8]);
+        let mut reader = io::Cursor::new(&bytes);
+        assert_eq!(read_records(&mut reader).unwrap(), records);
+        assert_eq!(reader.position(), 17);
+
+        assert!(matches!(read_records(&mut &bytes[..10]), Err(NtsError::Io(_))));
+    }
+
+    #[test]
+    fn servers_check_requests() {
+        let eom = Record::new(END_OF_MESSAGE, Vec::new());
+        let protocol = Record::u16s(NEXT_PROTOCOL, &[NTP_V4]);
+        let algorithms = Record::u16s(AEAD_ALGORITHM, &[30, AEAD_AES_SIV_CMAC_256]);
+        assert_eq!(check_request(&[protocol.clone(), algorithms.clone(), eom.clone()]), Ok(()));
+        assert_eq!(check_request(&[protocol.clone(), eom.clone()]), Err(BAD_REQUEST));
+        let unsupported = Record::u16s(AEAD_ALGORITHM, &[30]);
+        assert_eq!(check_request(&[unsupported, protocol.clone(), eom.clone()]), Err(BAD_REQUEST));
+
+        let unknown = Record { critical: true, record_type: 0x4000, body: Vec::new() };
+        assert_eq!(check_request(&[protocol, algorithms, unknown, eom]), Err(UNRECOGNIZED_CRITICAL_RECORD));
+    }
+
+    #[test]
+    fn cookies_hold_the_keys() {
+        let master = MasterKey::generate();
+        let keys = SessionKeys { c2s: [1; 32], s2c: [2; 32] };
+        let cookie = master.seal_cookie(&keys);
+        assert_eq!(cookie.len(), 96);
+        assert!(master.open_cookie(&cookie) == Some(keys.clone()));
+        // Each one is different, so they can't be used to link requests.
+        assert_ne!(master.seal_cookie(&keys), cookie);
+
+        let mut forged = cookie.clone();
+        forged[40] ^= 1;
+        assert!(master.open_cookie(&forged).is_none());
+        assert!(MasterKey::generate().open_cookie(&cookie).is_none());
+        assert!(master.open_cookie(&cookie[..10]).is_none());
+    }
+
+    #[test]
+    fn end_to_end() {
+        let (addr, config) = stand_in(ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let mut session = establish(addr, &config).unwrap();
+        assert!(session.address.ip().is_loopback());
+        assert_ne!(session.address.port(), 123);
+        assert_eq!(session.cookies(), COOKIES);
+
+        for _ in 0..2 * COOKIES {
+            let mut request = validate::request_with_nonce();
+            let unique_id = session.protect(&mut request).unwrap();
+            assert_eq!(session.cookies(), COOKIES - 1);
+
+            let datagram = exchange(&session, &request);
+            let response = NtpPacket::parse(&datagram).unwrap();
+            assert_eq!(session.verify(&unique_id, &response, &datagram).map_err(|err| err.to_string()), Ok(()));
+            assert_eq!(validate::validate(&request, &response), Ok(()));
+            // The cookie we used has been replaced.
+            assert_eq!(session.cookies(), COOKIES);
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let (addr, config) = stand_in(ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let mut session = establish(addr, &config).unwrap();
+        let mut request = validate::request_with_nonce();
+        let unique_id = session.protect(&mut request).unwrap();
+        let datagram = exchange(&session, &request);
+
+        // An earlier byte in the header.
+        let mut altered = datagram.clone();
+        altered[41] ^= 1;
+        let response = NtpPacket::parse(&altered).unwrap();
+        assert!(matches!(session.verify(&unique_id, &response, &altered), Err(NtsError::BadAuthenticator)));
+
+        let response = NtpPacket::parse(&datagram).unwrap();
+        let err = session.verify(&[0; UNIQUE_ID_LENGTH], &response, &datagram);
+        assert!(matches!(err, Err(NtsError::UniqueIdMismatch)));
+
+        let mut stripped = response.clone();
+        stripped.extensions.truncate(1);
+        let bytes = stripped.to_bytes();
+        assert!(matches!(session.verify(&unique_id, &stripped, &bytes), Err(NtsError::Unauthenticated)));
+
+        // Unauthenticated fields after the authenticator don't count.
+        let mut appended = response.clone();
+        appended.extensions.remove(0);
+        appended.extensions.push(ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.clone() });
+        let bytes = appended.to_bytes();
+        assert!(matches!(session.verify(&unique_id, &appended, &bytes), Err(NtsError::UniqueIdMismatch)));
+    }
+
+    #[test]
+    fn invalid_cookies_get_a_nak_and_a_new_key_exchange() {
+        let (addr, config) = stand_in(ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let mut session = establish(addr, &config).unwrap();
+        // As if the server had restarted with a new master key.
+        session.cookies = (0..COOKIES).map(|_| MasterKey::generate().seal_cookie(&session.keys)).collect();
+
+        let mut request = validate::request_with_nonce();
+        let unique_id = session.protect(&mut request).unwrap();
+        let datagram = exchange(&session, &request);
+        let response = NtpPacket::parse(&datagram).unwrap();
+        assert_eq!(response.reference_id_text(), Some("NTSN"));
+        assert!(matches!(session.verify(&unique_id, &response, &datagram), Err(NtsError::Nak)));
+        assert_eq!(session.cookies(), COOKIES - 1);
+        // No authenticated response is coming.
+        session.forget_cookies();
+
+        // Out of cookies, so the next request starts with NTS-KE.
+        let mut request = validate::request_with_nonce();
+        let unique_id = session.protect(&mut request).unwrap();
+        let datagram = exchange(&session, &request);
+        let response = NtpPacket::parse(&datagram).unwrap();
+        assert_eq!(session.verify(&unique_id, &response, &datagram).map_err(|err| err.to_string()), Ok(()));
+        assert_eq!(session.cookies(), COOKIES);
+    }
+
+    #[test]
+    fn spoofed_naks_keep_the_cookies() {
+        let (addr, config) = stand_in(ServerConfig { rate_limit: Duration::ZERO, ..ServerConfig::default() });
+        let mut session = establish(addr, &config).unwrap();
+        let mut request = validate::request_with_nonce();
+        let unique_id = session.protect(&mut request).unwrap();
+
+        // Anyone who saw the request can echo its unique identifier.
+        let mut spoofed = NtpPacket { stratum: 0, reference_id: *b"NTSN", ..request.clone() };
+        spoofed.extensions = vec![ExtensionField { field_type: UNIQUE_IDENTIFIER, value: unique_id.clone() }];
+        let bytes = spoofed.to_bytes();
+        assert!(matches!(session.verify(&unique_id, &spoofed, &bytes), Err(NtsError::Nak)));
+        assert_eq!(session.cookies(), COOKIES - 1);
+
+        // The real response still gets through.
+        let datagram = exchange(&session, &request);
+        let response = NtpPacket::parse(&datagram).unwrap();
+        assert_eq!(session.verify(&unique_id, &response, &datagram).map_err(|err| err.to_string()), Ok(()));
+        assert_eq!(session.cookies(), COOKIES);
+    }
+
+    #[test]
+    fn short_nonces_are_refused() {
+        let key = Siv::new(&[7; siv::KEY_LENGTH]);
+        let mut packet = validate::request_with_nonce();
+        let nonce = [1; 12];
+        let ciphertext = key.seal(&[&packet.to_bytes_without_mac(), &nonce], &[]);
+        let mut value = Vec::new();
+        value.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+        value.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+        value.extend_from_slice(&nonce);
+        value.extend_from_slice(&ciphertext);
+        packet.extensions.push(ExtensionField { field_type: AUTHENTICATOR, value });
+
+        let bytes = packet.to_bytes();
+        let packet = NtpPacket::parse(&bytes).unwrap();
+        assert!(matches!(open_authenticator(&packet, &bytes, &key), Err(NtsError::BadAuthenticator)));
+
+        let mut packet = validate::request_with_nonce();
+        append_authenticator(&mut packet, &key, &[]);
+        let bytes = packet.to_bytes();
+        assert_eq!(open_authenticator(&NtpPacket::parse(&bytes).unwrap(), &bytes, &key).ok(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn untrusted_certificates_are_refused() {
+        let (addr, _) = stand_in(ServerConfig::default());
+        let (_, other) = stand_in(ServerConfig::default());
+        assert!(matches!(establish(addr, &other), Err(NtsError::Io(_) | NtsError::Tls(_))));
+
+        let (addr, config) = stand_in(ServerConfig::default());
+        let wrong_name = NtsSession::establish(&config, "127.0.0.1", addr.port(), Duration::from_secs(2));
+        assert!(wrong_name.is_err());
+    }
+}
@@ -0,0 +1,624 @@
+/*!
+An NTP packet, as laid out in RFC 5905 section 7.3. Every field is big endian.
+
+```text
+ 0                   1                   2                   3
+ 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+|LI | VN  |Mode |    Stratum    |     Poll      |   Precision   |
+|                          Root Delay                           |
+|                        Root Dispersion                        |
+|                          Reference ID                         |
+|                   Reference Timestamp (64)                    |
+|                     Origin Timestamp (64)                     |
+|                     Receive Timestamp (64)                    |
+|                    Transmit Timestamp (64)                    |
+|                 Extension Fields (variable)                   |
+|                  Key Identifier + Digest (optional MAC)       |
++-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+*/
+
+use std::io::{Cursor, Read, Write};
+use std::{error, fmt};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use chrono::{DateTime, TimeZone, Timelike, Utc};
+
+pub const HEADER_LENGTH: usize = 48;
+pub const NTP_TO_UNIX_SECONDS: i64 = 2_208_988_800;
+
+/// The shortest extension field RFC 7822 allows, including its 4-byte header.
+const MIN_EXTENSION_LENGTH: usize = 16;
+/// The shortest the last extension field can be when no MAC follows it, so that it
+/// can't be mistaken for one.
+const MIN_LAST_EXTENSION_LENGTH: usize = 28;
+/// The longest value an extension field can hold: its length is a `u16` multiple of 4,
+/// and includes the 4-byte header.
+pub const MAX_EXTENSION_VALUE: usize = (u16::MAX as usize & !3) - 4;
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NTPTimestamp {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl NTPTimestamp {
+    pub const ZERO: NTPTimestamp = NTPTimestamp { seconds: 0, fraction: 0 };
+
+    pub fn is_zero(&self) -> bool {
+        *self == NTPTimestamp::ZERO
+    }
+
+    pub fn to_bits(self) -> u64 {
+        (self.seconds as u64) << 32 | self.fraction as u64
+    }
+
+    pub fn from_bits(bits: u64) -> Self {
+        NTPTimestamp { seconds: (bits >> 32) as u32, fraction: bits as u32 }
+    }
+}
+
+impl From<NTPTimestamp> for DateTime<Utc> {
+    fn from(ntp: NTPTimestamp) -> Self {
+        let secs = ntp.seconds as i64 - NTP_TO_UNIX_SECONDS;
+        let mut nanos = ntp.fraction as f64;
+        nanos *= 1e9;
+        nanos /= 2_f64.powi(32);
+
+        Utc.timestamp(secs, nanos as u32)
+    }
+}
+
+impl From<DateTime<Utc>> for NTPTimestamp {
+    fn from(utc: DateTime<Utc>) -> Self {
+        let secs = utc.timestamp() + NTP_TO_UNIX_SECONDS;
+        let mut fraction = utc.nanosecond() as f64;
+
+        fraction *= 2_f64.powi(32);
+        fraction /= 1e9;
+
+        NTPTimestamp {
+            seconds: secs as u32,
+            fraction: fraction as u32,
+        }
+    }
+}
+
+/// The 32-bit short format used for root delay and dispersion: 16.16 fixed point seconds.
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NtpShort(pub u32);
+
+impl NtpShort {
+    pub fn from_seconds(seconds: f64) -> Self {
+        NtpShort((seconds * 65536.0).round().clamp(0.0, u32::MAX as f64) as u32)
+    }
+
+    pub fn to_seconds(self) -> f64 {
+        self.0 as f64 / 65536.0
+    }
+}
+
+/// Warns of a leap second to be inserted or deleted at the end of the current day.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LeapIndicator {
+    NoWarning = 0,
+    /// The last minute of the day has 61 seconds.
+    InsertSecond = 1,
+    /// The last minute of the day has 59 seconds.
+    DeleteSecond = 2,
+    /// The clock is not synchronized.
+    Unsynchronized = 3,
+}
+
+impl LeapIndicator {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => LeapIndicator::NoWarning,
+            1 => LeapIndicator::InsertSecond,
+            2 => LeapIndicator::DeleteSecond,
+            _ => LeapIndicator::Unsynchronized,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Reserved = 0,
+    SymmetricActive = 1,
+    SymmetricPassive = 2,
+    Client = 3,
+    Server = 4,
+    Broadcast = 5,
+    Control = 6,
+    Private = 7,
+}
+
+impl Mode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0b111 {
+            0 => Mode::Reserved,
+            1 => Mode::SymmetricActive,
+            2 => Mode::SymmetricPassive,
+            3 => Mode::Client,
+            4 => Mode::Server,
+            5 => Mode::Broadcast,
+            6 => Mode::Control,
+            _ => Mode::Private,
+        }
+    }
+}
+
+/// An RFC 7822 extension field. Its value is padded with zeros to a multiple of 4 bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    pub value: Vec<u8>,
+}
+
+impl ExtensionField {
+    /// A field holding `value`, which must be at most [`MAX_EXTENSION_VALUE`] bytes.
+    pub fn new(field_type: u16, value: Vec<u8>) -> Result<Self, PacketError> {
+        if value.len() > MAX_EXTENSION_VALUE {
+            return Err(PacketError::ExtensionTooLong { length: value.len() });
+        }
+        Ok(ExtensionField { field_type, value })
+    }
+
+    /// The length on the wire, including the header and padding.
+    pub fn encoded_length(&self) -> usize {
+        self.padded_length(MIN_EXTENSION_LENGTH)
+    }
+
+    fn padded_length(&self, min_length: usize) -> usize {
+        (4 + self.value.len()).div_ceil(4).max(min_length / 4) * 4
+    }
+
+    /// Appends the field, as it goes on the wire, to `bytes`.
+    ///
+    /// # Panics
+    ///
+    /// If the value is longer than [`MAX_EXTENSION_VALUE`], which [`ExtensionField::new`] checks.
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        self.encode_padded(bytes, MIN_EXTENSION_LENGTH);
+    }
+
+    fn encode_padded(&self, bytes: &mut Vec<u8>, min_length: usize) {
+        let length = self.padded_length(min_length);
+        let Ok(encoded_length) = u16::try_from(length) else {
+            panic!("extension field value of {} bytes is over {}", self.value.len(), MAX_EXTENSION_VALUE);
+        };
+        // Writing to a Vec can't fail.
+        bytes.write_u16::<BigEndian>(self.field_type).unwrap();
+        bytes.write_u16::<BigEndian>(encoded_length).unwrap();
+        bytes.write_all(&self.value).unwrap();
+        bytes.resize(bytes.len() + length - 4 - self.value.len(), 0);
+    }
+}
+
+/// The message authentication code that may end a packet: a key identifier and a
+/// digest of everything before it. A key identifier with no digest is a crypto-NAK.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Mac {
+    pub key_id: u32,
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    TooShort { length: usize },
+    UnsupportedVersion(u8),
+    /// An extension field's length is under 16 bytes, isn't a multiple of 4, or runs
+    /// past the end of the packet.
+    InvalidExtensionLength { offset: usize, length: usize },
+    /// What follows the extension fields is too short to be one, and isn't a valid MAC.
+    TrailingBytes { offset: usize, length: usize },
+    /// An extension field value longer than [`MAX_EXTENSION_VALUE`].
+    ExtensionTooLong { length: usize },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PacketError::TooShort { length } => write!(f, "packet is {} bytes, need at least {}", length, HEADER_LENGTH),
+            PacketError::UnsupportedVersion(version) => write!(f, "unsupported NTP version {}", version),
+            PacketError::InvalidExtensionLength { offset, length } => {
+                write!(f, "invalid extension field length {} at byte {}", length, offset)
+            }
+            PacketError::TrailingBytes { offset, length } => write!(f, "{} unexpected bytes at byte {}", length, offset),
+            PacketError::ExtensionTooLong { length } => {
+                write!(f, "extension field value is {} bytes, at most {} fit", length, MAX_EXTENSION_VALUE)
+            }
+        }
+    }
+}
+
+impl error::Error for PacketError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub leap: LeapIndicator,
+    pub version: u8,
+    pub mode: Mode,
+    /// 0 is unspecified, or a kiss-o'-death message. 1 is a primary server with a
+    /// reference clock, 2..=15 count the hops from one, and 16 is unsynchronized.
+    pub stratum: u8,
+    /// The maximum interval between messages, as a power of two in seconds.
+    pub poll: i8,
+    /// The precision of the sender's clock, as a power of two in seconds.
+    pub precision: i8,
+    /// The round trip delay to the reference clock.
+    pub root_delay: NtpShort,
+    /// The maximum error relative to the reference clock.
+    pub root_dispersion: NtpShort,
+    /// At stratum 0 a kiss code, at stratum 1 the name of the reference clock, and
+    /// otherwise the IPv4 address of the upstream server or a hash of its IPv6 address.
+    pub reference_id: [u8; 4],
+    /// When the sender's clock was last set or corrected.
+    pub reference_timestamp: NTPTimestamp,
+    /// The client's transmit timestamp, echoed back by the server.
+    pub origin_timestamp: NTPTimestamp,
+    /// When the request arrived at the server.
+    pub receive_timestamp: NTPTimestamp,
+    /// When the packet left its sender.
+    pub transmit_timestamp: NTPTimestamp,
+    pub extensions: Vec<ExtensionField>,
+    pub mac: Option<Mac>,
+}
+
+impl Default for NtpPacket {
+    fn default() -> Self {
+        NtpPacket {
+            leap: LeapIndicator::NoWarning,
+            version: 4,
+            mode: Mode::Client,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: NtpShort::default(),
+            root_dispersion: NtpShort::default(),
+            reference_id: [0; 4],
+            reference_timestamp: NTPTimestamp::ZERO,
+            origin_timestamp: NTPTimestamp::ZERO,
+            receive_timestamp: NTPTimestamp::ZERO,
+            transmit_timestamp: NTPTimestamp::ZERO,
+            extensions: Vec::new(),
+            mac: None,
+        }
+    }
+}
+
+impl NtpPacket {
+    /// A version 4 client request with every other field zero.
+    pub fn client() -> Self {
+        NtpPacket::default()
+    }
+
+    /// The reference ID as text, when it is printable ASCII, as kiss codes and
+    /// reference clock names are.
+    pub fn reference_id_text(&self) -> Option<&str> {
+        let text = std::str::from_utf8(&self.reference_id).ok()?;
+        let text = text.trim_end_matches('\0');
+        let printable = !text.is_empty() && text.bytes().all(|b| b.is_ascii_graphic());
+        if printable { Some(text) } else { None }
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(PacketError::TooShort { length: bytes.len() });
+        }
+
+        let mut reader = Cursor::new(bytes);
+        let read = "the header length was checked";
+        let first = reader.read_u8().expect(read);
+        let version = (first >> 3) & 0b111;
+        if !(1..=4).contains(&version) {
+            return Err(PacketError::UnsupportedVersion(version));
+        }
+
+        let stratum = reader.read_u8().expect(read);
+        let poll = reader.read_i8().expect(read);
+        let precision = reader.read_i8().expect(read);
+        let root_delay = NtpShort(reader.read_u32::<BigEndian>().expect(read));
+        let root_dispersion = NtpShort(reader.read_u32::<BigEndian>().expect(read));
+        let mut reference_id = [0; 4];
+        reader.read_exact(&mut reference_id).expect(read);
+        let mut timestamps = [NTPTimestamp::ZERO; 4];
+        for timestamp in timestamps.iter_mut() {
+            *timestamp = NTPTimestamp::from_bits(reader.read_u64::<BigEndian>().expect(read));
+        }
+
+        let (extensions, mac) = parse_trailer(bytes)?;
+
+        Ok(NtpPacket {
+            leap: LeapIndicator::from_bits(first >> 6),
+            version,
+            mode: Mode::from_bits(first),
+            stratum,
+            poll,
+            precision,
+            root_delay,
+            root_dispersion,
+            reference_id,
+            reference_timestamp: timestamps[0],
+            origin_timestamp: timestamps[1],
+            receive_timestamp: timestamps[2],
+            transmit_timestamp: timestamps[3],
+            extensions,
+            mac,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.mac {
+            Some(mac) => {
+                let mut bytes = self.to_bytes_without_mac();
+                bytes.write_u32::<BigEndian>(mac.key_id).unwrap();
+                bytes.write_all(&mac.digest).unwrap();
+                bytes
+            }
+            // A last extension field of 20 or 24 bytes would read back as a MAC.
+            None => self.encode(MIN_LAST_EXTENSION_LENGTH),
+        }
+    }
+
+    /// The header and extension fields: the part of the packet that a MAC covers.
+    pub fn to_bytes_without_mac(&self) -> Vec<u8> {
+        self.encode(MIN_EXTENSION_LENGTH)
+    }
+
+    /// The header and extension fields, with the last field padded to `last_length`.
+    fn encode(&self, last_length: usize) -> Vec<u8> {
+        // Writing to a Vec can't fail.
+        let mut bytes = Vec::with_capacity(HEADER_LENGTH);
+        bytes.write_u8((self.leap as u8) << 6 | (self.version & 0b111) << 3 | self.mode as u8).unwrap();
+        bytes.write_u8(self.stratum).unwrap();
+        bytes.write_i8(self.poll).unwrap();
+        bytes.write_i8(self.precision).unwrap();
+        bytes.write_u32::<BigEndian>(self.root_delay.0).unwrap();
+        bytes.write_u32::<BigEndian>(self.root_dispersion.0).unwrap();
+        bytes.write_all(&self.reference_id).unwrap();
+        for timestamp in [
+            self.reference_timestamp,
+            self.origin_timestamp,
+            self.receive_timestamp,
+            self.transmit_timestamp,
+        ] {
+            bytes.write_u64::<BigEndian>(timestamp.to_bits()).unwrap();
+        }
+
+        if let Some((last, others)) = self.extensions.split_last() {
+            for extension in others {
+                extension.encode(&mut bytes);
+            }
+            last.encode_padded(&mut bytes, last_length);
+        }
+
+        bytes
+    }
+}
+
+/// Splits what follows the header into extension fields and a MAC.
+///
+/// The two can't be told apart by their contents, so RFC 7822 goes by length: what
+/// remains is a MAC if it's 4 bytes (a crypto-NAK), 20 bytes (a 128-bit digest such as
+/// AES-CMAC) or 24 bytes (SHA-1), and an extension field otherwise.
+fn parse_trailer(bytes: &[u8]) -> Result<(Vec<ExtensionField>, Option<Mac>), PacketError> {
+    let mut extensions = Vec::new();
+    let mut offset = HEADER_LENGTH;
+
+    while offset < bytes.len() {
+        let remaining = bytes.len() - offset;
+        if matches!(remaining, 4 | 20 | 24) {
+            let key_id = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap());
+            let mac = Mac { key_id, digest: bytes[offset + 4..].to_vec() };
+            return Ok((extensions, Some(mac)));
+        }
+        if remaining < MIN_EXTENSION_LENGTH {
+            return Err(PacketError::TrailingBytes { offset, length: remaining });
+        }
+
+        let field_type = u16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        if length < MIN_EXTENSION_LENGTH || !length.is_multiple_of(4) || length > remaining {
+            return Err(PacketError::InvalidExtensionLength { offset, length });
+        }
+
+        extensions.push(ExtensionField { field_type, value: bytes[offset + 4..offset + length].to_vec() });
+        offset += length;
+    }
+
+    Ok((extensions, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        let digits: Vec<char> = hex.chars().filter(|c| !c.is_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(&pair.iter().collect::<String>(), 16).unwrap())
+            .collect()
+    }
+
+    /// A response from a stratum 1 server with a GPS reference clock, to a client whose
+    /// transmit timestamp was 0xe6b6_4f0a_4189_374b.
+    const SERVER_RESPONSE: &str = "
+        24 01 03 e9  00 00 00 00  00 00 00 0c  47 50 53 00
+        e6 b6 4f 09  f8 5e 3b 2e  e6 b6 4f 0a  41 89 37 4b
+        e6 b6 4f 0a  4a 3d 70 a4  e6 b6 4f 0a  4a 40 83 12";
+
+    /// A kiss-o'-death asking the client to slow down.
+    const KISS_OF_DEATH: &str = "
+        24 00 0a 00  00 00 00 00  00 00 00 00  52 41 54 45
+        00 00 00 00  00 00 00 00  e6 b6 4f 0a  41 89 37 4b
+        00 00 00 00  00 00 00 00  00 00 00 00  00 00 00 00";
+
+    /// A version 3 client request, as the original client sent.
+    const CLIENT_REQUEST: &str = "
+        1b 00 00 00  00 00 00 00  00 00 00 00  00 00 00 00
+        00 00 00 00  00 00 00 00  00 00 00 00  00 00 00 00
+        00 00 00 00  00 00 00 00  00 00 00 00  00 00 00 00";
+
+    #[test]
+    fn server_response() {
+        let bytes = from_hex(SERVER_RESPONSE);
+        let packet = NtpPacket::parse(&bytes).unwrap();
+
+        assert_eq!(packet.leap, LeapIndicator::NoWarning);
+        assert_eq!(packet.version, 4);
+        assert_eq!(packet.mode, Mode::Server);
+        assert_eq!(packet.stratum, 1);
+        assert_eq!(packet.poll, 3);
+        assert_eq!(packet.precision, -23);
+        assert_eq!(packet.root_delay.to_seconds(), 0.0);
+        assert_eq!(packet.root_dispersion, NtpShort(12));
+        assert_eq!(packet.reference_id_text(), Some("GPS"));
+        assert_eq!(packet.origin_timestamp.to_bits(), 0xe6b6_4f0a_4189_374b);
+        assert_eq!(packet.receive_timestamp, NTPTimestamp { seconds: 0xe6b6_4f0a, fraction: 0x4a3d_70a4 });
+        assert!(packet.extensions.is_empty());
+        assert_eq!(packet.mac, None);
+
+        let received: DateTime<Utc> = packet.receive_timestamp.into();
+        assert_eq!(received.to_rfc3339(), "2022-08-28T20:31:06.290+00:00");
+
+        assert_eq!(packet.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        let bytes = from_hex(KISS_OF_DEATH);
+        let packet = NtpPacket::parse(&bytes).unwrap();
+        assert_eq!(packet.stratum, 0);
+        assert_eq!(packet.poll, 10);
+        assert_eq!(packet.reference_id_text(), Some("RATE"));
+        assert!(packet.transmit_timestamp.is_zero());
+        assert_eq!(packet.to_bytes(), bytes);
+    }
+
+    #[test]
+    fn client_request() {
+        let packet = NtpPacket::parse(&from_hex(CLIENT_REQUEST)).unwrap();
+        assert_eq!((packet.version, packet.mode), (3, Mode::Client));
+        assert_eq!(packet.reference_id_text(), None);
+
+        let request = NtpPacket::client().to_bytes();
+        assert_eq!(request.len(), HEADER_LENGTH);
+        assert_eq!(request[0], 0b00_100_011);
+        assert!(request[1..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn extension_fields_and_mac() {
+        let mut packet = NtpPacket::parse(&from_hex(SERVER_RESPONSE)).unwrap();
+        packet.extensions = vec![
+            ExtensionField { field_type: 0x0104, value: b"unique identifier".to_vec() },
+            ExtensionField { field_type: 0x0204, value: vec![1, 2, 3, 4] },
+        ];
+        packet.mac = Some(Mac { key_id: 7, digest: vec![0xAA; 16] });
+
+        let bytes = packet.to_bytes();
+        // 17 bytes of value padded to 20, plus a header; 4 bytes of value padded to the minimum.
+        assert_eq!(bytes.len(), HEADER_LENGTH + 24 + 16 + 20);
+        assert_eq!(bytes[48..52], [0x01, 0x04, 0, 24]);
+        assert_eq!(bytes[72..76], [0x02, 0x04, 0, 16]);
+        assert_eq!(bytes[88..92], [0, 0, 0, 7]);
+
+        let parsed = NtpPacket::parse(&bytes).unwrap();
+        assert_eq!(parsed.extensions[0].value, b"unique identifier\0\0\0");
+        assert_eq!(parsed.extensions[1].value, [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(parsed.mac, packet.mac);
+        assert_eq!(parsed.to_bytes(), bytes);
+
+        // A SHA-1 MAC, and a crypto-NAK.
+        for digest_length in [20, 0] {
+            let mut bytes = from_hex(SERVER_RESPONSE);
+            bytes.extend([0, 0, 0, 9]);
+            bytes.extend(vec![0x55; digest_length]);
+            let mac = NtpPacket::parse(&bytes).unwrap().mac.unwrap();
+            assert_eq!((mac.key_id, mac.digest.len()), (9, digest_length));
+        }
+    }
+
+    #[test]
+    fn last_extension_field_is_never_mistaken_for_a_mac() {
+        for value_length in [12, 16, 20, 24] {
+            let mut packet = NtpPacket::parse(&from_hex(SERVER_RESPONSE)).unwrap();
+            let first = ExtensionField::new(0x0104, vec![1; 32]).unwrap();
+            packet.extensions = vec![first, ExtensionField::new(0x0204, vec![2; value_length]).unwrap()];
+
+            let bytes = packet.to_bytes();
+            assert_eq!(bytes.len(), HEADER_LENGTH + 36 + (4 + value_length).max(28));
+            let parsed = NtpPacket::parse(&bytes).unwrap();
+            assert_eq!(parsed.mac, None, "{}-byte value", value_length);
+            assert_eq!(parsed.extensions.len(), 2);
+            assert_eq!(parsed.extensions[1].value[..value_length], vec![2; value_length][..]);
+            assert_eq!(parsed.to_bytes(), bytes);
+
+            // With a MAC, the last field needn't be any longer than the others.
+            packet.mac = Some(Mac { key_id: 7, digest: vec![0xAA; 16] });
+            let bytes = packet.to_bytes();
+            assert_eq!(bytes.len(), HEADER_LENGTH + 36 + (4 + value_length).max(16) + 20);
+            assert_eq!(NtpPacket::parse(&bytes).unwrap().mac, packet.mac);
+        }
+    }
+
+    #[test]
+    fn extension_field_values_have_a_limit() {
+        let longest = ExtensionField::new(1, vec![0; MAX_EXTENSION_VALUE]).unwrap();
+        assert_eq!(longest.encoded_length(), 65532);
+        let mut bytes = Vec::new();
+        longest.encode(&mut bytes);
+        assert_eq!(bytes[2..4], [0xff, 0xfc]);
+
+        let err = ExtensionField::new(1, vec![0; MAX_EXTENSION_VALUE + 1]).unwrap_err();
+        assert_eq!(err, PacketError::ExtensionTooLong { length: 65529 });
+    }
+
+    #[test]
+    #[should_panic(expected = "extension field value of 65532 bytes")]
+    fn over_long_extension_fields_are_not_truncated() {
+        ExtensionField { field_type: 1, value: vec![0; 65532] }.encode(&mut Vec::new());
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let bytes = from_hex(SERVER_RESPONSE);
+        assert_eq!(NtpPacket::parse(&bytes[..47]), Err(PacketError::TooShort { length: 47 }));
+
+        let mut bad_version = bytes.clone();
+        bad_version[0] = 0b00_101_100;
+        assert_eq!(NtpPacket::parse(&bad_version), Err(PacketError::UnsupportedVersion(5)));
+        bad_version[0] = 0b00_000_100;
+        assert_eq!(NtpPacket::parse(&bad_version), Err(PacketError::UnsupportedVersion(0)));
+
+        let mut trailing = bytes.clone();
+        trailing.extend([0; 8]);
+        assert_eq!(NtpPacket::parse(&trailing), Err(PacketError::TrailingBytes { offset: 48, length: 8 }));
+
+        for length in [12, 18, 40] {
+            let mut extension = bytes.clone();
+            extension.extend([0, 1, 0, length]);
+            extension.extend([0; 28]);
+            assert_eq!(
+                NtpPacket::parse(&extension),
+                Err(PacketError::InvalidExtensionLength { offset: 48, length: length as usize })
+            );
+        }
+    }
+
+    #[test]
+    fn timestamps_and_short_format() {
+        let t = Utc.ymd(2022, 8, 28).and_hms_milli(20, 31, 6, 250);
+        let ntp = NTPTimestamp::from(t);
+        assert_eq!(ntp.seconds, 0xe6b6_4f0a);
+        assert_eq!(ntp.fraction, 0x4000_0000);
+        assert_eq!(DateTime::<Utc>::from(ntp), t);
+        assert_eq!(NTPTimestamp::from_bits(ntp.to_bits()), ntp);
+
+        assert_eq!(NtpShort::from_seconds(1.5), NtpShort(0x0001_8000));
+        assert_eq!(NtpShort(0x0001_8000).to_seconds(), 1.5);
+    }
+}
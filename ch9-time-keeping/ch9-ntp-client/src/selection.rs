@@ -0,0 +1,542 @@
+/*!
+Turns samples from several servers into one offset, following RFC 5905 sections 10 and 11.
+
+1. The clock filter keeps each peer's last eight samples and picks the one with the
+   shortest round trip, which has the least room for asymmetric network delay.
+2. Each peer's offset is bracketed by a correctness interval: the true time lies within
+   its root distance of the offset, if the server is honest. The intersection algorithm,
+   Marzullo's algorithm as adapted by Mills, finds the interval that a majority of
+   peers agree on. Peers outside it are falsetickers.
+3. Clustering discards the survivors that disagree most with the others, as long as
+   doing so reduces the spread by more than the peers' own jitter.
+4. What's left is combined into an average weighted by root distance.
+
+Times are in seconds. Sample times are on any monotonic timescale, as long as `now`
+uses the same one.
+*/
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::validate::MAX_ROOT_DISTANCE;
+
+/// Samples kept by the clock filter.
+pub const NSTAGE: usize = 8;
+/// The rate at which dispersion grows with age: 15 ppm, a generous bound on a
+/// quartz clock's frequency error.
+pub const PHI: f64 = 15e-6;
+/// Samples this dispersed carry no information.
+pub const MAXDISP: f64 = 16.0;
+/// The smallest root delay assumed for any peer.
+pub const MINDISP: f64 = 0.005;
+/// Clustering stops once this few survivors remain.
+pub const NMIN: usize = 3;
+
+/// One exchange with a peer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sample {
+    pub offset: f64,
+    pub delay: f64,
+    /// The error bound from both clocks' precision and the round trip time.
+    pub dispersion: f64,
+    pub time: f64,
+}
+
+/// The clock filter's best guess for a peer.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Estimate {
+    pub offset: f64,
+    pub delay: f64,
+    pub dispersion: f64,
+    /// The RMS difference between the chosen sample's offset and the others'.
+    pub jitter: f64,
+    pub time: f64,
+}
+
+/// A shift register of a peer's most recent samples, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct ClockFilter {
+    register: VecDeque<Sample>,
+}
+
+impl ClockFilter {
+    pub fn push(&mut self, sample: Sample) {
+        self.register.push_front(sample);
+        self.register.truncate(NSTAGE);
+    }
+
+    pub fn len(&self) -> usize {
+        self.register.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.register.is_empty()
+    }
+
+    pub fn estimate(&self, now: f64) -> Option<Estimate> {
+        let mut samples: Vec<Sample> = self
+            .register
+            .iter()
+            .map(|sample| Sample { dispersion: sample.dispersion + PHI * (now - sample.time), ..*sample })
+            .filter(|sample| sample.dispersion < MAXDISP)
+            .collect();
+        samples.sort_by(|a, b| (a.delay / 2.0 + a.dispersion).total_cmp(&(b.delay / 2.0 + b.dispersion)));
+        let best = *samples.first()?;
+
+        // Each older (or longer) sample counts for half as much as the one before it.
+        // RFC 5905 counts stages that were never filled at MAXDISP, so that a peer
+        // needs several polls before it's trusted. Here they're left out, so that a
+        // single query, as `check-ntp` makes, can be used at all.
+        let dispersion = samples.iter().zip(1..).map(|(sample, i)| sample.dispersion / 2f64.powi(i)).sum();
+        let jitter = match samples.len() {
+            1 => 0.0,
+            n => {
+                let squares: f64 = samples[1..].iter().map(|sample| (sample.offset - best.offset).powi(2)).sum();
+                (squares / (n - 1) as f64).sqrt()
+            }
+        };
+
+        Some(Estimate { offset: best.offset, delay: best.delay, dispersion, jitter, time: best.time })
+    }
+}
+
+/// A server, what it says about itself, and the samples we've taken from it.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub name: String,
+    pub stratum: u8,
+    pub root_delay: f64,
+    pub root_dispersion: f64,
+    pub filter: ClockFilter,
+}
+
+impl Peer {
+    pub fn new(name: impl Into<String>) -> Self {
+        Peer { name: name.into(), stratum: 16, root_delay: 0.0, root_dispersion: 0.0, filter: ClockFilter::default() }
+    }
+
+    /// The maximum error of `estimate` relative to the peer's reference clock.
+    pub fn root_distance(&self, estimate: &Estimate) -> f64 {
+        (self.root_delay + estimate.delay).max(MINDISP) / 2.0
+            + self.root_dispersion
+            + estimate.dispersion
+            + estimate.jitter
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PeerStatus {
+    /// The best survivor, whose jitter the system inherits.
+    SystemPeer,
+    /// Used in the combined offset.
+    Survivor,
+    /// A truechimer discarded by clustering.
+    Outlier,
+    /// Outside the interval the majority agrees on.
+    Falseticker,
+    NoResponse,
+    Unsynchronized,
+    /// Further than `MAX_ROOT_DISTANCE` from its reference clock.
+    TooDistant,
+}
+
+impl PeerStatus {
+    /// The character `ntpq -p` shows for the status.
+    pub fn tally(&self) -> char {
+        match self {
+            PeerStatus::SystemPeer => '*',
+            PeerStatus::Survivor => '+',
+            PeerStatus::Outlier => '-',
+            PeerStatus::Falseticker => 'x',
+            PeerStatus::NoResponse | PeerStatus::Unsynchronized | PeerStatus::TooDistant => ' ',
+        }
+    }
+}
+
+impl fmt::Display for PeerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            PeerStatus::SystemPeer => "system peer",
+            PeerStatus::Survivor => "survivor",
+            PeerStatus::Outlier => "outlier",
+            PeerStatus::Falseticker => "falseticker",
+            PeerStatus::NoResponse => "no response",
+            PeerStatus::Unsynchronized => "unsynchronized",
+            PeerStatus::TooDistant => "too distant",
+        };
+        f.write_str(text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerReport {
+    pub name: String,
+    pub estimate: Option<Estimate>,
+    pub root_distance: Option<f64>,
+    pub status: PeerStatus,
+}
+
+/// What the selection algorithms made of a set of peers, in the order they were given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelectionReport {
+    pub peers: Vec<PeerReport>,
+    /// The interval that the majority of peers agree contains the true time.
+    pub intersection: Option<(f64, f64)>,
+    /// The combined offset of the survivors.
+    pub offset: Option<f64>,
+    pub jitter: Option<f64>,
+}
+
+impl SelectionReport {
+    pub fn system_peer(&self) -> Option<&PeerReport> {
+        self.peers.iter().find(|peer| peer.status == PeerStatus::SystemPeer)
+    }
+}
+
+impl fmt::Display for SelectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ms = |seconds: Option<f64>| seconds.map_or("-".to_string(), |s| format!("{:.3}", s * 1000.0));
+
+        writeln!(
+            f,
+            "  {:<32} {:>10} {:>10} {:>10} {:>10} {:>10}  status",
+            "server", "offset ms", "delay ms", "disp ms", "jitter ms", "dist ms"
+        )?;
+        for peer in &self.peers {
+            let estimate = peer.estimate.as_ref();
+            writeln!(
+                f,
+                "{} {:<32} {:>10} {:>10} {:>10} {:>10} {:>10}  {}",
+                peer.status.tally(),
+                peer.name,
+                ms(estimate.map(|e| e.offset)),
+                ms(estimate.map(|e| e.delay)),
+                ms(estimate.map(|e| e.dispersion)),
+                ms(estimate.map(|e| e.jitter)),
+                ms(peer.root_distance),
+                peer.status,
+            )?;
+        }
+
+        match (self.offset, self.jitter) {
+            (Some(offset), Some(jitter)) => write!(f, "offset {} ms, jitter {} ms", ms(Some(offset)), ms(Some(jitter))),
+            _ => write!(f, "no majority of servers agree on the time"),
+        }
+    }
+}
+
+/// Runs the selection, clustering and combining algorithms over `peers`.
+pub fn select(peers: &[Peer], now: f64) -> SelectionReport {
+    let mut reports: Vec<PeerReport> = peers
+        .iter()
+        .map(|peer| {
+            let estimate = peer.filter.estimate(now);
+            let root_distance = estimate.as_ref().map(|e| peer.root_distance(e));
+            let status = match root_distance {
+                None => PeerStatus::NoResponse,
+                Some(_) if peer.stratum == 0 || peer.stratum >= 16 => PeerStatus::Unsynchronized,
+                Some(distance) if distance > MAX_ROOT_DISTANCE => PeerStatus::TooDistant,
+                // Provisionally: the algorithms below demote it, or promote it.
+                Some(_) => PeerStatus::Survivor,
+            };
+            PeerReport { name: peer.name.clone(), estimate, root_distance, status }
+        })
+        .collect();
+
+    let candidates: Vec<usize> = (0..reports.len()).filter(|&i| reports[i].status == PeerStatus::Survivor).collect();
+    let offset = |i: usize| reports[i].estimate.unwrap().offset;
+    let distance = |i: usize| reports[i].root_distance.unwrap();
+
+    let intervals: Vec<(f64, f64, f64)> =
+        candidates.iter().map(|&i| (offset(i) - distance(i), offset(i), offset(i) + distance(i))).collect();
+    let intersection = intersect(&intervals);
+    let mut survivors: Vec<usize> = match intersection {
+        Some((low, high)) => candidates
+            .iter()
+            .copied()
+            .filter(|&i| offset(i) + distance(i) >= low && offset(i) - distance(i) <= high)
+            .collect(),
+        None => Vec::new(),
+    };
+    let falsetickers: Vec<usize> = candidates.iter().copied().filter(|i| !survivors.contains(i)).collect();
+
+    // Best first: lower strata, then shorter root distances.
+    let merit = |i: usize| peers[i].stratum as f64 * MAX_ROOT_DISTANCE + distance(i);
+    survivors.sort_by(|&a, &b| merit(a).total_cmp(&merit(b)));
+
+    let mut outliers = Vec::new();
+    while survivors.len() > NMIN {
+        let n = survivors.len() as f64;
+        let selection_jitter = |i: usize| {
+            let squares: f64 = survivors.iter().map(|&j| (offset(i) - offset(j)).powi(2)).sum();
+            (squares / (n - 1.0)).sqrt()
+        };
+        let (worst, max_jitter) = survivors
+            .iter()
+            .enumerate()
+            .map(|(k, &i)| (k, selection_jitter(i)))
+            .fold((0, f64::MIN), |max, next| if next.1 > max.1 { next } else { max });
+        let min_peer_jitter =
+            survivors.iter().map(|&i| reports[i].estimate.unwrap().jitter).fold(f64::INFINITY, f64::min);
+        if max_jitter <= min_peer_jitter {
+            break;
+        }
+        outliers.push(survivors.remove(worst));
+    }
+
+    let (combined, jitter) = match survivors.first() {
+        Some(&system_peer) => {
+            let weight = |i: usize| 1.0 / distance(i);
+            let total: f64 = survivors.iter().map(|&i| weight(i)).sum();
+            let combined = survivors.iter().map(|&i| offset(i) * weight(i)).sum::<f64>() / total;
+            let spread = survivors.iter().map(|&i| (offset(i) - offset(system_peer)).powi(2) * weight(i)).sum::<f64>() / total;
+            let peer_jitter = reports[system_peer].estimate.unwrap().jitter;
+            (Some(combined), Some((spread + peer_jitter.powi(2)).sqrt()))
+        }
+        None => (None, None),
+    };
+
+    for i in falsetickers {
+        reports[i].status = PeerStatus::Falseticker;
+    }
+    for i in outliers {
+        reports[i].status = PeerStatus::Outlier;
+    }
+    if let Some(&system_peer) = survivors.first() {
+        reports[system_peer].status = PeerStatus::SystemPeer;
+    }
+
+    SelectionReport { peers: reports, intersection, offset: combined, jitter }
+}
+
+/// The smallest interval containing the offsets of a majority of the `(low, offset, high)`
+/// correctness intervals, or `None` without a majority.
+fn intersect(intervals: &[(f64, f64, f64)]) -> Option<(f64, f64)> {
+    // Lower edges count -1, offsets 0, upper edges +1. At equal values lower edges sort
+    // first, so intervals that touch count as overlapping.
+    let mut edges: Vec<(f64, i32)> = intervals
+        .iter()
+        .flat_map(|&(low, offset, high)| [(low, -1), (offset, 0), (high, 1)])
+        .collect();
+    edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+    let n = intervals.len() as i32;
+    // The number of falsetickers allowed, which must stay a minority.
+    let mut allow = 0;
+    while 2 * allow < n {
+        let mut found = 0;
+
+        let mut chime = 0;
+        let mut low = None;
+        for &(edge, kind) in &edges {
+            chime -= kind;
+            if chime >= n - allow {
+                low = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        let mut chime = 0;
+        let mut high = None;
+        for &(edge, kind) in edges.iter().rev() {
+            chime += kind;
+            if chime >= n - allow {
+                high = Some(edge);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+
+        // More offsets outside the intersection than falsetickers allowed means the
+        // intersection is wrong, even if it exists.
+        if found <= allow {
+            if let (Some(low), Some(high)) = (low, high) {
+                if low < high {
+                    return Some((low, high));
+                }
+            }
+        }
+        allow += 1;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(offset: f64, delay: f64, time: f64) -> Sample {
+        Sample { offset, delay, dispersion: 0.001, time }
+    }
+
+    /// A stratum 2 peer with `offsets` sampled once a second, with delays of `delay`.
+    fn peer(name: &str, offsets: &[f64], delay: f64) -> Peer {
+        let mut peer = Peer { stratum: 2, root_delay: 0.01, root_dispersion: 0.01, ..Peer::new(name) };
+        for (time, &offset) in offsets.iter().enumerate() {
+            peer.filter.push(sample(offset, delay, time as f64));
+        }
+        peer
+    }
+
+    fn statuses(report: &SelectionReport) -> Vec<PeerStatus> {
+        report.peers.iter().map(|peer| peer.status).collect()
+    }
+
+    #[test]
+    fn filter_prefers_short_round_trips() {
+        let mut filter = ClockFilter::default();
+        assert_eq!(filter.estimate(0.0), None);
+
+        filter.push(sample(0.020, 0.100, 0.0));
+        filter.push(sample(0.001, 0.010, 1.0));
+        filter.push(sample(0.030, 0.120, 2.0));
+        let estimate = filter.estimate(2.0).unwrap();
+        assert_eq!(estimate.offset, 0.001);
+        assert_eq!(estimate.delay, 0.010);
+        assert_eq!(estimate.time, 1.0);
+
+        // RMS of 0.019 and 0.029 against the chosen sample.
+        let jitter = ((0.019f64.powi(2) + 0.029f64.powi(2)) / 2.0).sqrt();
+        assert!((estimate.jitter - jitter).abs() < 1e-12);
+        assert!(estimate.dispersion > 0.001 / 2.0 && estimate.dispersion < 0.001);
+    }
+
+    #[test]
+    fn filter_keeps_eight_samples_and_ages_them() {
+        let mut filter = ClockFilter::default();
+        filter.push(sample(0.5, 0.001, 0.0));
+        for time in 1..=NSTAGE {
+            filter.push(sample(0.0, 0.050, time as f64));
+        }
+        assert_eq!(filter.len(), NSTAGE);
+        // The short sample has been shifted out.
+        assert_eq!(filter.estimate(8.0).unwrap().delay, 0.050);
+
+        let fresh = filter.estimate(8.0).unwrap();
+        let aged = filter.estimate(8.0 + 1000.0).unwrap();
+        // Every sample ages alike, and the weights 1/2 + 1/4 + ... add up to 1 - 1/2^8.
+        let growth = PHI * 1000.0 * (1.0 - 0.5f64.powi(NSTAGE as i32));
+        assert!((aged.dispersion - fresh.dispersion - growth).abs() < 1e-9);
+
+        // Eventually every sample is too dispersed to use.
+        assert_eq!(filter.estimate(MAXDISP / PHI), None);
+    }
+
+    #[test]
+    fn intersection() {
+        assert_eq!(intersect(&[]), None);
+        assert_eq!(intersect(&[(-1.0, 0.0, 1.0)]), Some((-1.0, 1.0)));
+        assert_eq!(intersect(&[(-1.0, 0.5, 1.5), (0.0, 0.7, 2.0), (0.4, 0.6, 1.2)]), Some((0.4, 1.2)));
+        // All three overlap in 0.5..1.0, but the first offset lies outside that, so the
+        // first interval is allowed to be a falseticker and the two others decide.
+        assert_eq!(intersect(&[(-1.0, 0.0, 1.0), (0.0, 1.0, 2.0), (0.5, 1.0, 1.5)]), Some((0.0, 1.5)));
+        // Two disjoint intervals: neither is a majority.
+        assert_eq!(intersect(&[(-1.0, 0.0, 1.0), (9.0, 10.0, 11.0)]), None);
+        // One in three may be a falseticker.
+        assert_eq!(intersect(&[(-1.0, 0.0, 1.0), (-0.5, 0.5, 1.5), (9.0, 10.0, 11.0)]), Some((-0.5, 1.0)));
+    }
+
+    #[test]
+    fn rejects_a_falseticker() {
+        let peers = vec![
+            peer("a", &[0.010, 0.011, 0.012], 0.020),
+            peer("b", &[0.012, 0.013, 0.011], 0.030),
+            peer("liar", &[5.000, 5.001, 5.002], 0.020),
+            peer("c", &[0.009, 0.010, 0.011], 0.025),
+        ];
+        let report = select(&peers, 3.0);
+
+        assert_eq!(report.peers[2].status, PeerStatus::Falseticker);
+        assert_eq!(report.system_peer().unwrap().name, "a");
+        let offset = report.offset.unwrap();
+        assert!((0.009..=0.013).contains(&offset), "offset {}", offset);
+        let (low, high) = report.intersection.unwrap();
+        assert!(low <= offset && offset <= high);
+    }
+
+    #[test]
+    fn a_weighted_mean_would_have_been_fooled() {
+        // The liar has the shortest round trip, so 1 / delay² weighting trusts it most.
+        let peers = vec![
+            peer("a", &[0.0], 0.050),
+            peer("b", &[0.001], 0.050),
+            peer("liar", &[2.0], 0.001),
+        ];
+        let report = select(&peers, 1.0);
+        assert_eq!(statuses(&report), [PeerStatus::SystemPeer, PeerStatus::Survivor, PeerStatus::Falseticker]);
+        assert!(report.offset.unwrap().abs() < 0.002);
+    }
+
+    #[test]
+    fn no_majority() {
+        let peers = vec![peer("a", &[0.0], 0.01), peer("b", &[1.0], 0.01)];
+        let report = select(&peers, 1.0);
+        assert_eq!(statuses(&report), [PeerStatus::Falseticker, PeerStatus::Falseticker]);
+        assert_eq!(report.offset, None);
+        assert_eq!(report.system_peer(), None);
+        assert!(report.to_string().ends_with("no majority of servers agree on the time"));
+    }
+
+    #[test]
+    fn unfit_peers() {
+        let mut unsynchronized = peer("unsynchronized", &[0.0], 0.01);
+        unsynchronized.stratum = 16;
+        let mut distant = peer("distant", &[0.0], 0.01);
+        distant.root_dispersion = 2.0;
+        let peers = vec![Peer::new("silent"), unsynchronized, distant, peer("good", &[0.0], 0.01)];
+
+        let report = select(&peers, 1.0);
+        assert_eq!(
+            statuses(&report),
+            [PeerStatus::NoResponse, PeerStatus::Unsynchronized, PeerStatus::TooDistant, PeerStatus::SystemPeer]
+        );
+        assert_eq!(report.peers[0].estimate, None);
+        assert!(report.peers[2].root_distance.unwrap() > MAX_ROOT_DISTANCE);
+    }
+
+    #[test]
+    fn clustering_drops_the_noisiest_truechimer() {
+        // Six truechimers whose intervals all overlap, one of them well off to the side.
+        let peers = vec![
+            peer("a", &[0.000, 0.0001], 0.010),
+            peer("b", &[0.001, 0.0011], 0.010),
+            peer("c", &[0.002, 0.0021], 0.010),
+            peer("d", &[0.001, 0.0012], 0.010),
+            peer("e", &[0.0015, 0.0014], 0.010),
+            peer("wide", &[0.030, 0.0301], 0.010),
+        ];
+        let report = select(&peers, 2.0);
+        assert_eq!(report.peers[5].status, PeerStatus::Outlier);
+        assert!(report.intersection.is_some());
+        assert!(report.offset.unwrap() < 0.003);
+        assert!(report.peers.iter().filter(|peer| peer.status == PeerStatus::Survivor).count() >= NMIN - 1);
+    }
+
+    #[test]
+    fn system_peer_has_the_lowest_stratum() {
+        let mut primary = peer("primary", &[0.002], 0.040);
+        primary.stratum = 1;
+        let peers = vec![peer("a", &[0.001], 0.010), peer("b", &[0.001], 0.010), primary];
+        let report = select(&peers, 1.0);
+        assert_eq!(report.system_peer().unwrap().name, "primary");
+    }
+
+    #[test]
+    fn report_shows_tally_codes() {
+        let peers = vec![peer("a", &[0.0], 0.01), peer("b", &[0.0], 0.01), peer("liar", &[9.0], 0.01), Peer::new("silent")];
+        let text = select(&peers, 1.0).to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].starts_with("* a "));
+        assert!(lines[2].starts_with("+ b "));
+        assert!(lines[3].starts_with("x liar "));
+        assert!(lines[4].starts_with("  silent ") && lines[4].ends_with("no response"));
+        assert!(lines[5].starts_with("offset "));
+    }
+}
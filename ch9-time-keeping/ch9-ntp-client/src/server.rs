@@ -0,0 +1,356 @@
+/*!
+A minimal NTP server that answers client requests from the local clock.
+
+It's meant for a LAN without internet access, and as a stand-in for public servers in
+tests. It answers only mode 3 (client) requests, so it can't be used to reflect traffic
+at someone else. It doesn't discipline its own clock: whatever the local clock says is
+served, at the configured stratum.
+*/
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use chrono::{Duration as ChronoDuration, Utc};
+
+use crate::auth::{Keys, CRYPTO_NAK};
+use crate::nts::{self, MasterKey};
+use crate::packet::{Mac, Mode, NTPTimestamp, NtpPacket};
+
+/// The most clients whose last request is remembered. Once full, those that have been
+/// quiet for a few rate-limit intervals are forgotten, at most once an interval, and new
+/// clients go untracked until there's room: spoofed source addresses can't make the
+/// server use more memory, or make each request cost more.
+const MAX_CLIENTS: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub stratum: u8,
+    /// A reference clock name at stratum 1, such as `GPS`, or `LOCL` for an
+    /// undisciplined local clock.
+    pub reference_id: [u8; 4],
+    /// The precision of the local clock, as a power of two in seconds.
+    pub precision: i8,
+    /// The shortest interval allowed between requests from one address. Faster
+    /// clients are sent a RATE kiss-o'-death.
+    pub rate_limit: Duration,
+    /// Added to the local clock before it's served: handy for testing clients.
+    pub offset: ChronoDuration,
+    /// Requests with a MAC from one of these keys get a response signed with the same key.
+    pub keys: Keys,
+    /// Opens the cookies of NTS requests. Without it, they're answered without NTS.
+    pub nts: Option<MasterKey>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            stratum: 10,
+            reference_id: *b"LOCL",
+            precision: -20,
+            rate_limit: Duration::from_secs(2),
+            offset: ChronoDuration::zero(),
+            keys: Keys::default(),
+            nts: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Converts a reference ID given as text, padding short ones with zeros.
+    pub fn parse_reference_id(text: &str) -> Option<[u8; 4]> {
+        if text.is_empty() || text.len() > 4 || !text.bytes().all(|b| b.is_ascii_graphic()) {
+            return None;
+        }
+        let mut id = [0; 4];
+        id[..text.len()].copy_from_slice(text.as_bytes());
+        Some(id)
+    }
+
+    fn now(&self) -> NTPTimestamp {
+        (Utc::now() + self.offset).into()
+    }
+}
+
+pub struct Server {
+    socket: UdpSocket,
+    config: ServerConfig,
+    last_request: HashMap<IpAddr, Instant>,
+    /// When quiet clients were last forgotten.
+    last_sweep: Instant,
+}
+
+impl Server {
+    pub fn bind(addr: impl ToSocketAddrs, config: ServerConfig) -> io::Result<Server> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(Server { socket, config, last_request: HashMap::new(), last_sweep: Instant::now() })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Answers requests until the socket fails. Errors that concern one client, such as
+    /// an ICMP port unreachable from an earlier response, are logged and skipped.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            match self.serve_one() {
+                Ok(()) => {}
+                Err(err) if is_transient(&err) => eprintln!("NTP server: {}", err),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Waits for one datagram and answers it if it's a valid client request. A response
+    /// that can't be sent is logged rather than returned as an error.
+    pub fn serve_one(&mut self) -> io::Result<()> {
+        let mut buffer = [0; 1024];
+        let (length, client) = self.socket.recv_from(&mut buffer)?;
+        let receive = self.config.now();
+
+        let request = match NtpPacket::parse(&buffer[..length]) {
+            Ok(request) if request.mode == Mode::Client => request,
+            _ => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let limited = self.rate_limited(client.ip(), now);
+        let mut response = respond(&self.config, &request, receive, limited);
+        response.transmit_timestamp = self.config.now();
+        authenticate(&self.config.keys, &request, &buffer[..length], &mut response);
+        if let Some(master) = &self.config.nts {
+            nts::protect_response(master, &request, &buffer[..length], &mut response);
+        }
+        if let Err(err) = self.socket.send_to(&response.to_bytes(), client) {
+            eprintln!("NTP server: unable to answer {}: {}", client, err);
+        }
+        Ok(())
+    }
+
+    /// Records a request from `client` and reports whether it came too soon after the last.
+    fn rate_limited(&mut self, client: IpAddr, now: Instant) -> bool {
+        let rate_limit = self.config.rate_limit;
+        let full = self.last_request.len() >= MAX_CLIENTS;
+        if full && !self.last_request.contains_key(&client) && now.duration_since(self.last_sweep) >= rate_limit {
+            self.last_request.retain(|_, &mut last| now.duration_since(last) < rate_limit * 4);
+            self.last_sweep = now;
+        }
+        if self.last_request.len() >= MAX_CLIENTS && !self.last_request.contains_key(&client) {
+            return false;
+        }
+
+        match self.last_request.insert(client, now) {
+            Some(last) => now.duration_since(last) < rate_limit,
+            None => false,
+        }
+    }
+}
+
+/// Whether `err`, from receiving a request, concerns one client rather than the socket.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// The response to `request`, which arrived at `receive`, before its transmit
+/// timestamp is filled in. A `limited` client is sent a RATE kiss-o'-death instead.
+pub fn respond(config: &ServerConfig, request: &NtpPacket, receive: NTPTimestamp, limited: bool) -> NtpPacket {
+    // The poll exponent tells the client how often it may ask: at least the rate limit.
+    let min_poll = 64 - config.rate_limit.as_secs().max(1).leading_zeros() as i8;
+    let mut response = NtpPacket {
+        version: request.version,
+        mode: Mode::Server,
+        stratum: config.stratum,
+        poll: request.poll.max(min_poll),
+        precision: config.precision,
+        reference_id: config.reference_id,
+        // The local clock is its own reference.
+        reference_timestamp: receive,
+        origin_timestamp: request.transmit_timestamp,
+        receive_timestamp: receive,
+        ..NtpPacket::default()
+    };
+
+    if limited {
+        response.stratum = 0;
+        response.reference_id = *b"RATE";
+    }
+
+    response
+}
+
+/// Signs `response` with the key that signed `request`. A request with a MAC that can't
+/// be verified gets a crypto-NAK, and one without a MAC gets an unsigned response.
+pub fn authenticate(keys: &Keys, request: &NtpPacket, datagram: &[u8], response: &mut NtpPacket) {
+    let mac = match &request.mac {
+        Some(mac) => mac,
+        None => return,
+    };
+    match keys.get(mac.key_id) {
+        Some(key) if key.verify(request, datagram).is_ok() => key.sign(response),
+        _ => response.mac = Some(Mac { key_id: CRYPTO_NAK, digest: Vec::new() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::{self, KissCode, ResponseError};
+    use std::thread;
+
+    fn spawn(config: ServerConfig) -> SocketAddr {
+        let mut server = Server::bind("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+        addr
+    }
+
+    fn query(server: SocketAddr) -> (NtpPacket, NtpPacket) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let request = validate::request_with_nonce();
+        socket.send_to(&request.to_bytes(), server).unwrap();
+        let mut buffer = [0; 1024];
+        let length = socket.recv(&mut buffer).unwrap();
+        (request, NtpPacket::parse(&buffer[..length]).unwrap())
+    }
+
+    #[test]
+    fn parse_reference_id() {
+        assert_eq!(ServerConfig::parse_reference_id("GPS"), Some(*b"GPS\0"));
+        assert_eq!(ServerConfig::parse_reference_id("LOCL"), Some(*b"LOCL"));
+        assert_eq!(ServerConfig::parse_reference_id(""), None);
+        assert_eq!(ServerConfig::parse_reference_id("CLOCK"), None);
+        assert_eq!(ServerConfig::parse_reference_id("A B"), None);
+    }
+
+    #[test]
+    fn respond_echoes_the_request() {
+        let config = ServerConfig { stratum: 1, reference_id: *b"GPS\0", ..ServerConfig::default() };
+        let mut request = validate::request_with_nonce();
+        request.version = 3;
+        request.poll = 6;
+        let receive = NTPTimestamp { seconds: 3_900_000_000, fraction: 0 };
+
+        let response = respond(&config, &request, receive, false);
+        assert_eq!(response.mode, Mode::Server);
+        assert_eq!(response.version, 3);
+        assert_eq!(response.stratum, 1);
+        assert_eq!(response.poll, 6);
+        assert_eq!(response.reference_id_text(), Some("GPS"));
+        assert_eq!(response.origin_timestamp, request.transmit_timestamp);
+        assert_eq!(response.receive_timestamp, receive);
+
+        let kiss = respond(&config, &request, receive, true);
+        assert_eq!(kiss.stratum, 0);
+        assert_eq!(kiss.reference_id_text(), Some("RATE"));
+        assert_eq!(kiss.poll, 6);
+    }
+
+    #[test]
+    fn authenticate_signs_or_naks() {
+        let keys = Keys::parse("5 SHA1 shared").unwrap();
+        let key = keys.get(5).unwrap();
+        let answer = |request: &NtpPacket| {
+            let mut response = respond(&ServerConfig::default(), request, NTPTimestamp::ZERO, false);
+            authenticate(&keys, request, &request.to_bytes(), &mut response);
+            response
+        };
+
+        assert_eq!(answer(&NtpPacket::client()).mac, None);
+
+        let mut signed = NtpPacket::client();
+        key.sign(&mut signed);
+        let response = answer(&signed);
+        let bytes = response.to_bytes();
+        assert_eq!(key.verify(&NtpPacket::parse(&bytes).unwrap(), &bytes), Ok(()));
+
+        let mut unknown = NtpPacket::client();
+        unknown.mac = Some(Mac { key_id: 6, digest: vec![0; 20] });
+        assert_eq!(answer(&unknown).mac, Some(Mac { key_id: CRYPTO_NAK, digest: Vec::new() }));
+    }
+
+    #[test]
+    fn serves_the_local_clock() {
+        let config = ServerConfig { stratum: 3, offset: ChronoDuration::seconds(60), ..ServerConfig::default() };
+        let server = spawn(config);
+
+        let before: NTPTimestamp = (Utc::now() + ChronoDuration::seconds(60)).into();
+        let (request, response) = query(server);
+        let after: NTPTimestamp = (Utc::now() + ChronoDuration::seconds(60)).into();
+
+        assert_eq!(validate::validate(&request, &response), Ok(()));
+        assert_eq!(response.stratum, 3);
+        assert_eq!(response.reference_id_text(), Some("LOCL"));
+        assert!(before <= response.receive_timestamp);
+        assert!(response.receive_timestamp <= response.transmit_timestamp);
+        assert!(response.transmit_timestamp <= after);
+    }
+
+    #[test]
+    fn rate_limits_each_client() {
+        let server = spawn(ServerConfig { rate_limit: Duration::from_secs(60), ..ServerConfig::default() });
+
+        let (request, response) = query(server);
+        assert_eq!(validate::validate(&request, &response), Ok(()));
+        let (request, response) = query(server);
+        assert_eq!(validate::validate(&request, &response), Err(ResponseError::KissOfDeath(KissCode::Rate)));
+        assert!(response.poll >= 6);
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_clients() {
+        let config = ServerConfig { rate_limit: Duration::from_secs(60), ..ServerConfig::default() };
+        let mut server = Server::bind("127.0.0.1:0", config).unwrap();
+        let start = Instant::now();
+        let client = |n: u32| IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + n));
+
+        for n in 0..MAX_CLIENTS as u32 * 2 {
+            assert!(!server.rate_limited(client(n), start));
+        }
+        assert_eq!(server.last_request.len(), MAX_CLIENTS);
+        // Clients that are remembered are still limited, and the rest are let through.
+        assert!(server.rate_limited(client(0), start));
+        assert!(!server.rate_limited(client(MAX_CLIENTS as u32), start));
+
+        // Once the remembered clients have gone quiet, there's room for new ones.
+        let later = start + Duration::from_secs(600);
+        assert!(!server.rate_limited(client(MAX_CLIENTS as u32), later));
+        assert_eq!(server.last_request.len(), 1);
+        assert!(server.rate_limited(client(MAX_CLIENTS as u32), later));
+    }
+
+    #[test]
+    fn only_socket_errors_are_fatal() {
+        // What an ICMP port unreachable for an earlier response looks like on some systems.
+        assert!(is_transient(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(is_transient(&io::Error::from(io::ErrorKind::ConnectionRefused)));
+        assert!(is_transient(&io::Error::from(io::ErrorKind::NetworkUnreachable)));
+        assert!(!is_transient(&io::Error::other("bad file descriptor")));
+    }
+
+    #[test]
+    fn ignores_everything_but_client_requests() {
+        let server = spawn(ServerConfig::default());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        let mut not_a_request = NtpPacket::client();
+        not_a_request.mode = Mode::Server;
+        socket.send_to(&not_a_request.to_bytes(), server).unwrap();
+        socket.send_to(b"not ntp at all", server).unwrap();
+
+        let mut buffer = [0; 1024];
+        assert!(socket.recv(&mut buffer).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+/*!
+AES-SIV-CMAC-256 (RFC 5297), the authenticated encryption NTS uses to protect packets
+and cookies.
+
+SIV derives its IV from the message: a CMAC over the associated data and plaintext,
+called S2V. The IV doubles as the authentication tag, and then seeds AES-CTR to encrypt
+the plaintext. Reusing a nonce therefore leaks only whether two messages were equal,
+rather than the key stream as with AES-GCM, which makes it forgiving of a server that
+can't keep nonce state across restarts.
+
+The 32-byte key is two AES-128 keys: the first for S2V, the second for CTR.
+*/
+
+use aes::cipher::{KeyIvInit, StreamCipher};
+use aes::Aes128;
+use cmac::{Cmac, Mac};
+
+pub const KEY_LENGTH: usize = 32;
+/// The length of the synthetic IV, which is prepended to the ciphertext.
+pub const TAG_LENGTH: usize = 16;
+
+type Block = [u8; 16];
+
+#[derive(Clone)]
+pub struct Siv {
+    key: [u8; KEY_LENGTH],
+}
+
+impl Siv {
+    pub fn new(key: &[u8; KEY_LENGTH]) -> Siv {
+        Siv { key: *key }
+    }
+
+    /// Encrypts `plaintext`, authenticating it along with each of `associated`, and
+    /// returns the IV followed by the ciphertext.
+    pub fn seal(&self, associated: &[&[u8]], plaintext: &[u8]) -> Vec<u8> {
+        let iv = self.s2v(associated, plaintext);
+        let mut sealed = Vec::with_capacity(TAG_LENGTH + plaintext.len());
+        sealed.extend_from_slice(&iv);
+        sealed.extend_from_slice(plaintext);
+        self.ctr(&iv, &mut sealed[TAG_LENGTH..]);
+        sealed
+    }
+
+    /// Decrypts what `seal` returned, or returns `None` if it, or any of `associated`,
+    /// was altered.
+    pub fn open(&self, associated: &[&[u8]], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < TAG_LENGTH {
+            return None;
+        }
+        let (iv, ciphertext) = sealed.split_at(TAG_LENGTH);
+        let iv: Block = iv.try_into().unwrap();
+        let mut plaintext = ciphertext.to_vec();
+        self.ctr(&iv, &mut plaintext);
+
+        let expected = self.s2v(associated, &plaintext);
+        let diff = expected.iter().zip(&iv).fold(0, |diff, (x, y)| diff | (x ^ y));
+        if diff == 0 { Some(plaintext) } else { None }
+    }
+
+    fn cmac(&self, parts: &[&[u8]]) -> Block {
+        let mut mac = Cmac::<Aes128>::new_from_slice(&self.key[..16]).expect("AES-128 keys are 16 bytes");
+        for part in parts {
+            mac.update(part);
+        }
+        mac.finalize().into_bytes().into()
+    }
+
+    /// RFC 5297 section 2.4: a CMAC of every component, folded together so that
+    /// their boundaries count.
+    fn s2v(&self, associated: &[&[u8]], plaintext: &[u8]) -> Block {
+        let mut d = self.cmac(&[&[0; 16]]);
+        for component in associated {
+            d = xor(&dbl(&d), &self.cmac(&[component]));
+        }
+
+        if plaintext.len() >= 16 {
+            // XOR D into the last 16 bytes.
+            let (head, tail) = plaintext.split_at(plaintext.len() - 16);
+            self.cmac(&[head, &xor(tail.try_into().unwrap(), &d)])
+        } else {
+            let mut padded = [0; 16];
+            padded[..plaintext.len()].copy_from_slice(plaintext);
+            padded[plaintext.len()] = 0x80;
+            self.cmac(&[&xor(&dbl(&d), &padded)])
+        }
+    }
+
+    fn ctr(&self, iv: &Block, data: &mut [u8]) {
+        // Clearing these two bits lets CTR implementations use 32-bit counters.
+        let mut counter = *iv;
+        counter[8] &= 0x7f;
+        counter[12] &= 0x7f;
+        let mut cipher = ctr::Ctr128BE::<Aes128>::new((&self.key[16..]).into(), (&counter).into());
+        cipher.apply_keystream(data);
+    }
+}
+
+/// Multiplies by x in GF(2^128).
+fn dbl(block: &Block) -> Block {
+    let value = u128::from_be_bytes(*block);
+    let carry = if value >> 127 == 1 { 0x87 } else { 0 };
+    ((value << 1) ^ carry).to_be_bytes()
+}
+
+fn xor(a: &Block, b: &Block) -> Block {
+    (u128::from_be_bytes(*a) ^ u128::from_be_bytes(*b)).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn key(text: &str) -> [u8; KEY_LENGTH] {
+        hex(text).try_into().unwrap()
+    }
+
+    #[test]
+    fn deterministic_test_vector() {
+        // RFC 5297 appendix A.1.
+        let siv = Siv::new(&key("fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff"));
+        let associated = hex("101112131415161718191a1b1c1d1e1f2021222324252627");
+        let plaintext = hex("112233445566778899aabbccddee");
+
+        let sealed = siv.seal(&[&associated], &plaintext);
+        assert_eq!(sealed, hex("85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c"));
+        assert_eq!(siv.open(&[&associated], &sealed), Some(plaintext));
+    }
+
+    #[test]
+    fn nonce_based_test_vector() {
+        // RFC 5297 appendix A.2: several associated data components, the last a nonce,
+        // as NTS uses.
+        let siv = Siv::new(&key("7f7e7d7c7b7a79787776757473727170404142434445464748494a4b4c4d4e4f"));
+        let header = hex("00112233445566778899aabbccddeeffdeaddadadeaddadaffeeddccbbaa99887766554433221100");
+        let context = hex("102030405060708090a0");
+        let nonce = hex("09f911029d74e35bd84156c5635688c0");
+        let plaintext = hex(concat!(
+            "7468697320697320736f6d6520706c61696e7465787420746f20656e6372797074",
+            "207573696e67205349562d414553",
+        ));
+
+        let sealed = siv.seal(&[&header, &context, &nonce], &plaintext);
+        let expected = concat!(
+            "7bdb6e3b432667eb06f4d14bff2fbd0fcb900f2fddbe404326601965c889bf17",
+            "dba77ceb094fa663b7a3f748ba8af829ea64ad544a272e9c485b62a3fd5c0d",
+        );
+        assert_eq!(sealed, hex(expected));
+        assert_eq!(siv.open(&[&header, &context, &nonce], &sealed), Some(plaintext));
+    }
+
+    #[test]
+    fn empty_plaintext_test_vector() {
+        // Shaped like an NTS request: a packet and a nonce, sealing nothing. From
+        // another AES-SIV implementation.
+        let siv = Siv::new(&(0..KEY_LENGTH as u8).collect::<Vec<_>>().try_into().unwrap());
+        let mut packet = vec![0; 48];
+        packet[0] = 0x23;
+        let nonce: Vec<u8> = (0x10..0x20).collect();
+
+        let sealed = siv.seal(&[&packet, &nonce], &[]);
+        assert_eq!(sealed, hex("3777dcb75c59d2d268c392b961e4e365"));
+        assert_eq!(siv.open(&[&packet, &nonce], &sealed), Some(Vec::new()));
+    }
+
+    #[test]
+    fn long_plaintexts_round_trip() {
+        let siv = Siv::new(&[7; KEY_LENGTH]);
+        for length in [0, 15, 16, 17, 64, 100] {
+            let plaintext: Vec<u8> = (0..length as u8).collect();
+            let sealed = siv.seal(&[b"header", b"nonce"], &plaintext);
+            assert_eq!(sealed.len(), TAG_LENGTH + length);
+            assert_eq!(siv.open(&[b"header", b"nonce"], &sealed), Some(plaintext));
+        }
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let siv = Siv::new(&[7; KEY_LENGTH]);
+        let sealed = siv.seal(&[b"header", b"nonce"], b"a secret message");
+
+        for index in [0, TAG_LENGTH, sealed.len() - 1] {
+            let mut altered = sealed.clone();
+            altered[index] ^= 1;
+            assert_eq!(siv.open(&[b"header", b"nonce"], &altered), None);
+        }
+        assert_eq!(siv.open(&[b"header", b"other"], &sealed), None);
+        assert_eq!(siv.open(&[b"header"], &sealed), None);
+        assert_eq!(Siv::new(&[8; KEY_LENGTH]).open(&[b"header", b"nonce"], &sealed), None);
+        assert_eq!(siv.open(&[], &sealed[..TAG_LENGTH - 1]), None);
+    }
+}
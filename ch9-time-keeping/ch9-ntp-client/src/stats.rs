@@ -0,0 +1,283 @@
+/*!
+Summaries of the offset history, for `clock stats`.
+
+For each peer:
+
+- Percentiles of its offset, delay and jitter.
+- The frequency error of the local clock relative to the peer: the slope of a least
+  squares line through the offsets, negated, so that a clock that runs fast has a
+  positive error. While the daemon disciplines the clock, this is what remains of the
+  error after its correction.
+- The Allan deviation of the local clock at a range of averaging times τ. It measures
+  how much the frequency wanders. Typically it falls as τ grows while network jitter
+  dominates, then rises again once the oscillator's own drift takes over. The bottom
+  of that curve is the best poll interval. Samples are treated as evenly spaced, at
+  their median interval.
+*/
+
+use std::fmt;
+
+use crate::history::Record;
+
+/// Allan deviations are computed for at most this many averaging times.
+const MAX_TAUS: usize = 8;
+
+/// The `p`th percentile of `sorted`, interpolating between neighbours.
+pub fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (below, above) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+}
+
+/// The slope of the least squares line through `points` of `(time, offset)`.
+pub fn slope(points: &[(f64, f64)]) -> Option<f64> {
+    let n = points.len() as f64;
+    let mean_time = points.iter().map(|&(time, _)| time).sum::<f64>() / n;
+    let mean_offset = points.iter().map(|&(_, offset)| offset).sum::<f64>() / n;
+
+    let covariance: f64 = points.iter().map(|&(time, offset)| (time - mean_time) * (offset - mean_offset)).sum();
+    let variance: f64 = points.iter().map(|&(time, _)| (time - mean_time).powi(2)).sum();
+    if points.len() < 2 || variance == 0.0 { None } else { Some(covariance / variance) }
+}
+
+/// The Allan deviation at τ = `n` × `tau0`, from `phase` samples `tau0` seconds apart.
+pub fn allan_deviation(phase: &[f64], tau0: f64, n: usize) -> Option<f64> {
+    if n == 0 || phase.len() < 2 * n + 1 {
+        return None;
+    }
+    let terms = phase.len() - 2 * n;
+    let sum: f64 = (0..terms).map(|i| (phase[i + 2 * n] - 2.0 * phase[i + n] + phase[i]).powi(2)).sum();
+    let tau = n as f64 * tau0;
+    Some((sum / (2.0 * terms as f64 * tau * tau)).sqrt())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    pub name: String,
+    pub samples: usize,
+    /// Seconds from the first sample to the last.
+    pub span: f64,
+    /// The stratum in the latest sample.
+    pub stratum: u8,
+    /// The 5th, 50th and 95th percentiles of the offset.
+    pub offset: [f64; 3],
+    /// The median delay and jitter.
+    pub delay: f64,
+    pub jitter: f64,
+    /// In seconds per second, positive when the local clock is fast.
+    pub frequency_error: Option<f64>,
+    /// Pairs of an averaging time in seconds and the Allan deviation over it.
+    pub allan: Vec<(f64, f64)>,
+}
+
+impl PeerStats {
+    /// Summarizes `records`, which are all from one peer, oldest first.
+    fn new(records: &[&Record]) -> PeerStats {
+        let sorted = |value: fn(&Record) -> f64| {
+            let mut values: Vec<f64> = records.iter().map(|record| value(record)).collect();
+            values.sort_by(f64::total_cmp);
+            values
+        };
+        let offsets = sorted(|record| record.offset);
+        let first = records[0].time;
+
+        let points: Vec<(f64, f64)> = records.iter().map(|record| (record.time - first, record.offset)).collect();
+        let mut intervals: Vec<f64> = records.windows(2).map(|pair| pair[1].time - pair[0].time).collect();
+        intervals.sort_by(f64::total_cmp);
+        let mut allan = Vec::new();
+        if let Some(&tau0) = intervals.get(intervals.len() / 2).filter(|&&tau0| tau0 > 0.0) {
+            // The local clock's phase is the opposite of the peer's offset.
+            let phase: Vec<f64> = records.iter().map(|record| -record.offset).collect();
+            let taus = (0..MAX_TAUS).map(|exponent| 1 << exponent);
+            allan = taus
+                .map_while(|n| allan_deviation(&phase, tau0, n).map(|deviation| (n as f64 * tau0, deviation)))
+                .collect();
+        }
+
+        PeerStats {
+            name: records[0].peer.clone(),
+            samples: records.len(),
+            span: records[records.len() - 1].time - first,
+            stratum: records[records.len() - 1].stratum,
+            offset: [percentile(&offsets, 5.0), percentile(&offsets, 50.0), percentile(&offsets, 95.0)],
+            delay: percentile(&sorted(|record| record.delay), 50.0),
+            jitter: percentile(&sorted(|record| record.jitter), 50.0),
+            frequency_error: slope(&points).map(|slope| -slope),
+            allan,
+        }
+    }
+}
+
+/// Statistics for every peer in a history, in the order they first appear.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub peers: Vec<PeerStats>,
+}
+
+impl Summary {
+    pub fn new(records: &[Record]) -> Summary {
+        let mut names: Vec<&str> = Vec::new();
+        for record in records {
+            if !names.contains(&record.peer.as_str()) {
+                names.push(&record.peer);
+            }
+        }
+
+        let peers = names
+            .iter()
+            .map(|&name| {
+                let mut own: Vec<&Record> = records.iter().filter(|record| record.peer == name).collect();
+                own.sort_by(|a, b| a.time.total_cmp(&b.time));
+                PeerStats::new(&own)
+            })
+            .collect();
+        Summary { peers }
+    }
+}
+
+/// A duration in the largest unit that keeps it above 2, roughly.
+fn duration(seconds: f64) -> String {
+    match seconds {
+        s if s < 10.0 => format!("{:.1}s", s),
+        s if s < 120.0 => format!("{:.0}s", s),
+        s if s < 7200.0 => format!("{:.0}m", s / 60.0),
+        s if s < 172_800.0 => format!("{:.1}h", s / 3600.0),
+        s => format!("{:.1}d", s / 86_400.0),
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.peers.is_empty() {
+            return write!(f, "no samples yet");
+        }
+        let ms = |seconds: f64| format!("{:.3}", seconds * 1000.0);
+
+        writeln!(
+            f,
+            "{:<32} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9} {:>7} {:>9}",
+            "server", "samples", "span", "p5 ms", "p50 ms", "p95 ms", "delay ms", "jitter ms", "stratum", "freq ppm"
+        )?;
+        for peer in &self.peers {
+            writeln!(
+                f,
+                "{:<32} {:>7} {:>7} {:>9} {:>9} {:>9} {:>9} {:>9} {:>7} {:>9}",
+                peer.name,
+                peer.samples,
+                duration(peer.span),
+                ms(peer.offset[0]),
+                ms(peer.offset[1]),
+                ms(peer.offset[2]),
+                ms(peer.delay),
+                ms(peer.jitter),
+                peer.stratum,
+                peer.frequency_error.map_or("-".to_string(), |error| format!("{:+.3}", error * 1e6)),
+            )?;
+        }
+
+        write!(f, "\nAllan deviation")?;
+        for peer in &self.peers {
+            write!(f, "\n{:<32}", peer.name)?;
+            if peer.allan.is_empty() {
+                write!(f, " too few samples")?;
+            }
+            for &(tau, deviation) in &peer.allan {
+                write!(f, " {}: {:.2e}", duration(tau), deviation)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: f64, peer: &str, offset: f64) -> Record {
+        Record { time, peer: peer.to_string(), offset, delay: 0.02, jitter: 0.001, stratum: 2 }
+    }
+
+    #[test]
+    fn percentiles_interpolate() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 50.0), 3.0);
+        assert_eq!(percentile(&values, 100.0), 5.0);
+        assert!((percentile(&values, 90.0) - 4.6).abs() < 1e-12);
+        assert_eq!(percentile(&[7.0], 95.0), 7.0);
+    }
+
+    #[test]
+    fn slope_fits_a_line() {
+        let points: Vec<(f64, f64)> = (0..10).map(|t| (t as f64 * 64.0, 0.003 + 20e-6 * t as f64 * 64.0)).collect();
+        assert!((slope(&points).unwrap() - 20e-6).abs() < 1e-12);
+        assert_eq!(slope(&points[..1]), None);
+        assert_eq!(slope(&[(5.0, 1.0), (5.0, 2.0)]), None);
+    }
+
+    #[test]
+    fn allan_deviation_of_known_noise() {
+        // A constant frequency error has no instability at all.
+        let ramp: Vec<f64> = (0..20).map(|i| 1e-5 * i as f64).collect();
+        assert!(allan_deviation(&ramp, 16.0, 1).unwrap() < 1e-15);
+        assert!(allan_deviation(&ramp, 16.0, 4).unwrap() < 1e-15);
+
+        // Alternating phase: each second difference is 4a.
+        let alternating: Vec<f64> = (0..21).map(|i| if i % 2 == 0 { 1e-3 } else { -1e-3 }).collect();
+        let expected = 4e-3 / (16.0 * 2f64.sqrt());
+        assert!((allan_deviation(&alternating, 16.0, 1).unwrap() - expected).abs() < 1e-15);
+        // Over two samples the alternation cancels out.
+        assert!(allan_deviation(&alternating, 16.0, 2).unwrap() < 1e-15);
+
+        assert_eq!(allan_deviation(&alternating[..2], 16.0, 1), None);
+        assert_eq!(allan_deviation(&alternating, 16.0, 0), None);
+    }
+
+    #[test]
+    fn summarizes_each_peer() {
+        // A clock running 50 ppm fast, so each peer's offset falls over time.
+        let mut records = Vec::new();
+        for i in 0..40 {
+            let time = 1_760_000_000.0 + i as f64 * 64.0;
+            records.push(record(time, "fast", -50e-6 * i as f64 * 64.0));
+            if i % 2 == 0 {
+                records.push(record(time, "other (10.0.0.1)", 0.5));
+            }
+        }
+        records.push(Record { stratum: 3, ..record(1_760_010_000.0, "other (10.0.0.1)", 0.5) });
+
+        let summary = Summary::new(&records);
+        assert_eq!(summary.peers.len(), 2);
+
+        let fast = &summary.peers[0];
+        assert_eq!(fast.name, "fast");
+        assert_eq!(fast.samples, 40);
+        assert_eq!(fast.span, 39.0 * 64.0);
+        assert!((fast.frequency_error.unwrap() - 50e-6).abs() < 1e-12);
+        assert!((fast.offset[1] + 50e-6 * 19.5 * 64.0).abs() < 1e-9);
+        assert_eq!(fast.allan.len(), 5);
+        assert_eq!(fast.allan[0].0, 64.0);
+        assert_eq!(fast.allan[4].0, 1024.0);
+
+        let other = &summary.peers[1];
+        assert_eq!((other.samples, other.stratum), (21, 3));
+        assert_eq!(other.offset, [0.5; 3]);
+        assert_eq!(other.frequency_error, Some(-0.0));
+
+        let text = summary.to_string();
+        assert!(text.contains("fast"), "{}", text);
+        assert!(text.contains("+50.000"), "{}", text);
+        assert!(text.contains("42m"), "{}", text);
+        assert!(text.contains("Allan deviation"), "{}", text);
+    }
+
+    #[test]
+    fn empty_and_tiny_histories() {
+        assert_eq!(Summary::new(&[]).to_string(), "no samples yet");
+
+        let summary = Summary::new(&[record(0.0, "once", 0.25)]);
+        assert_eq!(summary.peers[0].frequency_error, None);
+        assert!(summary.peers[0].allan.is_empty());
+        assert!(summary.to_string().contains("too few samples"));
+    }
+}
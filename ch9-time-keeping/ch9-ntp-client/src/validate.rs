@@ -0,0 +1,316 @@
+/*!
+Checks that a response answers our request before any of its timestamps are used.
+
+UDP makes spoofing easy: anyone who can send a packet to our port can claim to be
+the server. The request's transmit timestamp is therefore a random nonce rather than
+the time, and a response only counts if its origin timestamp echoes it back. That also
+rejects stale responses to earlier requests. The client keeps the real send time, t1,
+to itself.
+*/
+
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+
+use crate::packet::{LeapIndicator, Mode, NTPTimestamp, NtpPacket};
+
+/// Responses whose root delay and dispersion add up to more than this many seconds
+/// are too far from a reference clock to be useful (MAXDIST in RFC 5905).
+pub const MAX_ROOT_DISTANCE: f64 = 1.5;
+/// The shortest and longest intervals between queries, as powers of two in seconds.
+pub const MIN_POLL: i8 = 4;
+pub const MAX_POLL: i8 = 17;
+
+/// The reason a server sent a kiss-o'-death (stratum 0) packet.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum KissCode {
+    /// Slow down: we're querying too often.
+    Rate,
+    /// Stop: access is denied.
+    Deny,
+    /// Stop: access is restricted.
+    Restrict,
+    Other([u8; 4]),
+}
+
+impl KissCode {
+    pub fn from_reference_id(id: [u8; 4]) -> Self {
+        match &id {
+            b"RATE" => KissCode::Rate,
+            b"DENY" => KissCode::Deny,
+            b"RSTR" => KissCode::Restrict,
+            _ => KissCode::Other(id),
+        }
+    }
+}
+
+impl fmt::Display for KissCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KissCode::Rate => write!(f, "RATE"),
+            KissCode::Deny => write!(f, "DENY"),
+            KissCode::Restrict => write!(f, "RSTR"),
+            KissCode::Other(id) => write!(f, "{}", String::from_utf8_lossy(id)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResponseError {
+    WrongMode(Mode),
+    WrongVersion { expected: u8, actual: u8 },
+    /// The origin timestamp isn't our nonce: the response is spoofed, or answers an
+    /// earlier request.
+    OriginMismatch,
+    KissOfDeath(KissCode),
+    /// The server's own clock isn't synchronized: stratum 16 or a leap indicator of 3.
+    Unsynchronized,
+    ZeroTimestamp,
+    /// The server claims to have sent the response before receiving the request.
+    TransmitBeforeReceive,
+    RootDistance(f64),
+}
+
+impl fmt::Display for ResponseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseError::WrongMode(mode) => write!(f, "expected a server response, got mode {:?}", mode),
+            ResponseError::WrongVersion { expected, actual } => {
+                write!(f, "expected NTP version {}, got {}", expected, actual)
+            }
+            ResponseError::OriginMismatch => write!(f, "response doesn't match our request"),
+            ResponseError::KissOfDeath(code) => write!(f, "kiss-o'-death {}", code),
+            ResponseError::Unsynchronized => write!(f, "server is unsynchronized"),
+            ResponseError::ZeroTimestamp => write!(f, "response has a zero timestamp"),
+            ResponseError::TransmitBeforeReceive => write!(f, "response was sent before the request arrived"),
+            ResponseError::RootDistance(distance) => write!(f, "server is {:.3}s from its reference clock", distance),
+        }
+    }
+}
+
+impl error::Error for ResponseError {}
+
+/// A client request whose transmit timestamp is a random nonce.
+pub fn request_with_nonce() -> NtpPacket {
+    let mut request = NtpPacket::client();
+    request.transmit_timestamp = NTPTimestamp::from_bits(rand::random());
+    request
+}
+
+/// Checks that `response` is a usable answer to `request`.
+pub fn validate(request: &NtpPacket, response: &NtpPacket) -> Result<(), ResponseError> {
+    if response.mode != Mode::Server {
+        return Err(ResponseError::WrongMode(response.mode));
+    }
+    if response.version != request.version {
+        return Err(ResponseError::WrongVersion { expected: request.version, actual: response.version });
+    }
+    // Before anything else is believed, including a kiss-o'-death: otherwise a spoofed
+    // DENY would be enough to make us drop a server.
+    if response.origin_timestamp != request.transmit_timestamp {
+        return Err(ResponseError::OriginMismatch);
+    }
+    if response.stratum == 0 {
+        return Err(ResponseError::KissOfDeath(KissCode::from_reference_id(response.reference_id)));
+    }
+    if response.stratum >= 16 || response.leap == LeapIndicator::Unsynchronized {
+        return Err(ResponseError::Unsynchronized);
+    }
+    if response.receive_timestamp.is_zero() || response.transmit_timestamp.is_zero() {
+        return Err(ResponseError::ZeroTimestamp);
+    }
+    if response.transmit_timestamp < response.receive_timestamp {
+        return Err(ResponseError::TransmitBeforeReceive);
+    }
+    let distance = response.root_delay.to_seconds() / 2.0 + response.root_dispersion.to_seconds();
+    if distance > MAX_ROOT_DISTANCE {
+        return Err(ResponseError::RootDistance(distance));
+    }
+
+    Ok(())
+}
+
+/// Whether a server may be queried now.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Permission {
+    Allowed,
+    Wait(Duration),
+    Denied(KissCode),
+}
+
+/// Tracks how a server has asked to be treated, from the kiss-o'-death packets it sent.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    poll: i8,
+    not_before: Option<Instant>,
+    denied: Option<KissCode>,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff { poll: MIN_POLL, not_before: None, denied: None }
+    }
+}
+
+impl Backoff {
+    pub fn permission(&self, now: Instant) -> Permission {
+        match (self.denied, self.not_before) {
+            (Some(code), _) => Permission::Denied(code),
+            (None, Some(not_before)) if now < not_before => Permission::Wait(not_before - now),
+            _ => Permission::Allowed,
+        }
+    }
+
+    /// The interval between queries, doubled for every RATE in a row.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(1 << self.poll)
+    }
+
+    /// Updates the state after a query answered with `response`.
+    pub fn record(&mut self, response: &NtpPacket, result: &Result<(), ResponseError>, now: Instant) {
+        match result {
+            Ok(()) => self.poll = (self.poll - 1).max(MIN_POLL),
+            Err(ResponseError::KissOfDeath(KissCode::Rate)) => {
+                // The server's poll field says how often it's willing to hear from us.
+                self.poll = (self.poll + 1).max(response.poll).clamp(MIN_POLL, MAX_POLL);
+                self.not_before = Some(now + self.interval());
+            }
+            Err(ResponseError::KissOfDeath(code @ (KissCode::Deny | KissCode::Restrict))) => self.denied = Some(*code),
+            Err(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::NtpShort;
+
+    fn exchange() -> (NtpPacket, NtpPacket) {
+        let request = request_with_nonce();
+        let response = NtpPacket {
+            mode: Mode::Server,
+            stratum: 2,
+            poll: 6,
+            precision: -20,
+            root_delay: NtpShort::from_seconds(0.02),
+            root_dispersion: NtpShort::from_seconds(0.03),
+            reference_id: [192, 0, 2, 1],
+            origin_timestamp: request.transmit_timestamp,
+            receive_timestamp: NTPTimestamp { seconds: 3_900_000_000, fraction: 1 },
+            transmit_timestamp: NTPTimestamp { seconds: 3_900_000_000, fraction: 2 },
+            ..NtpPacket::default()
+        };
+        (request, response)
+    }
+
+    fn kiss(code: &[u8; 4], poll: i8) -> (NtpPacket, NtpPacket) {
+        let (request, mut response) = exchange();
+        response.stratum = 0;
+        response.poll = poll;
+        response.reference_id = *code;
+        (request, response)
+    }
+
+    #[test]
+    fn nonces_differ() {
+        assert_ne!(request_with_nonce().transmit_timestamp, request_with_nonce().transmit_timestamp);
+    }
+
+    #[test]
+    fn valid_response() {
+        let (request, response) = exchange();
+        assert_eq!(validate(&request, &response), Ok(()));
+    }
+
+    #[test]
+    fn invalid_responses() {
+        type Corruption = fn(&mut NtpPacket);
+        let cases: Vec<(Corruption, ResponseError)> = vec![
+            (|r| r.mode = Mode::Client, ResponseError::WrongMode(Mode::Client)),
+            (|r| r.mode = Mode::Broadcast, ResponseError::WrongMode(Mode::Broadcast)),
+            (|r| r.version = 3, ResponseError::WrongVersion { expected: 4, actual: 3 }),
+            (|r| r.origin_timestamp.fraction ^= 1, ResponseError::OriginMismatch),
+            (|r| r.origin_timestamp = NTPTimestamp::ZERO, ResponseError::OriginMismatch),
+            (|r| r.stratum = 16, ResponseError::Unsynchronized),
+            (|r| r.leap = LeapIndicator::Unsynchronized, ResponseError::Unsynchronized),
+            (|r| r.receive_timestamp = NTPTimestamp::ZERO, ResponseError::ZeroTimestamp),
+            (|r| r.transmit_timestamp = NTPTimestamp::ZERO, ResponseError::ZeroTimestamp),
+            (|r| r.transmit_timestamp.fraction = 0, ResponseError::TransmitBeforeReceive),
+        ];
+
+        for (corrupt, expected) in cases {
+            let (request, mut response) = exchange();
+            corrupt(&mut response);
+            assert_eq!(validate(&request, &response), Err(expected));
+        }
+
+        let (request, mut response) = exchange();
+        response.root_dispersion = NtpShort::from_seconds(2.0);
+        match validate(&request, &response) {
+            Err(ResponseError::RootDistance(distance)) => assert!((distance - 2.01).abs() < 1e-4),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn kiss_of_death() {
+        for (code, expected) in [(b"RATE", KissCode::Rate), (b"DENY", KissCode::Deny), (b"RSTR", KissCode::Restrict)] {
+            let (request, response) = kiss(code, 0);
+            assert_eq!(validate(&request, &response), Err(ResponseError::KissOfDeath(expected)));
+        }
+        let (request, response) = kiss(b"INIT", 0);
+        assert_eq!(validate(&request, &response), Err(ResponseError::KissOfDeath(KissCode::Other(*b"INIT"))));
+
+        // A kiss-o'-death that doesn't echo our nonce could come from anyone.
+        let (request, mut response) = kiss(b"DENY", 0);
+        response.origin_timestamp = NTPTimestamp::ZERO;
+        assert_eq!(validate(&request, &response), Err(ResponseError::OriginMismatch));
+    }
+
+    #[test]
+    fn backoff_on_rate() {
+        let start = Instant::now();
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.permission(start), Permission::Allowed);
+
+        let (request, response) = kiss(b"RATE", 0);
+        backoff.record(&response, &validate(&request, &response), start);
+        assert_eq!(backoff.interval(), Duration::from_secs(32));
+        assert_eq!(backoff.permission(start), Permission::Wait(Duration::from_secs(32)));
+        assert_eq!(backoff.permission(start + Duration::from_secs(32)), Permission::Allowed);
+
+        // Each RATE in a row doubles the interval, and the server's poll sets a floor.
+        backoff.record(&response, &validate(&request, &response), start);
+        assert_eq!(backoff.interval(), Duration::from_secs(64));
+        let (request, response) = kiss(b"RATE", 10);
+        backoff.record(&response, &validate(&request, &response), start);
+        assert_eq!(backoff.interval(), Duration::from_secs(1024));
+        let (request, response) = kiss(b"RATE", 40);
+        backoff.record(&response, &validate(&request, &response), start);
+        assert_eq!(backoff.interval(), Duration::from_secs(1 << MAX_POLL));
+
+        // Good responses bring it back down.
+        let (request, response) = exchange();
+        backoff.record(&response, &validate(&request, &response), start);
+        assert_eq!(backoff.interval(), Duration::from_secs(1 << (MAX_POLL - 1)));
+    }
+
+    #[test]
+    fn deny_and_restrict_are_permanent() {
+        for (code, expected) in [(b"DENY", KissCode::Deny), (b"RSTR", KissCode::Restrict)] {
+            let now = Instant::now();
+            let mut backoff = Backoff::default();
+            let (request, response) = kiss(code, 0);
+            backoff.record(&response, &validate(&request, &response), now);
+            assert_eq!(backoff.permission(now + Duration::from_secs(1 << 20)), Permission::Denied(expected));
+        }
+
+        // Spoofed ones are ignored.
+        let now = Instant::now();
+        let mut backoff = Backoff::default();
+        let (request, mut response) = kiss(b"DENY", 0);
+        response.origin_timestamp.seconds += 1;
+        backoff.record(&response, &validate(&request, &response), now);
+        assert_eq!(backoff.permission(now), Permission::Allowed);
+    }
+}
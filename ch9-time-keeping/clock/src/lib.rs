@@ -0,0 +1,120 @@
+/*!
+Reading and setting the time, shared by `ch9-clock0` and `ch9-ntp-client`.
+
+The [`Clock`] trait has three implementations:
+
+- [`SystemClock`], the operating system's clock. Setting it needs privileges: root or
+  `CAP_SYS_TIME` on Linux, `SeSystemtimePrivilege` on Windows.
+- [`MockClock`], which stands still until told otherwise and remembers what it was
+  set to, for tests.
+- [`OffsetClock`], which runs a fixed offset ahead of (or behind) another clock.
+  Setting it changes the offset, so that a program can keep time of its own without
+  touching the clock it's built on.
+
+Clocks deal in UTC; [`Clock::local`] is there for display.
+*/
+
+use std::{error, fmt, io};
+
+use chrono::{DateTime, Local, Utc};
+
+mod mock;
+mod offset;
+mod system;
+
+pub use mock::MockClock;
+pub use offset::OffsetClock;
+pub use system::SystemClock;
+
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError>;
+
+    /// The current time in the local time zone.
+    fn local(&self) -> DateTime<Local> {
+        self.now().with_timezone(&Local)
+    }
+}
+
+#[derive(Debug)]
+pub enum ClockError {
+    /// The process isn't allowed to set the clock.
+    PermissionDenied,
+    /// The time is one the clock can't hold, such as one before 1970 on Unix.
+    OutOfRange(DateTime<Utc>),
+    Os(io::Error),
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockError::PermissionDenied => write!(f, "permission denied: setting the clock needs administrator rights"),
+            ClockError::OutOfRange(t) => write!(f, "{} is out of the clock's range", t.to_rfc3339()),
+            ClockError::Os(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ClockError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ClockError::Os(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClockError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => ClockError::PermissionDenied,
+            _ => ClockError::Os(err),
+        }
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &mut C {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        (**self).set(t)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Box<C> {
+    fn now(&self) -> DateTime<Utc> {
+        (**self).now()
+    }
+
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        (**self).set(t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_from_the_os() {
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(matches!(ClockError::from(denied), ClockError::PermissionDenied));
+
+        let other = io::Error::new(io::ErrorKind::InvalidInput, "bad time");
+        let err = ClockError::from(other);
+        assert!(matches!(err, ClockError::Os(_)));
+        assert_eq!(err.to_string(), "bad time");
+    }
+
+    #[test]
+    fn boxed_clocks() {
+        let start = Utc::now();
+        let mut clock: Box<dyn Clock> = Box::new(MockClock::new(start));
+        assert_eq!(clock.now(), start);
+        clock.set(start + chrono::Duration::hours(1)).unwrap();
+        assert_eq!(clock.now() - start, chrono::Duration::hours(1));
+    }
+}
@@ -0,0 +1,80 @@
+/*!
+A clock for tests, which only moves when told to.
+*/
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{Clock, ClockError};
+
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: DateTime<Utc>,
+    read_only: bool,
+    sets: Vec<DateTime<Utc>>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> MockClock {
+        MockClock { now, read_only: false, sets: Vec::new() }
+    }
+
+    /// A clock that refuses to be set, like the system clock of an unprivileged process.
+    pub fn read_only(now: DateTime<Utc>) -> MockClock {
+        MockClock { read_only: true, ..MockClock::new(now) }
+    }
+
+    pub fn advance(&mut self, by: Duration) {
+        self.now += by;
+    }
+
+    /// Every time the clock was set to, oldest first.
+    pub fn sets(&self) -> &[DateTime<Utc>] {
+        &self.sets
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.now
+    }
+
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        if self.read_only {
+            return Err(ClockError::PermissionDenied);
+        }
+        self.now = t;
+        self.sets.push(t);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn stands_still_until_moved() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 30, 23, 59, 59).unwrap();
+        let mut clock = MockClock::new(start);
+        assert_eq!(clock.now(), start);
+
+        clock.advance(Duration::milliseconds(1500));
+        assert_eq!(clock.now(), start + Duration::milliseconds(1500));
+
+        let later = Utc.with_ymd_and_hms(2025, 1, 1, 0, 0, 0).unwrap();
+        clock.set(later).unwrap();
+        clock.set(start).unwrap();
+        assert_eq!(clock.now(), start);
+        assert_eq!(clock.sets(), &[later, start]);
+    }
+
+    #[test]
+    fn read_only_clocks_refuse_to_be_set() {
+        let start = Utc.with_ymd_and_hms(2024, 6, 30, 0, 0, 0).unwrap();
+        let mut clock = MockClock::read_only(start);
+        assert!(matches!(clock.set(Utc::now()), Err(ClockError::PermissionDenied)));
+        assert_eq!(clock.now(), start);
+        assert!(clock.sets().is_empty());
+    }
+}
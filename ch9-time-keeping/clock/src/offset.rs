@@ -0,0 +1,78 @@
+/*!
+A clock that keeps its own time on top of another, by a fixed offset from it.
+*/
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{Clock, ClockError};
+
+#[derive(Debug, Clone)]
+pub struct OffsetClock<C> {
+    inner: C,
+    offset: Duration,
+}
+
+impl<C: Clock> OffsetClock<C> {
+    pub fn new(inner: C, offset: Duration) -> OffsetClock<C> {
+        OffsetClock { inner, offset }
+    }
+
+    /// How far ahead of the inner clock this one runs.
+    pub fn offset(&self) -> Duration {
+        self.offset
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+}
+
+impl<C: Clock> Clock for OffsetClock<C> {
+    fn now(&self) -> DateTime<Utc> {
+        self.inner.now() + self.offset
+    }
+
+    /// Changes the offset, leaving the inner clock alone.
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        self.offset = t - self.inner.now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MockClock;
+    use chrono::TimeZone;
+
+    #[test]
+    fn runs_ahead_of_its_inner_clock() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut clock = OffsetClock::new(MockClock::new(start), Duration::seconds(-30));
+        assert_eq!(clock.now(), start - Duration::seconds(30));
+
+        clock.set(start + Duration::minutes(5)).unwrap();
+        assert_eq!(clock.offset(), Duration::minutes(5));
+        assert_eq!(clock.now(), start + Duration::minutes(5));
+
+        let inner = clock.into_inner();
+        assert_eq!(inner.now(), start);
+        assert!(inner.sets().is_empty());
+    }
+
+    #[test]
+    fn follows_its_inner_clock() {
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let mut inner = MockClock::new(start);
+        {
+            let clock = OffsetClock::new(&mut inner, Duration::seconds(2));
+            clock.inner.advance(Duration::seconds(10));
+            assert_eq!(clock.now(), start + Duration::seconds(12));
+        }
+        assert_eq!(inner.now(), start + Duration::seconds(10));
+    }
+}
@@ -0,0 +1,104 @@
+/*!
+The operating system's clock: `settimeofday` on Unix, `SetSystemTime` on Windows.
+*/
+
+use std::io;
+use std::mem::zeroed;
+
+use chrono::{DateTime, Utc};
+
+use crate::{Clock, ClockError};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    #[cfg(not(windows))]
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        use libc::{settimeofday, suseconds_t, time_t, timeval, timezone};
+
+        let seconds = match time_t::try_from(t.timestamp()) {
+            Ok(seconds) if seconds >= 0 => seconds,
+            _ => return Err(ClockError::OutOfRange(t)),
+        };
+        let mut u: timeval = unsafe { zeroed() };
+        u.tv_sec = seconds;
+        // chrono counts a leap second as a second or more of nanoseconds.
+        u.tv_usec = t.timestamp_subsec_micros().min(999_999) as suseconds_t;
+
+        // The time zone argument is obsolete, and should be null.
+        let mock_tz: *const timezone = std::ptr::null();
+        if unsafe { settimeofday(&u as *const timeval, mock_tz) } != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    fn set(&mut self, t: DateTime<Utc>) -> Result<(), ClockError> {
+        use chrono::{Datelike, Timelike};
+        use kernel32::SetSystemTime;
+        use winapi::{SYSTEMTIME, WORD};
+
+        /// What `SetSystemTime` fails with when the process lacks `SeSystemtimePrivilege`.
+        const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+        // SYSTEMTIME starts at 1601, and a WORD runs out in 30827.
+        if !(1601..=30827).contains(&t.year()) {
+            return Err(ClockError::OutOfRange(t));
+        }
+
+        let mut ns = t.nanosecond();
+        let mut leap = 0;
+        let is_leap_second = ns >= 1_000_000_000;
+
+        if is_leap_second {
+            ns -= 1_000_000_000;
+            leap += 1;
+        }
+
+        // SetSystemTime takes UTC, not local time.
+        let mut systime: SYSTEMTIME = unsafe { zeroed() };
+        systime.wYear = t.year() as WORD;
+        systime.wMonth = t.month() as WORD;
+        systime.wDayOfWeek = t.weekday().num_days_from_sunday() as WORD;
+        systime.wDay = t.day() as WORD;
+        systime.wHour = t.hour() as WORD;
+        systime.wMinute = t.minute() as WORD;
+        systime.wSecond = (leap + t.second()) as WORD;
+        systime.wMilliseconds = (ns / 1_000_000) as WORD;
+
+        let systime_ptr = &systime as *const SYSTEMTIME;
+        if unsafe { SetSystemTime(systime_ptr) } == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) {
+                return Err(ClockError::PermissionDenied);
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn reads_the_time() {
+        let before = Utc::now();
+        let now = SystemClock.now();
+        assert!(now >= before && now <= Utc::now());
+    }
+
+    #[test]
+    fn refuses_times_it_cannot_hold() {
+        let t = Utc.with_ymd_and_hms(1500, 1, 1, 0, 0, 0).unwrap();
+        assert!(matches!(SystemClock.set(t), Err(ClockError::OutOfRange(_))));
+    }
+}